@@ -0,0 +1,41 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
+
+use deck_core::OutputId;
+
+/// Error returned by a `BinaryCache`.
+#[derive(Debug)]
+pub enum CacheError {
+    /// The requested output is not available in the cache.
+    NotFound(OutputId),
+    /// The cache responded with an unexpected HTTP status code.
+    HttpStatus(u16),
+    /// An I/O error occurred while communicating with the cache.
+    Io(IoError),
+}
+
+impl Display for CacheError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            CacheError::NotFound(ref id) => write!(fmt, "output `{}` not found in cache", id),
+            CacheError::HttpStatus(status) => write!(fmt, "cache returned HTTP status {}", status),
+            CacheError::Io(ref e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl Error for CacheError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CacheError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<IoError> for CacheError {
+    fn from(e: IoError) -> Self {
+        CacheError::Io(e)
+    }
+}
@@ -4,6 +4,7 @@
 
 pub extern crate deck_core as core;
 
+pub use self::error::CacheError;
 #[cfg(feature = "local")]
 pub use self::local::LocalCache;
 #[cfg(feature = "s3")]
@@ -16,6 +17,7 @@ use std::pin::Pin;
 use deck_core::OutputId;
 use futures::stream::Stream;
 
+mod error;
 mod https;
 #[cfg(feature = "local")]
 mod local;
@@ -26,10 +28,13 @@ mod s3;
 // types, this type alias, or `Pin<Box<_>>`. Replace _immediately_ once `async fn` in traits is
 // stabilized in Rust.
 
-pub type BinaryCacheFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ()>> + Send + 'a>>;
-pub type OutputStream<'a> = Pin<Box<dyn Stream<Item = Result<Vec<u8>, ()>> + Send + 'a>>;
+pub type BinaryCacheFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, CacheError>> + Send + 'a>>;
+pub type OutputStream<'a> = Pin<Box<dyn Stream<Item = Result<Vec<u8>, CacheError>> + Send + 'a>>;
 
 pub trait BinaryCache: Debug {
-    fn query_outputs<'a>(&'a mut self, id: &'a OutputId) -> BinaryCacheFuture<'a, ()>;
+    /// Returns whether the output `id` can be fetched from this cache.
+    fn query_outputs<'a>(&'a mut self, id: &'a OutputId) -> BinaryCacheFuture<'a, bool>;
+    /// Streams the tree of the output `id`, as written by `archive::write_tree()` in `deck-store`.
     fn fetch_output<'a>(&'a mut self, id: &'a OutputId) -> OutputStream<'a>;
 }
@@ -0,0 +1,87 @@
+//! Ways of reaching the store.
+//!
+//! In single-user mode, the client opens the store directly through a `LocalStore`. In multi-user
+//! mode, only `deck-daemon` may touch the store, and the client sends its requests to the daemon
+//! through a `DaemonBackend` instead.
+
+#[cfg(feature = "multi-user-mode")]
+pub use self::daemon::DaemonBackend;
+
+use std::fmt::Debug;
+
+use deck_core::{Manifest, ManifestId};
+use deck_store::progress::Action;
+use deck_store::{
+    BuildOptions, BuildStream, CheckContents, LogOptions, LogStream, Repair, StoreFuture,
+    VerifyStream,
+};
+
+#[cfg(feature = "multi-user-mode")]
+mod daemon;
+#[cfg(feature = "local")]
+mod local;
+
+/// Changes to the store caused by installing and removing a set of packages.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Transaction {
+    /// Every package in the closures of the installed packages, along with how it would be
+    /// brought into the store, sorted by ID.
+    pub installed: Vec<(ManifestId, Action)>,
+    /// Packages which would be removed.
+    pub removed: Vec<ManifestId>,
+}
+
+/// Operations which a `Client` forwards to the store.
+pub trait Backend: Debug + Send {
+    /// Computes what installing the packages in `install` and removing the packages in `remove`
+    /// would do, without downloading or building anything.
+    fn transaction_diff<'a>(
+        &'a mut self,
+        install: &'a [ManifestId],
+        remove: &'a [ManifestId],
+    ) -> StoreFuture<'a, Transaction>;
+
+    /// Builds every package in `ids` at the same time according to `options`, fetching their
+    /// manifests if needed.
+    fn build_packages(&mut self, ids: Vec<ManifestId>, options: BuildOptions) -> BuildStream;
+
+    /// Builds the package described by `manifest` according to `options`.
+    fn build_manifest(&mut self, manifest: Manifest, options: BuildOptions) -> BuildStream;
+
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a>;
+
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a>;
+
+    /// Lists the IDs of every manifest in the store, sorted by name and version.
+    fn manifests<'a>(&'a mut self) -> StoreFuture<'a, Vec<ManifestId>>;
+}
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn transaction_diff<'a>(
+        &'a mut self,
+        install: &'a [ManifestId],
+        remove: &'a [ManifestId],
+    ) -> StoreFuture<'a, Transaction> {
+        (**self).transaction_diff(install, remove)
+    }
+
+    fn build_packages(&mut self, ids: Vec<ManifestId>, options: BuildOptions) -> BuildStream {
+        (**self).build_packages(ids, options)
+    }
+
+    fn build_manifest(&mut self, manifest: Manifest, options: BuildOptions) -> BuildStream {
+        (**self).build_manifest(manifest, options)
+    }
+
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a> {
+        (**self).get_build_log(id, options)
+    }
+
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+        (**self).verify(check, repair)
+    }
+
+    fn manifests<'a>(&'a mut self) -> StoreFuture<'a, Vec<ManifestId>> {
+        (**self).manifests()
+    }
+}
@@ -0,0 +1,278 @@
+//! Multi-user mode, where the client sends its requests to `deck-daemon` over gRPC.
+
+use std::convert::TryFrom;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use deck_core::{Manifest, ManifestId};
+use deck_protocol::daemon::client::Daemon as DaemonClient;
+use deck_protocol::daemon::diff_response::install_details::{Kind, Local};
+use deck_protocol::daemon::{verify_request, AddManifestRequest, DiffResponse};
+use deck_protocol::daemon::{BuildRequest, DiffRequest, ListRequest, LogRequest, VerifyRequest};
+use deck_protocol::{from_status, ConversionError};
+use deck_store::progress::{Action, Progress};
+use deck_store::verify::Verified;
+use deck_store::{BuildOptions, BuildStream, CheckContents, LogOptions, LogStream, Repair};
+use deck_store::{StoreError, StoreFuture, VerifyStream};
+use futures::{Future as Future01, Stream as Stream01};
+use futures_preview::compat::{Future01CompatExt, Stream01CompatExt};
+use futures_preview::future::{self, FutureExt};
+use futures_preview::stream;
+use http::Uri;
+use tokio::executor::DefaultExecutor;
+use tokio::net::unix::ConnectFuture;
+use tokio::net::UnixStream;
+use tokio_connect::Connect;
+use tower_grpc::{BoxBody, Request};
+use tower_h2::client;
+use tower_http::add_origin::{AddOrigin, Builder};
+use tower_util::MakeService;
+
+use super::{Backend, Transaction};
+
+type Client = DaemonClient<AddOrigin<client::Connection<UnixStream, DefaultExecutor, BoxBody>>>;
+
+/// Connects to the socket at the given path.
+#[derive(Debug)]
+struct Socket(PathBuf);
+
+impl Connect for Socket {
+    type Connected = UnixStream;
+    type Error = io::Error;
+    type Future = ConnectFuture;
+
+    fn connect(&self) -> Self::Future {
+        UnixStream::connect(&self.0)
+    }
+}
+
+/// Store managed by a `deck-daemon` listening on a Unix domain socket.
+///
+/// Every request is sent over a connection of its own, so that a long-running build does not hold
+/// up other requests. Dropping a stream returned by the backend closes its connection, which
+/// cancels the request on the daemon.
+///
+/// Requests must be made from within a `tokio` runtime.
+#[derive(Debug)]
+pub struct DaemonBackend {
+    socket: PathBuf,
+}
+
+impl DaemonBackend {
+    /// Creates a backend which connects to the daemon listening on `socket`.
+    ///
+    /// No connection is made until the first request.
+    pub fn new(socket: PathBuf) -> Self {
+        DaemonBackend { socket }
+    }
+
+    /// Returns the path of the socket the daemon listens on.
+    #[inline]
+    pub fn socket_path(&self) -> &Path {
+        &self.socket
+    }
+
+    fn connect(&self) -> impl Future01<Item = Client, Error = StoreError> + Send {
+        let socket = Socket(self.socket.clone());
+        let display = self.socket.display().to_string();
+        let mut connect =
+            client::Connect::new(socket, Default::default(), DefaultExecutor::current());
+
+        connect.make_service(()).then(move |connection| {
+            let uri: Uri = "http://localhost".parse().expect("URI is valid");
+            connection
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|conn| {
+                    Builder::new()
+                        .uri(uri)
+                        .build(conn)
+                        .map_err(|e| format!("{:?}", e))
+                })
+                .map(DaemonClient::new)
+                .map_err(|e| {
+                    StoreError::Remote(format!("failed to connect to daemon at {}: {}", display, e))
+                })
+        })
+    }
+}
+
+impl Backend for DaemonBackend {
+    fn transaction_diff<'a>(
+        &'a mut self,
+        install: &'a [ManifestId],
+        remove: &'a [ManifestId],
+    ) -> StoreFuture<'a, Transaction> {
+        let request = DiffRequest {
+            to_install: install.iter().map(ToString::to_string).collect(),
+            to_upgrade: Vec::new(),
+            to_uninstall: remove.iter().map(ToString::to_string).collect(),
+        };
+
+        let diff = self
+            .connect()
+            .and_then(|mut client| {
+                let diff = client.get_transaction_diff(Request::new(request));
+                diff.map_err(from_status)
+            })
+            .and_then(|response| to_transaction(response.into_inner()));
+
+        diff.compat().boxed()
+    }
+
+    fn build_packages(&mut self, ids: Vec<ManifestId>, options: BuildOptions) -> BuildStream {
+        let request = BuildRequest {
+            manifest_id: ids.iter().map(ToString::to_string).collect(),
+            keep_going: options.keep_going,
+            check: options.check,
+        };
+
+        let progress = self
+            .connect()
+            .and_then(|mut client| {
+                let progress = client.build_manifest(Request::new(request));
+                progress.map_err(from_status)
+            })
+            .map(|response| response.into_inner().map_err(from_status))
+            .flatten_stream()
+            .and_then(|message| Progress::try_from(message).map_err(from_conversion));
+
+        BuildStream::new(progress.compat())
+    }
+
+    /// Adds `manifest` to the store of the daemon, and then builds it.
+    ///
+    /// The daemon only accepts manifests from trusted users, so this fails for everyone else.
+    fn build_manifest(&mut self, manifest: Manifest, options: BuildOptions) -> BuildStream {
+        let request = AddManifestRequest {
+            manifest: manifest.to_string(),
+        };
+
+        let mut backend = DaemonBackend::new(self.socket.clone());
+        let added = self
+            .connect()
+            .and_then(|mut client| {
+                let added = client.add_manifest(Request::new(request));
+                added.map_err(from_status)
+            })
+            .and_then(|response| parse_id("manifest_id", &response.into_inner().manifest_id));
+
+        let progress = added
+            .compat()
+            .map(move |added| match added {
+                Ok(id) => backend.build_packages(vec![id], options),
+                Err(err) => BuildStream::new(stream::once(future::ready(Err(err)))),
+            })
+            .flatten_stream();
+
+        BuildStream::new(progress)
+    }
+
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a> {
+        let request = LogRequest {
+            manifest_id: id.to_string(),
+            offset: options.offset,
+            tail: options.tail.unwrap_or(0) as u64,
+            follow: options.follow,
+        };
+
+        let chunks = self
+            .connect()
+            .and_then(|mut client| {
+                let chunks = client.get_build_log(Request::new(request));
+                chunks.map_err(from_status)
+            })
+            .map(|response| response.into_inner().map_err(from_status))
+            .flatten_stream()
+            .map(|message| message.description);
+
+        LogStream::new(chunks.compat())
+    }
+
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+        let check_contents = match check {
+            CheckContents::Enabled => verify_request::CheckContents::Enabled,
+            CheckContents::Disabled => verify_request::CheckContents::Disabled,
+        };
+        let repair = match repair {
+            Repair::Enabled => verify_request::Repair::Enabled,
+            Repair::Disabled => verify_request::Repair::Disabled,
+        };
+        let request = VerifyRequest {
+            check_contents: check_contents as i32,
+            repair: repair as i32,
+        };
+
+        let results = self
+            .connect()
+            .and_then(|mut client| {
+                let results = client.verify(Request::new(request));
+                results.map_err(from_status)
+            })
+            .map(|response| response.into_inner().map_err(from_status))
+            .flatten_stream()
+            .and_then(|message| Verified::try_from(message).map_err(from_conversion));
+
+        VerifyStream::new(results.compat())
+    }
+
+    fn manifests<'a>(&'a mut self) -> StoreFuture<'a, Vec<ManifestId>> {
+        let ids = self
+            .connect()
+            .and_then(|mut client| {
+                let ids = client.list_manifests(Request::new(ListRequest {}));
+                ids.map_err(from_status)
+            })
+            .and_then(|response| {
+                let ids = response.into_inner().manifest_id;
+                ids.iter()
+                    .map(|id| parse_id("manifest_id", id))
+                    .collect::<Result<_, _>>()
+            });
+
+        ids.compat().boxed()
+    }
+}
+
+fn to_transaction(diff: DiffResponse) -> Result<Transaction, StoreError> {
+    let installed = diff
+        .installed
+        .into_iter()
+        .map(|installed| {
+            let id = parse_id("manifest_id", &installed.manifest_id)?;
+            let kind = installed.details.and_then(|details| details.kind);
+            let action = match kind {
+                Some(Kind::Local(local)) => match Local::from_i32(local) {
+                    Some(Local::Memoize) => Action::Memoize,
+                    Some(Local::Reinstall) | Some(Local::Build) => Action::Build,
+                    None => {
+                        return Err(from_conversion(ConversionError::UnknownValue(
+                            "local", local,
+                        )))
+                    }
+                },
+                Some(Kind::FetchRemote(_)) => Action::Substitute,
+                Some(Kind::BuildRemote(_)) => Action::Build,
+                None => return Err(from_conversion(ConversionError::MissingField("kind"))),
+            };
+
+            Ok((id, action))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let removed = diff
+        .uninstalled
+        .iter()
+        .map(|id| parse_id("uninstalled", id))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Transaction { installed, removed })
+}
+
+fn parse_id(field: &'static str, id: &str) -> Result<ManifestId, StoreError> {
+    id.parse()
+        .map_err(|_| from_conversion(ConversionError::InvalidId(field, id.to_string())))
+}
+
+fn from_conversion(err: ConversionError) -> StoreError {
+    StoreError::Remote(format!("malformed response from daemon: {}", err))
+}
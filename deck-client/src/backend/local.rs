@@ -0,0 +1,56 @@
+//! Single-user mode, where the client opens the store directly.
+
+use std::collections::BTreeMap;
+
+use deck_core::{Manifest, ManifestId};
+use deck_store::local::LocalStore;
+use deck_store::{BuildOptions, BuildStream, CheckContents, LogOptions, LogStream, Repair, Store};
+use deck_store::{StoreFuture, VerifyStream};
+use futures_preview::future::{self, FutureExt};
+
+use super::{Backend, Transaction};
+
+impl Backend for LocalStore {
+    fn transaction_diff<'a>(
+        &'a mut self,
+        install: &'a [ManifestId],
+        remove: &'a [ManifestId],
+    ) -> StoreFuture<'a, Transaction> {
+        let store = self.clone();
+        let future = async move {
+            let mut installed = BTreeMap::new();
+            for id in install {
+                let plan = await!(store.plan_build(id.clone()))?;
+                installed.extend(plan);
+            }
+
+            Ok(Transaction {
+                installed: installed.into_iter().collect(),
+                removed: remove.to_vec(),
+            })
+        };
+
+        future.boxed()
+    }
+
+    fn build_packages(&mut self, ids: Vec<ManifestId>, options: BuildOptions) -> BuildStream {
+        let builds = ids.into_iter().map(|id| self.build_package(id, options));
+        BuildStream::merge(builds, options.keep_going)
+    }
+
+    fn build_manifest(&mut self, manifest: Manifest, options: BuildOptions) -> BuildStream {
+        self.build_manifest_with(manifest, options)
+    }
+
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a> {
+        Store::get_build_log(self, id, options)
+    }
+
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+        Store::verify(self, check, repair)
+    }
+
+    fn manifests<'a>(&'a mut self) -> StoreFuture<'a, Vec<ManifestId>> {
+        future::ready(LocalStore::manifests(self)).boxed()
+    }
+}
@@ -0,0 +1,206 @@
+//! High-level interface to a Deck store.
+
+#[cfg(feature = "multi-user-mode")]
+use std::fs;
+#[cfg(feature = "multi-user-mode")]
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+
+use deck_core::{Manifest, ManifestId};
+#[cfg(feature = "local")]
+use deck_store::local::LocalStore;
+use deck_store::{BuildOptions, BuildStream, CheckContents, LogOptions, LogStream, Repair};
+use deck_store::{StoreError, StoreFuture, VerifyStream};
+use futures_preview::future::{FutureExt, TryFutureExt};
+use regex::RegexSet;
+
+#[cfg(feature = "multi-user-mode")]
+use crate::backend::DaemonBackend;
+use crate::backend::{Backend, Transaction};
+
+/// Client for installing, building and inspecting packages in a store.
+///
+/// A `Client` is parameterized by the `Backend` it reaches the store through. `Client::open()`
+/// picks a backend at runtime, while `Client::new()` may be used to select one at compile time.
+#[derive(Debug)]
+pub struct Client<B = Box<dyn Backend>> {
+    backend: B,
+}
+
+impl Client {
+    /// Opens the store of this system.
+    ///
+    /// When built with both the `local` and `multi-user-mode` features, the daemon is used if a
+    /// socket exists at `socket_path`, and the store at `store_path` is opened directly otherwise.
+    /// When built with only one of them, that backend is always used.
+    pub fn open(store_path: PathBuf, socket_path: PathBuf) -> Result<Self, StoreError> {
+        match daemon_backend(socket_path) {
+            Some(backend) => Ok(Client::new(backend)),
+            None => local_backend(store_path).map(Client::new),
+        }
+    }
+}
+
+impl<B: Backend> Client<B> {
+    /// Creates a new `Client` which reaches the store through `backend`.
+    pub fn new(backend: B) -> Self {
+        Client { backend }
+    }
+
+    /// Returns a reference to the backend of this client.
+    #[inline]
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Computes what installing the packages in `install` and removing the packages in `remove`
+    /// would do, without changing anything.
+    pub fn plan<'a>(
+        &'a mut self,
+        install: &'a [ManifestId],
+        remove: &'a [ManifestId],
+    ) -> StoreFuture<'a, Transaction> {
+        self.backend.transaction_diff(install, remove)
+    }
+
+    /// Installs the packages `ids`, building or downloading every package in their closures.
+    ///
+    /// Profiles are not implemented yet, so this only brings the packages into the store.
+    pub fn install(&mut self, ids: Vec<ManifestId>) -> BuildStream {
+        self.backend.build_packages(ids, BuildOptions::default())
+    }
+
+    /// Removes the packages `ids`, returning the resulting transaction.
+    ///
+    /// Packages are only ever removed from profiles, and are kept in the store. Since profiles are
+    /// not implemented yet, this does not change anything besides validating the transaction.
+    pub fn remove<'a>(&'a mut self, ids: &'a [ManifestId]) -> StoreFuture<'a, Transaction> {
+        self.backend.transaction_diff(&[], ids)
+    }
+
+    /// Builds the package described by `manifest` according to `options`.
+    pub fn build(&mut self, manifest: Manifest, options: BuildOptions) -> BuildStream {
+        self.backend.build_manifest(manifest, options)
+    }
+
+    /// Retrieves the build log of the package `id`.
+    pub fn log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a> {
+        self.backend.get_build_log(id, options)
+    }
+
+    /// Verifies the integrity of every object in the store.
+    pub fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+        self.backend.verify(check, repair)
+    }
+
+    /// Lists the IDs of the manifests in the store whose names match any of `patterns`.
+    pub fn search<'a>(&'a mut self, patterns: &'a RegexSet) -> StoreFuture<'a, Vec<ManifestId>> {
+        let ids = self.backend.manifests();
+        let matching = ids.map_ok(move |ids| {
+            ids.into_iter()
+                .filter(|id| patterns.is_match(id.name()))
+                .collect()
+        });
+
+        matching.boxed()
+    }
+}
+
+#[cfg(feature = "multi-user-mode")]
+fn daemon_backend(socket_path: PathBuf) -> Option<Box<dyn Backend>> {
+    let is_socket = fs::metadata(&socket_path)
+        .map(|meta| meta.file_type().is_socket())
+        .unwrap_or(false);
+
+    if is_socket || cfg!(not(feature = "local")) {
+        Some(Box::new(DaemonBackend::new(socket_path)))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(feature = "multi-user-mode"))]
+fn daemon_backend(_: PathBuf) -> Option<Box<dyn Backend>> {
+    None
+}
+
+#[cfg(feature = "local")]
+fn local_backend(store_path: PathBuf) -> Result<Box<dyn Backend>, StoreError> {
+    let store = LocalStore::open(store_path)?;
+    Ok(Box::new(store))
+}
+
+#[cfg(not(feature = "local"))]
+fn local_backend(store_path: PathBuf) -> Result<Box<dyn Backend>, StoreError> {
+    Err(StoreError::UnsupportedStore(
+        store_path.display().to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_preview::executor;
+    use futures_preview::future;
+    use futures_preview::stream;
+
+    use super::*;
+
+    /// Backend which only knows the IDs of its manifests, and fails every other request.
+    #[derive(Debug)]
+    struct Manifests(Vec<ManifestId>);
+
+    fn unsupported() -> StoreError {
+        StoreError::Remote("not supported by this backend".to_string())
+    }
+
+    impl Backend for Manifests {
+        fn transaction_diff<'a>(
+            &'a mut self,
+            _: &'a [ManifestId],
+            _: &'a [ManifestId],
+        ) -> StoreFuture<'a, Transaction> {
+            future::err(unsupported()).boxed()
+        }
+
+        fn build_packages(&mut self, _: Vec<ManifestId>, _: BuildOptions) -> BuildStream {
+            BuildStream::new(stream::once(future::err(unsupported())))
+        }
+
+        fn build_manifest(&mut self, _: Manifest, _: BuildOptions) -> BuildStream {
+            BuildStream::new(stream::once(future::err(unsupported())))
+        }
+
+        fn get_build_log<'a>(&'a mut self, _: &'a ManifestId, _: LogOptions) -> LogStream<'a> {
+            LogStream::new(stream::once(future::err(unsupported())))
+        }
+
+        fn verify<'a>(&'a mut self, _: CheckContents, _: Repair) -> VerifyStream<'a> {
+            VerifyStream::new(stream::once(future::err(unsupported())))
+        }
+
+        fn manifests<'a>(&'a mut self) -> StoreFuture<'a, Vec<ManifestId>> {
+            future::ready(Ok(self.0.clone())).boxed()
+        }
+    }
+
+    #[test]
+    fn searches_manifest_names() {
+        let ids: Vec<ManifestId> = vec![
+            "firefox@67.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m",
+            "ffmpeg@4.1.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m",
+            "emacs@25.1.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m",
+        ]
+        .into_iter()
+        .map(|id| id.parse().unwrap())
+        .collect();
+        let mut client = Client::new(Manifests(ids.clone()));
+
+        let patterns = RegexSet::new(&["^ff", "macs$"]).unwrap();
+        let found = executor::block_on(client.search(&patterns)).unwrap();
+        assert_eq!(found, vec![ids[1].clone(), ids[2].clone()]);
+
+        let patterns = RegexSet::new(&["1.0.0"]).unwrap();
+        let found = executor::block_on(client.search(&patterns)).unwrap();
+        assert!(found.is_empty());
+    }
+}
@@ -1,2 +1,12 @@
 #![deny(missing_debug_implementations)]
+#![feature(async_await, await_macro, futures_api)]
 #![forbid(unsafe_code)]
+
+pub use self::backend::{Backend, Transaction};
+pub use self::client::Client;
+pub use self::progress::{ProgressMode, ProgressRenderer};
+
+pub mod backend;
+pub mod progress;
+
+mod client;
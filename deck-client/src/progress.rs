@@ -0,0 +1,603 @@
+//! Rendering of build progress.
+//!
+//! A `ProgressRenderer` turns the `Progress` events of a `BuildStream` into output for humans or
+//! for other programs, depending on its `ProgressMode`:
+//!
+//! * On a terminal, one bar is drawn per active package, showing the bytes fetched so far for
+//!   downloads and the current phase and task for builds. Finished packages are collapsed into a
+//!   single line printed above the bars.
+//! * Anywhere else, a plain line is printed whenever a package moves on to another step, leaving
+//!   out byte-level updates so that logs stay readable.
+//! * With `ProgressMode::Json`, every event is printed as a JSON object on a line of its own.
+//!
+//! Whenever a package fails to build, the last lines of its build output are printed along with
+//! the reason for the failure.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{self, Stdout, Write};
+use std::str::FromStr;
+
+use deck_core::ManifestId;
+use deck_store::progress::{Action, FinalStatus, Progress};
+
+/// Number of lines of build output kept per package, to be shown if its build fails.
+const TAIL_LINES: usize = 10;
+/// Width of the bar drawn for each package, in characters.
+const BAR_WIDTH: usize = 20;
+/// Width assumed for terminals whose size cannot be determined, in characters.
+const DEFAULT_WIDTH: usize = 80;
+
+/// How build progress is reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProgressMode {
+    /// Progress bars when writing to a terminal, plain lines otherwise.
+    Auto,
+    /// A plain line whenever a package moves on to another step.
+    Plain,
+    /// A JSON object per progress event, for consumption by other programs.
+    Json,
+}
+
+impl Default for ProgressMode {
+    fn default() -> Self {
+        ProgressMode::Auto
+    }
+}
+
+impl Display for ProgressMode {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            ProgressMode::Auto => fmt.write_str("auto"),
+            ProgressMode::Plain => fmt.write_str("plain"),
+            ProgressMode::Json => fmt.write_str("json"),
+        }
+    }
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ProgressMode::Auto),
+            "plain" => Ok(ProgressMode::Plain),
+            "json" => Ok(ProgressMode::Json),
+            _ => Err(format!(
+                "unknown progress mode `{}`, expected `auto`, `plain` or `json`",
+                s
+            )),
+        }
+    }
+}
+
+/// Renders the progress of a build to a writer.
+#[derive(Debug)]
+pub struct ProgressRenderer<W> {
+    writer: W,
+    style: Style,
+    packages: BTreeMap<ManifestId, Package>,
+    planned: usize,
+    finished: usize,
+    /// Number of lines currently taken up by progress bars, which are redrawn on every update.
+    drawn: usize,
+}
+
+impl ProgressRenderer<Stdout> {
+    /// Creates a renderer which writes to stdout.
+    ///
+    /// In `ProgressMode::Auto`, progress bars are only drawn if stdout is a terminal.
+    pub fn stdout(mode: ProgressMode) -> Self {
+        let width = if atty::is(atty::Stream::Stdout) {
+            let width = term_size::dimensions_stdout().map(|(width, _)| width);
+            Some(width.unwrap_or(DEFAULT_WIDTH))
+        } else {
+            None
+        };
+
+        ProgressRenderer::new(io::stdout(), mode, width)
+    }
+}
+
+impl<W: Write> ProgressRenderer<W> {
+    /// Creates a renderer which writes to `writer`.
+    ///
+    /// `width` is the width of the terminal `writer` is connected to, if any. In
+    /// `ProgressMode::Auto`, progress bars are only drawn if it is set.
+    pub fn new(writer: W, mode: ProgressMode, width: Option<usize>) -> Self {
+        let style = match (mode, width) {
+            (ProgressMode::Json, _) => Style::Json,
+            (ProgressMode::Auto, Some(width)) => Style::Bars { width },
+            (ProgressMode::Auto, None) | (ProgressMode::Plain, _) => Style::Plain,
+        };
+
+        ProgressRenderer {
+            writer,
+            style,
+            packages: BTreeMap::new(),
+            planned: 0,
+            finished: 0,
+            drawn: 0,
+        }
+    }
+
+    /// Reports a single progress event.
+    pub fn update(&mut self, progress: &Progress) -> io::Result<()> {
+        if self.style == Style::Json {
+            serde_json::to_writer(&mut self.writer, progress)?;
+            writeln!(self.writer)?;
+            return self.writer.flush();
+        }
+
+        let lines = self.record(progress);
+        self.clear()?;
+        for line in lines {
+            writeln!(self.writer, "{}", line)?;
+        }
+
+        if let Style::Bars { width } = self.style {
+            self.draw(width)?;
+        }
+
+        self.writer.flush()
+    }
+
+    /// Removes the progress bars, leaving only the lines printed for finished and failed packages.
+    ///
+    /// This should be called once the build is over.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.clear()?;
+        self.writer.flush()
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Updates the state of the package `progress` is reported for, returning the lines which
+    /// should be printed for it.
+    fn record(&mut self, progress: &Progress) -> Vec<String> {
+        let id = progress.package_id();
+        let state = match *progress {
+            Progress::Started(ref p) => {
+                self.planned = p.planned.len();
+                return vec![summarize_plan(&p.planned)];
+            }
+            Progress::Blocked(ref p) => State::Waiting(p.description.clone()),
+            Progress::Loading(_) => State::Waiting("loading manifest".to_string()),
+            Progress::Downloading(ref p) => State::Fetching {
+                what: p.source.clone(),
+                done: p.downloaded_bytes,
+                total: p.total_bytes,
+            },
+            Progress::Substituting(ref p) => State::Fetching {
+                what: p.output.to_string(),
+                done: p.downloaded_bytes,
+                total: p.total_bytes,
+            },
+            Progress::Substituted(_) => return Vec::new(),
+            Progress::Reusing(ref p) => State::Waiting(format!("reusing source `{}`", p.existing)),
+            Progress::Building(ref p) => {
+                let package = self.packages.entry(id.clone()).or_default();
+                package.capture(&p.stdout);
+                package.capture(&p.stderr);
+                State::Building {
+                    phase: p.description.clone(),
+                    current: p.current_task,
+                    total: p.total_tasks,
+                }
+            }
+            Progress::Installing(ref p) => State::Installing {
+                what: p.description.clone(),
+                done: p.written_bytes,
+                total: p.total_bytes,
+            },
+            Progress::Finished(ref p) => {
+                self.packages.remove(id);
+                self.finished += 1;
+                let mut line = format!("{} {}", final_status_name(&p.status), short_id(id));
+                if p.installed_bytes > 0 {
+                    line.push_str(&format!(" ({})", format_bytes(p.installed_bytes)));
+                }
+
+                return vec![line];
+            }
+            Progress::Failed(ref p) => {
+                let package = self.packages.remove(id).unwrap_or_default();
+                self.finished += 1;
+                let failed = format!("failed {}: {}", short_id(id), p.reason);
+                let output = package
+                    .output
+                    .into_iter()
+                    .map(|line| format!("  | {}", line));
+                return Some(failed).into_iter().chain(output).collect();
+            }
+        };
+
+        let package = self.packages.entry(id.clone()).or_default();
+        let moved_on = package
+            .state
+            .as_ref()
+            .map_or(true, |current| !current.is_same_step(&state));
+
+        let line = format!("{}: {}", short_id(id), state);
+        package.state = Some(state);
+        if self.style == Style::Plain && moved_on {
+            vec![line]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Draws one bar per active package, preceded by the number of packages finished so far.
+    fn draw(&mut self, width: usize) -> io::Result<()> {
+        let mut lines = Vec::with_capacity(self.packages.len() + 1);
+        if self.planned > 0 {
+            lines.push(format!(
+                "[{}/{}] packages done",
+                self.finished, self.planned
+            ));
+        }
+
+        for (id, package) in &self.packages {
+            if let Some(ref state) = package.state {
+                lines.push(format!("{} {}", short_id(id), state.bar()));
+            }
+        }
+
+        // Lines which wrap around would throw off the cursor movements in `clear()`.
+        let max_len = width.saturating_sub(1);
+        for line in &lines {
+            let line: String = line.chars().take(max_len).collect();
+            writeln!(self.writer, "{}", line)?;
+        }
+
+        self.drawn = lines.len();
+        Ok(())
+    }
+
+    /// Erases the progress bars drawn last.
+    fn clear(&mut self) -> io::Result<()> {
+        if self.drawn > 0 {
+            write!(self.writer, "\x1b[{}A\x1b[J", self.drawn)?;
+            self.drawn = 0;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Style {
+    Bars { width: usize },
+    Plain,
+    Json,
+}
+
+/// Package which has not finished building yet.
+#[derive(Debug, Default)]
+struct Package {
+    state: Option<State>,
+    /// Last lines written by the builder of the package.
+    output: VecDeque<String>,
+}
+
+impl Package {
+    fn capture(&mut self, output: &[u8]) {
+        for line in String::from_utf8_lossy(output).lines() {
+            if self.output.len() == TAIL_LINES {
+                self.output.pop_front();
+            }
+
+            self.output.push_back(line.to_string());
+        }
+    }
+}
+
+/// Step a package is currently at.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum State {
+    Waiting(String),
+    Fetching {
+        what: String,
+        done: u64,
+        total: Option<u64>,
+    },
+    Building {
+        phase: String,
+        current: u32,
+        total: u32,
+    },
+    Installing {
+        what: String,
+        done: u64,
+        total: Option<u64>,
+    },
+}
+
+impl State {
+    /// Returns whether `other` only reports further progress within the same step.
+    fn is_same_step(&self, other: &State) -> bool {
+        match (self, other) {
+            (State::Fetching { what: a, .. }, State::Fetching { what: b, .. }) => a == b,
+            (State::Installing { what: a, .. }, State::Installing { what: b, .. }) => a == b,
+            (State::Building { current: a, .. }, State::Building { current: b, .. }) => a == b,
+            (a, b) => a == b,
+        }
+    }
+
+    /// Returns the progress bar for this step, followed by its description.
+    fn bar(&self) -> String {
+        match *self {
+            State::Waiting(ref description) => description.clone(),
+            State::Fetching {
+                ref what,
+                done,
+                total,
+            } => format!("{} {} {}", bar(done, total), byte_count(done, total), what),
+            State::Building {
+                ref phase,
+                current,
+                total,
+            } => {
+                let tasks = format!("{}/{}", current, total);
+                let bar = bar(u64::from(current), Some(u64::from(total)));
+                format!("{} {} {}", bar, tasks, phase)
+            }
+            State::Installing {
+                ref what,
+                done,
+                total,
+            } => format!("{} {} {}", bar(done, total), byte_count(done, total), what),
+        }
+    }
+}
+
+impl Display for State {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            State::Waiting(ref description) => write!(fmt, "{}", description),
+            State::Fetching { ref what, .. } => write!(fmt, "fetching {}", what),
+            State::Building {
+                ref phase,
+                current,
+                total,
+            } => write!(fmt, "{} ({}/{})", phase, current, total),
+            State::Installing { ref what, .. } => write!(fmt, "{}", what),
+        }
+    }
+}
+
+fn bar(done: u64, total: Option<u64>) -> String {
+    let filled = match total {
+        Some(total) if total > 0 => (done.min(total) * BAR_WIDTH as u64 / total) as usize,
+        _ => 0,
+    };
+
+    format!("[{}{}]", "=".repeat(filled), " ".repeat(BAR_WIDTH - filled))
+}
+
+fn byte_count(done: u64, total: Option<u64>) -> String {
+    match total {
+        Some(total) => format!("{}/{}", format_bytes(done), format_bytes(total)),
+        None => format_bytes(done),
+    }
+}
+
+/// Formats a number of bytes in binary units.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Returns the ID of a package without its hash, which is too long to be shown everywhere.
+fn short_id(id: &ManifestId) -> String {
+    format!("{}@{}", id.name(), id.version())
+}
+
+fn final_status_name(status: &FinalStatus) -> &'static str {
+    match *status {
+        FinalStatus::Memoized => "memoized",
+        FinalStatus::Reinstalled => "reinstalled",
+        FinalStatus::Downloaded => "downloaded",
+        FinalStatus::Built => "built",
+        FinalStatus::Checked => "checked",
+    }
+}
+
+fn summarize_plan(planned: &[(ManifestId, Action)]) -> String {
+    let count = |action| planned.iter().filter(|&&(_, a)| a == action).count();
+    let steps = [
+        (count(Action::Build), "to build"),
+        (count(Action::Check), "to check"),
+        (count(Action::Substitute), "to download"),
+        (count(Action::Memoize), "already installed"),
+    ];
+
+    let steps: Vec<_> = steps
+        .iter()
+        .filter(|&&(count, _)| count > 0)
+        .map(|&(count, step)| format!("{} {}", count, step))
+        .collect();
+
+    format!("planned {} packages: {}", planned.len(), steps.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use deck_store::progress::{
+        BuildStatus, Building, Failed, FailureReason, Finished, Started, Substituting,
+    };
+
+    use super::*;
+
+    fn id(name: &str) -> ManifestId {
+        format!("{}@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m", name)
+            .parse()
+            .expect("Failed to parse manifest ID")
+    }
+
+    fn building(name: &str, task: u32, phase: &str, stdout: &[u8]) -> Progress {
+        Progress::Building(Building {
+            package_id: id(name),
+            status: BuildStatus::Compiling,
+            current_task: task,
+            total_tasks: 3,
+            description: phase.to_string(),
+            stdout: stdout.to_vec(),
+            stderr: Vec::new(),
+            timestamp: Utc::now(),
+        })
+    }
+
+    fn render(mode: ProgressMode, width: Option<usize>, events: &[Progress]) -> String {
+        let mut renderer = ProgressRenderer::new(Vec::new(), mode, width);
+        for event in events {
+            renderer.update(event).expect("Failed to render");
+        }
+
+        renderer.finish().expect("Failed to finish");
+        String::from_utf8(renderer.into_inner()).expect("Output is not UTF-8")
+    }
+
+    #[test]
+    fn parses_progress_modes() {
+        for mode in &[ProgressMode::Auto, ProgressMode::Plain, ProgressMode::Json] {
+            assert_eq!(mode.to_string().parse::<ProgressMode>(), Ok(*mode));
+        }
+
+        assert!("bars".parse::<ProgressMode>().is_err());
+    }
+
+    #[test]
+    fn plain_lines_only_report_new_steps() {
+        let events = vec![
+            Progress::Started(Started {
+                package_id: id("foo"),
+                planned: vec![(id("bar"), Action::Memoize), (id("foo"), Action::Build)],
+                timestamp: Utc::now(),
+            }),
+            building("foo", 1, "configure", b"checking for cc... cc\n"),
+            building("foo", 1, "configure", b"checking for ld... ld\n"),
+            building("foo", 2, "build", b""),
+            Progress::Finished(Finished {
+                package_id: id("foo"),
+                status: FinalStatus::Built,
+                installed_bytes: 3 * 1024 * 1024 / 2,
+                timestamp: Utc::now(),
+            }),
+        ];
+
+        let text = render(ProgressMode::Auto, None, &events);
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            vec![
+                "planned 2 packages: 1 to build, 1 already installed",
+                "foo@1.0.0: configure (1/3)",
+                "foo@1.0.0: build (2/3)",
+                "built foo@1.0.0 (1.5 MiB)",
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_show_last_lines_of_output() {
+        let mut events: Vec<_> = (0..TAIL_LINES + 2)
+            .map(|i| building("foo", 1, "build", format!("line {}\n", i).as_bytes()))
+            .collect();
+        events.push(Progress::Failed(Failed {
+            package_id: id("foo"),
+            reason: FailureReason::Error("exit code 2".to_string()),
+            timestamp: Utc::now(),
+        }));
+
+        let text = render(ProgressMode::Plain, Some(80), &events);
+        let lines: Vec<_> = text.lines().skip(1).collect();
+        assert_eq!(lines[0], "failed foo@1.0.0: exit code 2");
+        assert_eq!(lines.len(), TAIL_LINES + 1);
+        assert_eq!(lines[1], "  | line 2");
+        assert_eq!(lines[TAIL_LINES], format!("  | line {}", TAIL_LINES + 1));
+    }
+
+    #[test]
+    fn bars_are_redrawn_and_collapsed() {
+        let events = [
+            Progress::Substituting(Substituting {
+                package_id: id("bar"),
+                output: "bar@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+                    .parse()
+                    .expect("Failed to parse output ID"),
+                cache: 0,
+                downloaded_bytes: 512,
+                total_bytes: Some(2048),
+                timestamp: Utc::now(),
+            }),
+            building("foo", 2, "build", b""),
+            Progress::Finished(Finished {
+                package_id: id("bar"),
+                status: FinalStatus::Downloaded,
+                installed_bytes: 2048,
+                timestamp: Utc::now(),
+            }),
+        ];
+
+        let mut renderer = ProgressRenderer::new(Vec::new(), ProgressMode::Auto, Some(60));
+        renderer.update(&events[0]).expect("Failed to render");
+        renderer.update(&events[1]).expect("Failed to render");
+        let text = String::from_utf8(renderer.writer.clone()).expect("Output is not UTF-8");
+        let (_, redrawn) = text.split_at(text.rfind("\x1b[1A\x1b[J").expect("Bars not cleared"));
+        let bars: Vec<_> = redrawn
+            .trim_start_matches("\x1b[1A\x1b[J")
+            .lines()
+            .collect();
+        assert_eq!(bars.len(), 2);
+        assert!(bars[0].starts_with("bar@1.0.0 [=====               ] 512 B/2.0 KiB bar@1.0.0"));
+        assert_eq!(bars[0].chars().count(), 59);
+        assert_eq!(bars[1], "foo@1.0.0 [=============       ] 2/3 build");
+
+        renderer.update(&events[2]).expect("Failed to render");
+        renderer.finish().expect("Failed to finish");
+        let text = String::from_utf8(renderer.into_inner()).expect("Output is not UTF-8");
+        let expected = "\x1b[2A\x1b[Jdownloaded bar@1.0.0 (2.0 KiB)\n\
+                        foo@1.0.0 [=============       ] 2/3 build\n\
+                        \x1b[1A\x1b[J";
+        assert!(text.ends_with(expected));
+    }
+
+    #[test]
+    fn json_prints_one_object_per_event() {
+        let events = vec![
+            building("foo", 1, "build", b"hello\n"),
+            Progress::Finished(Finished {
+                package_id: id("foo"),
+                status: FinalStatus::Built,
+                installed_bytes: 42,
+                timestamp: Utc::now(),
+            }),
+        ];
+
+        let text = render(ProgressMode::Json, Some(80), &events);
+        let objects: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Failed to parse JSON"))
+            .collect();
+
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0]["Building"]["description"], "build");
+        assert_eq!(objects[1]["Finished"]["installed_bytes"], 42);
+    }
+}
@@ -3,9 +3,9 @@
 
 pub use self::hash::{Hash, HashBuilder};
 pub use self::id::{FilesystemId, ManifestId, OutputId, SourceId};
-pub use self::manifest::{Manifest, ManifestBuilder, Source};
+pub use self::manifest::{BuildScript, Manifest, ManifestBuilder, Phase, Source};
 pub use self::name::Name;
-pub use self::platform::Platform;
+pub use self::platform::{Arch, Os, Platform};
 
 mod hash;
 mod id;
@@ -1,5 +1,6 @@
 //! Reproducible package manifest data.
 
+pub use self::build::{BuildScript, Phase};
 pub use self::sources::Source;
 
 use std::collections::{BTreeMap, BTreeSet};
@@ -15,6 +16,7 @@ use crate::hash::Hash;
 use crate::id::{ManifestId, OutputId};
 use crate::name::Name;
 
+mod build;
 mod outputs;
 mod sources;
 
@@ -35,6 +37,8 @@ pub struct Manifest {
     package: Package,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BuildScript::is_empty")]
+    build: BuildScript,
     #[serde(rename = "output")]
     outputs: Outputs,
     #[serde(default, rename = "source", skip_serializing_if = "Sources::is_empty")]
@@ -139,6 +143,14 @@ impl Manifest {
         self.env.iter()
     }
 
+    /// Returns the script used to build the package from source.
+    ///
+    /// If the manifest does not declare a `build` table, the returned script is empty.
+    #[inline]
+    pub fn build_script(&self) -> &BuildScript {
+        &self.build
+    }
+
     /// Iterates over the package's build outputs.
     ///
     /// # Note
@@ -152,6 +164,22 @@ impl Manifest {
         self.outputs.iter_with(name, ver)
     }
 
+    /// Iterates over the references declared by the given build output.
+    ///
+    /// If `output` does not belong to this package, the iterator is empty.
+    #[inline]
+    pub fn output_references<'a>(
+        &'a self,
+        output: &OutputId,
+    ) -> impl Iterator<Item = &'a OutputId> + 'a {
+        let name = &self.package.name;
+        let version = &self.package.version;
+        self.outputs
+            .references_of(name, version, output)
+            .into_iter()
+            .flatten()
+    }
+
     /// Iterates over the package's sources.
     #[inline]
     pub fn sources(&self) -> impl Iterator<Item = &Source> {
@@ -184,6 +212,7 @@ impl FromStr for Manifest {
 pub struct ManifestBuilder {
     package: Result<Package, ()>,
     env: BTreeMap<String, String>,
+    build: BuildScript,
     sources: Sources,
     outputs: Result<Outputs, ()>,
 }
@@ -213,6 +242,7 @@ impl ManifestBuilder {
         ManifestBuilder {
             package,
             env: BTreeMap::new(),
+            build: BuildScript::default(),
             sources: Sources::new(),
             outputs,
         }
@@ -273,6 +303,12 @@ impl ManifestBuilder {
         self
     }
 
+    /// Sets the script used to build this manifest from source.
+    pub fn build_script(mut self, build: BuildScript) -> Self {
+        self.build = build;
+        self
+    }
+
     /// Adds an external fetchable source to this manifest.
     ///
     /// # Laziness
@@ -294,6 +330,7 @@ impl ManifestBuilder {
         Ok(Manifest {
             package: self.package?,
             env: self.env,
+            build: self.build,
             outputs: self.outputs?,
             sources: self.sources,
         })
@@ -315,6 +352,11 @@ mod tests {
         [env]
         LANG = "C_ALL"
 
+        [build]
+        builder = "/bin/sh"
+        args = ["-c"]
+        compile = "make"
+
         [[output]]
         precomputed-hash = "fc3j3vub6kodu4jtfoakfs5xhumqi62m"
         references = ["foo@1.2.3:bin-fc3j3vub6kodu4jtfoakfs5xhumqi62m"]
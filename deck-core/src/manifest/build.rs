@@ -0,0 +1,213 @@
+//! Represents the `build` table in the package manifest.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Phase of a package build, in the order in which they are run.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Phase {
+    Prepare,
+    Configure,
+    Compile,
+    Test,
+    Finalize,
+}
+
+impl Display for Phase {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match *self {
+            Phase::Prepare => "prepare",
+            Phase::Configure => "configure",
+            Phase::Compile => "compile",
+            Phase::Test => "test",
+            Phase::Finalize => "finalize",
+        };
+
+        write!(fmt, "{}", name)
+    }
+}
+
+/// Represents the `build` table in the package manifest.
+///
+/// Each phase is a script which is passed as the final argument to `builder`, after `args`. Phases
+/// without a script are skipped.
+///
+/// The `timeout` and `max-silent-time` keys, both in seconds, override the time limits configured
+/// for the build daemon.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildScript {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    builder: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prepare: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    configure: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    test: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    finalize: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_silent_time: Option<u64>,
+}
+
+impl BuildScript {
+    /// Creates a new `BuildScript` which runs each phase with the given `builder` executable.
+    pub fn new<T: Into<String>>(builder: T) -> Self {
+        BuildScript {
+            builder: builder.into(),
+            ..BuildScript::default()
+        }
+    }
+
+    /// Appends an argument to pass to the builder ahead of each phase script.
+    pub fn arg<T: Into<String>>(mut self, arg: T) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Sets the script to run for the given phase, replacing any existing one.
+    pub fn phase<T: Into<String>>(mut self, phase: Phase, script: T) -> Self {
+        let script = Some(script.into());
+        match phase {
+            Phase::Prepare => self.prepare = script,
+            Phase::Configure => self.configure = script,
+            Phase::Compile => self.compile = script,
+            Phase::Test => self.test = script,
+            Phase::Finalize => self.finalize = script,
+        }
+        self
+    }
+
+    /// Kills the build if it has not finished within `timeout`, rounded up to whole seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(whole_secs(timeout));
+        self
+    }
+
+    /// Kills the build if it has not written anything to stdout or stderr for `max_silent_time`,
+    /// rounded up to whole seconds.
+    pub fn with_max_silent_time(mut self, max_silent_time: Duration) -> Self {
+        self.max_silent_time = Some(whole_secs(max_silent_time));
+        self
+    }
+
+    /// Returns the builder executable.
+    #[inline]
+    pub fn builder(&self) -> &str {
+        &self.builder
+    }
+
+    /// Iterates over the arguments passed to the builder ahead of each phase script.
+    #[inline]
+    pub fn args(&self) -> impl Iterator<Item = &String> {
+        self.args.iter()
+    }
+
+    /// Iterates over each phase which has a script, in the order in which they are run.
+    pub fn phases(&self) -> impl Iterator<Item = (Phase, &str)> {
+        let phases = [
+            (Phase::Prepare, &self.prepare),
+            (Phase::Configure, &self.configure),
+            (Phase::Compile, &self.compile),
+            (Phase::Test, &self.test),
+            (Phase::Finalize, &self.finalize),
+        ];
+
+        phases
+            .iter()
+            .filter_map(|&(phase, script)| script.as_ref().map(|s| (phase, s.as_str())))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Returns how long the whole build may take, if it overrides the default.
+    #[inline]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// Returns how long the build may go without writing any output, if it overrides the default.
+    #[inline]
+    pub fn max_silent_time(&self) -> Option<Duration> {
+        self.max_silent_time.map(Duration::from_secs)
+    }
+
+    /// Returns whether no builder has been declared.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.builder.is_empty()
+    }
+}
+
+/// Rounds `duration` up to whole seconds, so that a nonzero limit never truncates to zero.
+fn whole_secs(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILD: &'static str = r#"
+        builder = "/bin/sh"
+        args = ["-e", "-c"]
+        configure = "./configure --prefix=$out"
+        compile = "make"
+        finalize = "make install"
+        max-silent-time = 600
+    "#;
+
+    #[test]
+    fn phases_in_order() {
+        let build: BuildScript = toml::from_str(BUILD).expect("Failed to parse build table");
+        assert_eq!(build.builder(), "/bin/sh");
+        assert_eq!(build.args().collect::<Vec<_>>(), vec!["-e", "-c"]);
+
+        let phases: Vec<_> = build.phases().collect();
+        assert_eq!(
+            phases,
+            vec![
+                (Phase::Configure, "./configure --prefix=$out"),
+                (Phase::Compile, "make"),
+                (Phase::Finalize, "make install"),
+            ]
+        );
+
+        assert_eq!(build.timeout(), None);
+        assert_eq!(build.max_silent_time(), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let build = BuildScript::new("/bin/sh")
+            .arg("-c")
+            .phase(Phase::Compile, "make")
+            .phase(Phase::Test, "make check")
+            .with_timeout(Duration::from_secs(3600));
+
+        let text = toml::to_string(&build).expect("Failed to serialize build table");
+        let parsed: BuildScript = toml::from_str(&text).expect("Failed to parse build table");
+        assert_eq!(build, parsed);
+        assert!(!parsed.is_empty());
+        assert!(BuildScript::default().is_empty());
+    }
+
+    #[test]
+    fn limits_round_up_to_whole_seconds() {
+        let build = BuildScript::new("/bin/sh")
+            .with_timeout(Duration::from_millis(1500))
+            .with_max_silent_time(Duration::from_millis(200));
+
+        assert_eq!(build.timeout(), Some(Duration::from_secs(2)));
+        assert_eq!(build.max_silent_time(), Some(Duration::from_secs(1)));
+    }
+}
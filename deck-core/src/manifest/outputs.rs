@@ -61,6 +61,20 @@ impl Outputs {
             .iter()
             .map(move |out| out.to_output_id(name.clone(), version.clone()))
     }
+
+    /// Returns the references declared by the output which renders as `id` with `name` and
+    /// `version`, if any such output exists.
+    pub fn references_of(
+        &self,
+        name: &Name,
+        version: &str,
+        id: &OutputId,
+    ) -> Option<&BTreeSet<OutputId>> {
+        self.0
+            .iter()
+            .find(|out| out.to_output_id(name.clone(), version.to_string()) == *id)
+            .map(|out| &out.references)
+    }
 }
 
 impl<'de> Deserialize<'de> for Outputs {
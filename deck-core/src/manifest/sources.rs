@@ -4,6 +4,9 @@ use std::collections::BTreeSet;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::id::SourceId;
 
 /// External fetchable source that can be cached in the store.
 ///
@@ -16,6 +19,32 @@ pub enum Source {
     Uri { uri: String, hash: String },
 }
 
+impl Source {
+    /// Returns the [`SourceId`] this source is expected to have once it is saved in the store.
+    ///
+    /// The name is taken from the last component of the path or URI, and the hash is the one
+    /// declared in the manifest. Returns `None` if either cannot be determined, e.g. for Git
+    /// repositories, whose IDs are only known after checking out the requested revision.
+    ///
+    /// [`SourceId`]: ../struct.SourceId.html
+    pub fn expected_id(&self) -> Option<SourceId> {
+        let (name, hash) = match *self {
+            Source::Git => return None,
+            Source::Path { ref path, ref hash } => {
+                let name = path.file_name()?.to_str()?.to_string();
+                (name, hash)
+            }
+            Source::Uri { ref uri, ref hash } => {
+                let url: Url = uri.parse().ok()?;
+                let name = url.path_segments()?.filter(|s| !s.is_empty()).last()?;
+                (name.to_string(), hash)
+            }
+        };
+
+        SourceId::new(name, hash.parse().ok()?).ok()
+    }
+}
+
 /// Represents the `source` array table in the package manifest.
 #[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub struct Sources(BTreeSet<Source>);
@@ -48,3 +77,46 @@ impl Sources {
         self.0.iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &'static str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+    #[test]
+    fn expected_id_from_uri() {
+        let source = Source::Uri {
+            uri: "https://www.example.com/files/hello.tar.gz".to_string(),
+            hash: HASH.to_string(),
+        };
+
+        let expected = format!("hello.tar.gz-{}", HASH)
+            .parse()
+            .expect("Failed to parse ID");
+        assert_eq!(source.expected_id(), Some(expected));
+    }
+
+    #[test]
+    fn expected_id_from_path() {
+        let source = Source::Path {
+            path: PathBuf::from("./patches/fix-build.patch"),
+            hash: HASH.to_string(),
+        };
+
+        let expected = format!("fix-build.patch-{}", HASH)
+            .parse()
+            .expect("Failed to parse ID");
+        assert_eq!(source.expected_id(), Some(expected));
+    }
+
+    #[test]
+    fn no_expected_id_for_invalid_hash() {
+        let source = Source::Uri {
+            uri: "https://www.example.com/hello.tar.gz".to_string(),
+            hash: "1234567890abcdef".to_string(),
+        };
+
+        assert_eq!(source.expected_id(), None);
+    }
+}
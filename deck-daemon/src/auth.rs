@@ -0,0 +1,195 @@
+//! Identification of the users connecting to the daemon.
+//!
+//! The user and group IDs of each client are read from the `SO_PEERCRED` credentials of its
+//! socket, which are filled in by the kernel and cannot be forged by the client. They are mapped
+//! to names through `/etc/passwd` and `/etc/group`, so that they can be matched against the
+//! `trusted-users` of the daemon configuration. Both files are cached in `Accounts`, which the
+//! daemon reloads periodically instead of reading them for every connection.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io;
+
+use tokio::net::UnixStream;
+
+const GROUP_FILE: &str = "/etc/group";
+const PASSWD_FILE: &str = "/etc/passwd";
+
+/// User owning the client process at the other end of a connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    /// Name of the user, if its user ID is listed in `/etc/passwd`.
+    pub user: Option<String>,
+    /// Names of the primary group of the user and of every group listing it as a member.
+    pub groups: Vec<String>,
+}
+
+impl Caller {
+    fn from_ids(uid: u32, gid: u32, passwd: &str, groups: &str) -> Self {
+        let user = passwd
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() >= 3 && fields[2].parse::<u32>().ok() == Some(uid))
+            .map(|fields| fields[0].to_string());
+
+        let groups = groups
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .filter(|fields| fields.len() >= 4)
+            .filter(|fields| {
+                let is_primary = fields[2].parse::<u32>().ok() == Some(gid);
+                let is_member = user.as_ref().map_or(false, |user| {
+                    fields[3]
+                        .split(',')
+                        .map(str::trim)
+                        .any(|member| member == user)
+                });
+                is_primary || is_member
+            })
+            .map(|fields| fields[0].to_string())
+            .collect();
+
+        Caller {
+            uid,
+            gid,
+            user,
+            groups,
+        }
+    }
+}
+
+impl Display for Caller {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self.user {
+            Some(ref user) => write!(fmt, "{} (uid {}, gid {})", user, self.uid, self.gid),
+            None => write!(fmt, "uid {} (gid {})", self.uid, self.gid),
+        }
+    }
+}
+
+/// Cached contents of the user and group databases of the system.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Accounts {
+    passwd: String,
+    groups: String,
+}
+
+impl Accounts {
+    /// Reads `/etc/passwd` and `/etc/group`, treating files which cannot be read as empty.
+    pub fn read() -> Self {
+        Accounts {
+            passwd: fs::read_to_string(PASSWD_FILE).unwrap_or_default(),
+            groups: fs::read_to_string(GROUP_FILE).unwrap_or_default(),
+        }
+    }
+
+    /// Identifies the user owning the process which connected through `socket`.
+    ///
+    /// Users and groups missing from the cached databases are left unnamed.
+    pub fn identify(&self, socket: &UnixStream) -> io::Result<Caller> {
+        let creds = socket.peer_cred()?;
+        Ok(Caller::from_ids(
+            creds.uid,
+            creds.gid,
+            &self.passwd,
+            &self.groups,
+        ))
+    }
+}
+
+/// Users allowed to add arbitrary manifests, sources and outputs to the store, and to repair it.
+///
+/// Entries starting with `@` name groups whose members are all trusted, and `*` trusts everyone.
+/// By default, only `root` is trusted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TrustedUsers {
+    users: Vec<String>,
+    groups: Vec<String>,
+}
+
+impl TrustedUsers {
+    /// Creates a list of trusted users from the given `trusted-users` entries.
+    pub fn new<I, S>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut users = Vec::new();
+        let mut groups = Vec::new();
+        for entry in entries {
+            let entry = entry.as_ref().trim();
+            if entry.starts_with('@') {
+                groups.push(entry[1..].to_string());
+            } else {
+                users.push(entry.to_string());
+            }
+        }
+
+        TrustedUsers { users, groups }
+    }
+
+    /// Returns whether `caller` is trusted.
+    pub fn contains(&self, caller: &Caller) -> bool {
+        let is_user = |name: &String| self.users.iter().any(|user| user == "*" || user == name);
+        let in_group = |name: &String| self.groups.contains(name);
+        caller.user.as_ref().map_or(false, is_user)
+            || caller.groups.iter().any(in_group)
+            || (caller.user.is_none() && self.users.iter().any(|user| user == "*"))
+    }
+}
+
+impl Default for TrustedUsers {
+    fn default() -> Self {
+        TrustedUsers::new(&["root"])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str = "root:x:0:0:root:/root:/bin/sh\n\
+                          alice:x:1000:1000::/home/alice:/bin/sh\n\
+                          bob:x:1001:100::/home/bob:/bin/sh\n";
+    const GROUP: &str = "root:x:0:\n\
+                         users:x:100:alice\n\
+                         alice:x:1000:\n\
+                         wheel:x:10:bob, alice\n";
+
+    #[test]
+    fn maps_ids_to_names() {
+        let alice = Caller::from_ids(1000, 1000, PASSWD, GROUP);
+        assert_eq!(alice.user, Some("alice".to_string()));
+        assert_eq!(alice.groups, vec!["users", "alice", "wheel"]);
+        assert_eq!(alice.to_string(), "alice (uid 1000, gid 1000)");
+
+        let unknown = Caller::from_ids(2000, 100, PASSWD, GROUP);
+        assert_eq!(unknown.user, None);
+        assert_eq!(unknown.groups, vec!["users"]);
+        assert_eq!(unknown.to_string(), "uid 2000 (gid 100)");
+    }
+
+    #[test]
+    fn trusts_listed_users_and_groups() {
+        let root = Caller::from_ids(0, 0, PASSWD, GROUP);
+        let alice = Caller::from_ids(1000, 1000, PASSWD, GROUP);
+        let bob = Caller::from_ids(1001, 100, PASSWD, GROUP);
+        let unknown = Caller::from_ids(2000, 2000, PASSWD, GROUP);
+
+        let default = TrustedUsers::default();
+        assert!(default.contains(&root));
+        assert!(!default.contains(&alice));
+
+        let trusted = TrustedUsers::new(&["alice", "@wheel"]);
+        assert!(!trusted.contains(&root));
+        assert!(trusted.contains(&alice));
+        assert!(trusted.contains(&bob));
+        assert!(!trusted.contains(&unknown));
+
+        let everyone = TrustedUsers::new(&["*"]);
+        assert!(everyone.contains(&unknown));
+        assert!(!TrustedUsers::new(Vec::<String>::new()).contains(&root));
+    }
+}
@@ -1,9 +1,86 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use serde::Deserialize;
 
-#[derive(Clone, Debug, Deserialize)]
+const DEFAULT_STORE_PATH: &str = "/deck/store";
+const DEFAULT_SOCKET_PATH: &str = "/deck/daemon.sock";
+
+/// Settings of the daemon, as read from its TOML configuration file.
+///
+/// Every setting is optional, and the default configuration uses the defaults of all of them.
+#[derive(Clone, Debug, Default, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Config {
+    store_path: Option<PathBuf>,
+    socket_path: Option<PathBuf>,
     build_group: Option<String>,
     max_builds: Option<u32>,
+    build_timeout: Option<u64>,
+    max_silent_time: Option<u64>,
     trusted_users: Option<Vec<String>>,
 }
+
+impl Config {
+    /// Reads the configuration from the TOML file at `path`.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Returns the path of the store managed by the daemon.
+    #[inline]
+    pub fn store_path(&self) -> &Path {
+        self.store_path
+            .as_ref()
+            .map(PathBuf::as_path)
+            .unwrap_or_else(|| Path::new(DEFAULT_STORE_PATH))
+    }
+
+    /// Returns the path of the Unix domain socket which the daemon listens on.
+    #[inline]
+    pub fn socket_path(&self) -> &Path {
+        self.socket_path
+            .as_ref()
+            .map(PathBuf::as_path)
+            .unwrap_or_else(|| Path::new(DEFAULT_SOCKET_PATH))
+    }
+
+    /// Returns the group whose members builds are run as, if builds are run as distinct users.
+    #[inline]
+    pub fn build_group(&self) -> Option<&str> {
+        self.build_group.as_ref().map(String::as_str)
+    }
+
+    /// Returns the maximum number of builds which may run at the same time, if any.
+    #[inline]
+    pub fn max_builds(&self) -> Option<usize> {
+        self.max_builds.map(|max| max as usize)
+    }
+
+    /// Returns how long a build may take before it is killed, if builds are limited in time.
+    ///
+    /// This can be overridden by the `timeout` of each package manifest.
+    #[inline]
+    pub fn build_timeout(&self) -> Option<Duration> {
+        self.build_timeout.map(Duration::from_secs)
+    }
+
+    /// Returns how long a builder may go without writing any output before it is killed, if any.
+    ///
+    /// This can be overridden by the `max-silent-time` of each package manifest.
+    #[inline]
+    pub fn max_silent_time(&self) -> Option<Duration> {
+        self.max_silent_time.map(Duration::from_secs)
+    }
+
+    /// Returns the users and `@groups` allowed to perform privileged operations, if configured.
+    ///
+    /// Only `root` is trusted if this is not set.
+    #[inline]
+    pub fn trusted_users(&self) -> Option<&[String]> {
+        self.trusted_users.as_ref().map(Vec::as_slice)
+    }
+}
@@ -1,19 +1,147 @@
 //! Deck daemon implementation.
 
 #![deny(missing_debug_implementations)]
+#![feature(async_await, await_macro, futures_api)]
 #![forbid(unsafe_code)]
 
-use crate::config::Config;
+pub use self::config::Config;
 
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use deck_protocol::daemon::server::DaemonServer;
+use deck_store::local::build_users::BuildUsers;
+use deck_store::local::builder::Limits;
+use deck_store::local::LocalStore;
+use deck_store::StoreError;
+use futures::{Future, Stream};
+use log::{debug, info, warn};
+use tokio::executor::DefaultExecutor;
+use tokio::net::UnixListener;
+use tokio::timer::Interval;
+use tower_h2::Server;
+
+use crate::auth::{Accounts, TrustedUsers};
+use crate::service::Service;
+
+mod auth;
 mod config;
+mod service;
+
+/// Permissions of the socket, which lets every user connect and leaves authorization to the daemon.
+const SOCKET_MODE: u32 = 0o666;
+/// How often the user and group databases are reloaded, so that changes to them are picked up.
+const ACCOUNTS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Daemon {
     cfg: Config,
+    store: LocalStore,
+    trusted: TrustedUsers,
 }
 
 impl Daemon {
-    pub fn new(cfg: Config) -> Result<Self, ()> {
-        Ok(Daemon { cfg })
+    /// Creates a new daemon from the given configuration, managing the store at `store-path`.
+    ///
+    /// At most `max-builds` packages are built at a time, and builds are killed once they exceed
+    /// `build-timeout` or `max-silent-time`. If `build-group` is set, builds are also run as
+    /// distinct members of that group. Fails if the store cannot be opened, or if the group has
+    /// no unprivileged members.
+    ///
+    /// Only the `trusted-users` may add arbitrary objects to the store or repair it, and everyone
+    /// else may only substitute outputs whose contents can be verified.
+    pub fn new(cfg: Config) -> Result<Self, StoreError> {
+        let mut limits = Limits::default();
+        if let Some(max_builds) = cfg.max_builds() {
+            limits.max_builds = max_builds;
+        }
+        limits.build_timeout = cfg.build_timeout();
+        limits.max_silent_time = cfg.max_silent_time();
+
+        let mut store = LocalStore::open(cfg.store_path().to_owned())?.with_limits(limits);
+        if let Some(group) = cfg.build_group() {
+            let users = BuildUsers::from_group(group, cfg.max_builds())?;
+            store = store.with_build_users(users);
+        }
+
+        let trusted = match cfg.trusted_users() {
+            Some(users) => TrustedUsers::new(users),
+            None => TrustedUsers::default(),
+        };
+
+        Ok(Daemon {
+            cfg,
+            store,
+            trusted,
+        })
+    }
+
+    /// Listens on `socket-path` and returns a future which serves every client connecting to it.
+    ///
+    /// A stale socket left behind by a previous daemon is replaced, and the socket is made
+    /// accessible to every user. Each client connection is served by a separate task, so the
+    /// future must be run on a `tokio` runtime, and it only resolves if accepting connections
+    /// fails. Connections whose peer credentials cannot be read are dropped.
+    pub fn serve(self) -> io::Result<impl Future<Item = (), Error = io::Error> + Send> {
+        let path = self.cfg.socket_path().to_owned();
+        match fs::symlink_metadata(&path) {
+            Ok(ref meta) if meta.file_type().is_socket() => fs::remove_file(&path)?,
+            Ok(_) => {
+                let msg = format!("{} exists and is not a socket", path.display());
+                return Err(io::Error::new(ErrorKind::AlreadyExists, msg));
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, Permissions::from_mode(SOCKET_MODE))?;
+        info!("listening on {}", path.display());
+
+        let accounts = Arc::new(RwLock::new(Accounts::read()));
+        let reloaded = accounts.clone();
+        let start = Instant::now() + ACCOUNTS_RELOAD_INTERVAL;
+        let reloading = Interval::new(start, ACCOUNTS_RELOAD_INTERVAL)
+            .map_err(|e| io::Error::new(ErrorKind::Other, e))
+            .for_each(move |_| {
+                let latest = Accounts::read();
+                *reloaded.write().expect("Accounts lock was poisoned") = latest;
+                Ok(())
+            });
+
+        let Daemon { store, trusted, .. } = self;
+        let serving = listener.incoming().for_each(move |socket| {
+            let identified = accounts
+                .read()
+                .expect("Accounts lock was poisoned")
+                .identify(&socket);
+            let caller = match identified {
+                Ok(caller) => caller,
+                Err(e) => {
+                    warn!("failed to identify client, dropping connection: {}", e);
+                    return Ok(());
+                }
+            };
+
+            let is_trusted = trusted.contains(&caller);
+            debug!(
+                "accepted connection from {} (trusted: {})",
+                caller, is_trusted
+            );
+
+            let service = Service::new(store.clone(), Arc::new(caller), is_trusted);
+            let server = DaemonServer::new(service);
+            let mut server = Server::new(server, Default::default(), DefaultExecutor::current());
+            let connection = server
+                .serve(socket)
+                .map_err(|e| warn!("client connection failed: {:?}", e));
+            tokio::spawn(connection);
+            Ok(())
+        });
+
+        Ok(serving.join(reloading).map(|_| ()))
     }
 }
@@ -0,0 +1,349 @@
+//! Implementation of the `Daemon` gRPC service on top of the local store.
+//!
+//! Every request is served by its own clone of the `LocalStore`, so requests from any number of
+//! clients run concurrently while sharing the same build limits. Streams which borrow the store,
+//! such as build logs and verification results, are driven by a separate task and forwarded to
+//! the client through a channel.
+//!
+//! Each connection is served by its own `Service`, which knows the user at the other end of it.
+//! Requests which add arbitrary objects to the store or repair it are only accepted from trusted
+//! users, and are logged along with the identity of the caller. Untrusted users may only
+//! substitute outputs whose contents can be verified, and have every other package built instead.
+//! Since substitutes are not signed, that only includes outputs whose hash the store already
+//! knows, as described by `Substitutes::Verified`.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use deck_core::{Manifest, ManifestId};
+use deck_protocol::daemon::diff_response::install_details::{FetchRemote, Kind, Local};
+use deck_protocol::daemon::diff_response::{InstallDetails, Installed};
+use deck_protocol::daemon::verify_request;
+use deck_protocol::daemon::{
+    server, AddManifestRequest, AddManifestResponse, BuildRequest, BuildResponse, DiffRequest,
+    DiffResponse, ImportRequest, ImportResponse, ListRequest, ListResponse, LogRequest,
+    LogResponse, VerifyRequest, VerifyResponse,
+};
+use deck_protocol::to_status;
+use deck_store::local::context::Substitutes;
+use deck_store::local::LocalStore;
+use deck_store::progress::Action;
+use deck_store::{BuildOptions, BuildStream, CheckContents, LogOptions, Repair, Store, StoreError};
+use futures::future::{self, FutureResult};
+use futures::{Future as Future01, Stream as Stream01};
+use futures_preview::channel::mpsc::{self, Receiver, Sender};
+use futures_preview::future::{FutureExt, TryFutureExt};
+use futures_preview::sink::SinkExt;
+use futures_preview::stream::{Stream, StreamExt, TryStreamExt};
+use log::{info, warn};
+use tower_grpc::{Code, Request, Response, Status};
+
+use crate::auth::Caller;
+
+/// Number of chunks or results buffered for a client which is slow to read them.
+const CHANNEL_CAPACITY: usize = 16;
+
+type BoxFuture<T> = Box<dyn Future01<Item = Response<T>, Error = Status> + Send>;
+type BoxStream<T> = Box<dyn Stream01<Item = T, Error = Status> + Send>;
+type StreamFuture<T> = FutureResult<Response<BoxStream<T>>, Status>;
+
+#[derive(Clone, Debug)]
+pub struct Service {
+    store: LocalStore,
+    caller: Arc<Caller>,
+    trusted: bool,
+}
+
+impl Service {
+    /// Creates a new service for requests sent by `caller`, who is only allowed to perform
+    /// privileged operations if `trusted` is `true`.
+    pub fn new(store: LocalStore, caller: Arc<Caller>, trusted: bool) -> Self {
+        let store = if trusted {
+            store
+        } else {
+            store.with_substitutes(Substitutes::Verified)
+        };
+
+        Service {
+            store,
+            caller,
+            trusted,
+        }
+    }
+
+    /// Logs the privileged `operation` if the caller is trusted, and rejects it otherwise.
+    fn authorize<D: Display>(&self, operation: D) -> Result<(), Status> {
+        if self.trusted {
+            info!("{}: {}", self.caller, operation);
+            Ok(())
+        } else {
+            warn!("{}: denied untrusted user: {}", self.caller, operation);
+            let message = format!("{} requires a trusted user", operation);
+            Err(Status::new(Code::PermissionDenied, message))
+        }
+    }
+}
+
+impl server::Daemon for Service {
+    type GetTransactionDiffFuture = BoxFuture<DiffResponse>;
+    type BuildManifestStream = BoxStream<BuildResponse>;
+    type BuildManifestFuture = StreamFuture<BuildResponse>;
+    type GetBuildLogStream = BoxStream<LogResponse>;
+    type GetBuildLogFuture = StreamFuture<LogResponse>;
+    type VerifyStream = BoxStream<VerifyResponse>;
+    type VerifyFuture = StreamFuture<VerifyResponse>;
+    type ListManifestsFuture = FutureResult<Response<ListResponse>, Status>;
+    type AddManifestFuture = BoxFuture<AddManifestResponse>;
+    type ImportFuture = BoxFuture<ImportResponse>;
+
+    /// Computes which packages would be installed by building every manifest in `to_install`.
+    ///
+    /// Upgrades are not supported yet, since the daemon does not manage profiles. The download
+    /// sizes of substituted packages are not known until they are fetched, and are left as zero.
+    fn get_transaction_diff(
+        &mut self,
+        request: Request<DiffRequest>,
+    ) -> Self::GetTransactionDiffFuture {
+        let request = request.into_inner();
+        if !request.to_upgrade.is_empty() {
+            let status = Status::new(Code::Unimplemented, "upgrading packages is not supported");
+            return Box::new(future::err(status));
+        }
+
+        let (to_install, to_uninstall) = match (
+            parse_ids(&request.to_install),
+            parse_ids(&request.to_uninstall),
+        ) {
+            (Ok(to_install), Ok(to_uninstall)) => (to_install, to_uninstall),
+            (Err(status), _) | (_, Err(status)) => return Box::new(future::err(status)),
+        };
+
+        let store = self.store.clone();
+        let diff = async move {
+            let mut planned = BTreeMap::new();
+            for id in to_install {
+                let plan = await!(store.plan_build(id)).map_err(to_status)?;
+                planned.extend(plan);
+            }
+
+            let installed = planned
+                .into_iter()
+                .map(|(id, action)| Installed {
+                    manifest_id: id.to_string(),
+                    details: Some(install_details(action)),
+                })
+                .collect();
+
+            Ok::<_, Status>(Response::new(DiffResponse {
+                installed,
+                upgraded: Vec::new(),
+                uninstalled: to_uninstall.iter().map(ToString::to_string).collect(),
+            }))
+        };
+
+        Box::new(diff.boxed().compat())
+    }
+
+    /// Builds every manifest in the request at the same time, merging their progress.
+    ///
+    /// Unless `keep_going` is set, the response ends with an error status as soon as any of the
+    /// builds fails, which cancels the others. Otherwise, it only ends once every build has ended.
+    /// If `check` is set, installed packages are rebuilt and compared with their outputs instead.
+    /// Closing the response stream cancels every build.
+    fn build_manifest(&mut self, request: Request<BuildRequest>) -> Self::BuildManifestFuture {
+        let ids = match parse_ids(&request.get_ref().manifest_id) {
+            Ok(ref ids) if ids.is_empty() => {
+                let status = Status::new(Code::InvalidArgument, "no manifests to build");
+                return future::err(status);
+            }
+            Ok(ids) => ids,
+            Err(status) => return future::err(status),
+        };
+
+        let options = BuildOptions {
+            keep_going: request.get_ref().keep_going,
+            check: request.get_ref().check,
+        };
+        let builds = ids
+            .into_iter()
+            .map(|id| self.store.build_package(id, options));
+        let progress = BuildStream::merge(builds, options.keep_going)
+            .map_ok(BuildResponse::from)
+            .map_err(to_status)
+            .compat();
+
+        future::ok(Response::new(Box::new(progress) as BoxStream<_>))
+    }
+
+    fn get_build_log(&mut self, request: Request<LogRequest>) -> Self::GetBuildLogFuture {
+        let request = request.into_inner();
+        let id = match parse_ids(&[request.manifest_id]) {
+            Ok(mut ids) => ids.remove(0),
+            Err(status) => return future::err(status),
+        };
+
+        let options = LogOptions {
+            offset: request.offset,
+            tail: Some(request.tail as usize).filter(|&tail| tail > 0),
+            follow: request.follow,
+        };
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut store = self.store.clone();
+        let reading = async move {
+            let chunks = store.get_build_log(&id, options);
+            let chunks = chunks.map_ok(|description| LogResponse { description });
+            await!(forward(chunks, tx));
+            Ok::<(), ()>(())
+        };
+        tokio::spawn(reading.boxed().compat());
+
+        future::ok(Response::new(into_response_stream(rx)))
+    }
+
+    /// Verifies every object in the store, repairing invalid ones if requested.
+    ///
+    /// Only trusted users may repair the store.
+    fn verify(&mut self, request: Request<VerifyRequest>) -> Self::VerifyFuture {
+        let request = request.into_inner();
+        let check = match verify_request::CheckContents::from_i32(request.check_contents) {
+            Some(verify_request::CheckContents::Enabled) => CheckContents::Enabled,
+            Some(verify_request::CheckContents::Disabled) => CheckContents::Disabled,
+            None => return future::err(unknown_value("check_contents", request.check_contents)),
+        };
+        let repair = match verify_request::Repair::from_i32(request.repair) {
+            Some(verify_request::Repair::Enabled) => Repair::Enabled,
+            Some(verify_request::Repair::Disabled) => Repair::Disabled,
+            None => return future::err(unknown_value("repair", request.repair)),
+        };
+
+        if repair == Repair::Enabled {
+            if let Err(status) = self.authorize("repair store") {
+                return future::err(status);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut store = self.store.clone();
+        let verifying = async move {
+            let results = store.verify(check, repair).map_ok(VerifyResponse::from);
+            await!(forward(results, tx));
+            Ok::<(), ()>(())
+        };
+        tokio::spawn(verifying.boxed().compat());
+
+        future::ok(Response::new(into_response_stream(rx)))
+    }
+
+    fn list_manifests(&mut self, _: Request<ListRequest>) -> Self::ListManifestsFuture {
+        let ids = match self.store.manifests() {
+            Ok(ids) => ids,
+            Err(err) => return future::err(to_status(err)),
+        };
+
+        future::ok(Response::new(ListResponse {
+            manifest_id: ids.iter().map(ToString::to_string).collect(),
+        }))
+    }
+
+    /// Adds the manifest in the request to the store. Only trusted users may add manifests.
+    fn add_manifest(&mut self, request: Request<AddManifestRequest>) -> Self::AddManifestFuture {
+        let manifest: Manifest = match request.get_ref().manifest.parse() {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                let err = StoreError::InvalidManifest(err.to_string());
+                return Box::new(future::err(to_status(err)));
+            }
+        };
+
+        let id = manifest.compute_id();
+        if let Err(status) = self.authorize(format_args!("add manifest {}", id)) {
+            return Box::new(future::err(status));
+        }
+
+        let store = self.store.clone();
+        let adding = async move {
+            let id = await!(store.add_manifest(manifest)).map_err(to_status)?;
+            Ok::<_, Status>(Response::new(AddManifestResponse {
+                manifest_id: id.to_string(),
+            }))
+        };
+
+        Box::new(adding.boxed().compat())
+    }
+
+    /// Imports the objects in the archive into the store. Only trusted users may import archives,
+    /// since the outputs they contain are not checked against anything but their own contents.
+    fn import(&mut self, request: Request<ImportRequest>) -> Self::ImportFuture {
+        let archive = request.into_inner().archive;
+        let size = archive.len();
+        if let Err(status) = self.authorize(format_args!("import archive of {} bytes", size)) {
+            return Box::new(future::err(status));
+        }
+
+        let store = self.store.clone();
+        let importing = async move {
+            let imported = await!(store.import(Cursor::new(archive))).map_err(to_status)?;
+            Ok::<_, Status>(Response::new(ImportResponse {
+                manifest_id: imported.manifests.iter().map(ToString::to_string).collect(),
+                source_id: imported.sources.iter().map(ToString::to_string).collect(),
+                output_id: imported.outputs.iter().map(ToString::to_string).collect(),
+            }))
+        };
+
+        Box::new(importing.boxed().compat())
+    }
+}
+
+/// Sends every item of `stream` to `tx`, stopping after the first error or once the client has
+/// closed the response stream.
+async fn forward<S, T>(mut stream: S, mut tx: Sender<Result<T, StoreError>>)
+where
+    S: Stream<Item = Result<T, StoreError>> + Unpin,
+{
+    while let Some(item) = await!(stream.next()) {
+        let failed = item.is_err();
+        if await!(tx.send(item)).is_err() || failed {
+            break;
+        }
+    }
+}
+
+fn into_response_stream<T>(rx: Receiver<Result<T, StoreError>>) -> BoxStream<T>
+where
+    T: Send + 'static,
+{
+    Box::new(rx.map(|item| item.map_err(to_status)).compat())
+}
+
+fn install_details(action: Action) -> InstallDetails {
+    let kind = match action {
+        Action::Memoize => Kind::Local(Local::Memoize as i32),
+        Action::Build | Action::Check => Kind::Local(Local::Build as i32),
+        Action::Substitute => Kind::FetchRemote(FetchRemote {
+            size: 0,
+            unpacked_size: 0,
+            source: None,
+        }),
+    };
+
+    InstallDetails { kind: Some(kind) }
+}
+
+fn parse_ids(ids: &[String]) -> Result<Vec<ManifestId>, Status> {
+    ids.iter()
+        .map(|id| {
+            id.parse().map_err(|_| {
+                let err = StoreError::InvalidId(id.clone());
+                Status::new(Code::InvalidArgument, err.to_string())
+            })
+        })
+        .collect()
+}
+
+fn unknown_value(field: &str, value: i32) -> Status {
+    let message = format!("unknown value {} for field `{}`", value, field);
+    Status::new(Code::InvalidArgument, message)
+}
@@ -0,0 +1,496 @@
+//! Requests sent over gRPC to a daemon serving a temporary store on a temporary socket.
+
+#![cfg(unix)]
+#![feature(async_await, await_macro, futures_api)]
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::future::Future as Future03;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+use deck_client::backend::DaemonBackend;
+use deck_client::Client as DeckClient;
+use deck_core::{BuildScript, FilesystemId, Hash, Manifest, ManifestId, Phase};
+use deck_daemon::{Config, Daemon};
+use deck_protocol::daemon::client::Daemon as DaemonClient;
+use deck_protocol::daemon::diff_response::install_details::{Kind, Local};
+use deck_protocol::daemon::verify_request::{CheckContents, Repair};
+use deck_protocol::daemon::{AddManifestRequest, BuildRequest, DiffRequest, ImportRequest};
+use deck_protocol::daemon::{ListRequest, LogRequest, VerifyRequest};
+use deck_store::progress::{FinalStatus, Progress};
+use deck_store::verify::{Invalid, Object, Problem, Verified};
+use deck_store::{BuildOptions, LogOptions, StoreError};
+use futures::future::{self, Future};
+use futures::Stream;
+use futures_preview::future::{FutureExt, TryFutureExt};
+use futures_preview::stream::StreamExt;
+use http::Uri;
+use tokio::executor::DefaultExecutor;
+use tokio::net::unix::ConnectFuture;
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+use tokio_connect::Connect;
+use tower_grpc::{BoxBody, Code, Request, Status};
+use tower_h2::client;
+use tower_http::add_origin::{AddOrigin, Builder};
+use tower_util::MakeService;
+
+type Client = DaemonClient<AddOrigin<client::Connection<UnixStream, DefaultExecutor, BoxBody>>>;
+
+const MISSING_ID: &str = "bar@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+/// Connects to the socket at the given path.
+struct Socket(PathBuf);
+
+impl Connect for Socket {
+    type Connected = UnixStream;
+    type Error = io::Error;
+    type Future = ConnectFuture;
+
+    fn connect(&self) -> Self::Future {
+        UnixStream::connect(&self.0)
+    }
+}
+
+struct TempDaemon {
+    dir: PathBuf,
+    socket: PathBuf,
+    runtime: Runtime,
+}
+
+impl TempDaemon {
+    fn start() -> Self {
+        TempDaemon::start_with("")
+    }
+
+    /// Starts a daemon with `extra` appended to its configuration.
+    fn start_with(extra: &str) -> Self {
+        let dir = env::temp_dir().join(format!("deck-daemon-test-{}", Hash::random()));
+        let store = dir.join("store");
+        let socket = dir.join("daemon.sock");
+        fs::create_dir_all(&store).expect("Failed to create store");
+
+        let cfg = format!(
+            "store-path = {:?}\nsocket-path = {:?}\n{}",
+            store, socket, extra
+        );
+        let cfg: Config = toml::from_str(&cfg).expect("Failed to parse config");
+        let daemon = Daemon::new(cfg).expect("Failed to create daemon");
+        let serving = daemon.serve().expect("Failed to listen on socket");
+
+        let mut runtime = Runtime::new().expect("Failed to start runtime");
+        runtime.spawn(serving.map_err(|e| panic!("Daemon stopped: {}", e)));
+
+        TempDaemon {
+            dir,
+            socket,
+            runtime,
+        }
+    }
+
+    /// Writes an unregistered manifest named `foo` directly into the store.
+    fn add_manifest(&self) -> ManifestId {
+        let manifest = foo_manifest();
+        let id = manifest.compute_id();
+        let dir = self.dir.join("store").join("manifests");
+        fs::create_dir_all(&dir).expect("Failed to create manifests dir");
+        fs::write(dir.join(id.to_path()), manifest.to_string()).expect("Failed to write manifest");
+        id
+    }
+
+    fn connect(&mut self) -> Client {
+        let socket = Socket(self.socket.clone());
+        let mut connect =
+            client::Connect::new(socket, Default::default(), DefaultExecutor::current());
+        let client = connect.make_service(()).map(|connection| {
+            let uri: Uri = "http://localhost".parse().unwrap();
+            let connection = Builder::new().uri(uri).build(connection).unwrap();
+            DaemonClient::new(connection)
+        });
+
+        self.runtime
+            .block_on(client)
+            .expect("Failed to connect to daemon")
+    }
+
+    fn run<F, T>(&mut self, future: F) -> Result<T, Status>
+    where
+        F: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.runtime.block_on(future)
+    }
+
+    /// Returns a high-level client which sends its requests to the daemon.
+    fn client(&self) -> DeckClient<DaemonBackend> {
+        DeckClient::new(DaemonBackend::new(self.socket.clone()))
+    }
+
+    /// Runs `future`, such as a request made through `TempDaemon::client()`, to completion.
+    fn run_client<F, T>(&mut self, future: F) -> T
+    where
+        F: Future03<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let future = future.map(Ok::<T, ()>).boxed().compat();
+        self.runtime.block_on(future).expect("Future failed")
+    }
+}
+
+impl Drop for TempDaemon {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn foo_manifest() -> Manifest {
+    Manifest::build("foo", "1.0.0", "fc3j3vub6kodu4jtfoakfs5xhumqi62m", None)
+        .finish()
+        .expect("Failed to create manifest")
+}
+
+/// Creates a manifest for `foo` whose build writes a greeting into its output.
+fn greeting_manifest() -> Manifest {
+    let script = BuildScript::new("/bin/sh")
+        .arg("-c")
+        .phase(Phase::Compile, "echo 'Hello, world!' > \"$out/greeting\"");
+    Manifest::build("foo", "1.0.0", "fc3j3vub6kodu4jtfoakfs5xhumqi62m", None)
+        .build_script(script)
+        .finish()
+        .expect("Failed to create manifest")
+}
+
+#[test]
+fn accepts_connections_from_every_user() {
+    let daemon = TempDaemon::start();
+    let meta = fs::metadata(&daemon.socket).expect("Socket is missing");
+    assert_eq!(meta.permissions().mode() & 0o777, 0o666);
+}
+
+#[test]
+fn verifies_store() {
+    let mut daemon = TempDaemon::start();
+    let id = daemon.add_manifest();
+    let mut client = daemon.connect();
+
+    let request = VerifyRequest {
+        check_contents: CheckContents::Disabled as i32,
+        repair: Repair::Disabled as i32,
+    };
+    let results = client
+        .verify(Request::new(request))
+        .and_then(|response| response.into_inner().collect());
+    let results = daemon.run(results).expect("Failed to verify store");
+
+    let results: Vec<_> = results.into_iter().map(Verified::try_from).collect();
+    let expected = Verified::Invalid(Invalid {
+        object: Object::Manifest(id),
+        problem: Problem::Unregistered,
+    });
+    assert_eq!(results, vec![Ok(expected)]);
+}
+
+#[test]
+fn serves_concurrent_clients() {
+    let mut daemon = TempDaemon::start();
+    let mut clients: Vec<_> = (0..4).map(|_| daemon.connect()).collect();
+
+    let requests: Vec<_> = clients
+        .iter_mut()
+        .map(|client| {
+            let request = VerifyRequest::default();
+            let results = client.verify(Request::new(request));
+            results.and_then(|response| response.into_inner().collect())
+        })
+        .collect();
+
+    let results = daemon
+        .run(future::join_all(requests))
+        .expect("Request failed");
+    assert_eq!(results.len(), 4);
+    assert!(results.iter().all(Vec::is_empty));
+}
+
+#[test]
+fn computes_transaction_diff() {
+    let mut daemon = TempDaemon::start();
+    let id = daemon.add_manifest();
+    let mut client = daemon.connect();
+
+    let request = DiffRequest {
+        to_install: vec![id.to_string()],
+        to_upgrade: Vec::new(),
+        to_uninstall: vec![MISSING_ID.to_string()],
+    };
+    let diff = client.get_transaction_diff(Request::new(request));
+    let diff = daemon
+        .run(diff)
+        .expect("Failed to compute diff")
+        .into_inner();
+
+    assert_eq!(diff.installed.len(), 1);
+    assert_eq!(diff.installed[0].manifest_id, id.to_string());
+    let details = diff.installed[0]
+        .details
+        .clone()
+        .and_then(|details| details.kind);
+    assert_eq!(details, Some(Kind::Local(Local::Build as i32)));
+    assert_eq!(diff.uninstalled, vec![MISSING_ID.to_string()]);
+}
+
+#[test]
+fn lists_manifests() {
+    let mut daemon = TempDaemon::start();
+    let id = daemon.add_manifest();
+    let mut client = daemon.connect();
+
+    let ids = client.list_manifests(Request::new(ListRequest {}));
+    let ids = daemon
+        .run(ids)
+        .expect("Failed to list manifests")
+        .into_inner();
+    assert_eq!(ids.manifest_id, vec![id.to_string()]);
+}
+
+#[test]
+fn reports_missing_manifests() {
+    let mut daemon = TempDaemon::start();
+    let mut client = daemon.connect();
+
+    let request = BuildRequest {
+        manifest_id: vec![MISSING_ID.to_string()],
+        keep_going: false,
+        check: false,
+    };
+    let progress = client
+        .build_manifest(Request::new(request))
+        .and_then(|response| response.into_inner().collect());
+    let err = daemon.run(progress).expect_err("Build should have failed");
+    assert_eq!(err.code(), Code::NotFound);
+
+    let request = LogRequest {
+        manifest_id: MISSING_ID.to_string(),
+        ..LogRequest::default()
+    };
+    let log = client
+        .get_build_log(Request::new(request))
+        .and_then(|response| response.into_inner().collect());
+    let err = daemon.run(log).expect_err("Log should not exist");
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[test]
+fn builds_manifests() {
+    let mut daemon = TempDaemon::start_with("trusted-users = [\"*\"]");
+    let mut client = daemon.connect();
+
+    let manifest = greeting_manifest();
+    let id = manifest.compute_id();
+    let output = manifest.outputs().next().expect("Manifest has no outputs");
+
+    let request = AddManifestRequest {
+        manifest: manifest.to_string(),
+    };
+    let added = client.add_manifest(Request::new(request));
+    daemon.run(added).expect("Failed to add manifest");
+
+    let request = BuildRequest {
+        manifest_id: vec![id.to_string()],
+        keep_going: false,
+        check: false,
+    };
+    let progress = client
+        .build_manifest(Request::new(request))
+        .and_then(|response| response.into_inner().collect());
+    let progress = daemon.run(progress).expect("Build failed");
+    let progress: Vec<_> = progress
+        .into_iter()
+        .map(|message| Progress::try_from(message).expect("Invalid progress"))
+        .collect();
+
+    match progress.last() {
+        Some(Progress::Finished(ref finished)) => {
+            assert_eq!(finished.package_id, id);
+            match finished.status {
+                FinalStatus::Built => {}
+                ref status => panic!("Package was not built: {:?}", status),
+            }
+        }
+        last => panic!("Build did not finish: {:?}", last),
+    }
+
+    let greeting = daemon
+        .dir
+        .join("store")
+        .join("outputs")
+        .join(output.to_path())
+        .join("greeting");
+    let greeting = fs::read_to_string(greeting).expect("Missing output");
+    assert_eq!(greeting, "Hello, world!\n");
+}
+
+#[test]
+fn rejects_invalid_ids() {
+    let mut daemon = TempDaemon::start();
+    let mut client = daemon.connect();
+
+    let request = BuildRequest {
+        manifest_id: vec!["not a manifest".to_string()],
+        keep_going: false,
+        check: false,
+    };
+    let progress = client
+        .build_manifest(Request::new(request))
+        .and_then(|response| response.into_inner().collect());
+    let err = daemon.run(progress).expect_err("Build should have failed");
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+#[test]
+fn adds_manifests_for_trusted_users() {
+    let mut daemon = TempDaemon::start_with("trusted-users = [\"*\"]");
+    let mut client = daemon.connect();
+
+    let manifest = foo_manifest();
+    let request = AddManifestRequest {
+        manifest: manifest.to_string(),
+    };
+    let added = client.add_manifest(Request::new(request));
+    let added = daemon
+        .run(added)
+        .expect("Failed to add manifest")
+        .into_inner();
+    assert_eq!(added.manifest_id, manifest.compute_id().to_string());
+
+    let ids = client.list_manifests(Request::new(ListRequest {}));
+    let ids = daemon
+        .run(ids)
+        .expect("Failed to list manifests")
+        .into_inner();
+    assert_eq!(ids.manifest_id, vec![added.manifest_id]);
+}
+
+#[test]
+fn rejects_privileged_requests_from_untrusted_users() {
+    let mut daemon = TempDaemon::start_with("trusted-users = []");
+    let mut client = daemon.connect();
+
+    let request = AddManifestRequest {
+        manifest: foo_manifest().to_string(),
+    };
+    let added = client.add_manifest(Request::new(request));
+    let err = daemon
+        .run(added)
+        .expect_err("Untrusted user added manifest");
+    assert_eq!(err.code(), Code::PermissionDenied);
+
+    let request = ImportRequest {
+        archive: Vec::new(),
+    };
+    let imported = client.import(Request::new(request));
+    let err = daemon
+        .run(imported)
+        .expect_err("Untrusted user imported archive");
+    assert_eq!(err.code(), Code::PermissionDenied);
+
+    let request = VerifyRequest {
+        check_contents: CheckContents::Disabled as i32,
+        repair: Repair::Enabled as i32,
+    };
+    let results = client
+        .verify(Request::new(request))
+        .and_then(|response| response.into_inner().collect());
+    let err = daemon
+        .run(results)
+        .expect_err("Untrusted user repaired store");
+    assert_eq!(err.code(), Code::PermissionDenied);
+}
+
+#[test]
+fn client_builds_checks_and_reads_logs() {
+    let mut daemon = TempDaemon::start_with("trusted-users = [\"*\"]");
+    let mut client = daemon.client();
+    let manifest = greeting_manifest();
+    let id = manifest.compute_id();
+
+    let (client, statuses) = daemon.run_client(async move {
+        let mut statuses = Vec::new();
+        for &check in &[false, true] {
+            let options = BuildOptions {
+                check,
+                ..BuildOptions::default()
+            };
+            let mut build = client.build(manifest.clone(), options);
+            let mut last = None;
+            while let Some(progress) = await!(build.next()) {
+                last = Some(progress.expect("Build failed"));
+            }
+
+            match last {
+                Some(Progress::Finished(finished)) => statuses.push(finished.status),
+                last => panic!("Build did not finish: {:?}", last),
+            }
+        }
+
+        (client, statuses)
+    });
+    match statuses.as_slice() {
+        [FinalStatus::Built, FinalStatus::Checked] => {}
+        other => panic!("expected a build and a check, got: {:?}", other),
+    }
+
+    let log = daemon.run_client(async move {
+        let mut client = client;
+        let mut log = client.log(&id, LogOptions::default());
+        let mut text = Vec::new();
+        while let Some(chunk) = await!(log.next()) {
+            text.extend(chunk.expect("Failed to read log"));
+        }
+        String::from_utf8(text).expect("Log is not UTF-8")
+    });
+    assert!(log.contains("=== build of "), "{}", log);
+}
+
+#[test]
+fn client_maps_error_codes() {
+    let mut daemon = TempDaemon::start_with("trusted-users = []");
+    let mut client = daemon.client();
+    let missing: ManifestId = MISSING_ID.parse().unwrap();
+
+    let (denied, not_found, logged) = daemon.run_client(async move {
+        let mut build = client.build(foo_manifest(), BuildOptions::default());
+        let denied = await!(build.next());
+        drop(build);
+
+        let mut install = client.install(vec![missing.clone()]);
+        let mut not_found = None;
+        while let Some(progress) = await!(install.next()) {
+            if let Err(err) = progress {
+                not_found = Some(err);
+            }
+        }
+        drop(install);
+
+        let mut log = client.log(&missing, LogOptions::default());
+        let logged = await!(log.next());
+        (denied, not_found, logged)
+    });
+
+    match denied {
+        Some(Err(StoreError::PermissionDenied(_))) => {}
+        other => panic!("expected the manifest to be rejected, got: {:?}", other),
+    }
+
+    let missing = Object::Manifest(MISSING_ID.parse().unwrap());
+    match not_found {
+        Some(StoreError::NotFound(ref object)) => assert_eq!(*object, missing),
+        other => panic!("expected a missing manifest, got: {:?}", other),
+    }
+    match logged {
+        Some(Err(StoreError::NotFound(ref object))) => assert_eq!(*object, missing),
+        other => panic!("expected a missing log, got: {:?}", other),
+    }
+}
@@ -0,0 +1,45 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+/// Error returned when a protocol message cannot be converted into its native counterpart.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConversionError {
+    /// A required field of the message is not set.
+    MissingField(&'static str),
+    /// An enum field holds a value which is not known.
+    UnknownValue(&'static str, i32),
+    /// A field holds an ID which cannot be parsed.
+    InvalidId(&'static str, String),
+    /// A timestamp or duration field holds a value which cannot be represented.
+    OutOfRange(&'static str),
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            ConversionError::MissingField(ref field) => write!(fmt, "missing field `{}`", field),
+            ConversionError::UnknownValue(ref field, ref value) => {
+                write!(fmt, "unknown value {} for field `{}`", value, field)
+            }
+            ConversionError::InvalidId(ref field, ref id) => {
+                write!(fmt, "invalid ID `{}` in field `{}`", id, field)
+            }
+            ConversionError::OutOfRange(ref field) => {
+                write!(fmt, "value of field `{}` is out of range", field)
+            }
+        }
+    }
+}
+
+impl Error for ConversionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// Parses the ID held by `field`, for use when converting messages.
+pub(crate) fn parse_id<T: FromStr>(field: &'static str, id: &str) -> Result<T, ConversionError> {
+    id.parse()
+        .map_err(|_| ConversionError::InvalidId(field, id.to_string()))
+}
@@ -3,7 +3,15 @@
 #[macro_use]
 extern crate prost_derive;
 
+pub use self::error::ConversionError;
+pub use self::status::{from_status, to_status};
+
 /// Protocol for communicating with a daemon managing a central store in multi-user mode.
 pub mod daemon {
     include!(concat!(env!("OUT_DIR"), "/deck.daemon.v1alpha1.rs"));
 }
+
+mod error;
+mod progress;
+mod status;
+mod verify;
@@ -0,0 +1,609 @@
+//! Conversions between build progress and `BuildResponse` messages.
+//!
+//! Every field of `Progress` has a counterpart in `BuildResponse`, so converting progress into a
+//! message and back yields the same progress, which lets a build running on the daemon be
+//! reported exactly like a local one.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use deck_store::progress::{
+    Action, Blocked, BuildStatus, Building, Downloading, Failed, FailureReason, FinalStatus,
+    Finished, Installing, LoadSource, Loading, Progress, Reusing, Started, Substituted,
+    Substituting, TimeLimit,
+};
+use prost_types::{Duration as ProtoDuration, Timestamp};
+
+use crate::daemon::build_response::{self as response, Status};
+use crate::daemon::BuildResponse;
+use crate::error::{parse_id, ConversionError};
+
+impl From<Progress> for BuildResponse {
+    fn from(progress: Progress) -> Self {
+        let update_time = Some(to_timestamp(progress.timestamp()));
+        let status = match progress {
+            Progress::Started(p) => Status::Started(response::Started {
+                manifest_id: p.package_id.to_string(),
+                planned: p
+                    .planned
+                    .into_iter()
+                    .map(|(id, action)| response::started::Planned {
+                        manifest_id: id.to_string(),
+                        action: to_action(action) as i32,
+                    })
+                    .collect(),
+            }),
+            Progress::Blocked(p) => Status::Blocked(response::Blocked {
+                manifest_id: p.package_id.to_string(),
+                description: p.description,
+            }),
+            Progress::Loading(p) => {
+                let (source, index) = match p.source {
+                    LoadSource::Store => (response::loading::Source::Store, 0),
+                    LoadSource::Repository(i) => (response::loading::Source::Repository, i),
+                    LoadSource::RemoteStore(i) => (response::loading::Source::RemoteStore, i),
+                };
+
+                Status::Loading(response::Loading {
+                    manifest_id: p.package_id.to_string(),
+                    source: source as i32,
+                    index: index as u32,
+                })
+            }
+            Progress::Downloading(p) => Status::Downloading(response::Downloading {
+                manifest_id: p.package_id.to_string(),
+                source: p.source,
+                downloaded_bytes: p.downloaded_bytes,
+                total_bytes: p.total_bytes,
+            }),
+            Progress::Substituting(p) => Status::Substituting(response::Substituting {
+                manifest_id: p.package_id.to_string(),
+                output_id: p.output.to_string(),
+                cache_index: p.cache as u32,
+                downloaded_bytes: p.downloaded_bytes,
+                total_bytes: p.total_bytes,
+            }),
+            Progress::Substituted(p) => Status::Substituted(response::Substituted {
+                manifest_id: p.package_id.to_string(),
+                output_id: p.output.to_string(),
+                installed_bytes: p.installed_bytes,
+            }),
+            Progress::Reusing(p) => Status::Reusing(response::Reusing {
+                manifest_id: p.package_id.to_string(),
+                source_id: p.source.to_string(),
+                existing_source_id: p.existing.to_string(),
+            }),
+            Progress::Building(p) => Status::Building(response::Building {
+                manifest_id: p.package_id.to_string(),
+                status: to_build_status(&p.status) as i32,
+                current_task: p.current_task,
+                total_tasks: p.total_tasks,
+                description: p.description,
+                stdout: p.stdout,
+                stderr: p.stderr,
+            }),
+            Progress::Installing(p) => Status::Installing(response::Installing {
+                manifest_id: p.package_id.to_string(),
+                description: p.description,
+                written_bytes: p.written_bytes,
+                total_bytes: p.total_bytes,
+            }),
+            Progress::Finished(p) => Status::Finished(response::Finished {
+                manifest_id: p.package_id.to_string(),
+                status: to_final_status(&p.status) as i32,
+                installed_bytes: p.installed_bytes,
+            }),
+            Progress::Failed(p) => {
+                let reason = match p.reason {
+                    FailureReason::Error(e) => response::failed::Reason::Error(e),
+                    FailureReason::TimedOut(limit) => {
+                        response::failed::Reason::TimedOut(to_time_limit(limit))
+                    }
+                    FailureReason::DependencyFailed(id) => {
+                        response::failed::Reason::DependencyFailed(id.to_string())
+                    }
+                };
+
+                Status::Failed(response::Failed {
+                    manifest_id: p.package_id.to_string(),
+                    reason: Some(reason),
+                })
+            }
+        };
+
+        BuildResponse {
+            update_time,
+            status: Some(status),
+        }
+    }
+}
+
+impl TryFrom<BuildResponse> for Progress {
+    type Error = ConversionError;
+
+    fn try_from(message: BuildResponse) -> Result<Self, Self::Error> {
+        let update_time = message
+            .update_time
+            .ok_or(ConversionError::MissingField("update_time"))?;
+        let timestamp = from_timestamp(update_time)?;
+
+        let progress = match message
+            .status
+            .ok_or(ConversionError::MissingField("status"))?
+        {
+            Status::Started(p) => {
+                let planned = p
+                    .planned
+                    .into_iter()
+                    .map(|planned| {
+                        let id = parse_id("planned.manifest_id", &planned.manifest_id)?;
+                        Ok((id, from_action(planned.action)?))
+                    })
+                    .collect::<Result<_, ConversionError>>()?;
+
+                Progress::Started(Started {
+                    package_id: parse_id("manifest_id", &p.manifest_id)?,
+                    planned,
+                    timestamp,
+                })
+            }
+            Status::Blocked(p) => Progress::Blocked(Blocked {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                description: p.description,
+                timestamp,
+            }),
+            Status::Loading(p) => {
+                let index = p.index as usize;
+                let source = match response::loading::Source::from_i32(p.source) {
+                    Some(response::loading::Source::Store) => LoadSource::Store,
+                    Some(response::loading::Source::Repository) => LoadSource::Repository(index),
+                    Some(response::loading::Source::RemoteStore) => LoadSource::RemoteStore(index),
+                    None => return Err(ConversionError::UnknownValue("source", p.source)),
+                };
+
+                Progress::Loading(Loading {
+                    package_id: parse_id("manifest_id", &p.manifest_id)?,
+                    source,
+                    timestamp,
+                })
+            }
+            Status::Downloading(p) => Progress::Downloading(Downloading {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                source: p.source,
+                downloaded_bytes: p.downloaded_bytes,
+                total_bytes: p.total_bytes,
+                timestamp,
+            }),
+            Status::Substituting(p) => Progress::Substituting(Substituting {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                output: parse_id("output_id", &p.output_id)?,
+                cache: p.cache_index as usize,
+                downloaded_bytes: p.downloaded_bytes,
+                total_bytes: p.total_bytes,
+                timestamp,
+            }),
+            Status::Substituted(p) => Progress::Substituted(Substituted {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                output: parse_id("output_id", &p.output_id)?,
+                installed_bytes: p.installed_bytes,
+                timestamp,
+            }),
+            Status::Reusing(p) => Progress::Reusing(Reusing {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                source: parse_id("source_id", &p.source_id)?,
+                existing: parse_id("existing_source_id", &p.existing_source_id)?,
+                timestamp,
+            }),
+            Status::Building(p) => Progress::Building(Building {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                status: from_build_status(p.status)?,
+                current_task: p.current_task,
+                total_tasks: p.total_tasks,
+                description: p.description,
+                stdout: p.stdout,
+                stderr: p.stderr,
+                timestamp,
+            }),
+            Status::Installing(p) => Progress::Installing(Installing {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                description: p.description,
+                written_bytes: p.written_bytes,
+                total_bytes: p.total_bytes,
+                timestamp,
+            }),
+            Status::Finished(p) => Progress::Finished(Finished {
+                package_id: parse_id("manifest_id", &p.manifest_id)?,
+                status: from_final_status(p.status)?,
+                installed_bytes: p.installed_bytes,
+                timestamp,
+            }),
+            Status::Failed(p) => {
+                let reason = match p.reason.ok_or(ConversionError::MissingField("reason"))? {
+                    response::failed::Reason::Error(e) => FailureReason::Error(e),
+                    response::failed::Reason::TimedOut(limit) => {
+                        FailureReason::TimedOut(from_time_limit(limit)?)
+                    }
+                    response::failed::Reason::DependencyFailed(id) => {
+                        FailureReason::DependencyFailed(parse_id("dependency_failed", &id)?)
+                    }
+                };
+
+                Progress::Failed(Failed {
+                    package_id: parse_id("manifest_id", &p.manifest_id)?,
+                    reason,
+                    timestamp,
+                })
+            }
+        };
+
+        Ok(progress)
+    }
+}
+
+fn to_timestamp(time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn from_timestamp(timestamp: Timestamp) -> Result<DateTime<Utc>, ConversionError> {
+    if timestamp.nanos < 0 {
+        return Err(ConversionError::OutOfRange("update_time"));
+    }
+
+    NaiveDateTime::from_timestamp_opt(timestamp.seconds, timestamp.nanos as u32)
+        .map(|time| DateTime::from_utc(time, Utc))
+        .ok_or(ConversionError::OutOfRange("update_time"))
+}
+
+fn to_duration(duration: Duration) -> ProtoDuration {
+    ProtoDuration {
+        seconds: duration.as_secs() as i64,
+        nanos: duration.subsec_nanos() as i32,
+    }
+}
+
+fn from_duration(
+    field: &'static str,
+    duration: ProtoDuration,
+) -> Result<Duration, ConversionError> {
+    if duration.seconds < 0 || duration.nanos < 0 || duration.nanos >= 1_000_000_000 {
+        return Err(ConversionError::OutOfRange(field));
+    }
+
+    Ok(Duration::new(
+        duration.seconds as u64,
+        duration.nanos as u32,
+    ))
+}
+
+fn to_time_limit(limit: TimeLimit) -> response::failed::TimeLimit {
+    use self::response::failed::time_limit::Limit;
+
+    let limit = match limit {
+        TimeLimit::Timeout(duration) => Limit::Timeout(to_duration(duration)),
+        TimeLimit::MaxSilentTime(duration) => Limit::MaxSilentTime(to_duration(duration)),
+    };
+
+    response::failed::TimeLimit { limit: Some(limit) }
+}
+
+fn from_time_limit(limit: response::failed::TimeLimit) -> Result<TimeLimit, ConversionError> {
+    use self::response::failed::time_limit::Limit;
+
+    match limit
+        .limit
+        .ok_or(ConversionError::MissingField("timed_out.limit"))?
+    {
+        Limit::Timeout(duration) => from_duration("timeout", duration).map(TimeLimit::Timeout),
+        Limit::MaxSilentTime(duration) => {
+            from_duration("max_silent_time", duration).map(TimeLimit::MaxSilentTime)
+        }
+    }
+}
+
+fn to_action(action: Action) -> response::started::Action {
+    match action {
+        Action::Memoize => response::started::Action::Memoize,
+        Action::Substitute => response::started::Action::Substitute,
+        Action::Build => response::started::Action::Build,
+        Action::Check => response::started::Action::Check,
+    }
+}
+
+fn from_action(action: i32) -> Result<Action, ConversionError> {
+    match response::started::Action::from_i32(action) {
+        Some(response::started::Action::Memoize) => Ok(Action::Memoize),
+        Some(response::started::Action::Substitute) => Ok(Action::Substitute),
+        Some(response::started::Action::Build) => Ok(Action::Build),
+        Some(response::started::Action::Check) => Ok(Action::Check),
+        None => Err(ConversionError::UnknownValue("action", action)),
+    }
+}
+
+fn to_build_status(status: &BuildStatus) -> response::building::Status {
+    match *status {
+        BuildStatus::Started => response::building::Status::Started,
+        BuildStatus::Preparing => response::building::Status::Preparing,
+        BuildStatus::Configuring => response::building::Status::Configuring,
+        BuildStatus::Compiling => response::building::Status::Compiling,
+        BuildStatus::Testing => response::building::Status::Testing,
+        BuildStatus::Finalizing => response::building::Status::Finalizing,
+    }
+}
+
+fn from_build_status(status: i32) -> Result<BuildStatus, ConversionError> {
+    match response::building::Status::from_i32(status) {
+        Some(response::building::Status::Started) => Ok(BuildStatus::Started),
+        Some(response::building::Status::Preparing) => Ok(BuildStatus::Preparing),
+        Some(response::building::Status::Configuring) => Ok(BuildStatus::Configuring),
+        Some(response::building::Status::Compiling) => Ok(BuildStatus::Compiling),
+        Some(response::building::Status::Testing) => Ok(BuildStatus::Testing),
+        Some(response::building::Status::Finalizing) => Ok(BuildStatus::Finalizing),
+        None => Err(ConversionError::UnknownValue("status", status)),
+    }
+}
+
+fn to_final_status(status: &FinalStatus) -> response::finished::Status {
+    match *status {
+        FinalStatus::Memoized => response::finished::Status::Memoized,
+        FinalStatus::Reinstalled => response::finished::Status::Reinstalled,
+        FinalStatus::Downloaded => response::finished::Status::Downloaded,
+        FinalStatus::Built => response::finished::Status::Built,
+        FinalStatus::Checked => response::finished::Status::Checked,
+    }
+}
+
+fn from_final_status(status: i32) -> Result<FinalStatus, ConversionError> {
+    match response::finished::Status::from_i32(status) {
+        Some(response::finished::Status::Memoized) => Ok(FinalStatus::Memoized),
+        Some(response::finished::Status::Reinstalled) => Ok(FinalStatus::Reinstalled),
+        Some(response::finished::Status::Downloaded) => Ok(FinalStatus::Downloaded),
+        Some(response::finished::Status::Built) => Ok(FinalStatus::Built),
+        Some(response::finished::Status::Checked) => Ok(FinalStatus::Checked),
+        None => Err(ConversionError::UnknownValue("status", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use deck_core::{Hash, ManifestId, OutputId, SourceId};
+    use prost::Message;
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+
+    use super::*;
+
+    /// Wrapper which generates arbitrary `Progress` values.
+    #[derive(Clone, Debug)]
+    struct AnyProgress(Progress);
+
+    impl Arbitrary for AnyProgress {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let package_id = manifest_id(g);
+            let timestamp = timestamp(g);
+            let progress = match g.gen_range(0, 11) {
+                0 => Progress::Started(Started {
+                    package_id,
+                    planned: (0..g.gen_range(0, 4))
+                        .map(|_| (manifest_id(g), action(g)))
+                        .collect(),
+                    timestamp,
+                }),
+                1 => Progress::Blocked(Blocked {
+                    package_id,
+                    description: String::arbitrary(g),
+                    timestamp,
+                }),
+                2 => Progress::Loading(Loading {
+                    package_id,
+                    source: match g.gen_range(0, 3) {
+                        0 => LoadSource::Store,
+                        1 => LoadSource::Repository(g.gen_range(0, 8)),
+                        _ => LoadSource::RemoteStore(g.gen_range(0, 8)),
+                    },
+                    timestamp,
+                }),
+                3 => Progress::Downloading(Downloading {
+                    package_id,
+                    source: String::arbitrary(g),
+                    downloaded_bytes: g.gen(),
+                    total_bytes: Option::arbitrary(g),
+                    timestamp,
+                }),
+                4 => Progress::Substituting(Substituting {
+                    package_id,
+                    output: output_id(g),
+                    cache: g.gen_range(0, 8),
+                    downloaded_bytes: g.gen(),
+                    total_bytes: Option::arbitrary(g),
+                    timestamp,
+                }),
+                5 => Progress::Substituted(Substituted {
+                    package_id,
+                    output: output_id(g),
+                    installed_bytes: g.gen(),
+                    timestamp,
+                }),
+                6 => Progress::Reusing(Reusing {
+                    package_id,
+                    source: source_id(g),
+                    existing: source_id(g),
+                    timestamp,
+                }),
+                7 => Progress::Building(Building {
+                    package_id,
+                    status: match g.gen_range(0, 6) {
+                        0 => BuildStatus::Started,
+                        1 => BuildStatus::Preparing,
+                        2 => BuildStatus::Configuring,
+                        3 => BuildStatus::Compiling,
+                        4 => BuildStatus::Testing,
+                        _ => BuildStatus::Finalizing,
+                    },
+                    current_task: g.gen(),
+                    total_tasks: g.gen(),
+                    description: String::arbitrary(g),
+                    stdout: Vec::arbitrary(g),
+                    stderr: Vec::arbitrary(g),
+                    timestamp,
+                }),
+                8 => Progress::Installing(Installing {
+                    package_id,
+                    description: String::arbitrary(g),
+                    written_bytes: g.gen(),
+                    total_bytes: Option::arbitrary(g),
+                    timestamp,
+                }),
+                9 => Progress::Finished(Finished {
+                    package_id,
+                    status: match g.gen_range(0, 5) {
+                        0 => FinalStatus::Memoized,
+                        1 => FinalStatus::Reinstalled,
+                        2 => FinalStatus::Downloaded,
+                        3 => FinalStatus::Built,
+                        _ => FinalStatus::Checked,
+                    },
+                    installed_bytes: g.gen(),
+                    timestamp,
+                }),
+                _ => Progress::Failed(Failed {
+                    package_id,
+                    reason: match g.gen_range(0, 4) {
+                        0 => FailureReason::Error(String::arbitrary(g)),
+                        1 => FailureReason::TimedOut(TimeLimit::Timeout(duration(g))),
+                        2 => FailureReason::TimedOut(TimeLimit::MaxSilentTime(duration(g))),
+                        _ => FailureReason::DependencyFailed(manifest_id(g)),
+                    },
+                    timestamp,
+                }),
+            };
+
+            AnyProgress(progress)
+        }
+    }
+
+    fn hash<G: Gen>(g: &mut G) -> String {
+        let seed: u64 = g.gen();
+        Hash::compute()
+            .input(seed.to_le_bytes())
+            .finish()
+            .to_string()
+    }
+
+    fn manifest_id<G: Gen>(g: &mut G) -> ManifestId {
+        let name = ["foo", "bar", "baz-quux"].choose(g).unwrap();
+        let version = format!("{}.{}.{}", g.gen::<u8>(), g.gen::<u8>(), g.gen::<u8>());
+        format!("{}@{}-{}", name, version, hash(g)).parse().unwrap()
+    }
+
+    fn output_id<G: Gen>(g: &mut G) -> OutputId {
+        let output = ["", ":man", ":dev"].choose(g).unwrap();
+        format!("foo@1.0.0{}-{}", output, hash(g)).parse().unwrap()
+    }
+
+    fn source_id<G: Gen>(g: &mut G) -> SourceId {
+        let name = ["foo.tar.gz", "bar.json"].choose(g).unwrap();
+        format!("{}-{}", name, hash(g)).parse().unwrap()
+    }
+
+    fn action<G: Gen>(g: &mut G) -> Action {
+        let actions = [
+            Action::Memoize,
+            Action::Substitute,
+            Action::Build,
+            Action::Check,
+        ];
+        *actions.choose(g).unwrap()
+    }
+
+    fn timestamp<G: Gen>(g: &mut G) -> DateTime<Utc> {
+        let seconds = g.gen_range(0, 4_000_000_000);
+        Utc.timestamp(seconds, g.gen_range(0, 1_000_000_000))
+    }
+
+    fn duration<G: Gen>(g: &mut G) -> Duration {
+        Duration::new(g.gen_range(0, 1 << 40), g.gen_range(0, 1_000_000_000))
+    }
+
+    quickcheck! {
+        fn progress_round_trips(progress: AnyProgress) -> bool {
+            let original = progress.0;
+            let response = BuildResponse::from(original.clone());
+
+            let mut encoded = Vec::new();
+            response.encode(&mut encoded).expect("Failed to encode response");
+            let decoded = BuildResponse::decode(&encoded[..]).expect("Failed to decode response");
+
+            let converted = Progress::try_from(decoded).expect("Failed to convert response");
+            format!("{:?}", converted) == format!("{:?}", original)
+        }
+    }
+
+    #[test]
+    fn unknown_total_bytes_are_unset() {
+        let id: ManifestId = "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .unwrap();
+        let downloading = |total_bytes| {
+            Progress::Downloading(Downloading {
+                package_id: id.clone(),
+                source: "https://example.com/foo.tar.gz".to_string(),
+                downloaded_bytes: 0,
+                total_bytes,
+                timestamp: Utc::now(),
+            })
+        };
+
+        match BuildResponse::from(downloading(None)).status {
+            Some(Status::Downloading(ref p)) => assert_eq!(p.total_bytes, None),
+            ref status => panic!("expected downloading status, got: {:?}", status),
+        }
+
+        match BuildResponse::from(downloading(Some(0))).status {
+            Some(Status::Downloading(ref p)) => assert_eq!(p.total_bytes, Some(0)),
+            ref status => panic!("expected downloading status, got: {:?}", status),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_responses() {
+        let missing = BuildResponse {
+            update_time: Some(to_timestamp(Utc::now())),
+            status: None,
+        };
+        assert_eq!(
+            Progress::try_from(missing).unwrap_err(),
+            ConversionError::MissingField("status")
+        );
+
+        let unknown_status = BuildResponse {
+            update_time: Some(to_timestamp(Utc::now())),
+            status: Some(Status::Finished(response::Finished {
+                manifest_id: "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m".to_string(),
+                status: 42,
+                installed_bytes: 0,
+            })),
+        };
+        assert_eq!(
+            Progress::try_from(unknown_status).unwrap_err(),
+            ConversionError::UnknownValue("status", 42)
+        );
+
+        let invalid_id = BuildResponse {
+            update_time: Some(to_timestamp(Utc::now())),
+            status: Some(Status::Blocked(response::Blocked {
+                manifest_id: "foo".to_string(),
+                description: String::new(),
+            })),
+        };
+        assert_eq!(
+            Progress::try_from(invalid_id).unwrap_err(),
+            ConversionError::InvalidId("manifest_id", "foo".to_string())
+        );
+    }
+}
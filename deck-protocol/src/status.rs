@@ -0,0 +1,141 @@
+//! Conversions between store errors and the statuses returned by the daemon.
+//!
+//! A status only carries a code and a message, so only the errors which clients act upon are
+//! converted back into their original variant. The message of a `NotFound` status names the kind
+//! of the missing object, so that its ID can be parsed unambiguously. Every other status is
+//! returned as a `StoreError::Remote`.
+
+use deck_store::verify::Object;
+use deck_store::StoreError;
+use tower_grpc::{Code, Status};
+
+use crate::error::parse_id;
+
+const NOT_FOUND_SUFFIX: &str = "` not found in store";
+
+/// Converts a store error into the status returned to the client.
+pub fn to_status(err: StoreError) -> Status {
+    let code = match err {
+        StoreError::NotFound(ref object) => {
+            let (kind, id) = match *object {
+                Object::Manifest(ref id) => ("manifest", id.to_string()),
+                Object::Output(ref id) => ("output", id.to_string()),
+                Object::Source(ref id) => ("source", id.to_string()),
+            };
+
+            let message = format!("{} `{}{}", kind, id, NOT_FOUND_SUFFIX);
+            return Status::new(Code::NotFound, message);
+        }
+        StoreError::PermissionDenied(ref reason) => {
+            return Status::new(Code::PermissionDenied, reason.clone());
+        }
+        StoreError::InvalidId(_)
+        | StoreError::InvalidManifest(_)
+        | StoreError::InvalidArchive(_)
+        | StoreError::InvalidSource(_) => Code::InvalidArgument,
+        StoreError::BuildFailed { .. }
+        | StoreError::BuildsFailed { .. }
+        | StoreError::TimedOut { .. }
+        | StoreError::NotReproducible { .. } => Code::Aborted,
+        StoreError::Http(..)
+        | StoreError::HttpStatus(..)
+        | StoreError::Cache(_)
+        | StoreError::Remote(_)
+        | StoreError::LookupFailed { .. } => Code::Unavailable,
+        _ => Code::Internal,
+    };
+
+    Status::new(code, err.to_string())
+}
+
+/// Converts a status returned by the daemon back into a store error.
+pub fn from_status(status: Status) -> StoreError {
+    let message = status.message();
+    match status.code() {
+        Code::NotFound => match parse_not_found(message) {
+            Some(object) => StoreError::NotFound(object),
+            None => StoreError::Remote(message.to_string()),
+        },
+        Code::PermissionDenied => StoreError::PermissionDenied(message.to_string()),
+        _ => StoreError::Remote(message.to_string()),
+    }
+}
+
+/// Parses the object named by the message of a `NotFound` status created by `to_status()`.
+fn parse_not_found(message: &str) -> Option<Object> {
+    if !message.ends_with(NOT_FOUND_SUFFIX) {
+        return None;
+    }
+
+    let named = &message[..message.len() - NOT_FOUND_SUFFIX.len()];
+    let mut tokens = named.splitn(2, " `");
+    let kind = tokens.next()?;
+    let id = tokens.next()?;
+
+    match kind {
+        "manifest" => parse_id("manifest_id", id).ok().map(Object::Manifest),
+        "output" => parse_id("output_id", id).ok().map(Object::Output),
+        "source" => parse_id("source_id", id).ok().map(Object::Source),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(err: StoreError) -> StoreError {
+        from_status(to_status(err))
+    }
+
+    #[test]
+    fn missing_objects_round_trip() {
+        let manifest = "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+        let objects = vec![
+            Object::Manifest(manifest.parse().unwrap()),
+            Object::Output(manifest.parse().unwrap()),
+            Object::Output(
+                "foo@1.0.0:man-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+                    .parse()
+                    .unwrap(),
+            ),
+            Object::Source(
+                "foo.tar.gz-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+                    .parse()
+                    .unwrap(),
+            ),
+        ];
+
+        for object in objects {
+            match round_trip(StoreError::NotFound(object.clone())) {
+                StoreError::NotFound(ref found) => assert_eq!(*found, object),
+                other => panic!("expected `{}` to be missing, got: {:?}", object, other),
+            }
+        }
+    }
+
+    #[test]
+    fn denied_requests_round_trip() {
+        let denied =
+            StoreError::PermissionDenied("adding manifests requires a trusted user".into());
+        match round_trip(denied) {
+            StoreError::PermissionDenied(ref reason) => {
+                assert_eq!(reason, "adding manifests requires a trusted user")
+            }
+            other => panic!("expected a denied request, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_statuses_are_remote_errors() {
+        match from_status(Status::new(Code::NotFound, "no such thing")) {
+            StoreError::Remote(ref message) => assert_eq!(message, "no such thing"),
+            other => panic!("expected a remote error, got: {:?}", other),
+        }
+
+        match from_status(Status::new(Code::Unavailable, "try again")) {
+            StoreError::Remote(ref message) => assert_eq!(message, "try again"),
+            other => panic!("expected a remote error, got: {:?}", other),
+        }
+    }
+}
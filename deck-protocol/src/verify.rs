@@ -0,0 +1,197 @@
+//! Conversions between verification results and `VerifyResponse` messages.
+
+use std::convert::TryFrom;
+
+use deck_store::verify::{Action, Invalid, Object, Problem, Repaired, Verified};
+
+use crate::daemon::verify_response::{self as response, Status};
+use crate::daemon::VerifyResponse;
+use crate::error::{parse_id, ConversionError};
+
+impl From<Verified> for VerifyResponse {
+    fn from(verified: Verified) -> Self {
+        let status = match verified {
+            Verified::Checked(object) => Status::Checked(response::Checked {
+                resource: Some(match object {
+                    Object::Manifest(id) => response::checked::Resource::ManifestId(id.to_string()),
+                    Object::Output(id) => response::checked::Resource::OutputId(id.to_string()),
+                    Object::Source(id) => response::checked::Resource::SourceId(id.to_string()),
+                }),
+            }),
+            Verified::Invalid(invalid) => Status::Invalid(response::Invalid {
+                resource: Some(match invalid.object {
+                    Object::Manifest(id) => response::invalid::Resource::ManifestId(id.to_string()),
+                    Object::Output(id) => response::invalid::Resource::OutputId(id.to_string()),
+                    Object::Source(id) => response::invalid::Resource::SourceId(id.to_string()),
+                }),
+                problem: to_problem(invalid.problem) as i32,
+            }),
+            Verified::Repaired(repaired) => Status::Repaired(response::Repaired {
+                resource: Some(match repaired.object {
+                    Object::Manifest(id) => {
+                        response::repaired::Resource::ManifestId(id.to_string())
+                    }
+                    Object::Output(id) => response::repaired::Resource::OutputId(id.to_string()),
+                    Object::Source(id) => response::repaired::Resource::SourceId(id.to_string()),
+                }),
+                problem: to_problem(repaired.problem) as i32,
+                action: to_action(repaired.action) as i32,
+            }),
+        };
+
+        VerifyResponse {
+            status: Some(status),
+        }
+    }
+}
+
+impl TryFrom<VerifyResponse> for Verified {
+    type Error = ConversionError;
+
+    fn try_from(message: VerifyResponse) -> Result<Self, Self::Error> {
+        let missing = ConversionError::MissingField("resource");
+        match message
+            .status
+            .ok_or(ConversionError::MissingField("status"))?
+        {
+            Status::Checked(checked) => {
+                let object = match checked.resource.ok_or(missing)? {
+                    response::checked::Resource::ManifestId(id) => {
+                        Object::Manifest(parse_id("manifest_id", &id)?)
+                    }
+                    response::checked::Resource::OutputId(id) => {
+                        Object::Output(parse_id("output_id", &id)?)
+                    }
+                    response::checked::Resource::SourceId(id) => {
+                        Object::Source(parse_id("source_id", &id)?)
+                    }
+                };
+
+                Ok(Verified::Checked(object))
+            }
+            Status::Invalid(invalid) => {
+                let object = match invalid.resource.ok_or(missing)? {
+                    response::invalid::Resource::ManifestId(id) => {
+                        Object::Manifest(parse_id("manifest_id", &id)?)
+                    }
+                    response::invalid::Resource::OutputId(id) => {
+                        Object::Output(parse_id("output_id", &id)?)
+                    }
+                    response::invalid::Resource::SourceId(id) => {
+                        Object::Source(parse_id("source_id", &id)?)
+                    }
+                };
+
+                Ok(Verified::Invalid(Invalid {
+                    object,
+                    problem: from_problem(invalid.problem)?,
+                }))
+            }
+            Status::Repaired(repaired) => {
+                let object = match repaired.resource.ok_or(missing)? {
+                    response::repaired::Resource::ManifestId(id) => {
+                        Object::Manifest(parse_id("manifest_id", &id)?)
+                    }
+                    response::repaired::Resource::OutputId(id) => {
+                        Object::Output(parse_id("output_id", &id)?)
+                    }
+                    response::repaired::Resource::SourceId(id) => {
+                        Object::Source(parse_id("source_id", &id)?)
+                    }
+                };
+
+                Ok(Verified::Repaired(Repaired {
+                    object,
+                    problem: from_problem(repaired.problem)?,
+                    action: from_action(repaired.action)?,
+                }))
+            }
+        }
+    }
+}
+
+fn to_problem(problem: Problem) -> response::Problem {
+    match problem {
+        Problem::Missing => response::Problem::Missing,
+        Problem::Unregistered => response::Problem::Unregistered,
+        Problem::Corrupted => response::Problem::Corrupted,
+    }
+}
+
+fn from_problem(problem: i32) -> Result<Problem, ConversionError> {
+    match response::Problem::from_i32(problem) {
+        Some(response::Problem::Missing) => Ok(Problem::Missing),
+        Some(response::Problem::Unregistered) => Ok(Problem::Unregistered),
+        Some(response::Problem::Corrupted) => Ok(Problem::Corrupted),
+        None => Err(ConversionError::UnknownValue("problem", problem)),
+    }
+}
+
+fn to_action(action: Action) -> response::Action {
+    match action {
+        Action::Registered => response::Action::Registered,
+        Action::Deregistered => response::Action::Deregistered,
+        Action::Refetched => response::Action::Refetched,
+        Action::Quarantined => response::Action::Quarantined,
+    }
+}
+
+fn from_action(action: i32) -> Result<Action, ConversionError> {
+    match response::Action::from_i32(action) {
+        Some(response::Action::Registered) => Ok(Action::Registered),
+        Some(response::Action::Deregistered) => Ok(Action::Deregistered),
+        Some(response::Action::Refetched) => Ok(Action::Refetched),
+        Some(response::Action::Quarantined) => Ok(Action::Quarantined),
+        None => Err(ConversionError::UnknownValue("action", action)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verified_round_trips() {
+        let manifest = "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .unwrap();
+        let output = "foo@1.0.0:man-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .unwrap();
+        let source = "foo.tar.gz-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .unwrap();
+        let results = vec![
+            Verified::Checked(Object::Manifest(manifest)),
+            Verified::Invalid(Invalid {
+                object: Object::Output(output),
+                problem: Problem::Unregistered,
+            }),
+            Verified::Repaired(Repaired {
+                object: Object::Source(source),
+                problem: Problem::Corrupted,
+                action: Action::Quarantined,
+            }),
+        ];
+
+        for verified in results {
+            let message = VerifyResponse::from(verified.clone());
+            assert_eq!(Verified::try_from(message), Ok(verified));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_problem() {
+        let message = VerifyResponse {
+            status: Some(Status::Invalid(response::Invalid {
+                resource: Some(response::invalid::Resource::SourceId(
+                    "foo.tar.gz-fc3j3vub6kodu4jtfoakfs5xhumqi62m".to_string(),
+                )),
+                problem: 7,
+            })),
+        };
+
+        let err = Verified::try_from(message).unwrap_err();
+        assert_eq!(err, ConversionError::UnknownValue("problem", 7));
+    }
+}
@@ -0,0 +1,39 @@
+//! Serves the local store over stdin and stdout, for use by remote `ssh+` and `command+` stores.
+//!
+//! Usage: `deck-store-serve [STORE_PATH]`, where the store path defaults to `/deck/store`.
+
+#![feature(async_await, await_macro, futures_api)]
+#![forbid(unsafe_code)]
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use deck_store::local::LocalStore;
+use deck_store::remote;
+use futures_preview::future::{FutureExt, TryFutureExt};
+
+const DEFAULT_STORE_PATH: &str = "/deck/store";
+
+fn main() {
+    let path = env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STORE_PATH));
+
+    let serving = async move {
+        let store = LocalStore::open(path)?;
+        await!(remote::serve(
+            store,
+            tokio::io::stdin(),
+            tokio::io::stdout()
+        ))
+    };
+
+    let serving = serving.map_err(|e| {
+        eprintln!("deck-store-serve: {}", e);
+        process::exit(1);
+    });
+
+    tokio::run(serving.boxed().compat());
+}
@@ -0,0 +1,141 @@
+//! Cancellation of running builds.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Poll, Waker};
+
+/// Handle which cancels a build from anywhere, e.g. another task.
+///
+/// Every clone of a `CancelHandle` refers to the same build.
+#[derive(Clone, Default)]
+pub struct CancelHandle(Arc<Cancellation>);
+
+#[derive(Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl CancelHandle {
+    /// Creates a new `CancelHandle` which has not been cancelled yet.
+    pub fn new() -> Self {
+        CancelHandle::default()
+    }
+
+    /// Cancels the build, waking up everything waiting on it.
+    ///
+    /// Cancelling a build more than once has no effect.
+    pub fn cancel(&self) {
+        if !self.0.cancelled.swap(true, Ordering::SeqCst) {
+            let wakers = {
+                let mut wakers = self.0.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+                wakers.drain(..).collect::<Vec<_>>()
+            };
+
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Returns whether the build has been cancelled.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether the build has been cancelled, waking up `waker` once it is if not.
+    pub(crate) fn poll_cancelled(&self, waker: &Waker) -> bool {
+        if self.is_cancelled() {
+            return true;
+        }
+
+        {
+            let mut wakers = self.0.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+            if !wakers.iter().any(|w| w.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        }
+
+        // Checked again, in case the build was cancelled before the waker was registered.
+        self.is_cancelled()
+    }
+}
+
+impl Debug for CancelHandle {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct(stringify!(CancelHandle))
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+/// Future which completes early, dropping `future`, once the build is cancelled.
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct Cancellable<F> {
+    future: Option<Pin<Box<F>>>,
+    handle: CancelHandle,
+}
+
+impl<F: Future<Output = ()>> Cancellable<F> {
+    pub fn new(future: F, handle: CancelHandle) -> Self {
+        Cancellable {
+            future: Some(Box::pin(future)),
+            handle,
+        }
+    }
+}
+
+impl<F> Debug for Cancellable<F> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct(stringify!(Cancellable))
+            .field("handle", &self.handle)
+            .finish()
+    }
+}
+
+impl<F: Future<Output = ()>> Future for Cancellable<F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Self::Output> {
+        if self.handle.poll_cancelled(waker) {
+            // Dropping the future aborts every job which is still running.
+            self.future = None;
+            return Poll::Ready(());
+        }
+
+        match self.future.as_mut() {
+            Some(future) => future.as_mut().poll(waker),
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_preview::executor::block_on;
+    use futures_preview::future;
+
+    use super::*;
+
+    #[test]
+    fn cancelled_futures_are_dropped() {
+        let handle = CancelHandle::new();
+        let guard = Arc::new(());
+        let held = guard.clone();
+        let pending = async move {
+            let _held = held;
+            await!(future::empty::<()>());
+        };
+
+        let mut cancellable = Cancellable::new(pending, handle.clone());
+        handle.cancel();
+        block_on(&mut cancellable);
+
+        assert!(handle.is_cancelled());
+        assert_eq!(Arc::strong_count(&guard), 1);
+    }
+}
@@ -44,6 +44,12 @@ impl Closure {
         &self.packages[&self.target]
     }
 
+    /// Iterates over every package contained in this closure, including the target.
+    #[inline]
+    pub fn manifests(&self) -> impl Iterator<Item = (&ManifestId, &Manifest)> {
+        self.packages.iter()
+    }
+
     /// Returns a set of sub-closures for each dependency of the target.
     #[inline]
     pub fn dependent_closures(&self) -> impl Iterator<Item = Closure> + '_ {
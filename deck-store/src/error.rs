@@ -0,0 +1,212 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+
+use deck_binary_cache::CacheError;
+use deck_core::{Hash, ManifestId, OutputId, Phase};
+
+use crate::closure::ClosureError;
+use crate::progress::TimeLimit;
+use crate::verify::Object;
+
+/// Error returned by a `Store`.
+#[derive(Debug)]
+pub enum StoreError {
+    /// An I/O error occurred.
+    Io(IoError),
+    /// An I/O error occurred while accessing the given path.
+    Path(PathBuf, IoError),
+    /// A string could not be parsed as the ID of a store object.
+    InvalidId(String),
+    /// A manifest could not be parsed or does not match its ID.
+    InvalidManifest(String),
+    /// A portable archive is malformed or inconsistent with the store.
+    InvalidArchive(String),
+    /// A source does not have a valid URI, name, or hash.
+    InvalidSource(String),
+    /// The object is not present in the store.
+    NotFound(Object),
+    /// The manifest is not present in the store, and looking it up failed with the given errors,
+    /// one for each repository or remote store which could not provide it.
+    LookupFailed {
+        manifest: ManifestId,
+        errors: Vec<String>,
+    },
+    /// The contents of an object do not match its expected hash.
+    HashMismatch {
+        object: Object,
+        expected: Hash,
+        found: Hash,
+    },
+    /// An HTTP request to the given URI failed before receiving a response.
+    Http(String, hyper::Error),
+    /// An HTTP request to the given URI returned an unsuccessful status code.
+    HttpStatus(String, u16),
+    /// A binary cache returned an error.
+    Cache(CacheError),
+    /// A closure could not be computed from the manifests in the store.
+    Closure(ClosureError),
+    /// A phase of the builder of the given package exited unsuccessfully.
+    BuildFailed {
+        package: ManifestId,
+        phase: Phase,
+        status: ExitStatus,
+    },
+    /// A phase of the builder of the given package exceeded a time limit and was killed.
+    TimedOut {
+        package: ManifestId,
+        phase: Phase,
+        limit: TimeLimit,
+    },
+    /// Rebuilding the given output produced different contents than the output in the store.
+    ///
+    /// The paths of the first entries which differ are listed, relative to the output.
+    NotReproducible {
+        output: OutputId,
+        differences: Vec<PathBuf>,
+    },
+    /// Some packages could not be built, and others were skipped because they depend on them.
+    BuildsFailed {
+        failed: Vec<ManifestId>,
+        skipped: Vec<ManifestId>,
+    },
+    /// The given build group does not exist or has no unprivileged members.
+    NoBuildUsers(String),
+    /// Another job which was writing the same object to the store has failed.
+    WriteFailed(String),
+    /// The request was rejected by the store for the given reason.
+    PermissionDenied(String),
+    /// A remote store returned an error with the given message.
+    Remote(String),
+    /// The store with the given ID cannot be opened by this build of Deck.
+    UnsupportedStore(String),
+}
+
+impl StoreError {
+    /// Returns a closure which attaches `path` to an I/O error, for use with `map_err()`.
+    pub(crate) fn at(path: &Path) -> impl FnOnce(IoError) -> Self {
+        let path = path.to_owned();
+        move |e| StoreError::Path(path, e)
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            StoreError::Io(ref e) => write!(fmt, "{}", e),
+            StoreError::Path(ref path, ref e) => write!(fmt, "{}: {}", path.display(), e),
+            StoreError::InvalidId(ref id) => write!(fmt, "invalid store object ID `{}`", id),
+            StoreError::InvalidManifest(ref reason) => write!(fmt, "invalid manifest: {}", reason),
+            StoreError::InvalidArchive(ref reason) => write!(fmt, "invalid archive: {}", reason),
+            StoreError::InvalidSource(ref uri) => write!(fmt, "invalid source `{}`", uri),
+            StoreError::NotFound(ref object) => write!(fmt, "`{}` not found in store", object),
+            StoreError::LookupFailed {
+                ref manifest,
+                ref errors,
+            } => write!(
+                fmt,
+                "failed to look up manifest `{}`: {}",
+                manifest,
+                errors.join("; ")
+            ),
+            StoreError::HashMismatch {
+                ref object,
+                ref expected,
+                ref found,
+            } => write!(
+                fmt,
+                "hash mismatch for `{}`: expected {}, found {}",
+                object, expected, found
+            ),
+            StoreError::Http(ref uri, ref e) => write!(fmt, "failed to fetch `{}`: {}", uri, e),
+            StoreError::HttpStatus(ref uri, status) => {
+                write!(fmt, "failed to fetch `{}`: HTTP status {}", uri, status)
+            }
+            StoreError::Cache(ref e) => write!(fmt, "binary cache error: {}", e),
+            StoreError::Closure(ref e) => write!(fmt, "{}", e),
+            StoreError::BuildFailed {
+                ref package,
+                phase,
+                status,
+            } => write!(fmt, "{} phase of `{}` failed: {}", phase, package, status),
+            StoreError::TimedOut {
+                ref package,
+                phase,
+                limit,
+            } => write!(
+                fmt,
+                "{} phase of `{}` was killed: {}",
+                phase, package, limit
+            ),
+            StoreError::NotReproducible {
+                ref output,
+                ref differences,
+            } => {
+                let paths: Vec<_> = differences
+                    .iter()
+                    .map(|path| format!("`{}`", path.display()))
+                    .collect();
+                write!(fmt, "output `{}` is not reproducible", output)?;
+                if !paths.is_empty() {
+                    write!(fmt, ", differing paths: {}", paths.join(", "))?;
+                }
+
+                Ok(())
+            }
+            StoreError::BuildsFailed {
+                ref failed,
+                ref skipped,
+            } => {
+                let ids: Vec<_> = failed.iter().map(|id| format!("`{}`", id)).collect();
+                write!(fmt, "failed to build {}", ids.join(", "))?;
+                if !skipped.is_empty() {
+                    write!(fmt, " ({} dependent packages skipped)", skipped.len())?;
+                }
+
+                Ok(())
+            }
+            StoreError::NoBuildUsers(ref group) => {
+                write!(fmt, "no build users found in group `{}`", group)
+            }
+            StoreError::WriteFailed(ref id) => write!(fmt, "concurrent write of `{}` failed", id),
+            StoreError::PermissionDenied(ref reason) => {
+                write!(fmt, "permission denied: {}", reason)
+            }
+            StoreError::Remote(ref msg) => write!(fmt, "remote store error: {}", msg),
+            StoreError::UnsupportedStore(ref id) => write!(fmt, "unsupported store `{}`", id),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StoreError::Io(ref e) => Some(e),
+            StoreError::Path(_, ref e) => Some(e),
+            StoreError::Http(_, ref e) => Some(e),
+            StoreError::Cache(ref e) => Some(e),
+            StoreError::Closure(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<IoError> for StoreError {
+    fn from(e: IoError) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<CacheError> for StoreError {
+    fn from(e: CacheError) -> Self {
+        StoreError::Cache(e)
+    }
+}
+
+impl From<ClosureError> for StoreError {
+    fn from(e: ClosureError) -> Self {
+        StoreError::Closure(e)
+    }
+}
@@ -14,6 +14,7 @@ pub enum ParseError {
     UnsupportedPrefix(String),
     UnsupportedScheme(String),
     MissingHost,
+    InvalidHost(String),
     MissingContainerInfo,
     MissingRequiredQueryPair(String),
     UnknownFragment(String),
@@ -29,6 +30,7 @@ impl Display for ParseError {
             UnsupportedPrefix(ref p) => write!(fmt, "unsupported prefix `{}+`", p),
             UnsupportedScheme(ref s) => write!(fmt, "unsupported URL scheme `{}://`", s),
             MissingHost => write!(fmt, "URL scheme requires a host"),
+            InvalidHost(ref h) => write!(fmt, "invalid host `{}`", h),
             MissingContainerInfo => write!(
                 fmt,
                 "Docker store ID missing a `?container_id=` or `?container_name=`"
@@ -59,6 +61,8 @@ pub enum Kind {
     Ssh,
     /// A remote Docker store.
     Docker(DockerContainer),
+    /// A store served by a local command over its stdin and stdout.
+    Command,
 }
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -76,6 +80,7 @@ pub enum DockerContainer {
 /// `docker+unix:///var/run/docker.sock?container_id=foo&container_name=bar&user=baz`
 /// `docker+https://host[:port]?container_id=foo&container_name=bar&user=baz`
 /// `docker+ssh://host[:port]?container_id=foo&container_name=bar&user=baz`
+/// `command+file:///usr/bin/deck-store-serve?arg=/deck/store`
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct StoreId {
     url: Url,
@@ -99,6 +104,7 @@ impl StoreId {
             },
             "ssh" => Self::for_ssh(url),
             "docker" => Self::for_docker(url),
+            "command" => Self::for_command(url),
             p => Err(ParseError::UnsupportedPrefix(p.into())),
         }
     }
@@ -122,10 +128,9 @@ impl StoreId {
         if url.scheme() != "ssh" {
             let scheme = url.scheme();
             return Err(ParseError::UnsupportedScheme(scheme.into()));
-        } else if url.host().is_none() {
-            return Err(ParseError::MissingHost);
         }
 
+        check_ssh_host(&url)?;
         url.set_fragment(None);
         url.set_query(None);
 
@@ -141,7 +146,9 @@ impl StoreId {
             s => return Err(ParseError::UnsupportedScheme(s.into())),
         }
 
-        if url.host().is_none() {
+        if url.scheme() == "ssh" {
+            check_ssh_host(&url)?;
+        } else if url.host().is_none() {
             return Err(ParseError::MissingHost);
         }
 
@@ -179,6 +186,22 @@ impl StoreId {
         })
     }
 
+    pub fn for_command(mut url: Url) -> Result<Self, ParseError> {
+        if url.scheme() != "file" {
+            let scheme = url.scheme();
+            return Err(ParseError::UnsupportedScheme(scheme.into()));
+        } else if let Some((k, v)) = url.query_pairs().find(|(k, _)| k != "arg") {
+            return Err(ParseError::UnknownQueryPair(k.into_owned(), v.into_owned()));
+        }
+
+        url.set_fragment(None);
+
+        Ok(StoreId {
+            url,
+            kind: Kind::Command,
+        })
+    }
+
     #[inline]
     pub fn is_local(&self) -> bool {
         self.kind == Kind::Local
@@ -197,12 +220,26 @@ impl StoreId {
         }
     }
 
+    #[inline]
+    pub fn is_command(&self) -> bool {
+        self.kind == Kind::Command
+    }
+
     #[inline]
     pub fn as_url(&self) -> &Url {
         &self.url
     }
 }
 
+/// Ensures that the host of `url` is present and cannot be mistaken for an option by `ssh`.
+fn check_ssh_host(url: &Url) -> Result<(), ParseError> {
+    match url.host_str() {
+        None | Some("") => Err(ParseError::MissingHost),
+        Some(host) if host.starts_with('-') => Err(ParseError::InvalidHost(host.into())),
+        Some(_) => Ok(()),
+    }
+}
+
 impl Display for StoreId {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         write!(fmt, "{}", self.url)
@@ -291,6 +328,13 @@ mod tests {
 
         let result = "ssh+http://www.example.com".parse::<StoreId>();
         assert!(result.is_err());
+
+        let result = "ssh+ssh://".parse::<StoreId>();
+        assert_eq!(result, Err(ParseError::MissingHost));
+
+        let result = "ssh+ssh://-oProxyCommand=foo".parse::<StoreId>();
+        let expected = ParseError::InvalidHost("-oProxyCommand=foo".into());
+        assert_eq!(result, Err(expected));
     }
 
     #[test]
@@ -335,4 +379,34 @@ mod tests {
         let result = "docker+ftp://ftp.example.com".parse::<StoreId>();
         assert!(result.is_err());
     }
+
+    #[test]
+    fn parses_command_urls() {
+        let actual = "command+file:///usr/bin/deck-store-serve".parse();
+        let expected = Ok(StoreId {
+            url: "file:///usr/bin/deck-store-serve"
+                .parse()
+                .expect("Failed to parse URL"),
+            kind: Kind::Command,
+        });
+        assert_eq!(actual, expected);
+
+        let actual = "command+file:///usr/bin/deck-store-serve#fragment".parse();
+        assert_eq!(actual, expected);
+
+        let actual = "command+file:///usr/bin/deck-store-serve?arg=/deck/store&arg=-v".parse();
+        let expected = Ok(StoreId {
+            url: "file:///usr/bin/deck-store-serve?arg=/deck/store&arg=-v"
+                .parse()
+                .expect("Failed to parse URL"),
+            kind: Kind::Command,
+        });
+        assert_eq!(actual, expected);
+
+        let result = "command+file:///usr/bin/deck-store-serve?foo=bar".parse::<StoreId>();
+        assert!(result.is_err());
+
+        let result = "command+http://www.example.com".parse::<StoreId>();
+        assert!(result.is_err());
+    }
 }
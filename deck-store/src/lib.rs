@@ -6,10 +6,11 @@
 
 pub extern crate deck_core as core;
 
-pub use self::closure::Closure;
+pub use self::cancel::CancelHandle;
+pub use self::closure::{Closure, ClosureError};
+pub use self::error::StoreError;
 pub use self::id::StoreId;
 
-use std::ffi::OsString;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::future::Future;
 use std::pin::Pin;
@@ -17,26 +18,33 @@ use std::task::{Poll, Waker};
 
 use deck_binary_cache::BinaryCache;
 use deck_core::{Manifest, ManifestId, Platform};
-use futures_preview::stream::{Stream, StreamExt};
+use futures_preview::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 
 use self::progress::Progress;
+use self::verify::Verified;
 
 #[cfg(feature = "local")]
 pub mod local;
 pub mod progress;
 pub mod remote;
+pub mod verify;
 
+mod cancel;
 mod closure;
+mod error;
 mod id;
 
 // NOTE: All this noise has been to work fine with a simple `async fn`, with no need for associated
 // types, this type alias, or `Pin<Box<_>>`. Replace _immediately_ once `async fn` in traits is
 // stabilized in Rust.
 
-pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ()>> + Send + 'a>>;
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StoreError>> + Send + 'a>>;
+
+type ProgressStream = Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>;
 
 /// Sets whether the hashes of the store contents should be recomputed and verified.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
 pub enum CheckContents {
     /// Each item in the store should have its hash recomputed and verified.
     Enabled,
@@ -45,50 +53,339 @@ pub enum CheckContents {
 }
 
 /// Sets whether store inconsistencies should be repaired.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
 pub enum Repair {
-    /// Missing paths should be registered and inconsistent hashes should be recomputed.
+    /// Valid unregistered paths should be registered, stale registrations should be removed, and
+    /// corrupt paths should be fetched again or moved into quarantine.
     Enabled,
     /// Nothing should be repaired, report errors without modifying the store.
     Disabled,
 }
 
+/// Selects which part of a build log should be retrieved.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub struct LogOptions {
+    /// Number of bytes to skip from the start of the log.
+    pub offset: u64,
+    /// Only retrieve this many lines from the end of the log, if set.
+    pub tail: Option<usize>,
+    /// Keep streaming new output until the build finishes, if it is still in progress.
+    pub follow: bool,
+}
+
+/// Options controlling how packages are built.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub struct BuildOptions {
+    /// Keep building every package which does not depend on a failed package, instead of
+    /// aborting the build on the first failure.
+    pub keep_going: bool,
+    /// Rebuild the requested packages even though they are installed, failing if the rebuilt
+    /// outputs differ from the installed ones. Dependencies are not rebuilt.
+    pub check: bool,
+}
+
 /// Represents a content-addressable store of packages.
 pub trait Store: BinaryCache + Debug {
     fn supported_platforms<'a>(&'a self) -> StoreFuture<'a, Vec<Platform>>;
+    fn query_manifest<'a>(&'a mut self, id: &'a ManifestId) -> StoreFuture<'a, Manifest>;
     fn build_manifest(&mut self, manifest: Manifest) -> BuildStream;
-    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId) -> StoreFuture<'a, Option<OsString>>;
-    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> StoreFuture<'a, ()>;
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a>;
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a>;
 }
 
 /// Stream which reports the current progress of a builder.
 ///
-/// Created from the `Store::build_manifest()` method.
+/// Created from the `Store::build_manifest()` method. Dropping the stream or calling
+/// `BuildStream::cancel()` cancels the build, after which the stream ends.
 #[must_use = "streams do nothing unless polled"]
-pub struct BuildStream(Pin<Box<dyn Stream<Item = Result<Progress, ()>> + Send>>);
+pub struct BuildStream {
+    inner: Option<Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>>,
+    cancel: CancelHandle,
+}
 
 impl BuildStream {
     /// Creates a new `BuildStream` from the given progress stream.
     pub fn new<S>(stream: S) -> Self
     where
-        S: Stream<Item = Result<Progress, ()>> + Send + 'static,
+        S: Stream<Item = Result<Progress, StoreError>> + Send + 'static,
     {
-        BuildStream(stream.boxed())
+        BuildStream::with_cancel_handle(stream, CancelHandle::new())
+    }
+
+    /// Creates a new `BuildStream` from the given progress stream, which is cancelled through
+    /// `handle`.
+    ///
+    /// This lets the producer of the stream abort any work running in the background once the
+    /// build is cancelled.
+    pub fn with_cancel_handle<S>(stream: S, handle: CancelHandle) -> Self
+    where
+        S: Stream<Item = Result<Progress, StoreError>> + Send + 'static,
+    {
+        BuildStream {
+            inner: Some(stream.boxed()),
+            cancel: handle,
+        }
+    }
+
+    /// Runs `builds` at the same time, interleaving their progress into a single stream.
+    ///
+    /// Unless `keep_going` is set, the stream ends with the first error of any build, which
+    /// cancels the others. Otherwise, every build runs to completion, and the stream then ends with
+    /// a `StoreError::BuildsFailed` listing the failed and skipped packages of all of them, if any.
+    /// Errors other than failed builds take precedence over it.
+    pub fn merge<I: IntoIterator<Item = BuildStream>>(builds: I, keep_going: bool) -> Self {
+        let empty: ProgressStream = stream::empty().boxed();
+        let progress = builds
+            .into_iter()
+            .fold(empty, |progress, build| progress.select(build).boxed());
+
+        BuildStream::new(Merged {
+            progress: Some(progress),
+            keep_going,
+            error: None,
+        })
+    }
+
+    /// Returns a handle which cancels this build, even while the stream is being polled elsewhere.
+    #[inline]
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Cancels the build.
+    #[inline]
+    pub fn cancel(&self) {
+        self.cancel.cancel();
     }
 }
 
 impl Debug for BuildStream {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        fmt.debug_tuple(stringify!(BuildStream))
-            .field(&"Pin<Box<dyn Stream<Item = Result<Progress, Error>> + Send>>")
+        fmt.debug_struct(stringify!(BuildStream))
+            .field(
+                "inner",
+                &"Option<Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>>",
+            )
+            .field("cancel", &self.cancel)
             .finish()
     }
 }
 
+impl Drop for BuildStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
 impl Stream for BuildStream {
-    type Item = Result<Progress, ()>;
+    type Item = Result<Progress, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        if self.cancel.poll_cancelled(waker) {
+            self.inner = None;
+        }
+
+        match self.inner.as_mut() {
+            Some(inner) => inner.as_mut().poll_next(waker),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// Progress of several builds, as merged by `BuildStream::merge()`.
+struct Merged {
+    progress: Option<ProgressStream>,
+    keep_going: bool,
+    error: Option<StoreError>,
+}
+
+impl Stream for Merged {
+    type Item = Result<Progress, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        loop {
+            let next = match self.progress.as_mut() {
+                Some(progress) => progress.as_mut().poll_next(waker),
+                None => return Poll::Ready(None),
+            };
+
+            match next {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(progress))) => return Poll::Ready(Some(Ok(progress))),
+                Poll::Ready(Some(Err(err))) if !self.keep_going => {
+                    self.progress = None;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    let previous = self.error.take();
+                    self.error = Some(combine_errors(previous, err));
+                }
+                Poll::Ready(None) => {
+                    self.progress = None;
+                    return Poll::Ready(self.error.take().map(Err));
+                }
+            }
+        }
+    }
+}
+
+/// Combines the errors of two builds, merging the packages of failed builds.
+fn combine_errors(previous: Option<StoreError>, next: StoreError) -> StoreError {
+    match (previous, next) {
+        (None, next) => next,
+        (
+            Some(StoreError::BuildsFailed {
+                mut failed,
+                mut skipped,
+            }),
+            StoreError::BuildsFailed {
+                failed: more_failed,
+                skipped: more_skipped,
+            },
+        ) => {
+            failed.extend(more_failed.into_iter().filter(|id| !failed.contains(id)));
+            skipped.extend(more_skipped.into_iter().filter(|id| !skipped.contains(id)));
+            StoreError::BuildsFailed { failed, skipped }
+        }
+        (Some(StoreError::BuildsFailed { .. }), next) => next,
+        (Some(previous), _) => previous,
+    }
+}
+
+/// Stream which yields the contents of a build log in chunks.
+///
+/// Created from the `Store::get_build_log()` method.
+#[must_use = "streams do nothing unless polled"]
+pub struct LogStream<'a>(Pin<Box<dyn Stream<Item = Result<Vec<u8>, StoreError>> + Send + 'a>>);
+
+impl<'a> LogStream<'a> {
+    /// Creates a new `LogStream` from the given stream of chunks.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Vec<u8>, StoreError>> + Send + 'a,
+    {
+        LogStream(Box::pin(stream))
+    }
+}
+
+impl<'a> Debug for LogStream<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple(stringify!(LogStream))
+            .field(&"Pin<Box<dyn Stream<Item = Result<Vec<u8>, StoreError>> + Send>>")
+            .finish()
+    }
+}
+
+impl<'a> Stream for LogStream<'a> {
+    type Item = Result<Vec<u8>, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        self.0.as_mut().poll_next(waker)
+    }
+}
+
+/// Stream which reports the result of verifying each object in a store.
+///
+/// Created from the `Store::verify()` method.
+#[must_use = "streams do nothing unless polled"]
+pub struct VerifyStream<'a>(Pin<Box<dyn Stream<Item = Result<Verified, StoreError>> + Send + 'a>>);
+
+impl<'a> VerifyStream<'a> {
+    /// Creates a new `VerifyStream` from the given stream of results.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<Verified, StoreError>> + Send + 'a,
+    {
+        VerifyStream(Box::pin(stream))
+    }
+}
+
+impl<'a> Debug for VerifyStream<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple(stringify!(VerifyStream))
+            .field(&"Pin<Box<dyn Stream<Item = Result<Verified, StoreError>> + Send>>")
+            .finish()
+    }
+}
+
+impl<'a> Stream for VerifyStream<'a> {
+    type Item = Result<Verified, StoreError>;
 
     fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
         self.0.as_mut().poll_next(waker)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use futures_preview::executor::block_on;
+
+    use super::*;
+    use crate::progress::{FinalStatus, Finished};
+
+    fn id(name: &str) -> ManifestId {
+        format!("{}@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m", name)
+            .parse()
+            .unwrap()
+    }
+
+    fn finished(name: &str) -> Result<Progress, StoreError> {
+        Ok(Progress::Finished(Finished {
+            package_id: id(name),
+            status: FinalStatus::Built,
+            installed_bytes: 0,
+            timestamp: Utc::now(),
+        }))
+    }
+
+    fn failed(name: &str) -> Result<Progress, StoreError> {
+        Err(StoreError::BuildsFailed {
+            failed: vec![id(name)],
+            skipped: Vec::new(),
+        })
+    }
+
+    fn builds() -> Vec<BuildStream> {
+        let failing = stream::iter(vec![finished("foo"), failed("bar")]);
+        let succeeding = stream::iter(vec![finished("baz")]);
+        let also_failing = stream::iter(vec![failed("quux")]);
+        vec![
+            BuildStream::new(failing),
+            BuildStream::new(succeeding),
+            BuildStream::new(also_failing),
+        ]
+    }
+
+    #[test]
+    fn merged_builds_keep_going_after_failures() {
+        let progress = block_on(BuildStream::merge(builds(), true).collect::<Vec<_>>());
+
+        let built: Vec<_> = progress
+            .iter()
+            .filter_map(|progress| match progress {
+                Ok(Progress::Finished(ref finished)) => Some(finished.package_id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(built.len(), 2);
+        assert!(built.contains(&id("foo")) && built.contains(&id("baz")));
+
+        match progress.last() {
+            Some(Err(StoreError::BuildsFailed { failed, skipped })) => {
+                assert_eq!(failed.len(), 2);
+                assert!(failed.contains(&id("bar")) && failed.contains(&id("quux")));
+                assert!(skipped.is_empty());
+            }
+            last => panic!("expected failed builds, got: {:?}", last),
+        }
+        assert_eq!(progress.iter().filter(|p| p.is_err()).count(), 1);
+    }
+
+    #[test]
+    fn merged_builds_stop_at_first_failure() {
+        let progress = block_on(BuildStream::merge(builds(), false).collect::<Vec<_>>());
+        assert!(progress.last().map_or(false, Result::is_err));
+        assert_eq!(progress.iter().filter(|p| p.is_err()).count(), 1);
+    }
+}
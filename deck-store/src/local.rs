@@ -1,62 +1,299 @@
-use std::ffi::OsString;
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use deck_binary_cache::{BinaryCache, BinaryCacheFuture, OutputStream};
-use deck_core::{Manifest, ManifestId, OutputId, Platform};
+use deck_binary_cache::{BinaryCache, BinaryCacheFuture, CacheError, OutputStream};
+use deck_core::{Arch, Manifest, ManifestId, Os, OutputId, Platform};
 use deck_repository::Repository;
+use futures_locks::Mutex;
+use futures_preview::future::{self, FutureExt};
+use futures_preview::stream::{self, StreamExt};
+use hyper::Client;
+use hyper_tls::HttpsConnector;
 
-use super::{BuildStream, CheckContents, Repair, Store, StoreFuture};
+use self::build_users::BuildUsers;
+use self::builder::{Builder, Limits};
+use self::context::{Context, Substitutes};
+use self::sandbox::SandboxConfig;
+use self::store_dir::{Imported, StoreDir};
+use super::progress::Action;
+use super::verify::Object;
+use super::{
+    BuildOptions, BuildStream, CheckContents, LogOptions, LogStream, Repair, Store, StoreError,
+    StoreFuture, VerifyStream,
+};
 
+pub mod archive;
+pub mod build_users;
 pub mod builder;
 pub mod context;
 pub mod dir;
+pub mod sandbox;
 pub mod store_dir;
 
 mod file;
+mod log;
+mod registry;
+#[cfg(test)]
+mod test_util;
+mod verify;
 
 const TEMP_DIR_NAME: &str = "tmp";
 const VAR_DIR_NAME: &str = "var";
 
-#[derive(Debug)]
-pub struct LocalStore;
+/// Number of threads used to resolve host names when downloading sources.
+const DNS_THREADS: usize = 4;
+
+/// Handle to a store on the local filesystem.
+///
+/// Cloning a `LocalStore` is cheap, and every clone shares the same store directory, registries
+/// and build limits, so clones may serve concurrent requests. Registries added to one clone are
+/// not seen by the others, nor by builds which are already running.
+#[derive(Clone, Debug)]
+pub struct LocalStore {
+    context: Context,
+}
 
 impl LocalStore {
-    pub async fn add_binary_cache<B: BinaryCache>(&mut self, _cache: B) -> Result<(), ()> {
-        unimplemented!()
+    /// Opens the local store located at `path`.
+    pub fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let https = HttpsConnector::new(DNS_THREADS)
+            .map_err(|e| StoreError::Io(IoError::new(ErrorKind::Other, e)))?;
+        let client = Client::builder().build(https);
+
+        let context = Context::new(
+            Arc::new(StoreDir::open(path)?),
+            Arc::new(client),
+            Arc::new(Vec::new()),
+            Arc::new(Vec::new()),
+            Arc::new(Vec::new()),
+        );
+
+        Ok(LocalStore { context })
+    }
+
+    /// Caps the resources used by builds according to `limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.context = self.context.with_limits(limits);
+        self
     }
 
-    pub async fn add_remote_store<S: Store>(&mut self, _store: S) -> Result<(), ()> {
-        unimplemented!()
+    /// Runs every builder inside a sandbox configured by `config`.
+    pub fn with_sandbox(mut self, config: SandboxConfig) -> Self {
+        self.context = self.context.with_sandbox(config);
+        self
     }
 
-    pub async fn add_repository<R: Repository>(&mut self, _repo: R) -> Result<(), ()> {
-        unimplemented!()
+    /// Runs every builder as a distinct user leased from `users`.
+    pub fn with_build_users(mut self, users: BuildUsers) -> Self {
+        self.context = self.context.with_build_users(users);
+        self
+    }
+
+    /// Restricts which outputs may be substituted from binary caches.
+    pub fn with_substitutes(mut self, substitutes: Substitutes) -> Self {
+        self.context = self.context.with_substitutes(substitutes);
+        self
+    }
+
+    pub async fn add_binary_cache<B>(&mut self, cache: B) -> Result<(), StoreError>
+    where
+        B: BinaryCache + Send + 'static,
+    {
+        let caches = Arc::make_mut(&mut self.context.binary_caches);
+        caches.push(Mutex::new(Box::new(cache)));
+        Ok(())
+    }
+
+    /// Adds a remote store to query for manifests missing from this store.
+    ///
+    /// Remote stores are queried in the order they were added, after all repositories.
+    pub async fn add_remote_store<S>(&mut self, store: S) -> Result<(), StoreError>
+    where
+        S: Store + Send + 'static,
+    {
+        let remotes = Arc::make_mut(&mut self.context.remotes);
+        remotes.push(Mutex::new(Box::new(store)));
+        Ok(())
+    }
+
+    /// Adds a repository to query for manifests missing from this store.
+    ///
+    /// Repositories are queried in the order they were added.
+    pub async fn add_repository<R>(&mut self, repo: R) -> Result<(), StoreError>
+    where
+        R: Repository + Send + 'static,
+    {
+        let repositories = Arc::make_mut(&mut self.context.repositories);
+        repositories.push(Mutex::new(Box::new(repo)));
+        Ok(())
+    }
+
+    /// Builds the package `id` according to `options`, fetching its manifest from the
+    /// repositories or remote stores if it is missing from the store.
+    pub fn build_package(&self, id: ManifestId, options: BuildOptions) -> BuildStream {
+        let builder = Builder::new(self.context.clone(), id);
+        build(builder, options)
+    }
+
+    /// Writes `manifest` into the store and builds it according to `options`.
+    pub fn build_manifest_with(&self, manifest: Manifest, options: BuildOptions) -> BuildStream {
+        let builder = Builder::from_manifest(self.context.clone(), manifest);
+        build(builder, options)
+    }
+
+    /// Writes `manifest` into the store, returning its ID.
+    pub async fn add_manifest(&self, manifest: Manifest) -> Result<ManifestId, StoreError> {
+        let id = manifest.compute_id();
+        let mut writing = self.context.store.write_manifest(manifest);
+        while let Some(state) = await!(writing.next()) {
+            state?;
+        }
+
+        Ok(id)
+    }
+
+    /// Imports every object in the portable archive read from `reader`.
+    ///
+    /// Outputs in the archive are added with the hash of their contents as found in the archive,
+    /// so the archive must come from a trusted source.
+    pub async fn import<R: Read>(&self, reader: R) -> Result<Imported, StoreError> {
+        await!(self.context.store.import(reader))
+    }
+
+    /// Lists the IDs of every manifest in the store, sorted by name and version.
+    pub fn manifests(&self) -> Result<Vec<ManifestId>, StoreError> {
+        let mut ids = self.context.store.manifest_ids()?;
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Computes what building the package `id` would do with every package in its closure,
+    /// without downloading or building anything.
+    ///
+    /// Missing manifests are still fetched into the store in order to compute the closure.
+    pub async fn plan_build(
+        &self,
+        id: ManifestId,
+    ) -> Result<Vec<(ManifestId, Action)>, StoreError> {
+        let builder = Builder::new(self.context.clone(), id)
+            .load_manifest()
+            .try_substitute()
+            .fetch_sources()
+            .build_dependencies();
+
+        await!(builder.plan())
     }
 }
 
 impl BinaryCache for LocalStore {
-    fn query_outputs<'a>(&'a mut self, _id: &'a OutputId) -> BinaryCacheFuture<'a, ()> {
-        unimplemented!()
+    fn query_outputs<'a>(&'a mut self, id: &'a OutputId) -> BinaryCacheFuture<'a, bool> {
+        future::ready(Ok(self.context.store.contains_output(id))).boxed()
     }
 
-    fn fetch_output<'a>(&'a mut self, _id: &'a OutputId) -> OutputStream<'a> {
-        unimplemented!()
+    fn fetch_output<'a>(&'a mut self, id: &'a OutputId) -> OutputStream<'a> {
+        let store = &self.context.store;
+        let tree = if store.contains_output(id) {
+            let mut tree = Vec::new();
+            archive::write_tree(&mut tree, store.output_path(id))
+                .map(|_| tree)
+                .map_err(CacheError::Io)
+        } else {
+            Err(CacheError::NotFound(id.clone()))
+        };
+
+        stream::once(future::ready(tree)).boxed()
     }
 }
 
 impl Store for LocalStore {
     fn supported_platforms<'a>(&'a self) -> StoreFuture<'a, Vec<Platform>> {
-        unimplemented!()
+        let platforms = host_platform().into_iter().collect();
+        future::ready(Ok(platforms)).boxed()
+    }
+
+    fn query_manifest<'a>(&'a mut self, id: &'a ManifestId) -> StoreFuture<'a, Manifest> {
+        let future = async move {
+            match await!(self.context.store.read_manifest(id))? {
+                Some(manifest) => Ok(manifest),
+                None => Err(StoreError::NotFound(Object::Manifest(id.clone()))),
+            }
+        };
+
+        future.boxed()
+    }
+
+    fn build_manifest(&mut self, manifest: Manifest) -> BuildStream {
+        self.build_manifest_with(manifest, BuildOptions::default())
     }
 
-    fn build_manifest(&mut self, _manifest: Manifest) -> BuildStream {
-        unimplemented!()
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a> {
+        let logs = self.context.store.logs();
+        if options.follow {
+            LogStream::new(logs.follow(id, &options))
+        } else {
+            let text = logs.read(id, &options).map_err(StoreError::Io);
+            let text = text.and_then(|text| {
+                text.ok_or_else(|| StoreError::NotFound(Object::Manifest(id.clone())))
+            });
+            LogStream::new(stream::once(future::ready(text)))
+        }
     }
 
-    fn get_build_log<'a>(&'a mut self, _id: &'a ManifestId) -> StoreFuture<'a, Option<OsString>> {
-        unimplemented!()
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+        verify::verify(&self.context, check, repair)
     }
+}
+
+/// Drives `builder` to completion according to `options`.
+fn build(builder: Builder, options: BuildOptions) -> BuildStream {
+    builder
+        .keep_going(options.keep_going)
+        .check(options.check)
+        .load_manifest()
+        .try_substitute()
+        .fetch_sources()
+        .build_dependencies()
+        .build_package()
+}
+
+/// Returns the platform the store was compiled for, which is the only one its builders can run on.
+///
+/// Returns `None` if the platform cannot be described by a `Platform`.
+fn host_platform() -> Option<Platform> {
+    let target_arch = if cfg!(target_arch = "x86_64") {
+        Arch::X86_64
+    } else if cfg!(target_arch = "x86") {
+        Arch::I686
+    } else {
+        return None;
+    };
+
+    let target_os = if cfg!(target_os = "linux") {
+        Os::Linux
+    } else if cfg!(target_os = "macos") {
+        Os::Darwin
+    } else if cfg!(target_os = "freebsd") {
+        Os::FreeBsd
+    } else if cfg!(target_os = "netbsd") {
+        Os::NetBsd
+    } else if cfg!(target_os = "windows") {
+        Os::Windows
+    } else {
+        return None;
+    };
+
+    Some(Platform {
+        target_arch,
+        target_os,
+    })
+}
 
-    fn verify<'a>(&'a mut self, _check: CheckContents, _repair: Repair) -> StoreFuture<'a, ()> {
-        unimplemented!()
+/// Converts an error returned by a system call into an I/O error, keeping its OS error code.
+#[cfg(unix)]
+fn to_io(error: nix::Error) -> IoError {
+    match error {
+        nix::Error::Sys(errno) => IoError::from_raw_os_error(errno as i32),
+        other => IoError::new(ErrorKind::Other, other),
     }
 }
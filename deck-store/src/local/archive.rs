@@ -0,0 +1,682 @@
+//! Portable, self-describing archive format for moving store objects between machines.
+//!
+//! An archive is a single stream containing any number of manifests, sources, and outputs, each
+//! preceded by a header describing its ID, its references, and the hash of its contents. Archives
+//! can be written and read incrementally, so they can be piped over the network or through
+//! removable media without ever being held in memory.
+//!
+//! # Format
+//!
+//! All integers are encoded in little-endian byte order.
+//!
+//! ```text
+//! archive := MAGIC version:u32 entry* END
+//! entry   := ENTRY header:string node
+//! node    := FILE executable:u8 length:u64 byte*
+//!          | DIRECTORY count:u64 (name:string node)*
+//!          | SYMLINK target:string
+//! string  := length:u64 utf8-byte*
+//! ```
+//!
+//! Each `header` is a TOML document deserializing to an [`EntryHeader`]. The `content-hash` field
+//! of the header is the hash of the serialized `node` which follows it. Directory children are
+//! always serialized in sorted order, so identical trees always produce identical bytes and hashes
+//! regardless of the order the underlying filesystem lists them in.
+//!
+//! [`EntryHeader`]: ./struct.EntryHeader.html
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufReader, Error as IoError, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use deck_core::{Hash, HashBuilder};
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8] = b"deck-archive\0";
+const VERSION: u32 = 1;
+
+const ENTRY: u8 = b'e';
+const END: u8 = b'.';
+
+const FILE: u8 = b'f';
+const DIRECTORY: u8 = b'd';
+const SYMLINK: u8 = b'l';
+
+const MAX_NAME_LEN: u64 = 4096;
+const MAX_HEADER_LEN: u64 = 1024 * 1024;
+
+/// Kinds of store objects which can be contained in an archive.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// A package manifest, stored in `manifests`.
+    Manifest,
+    /// A fetched package source, stored in `sources`.
+    Source,
+    /// A package build output, stored in `outputs`.
+    Output,
+}
+
+/// Describes a single store object contained in an archive.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EntryHeader {
+    /// Kind of store object this entry represents.
+    pub kind: Kind,
+    /// ID of the store object, as it appears on disk.
+    pub id: String,
+    /// IDs of other store objects this object depends on.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub references: BTreeSet<String>,
+    /// Hash of the serialized contents of this entry.
+    pub content_hash: Hash,
+}
+
+/// Computes the content hash of the file or directory tree located at `path`.
+///
+/// This is the same hash which is recorded in the header of an archive entry, so it can be used to
+/// verify store objects against their registered hashes.
+pub fn hash_path<P: AsRef<Path>>(path: P) -> io::Result<Hash> {
+    let mut writer = HashWriter::new();
+    write_node(&mut writer, path.as_ref())?;
+    Ok(writer.finish())
+}
+
+/// Compares the file or directory trees located at `a` and `b`, returning the paths relative to
+/// them of the first `limit` entries which differ, in sorted order.
+///
+/// Entries are compared the same way they are archived, so two trees with the same content hash
+/// never differ. An entry differs if it only exists in one of the trees, or if its kind, contents,
+/// executable bit or symlink target are different.
+pub fn diff_trees<P, Q>(a: P, b: Q, limit: usize) -> io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let mut differences = Vec::new();
+    diff_node(
+        a.as_ref(),
+        b.as_ref(),
+        Path::new(""),
+        limit,
+        &mut differences,
+    )?;
+    Ok(differences)
+}
+
+/// Returns the total size of the regular files in the tree located at `path`, in bytes.
+///
+/// Symlinks are not followed, and neither they nor directories count towards the size.
+pub fn tree_size<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path.as_ref())?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        Ok(0)
+    } else if file_type.is_dir() {
+        fs::read_dir(path.as_ref())?.try_fold(0, |size, entry| {
+            let child = tree_size(entry?.path())?;
+            Ok(size + child)
+        })
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+/// Serializes a file or directory tree into `writer` without an archive header.
+///
+/// This is used for streaming a single store object, e.g. an output fetched from a binary cache.
+pub fn write_tree<W: Write, P: AsRef<Path>>(writer: &mut W, path: P) -> io::Result<()> {
+    write_node(writer, path.as_ref())
+}
+
+/// Deserializes a file or directory tree from `reader` into `dest`, returning its content hash.
+///
+/// `dest` must not exist yet. This is the counterpart of `write_tree()`.
+pub fn read_tree<R: Read, P: AsRef<Path>>(reader: &mut R, dest: P) -> io::Result<Hash> {
+    let mut hashing = HashingReader::new(reader);
+    read_node(&mut hashing, dest.as_ref())?;
+    Ok(hashing.finish())
+}
+
+/// Streams store objects into a new archive.
+#[derive(Debug)]
+pub struct ArchiveWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Begins a new archive, writing the archive preamble to `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(ArchiveWriter { writer })
+    }
+
+    /// Appends the file or directory tree located at `path` to the archive.
+    ///
+    /// The content hash is computed from `path` before writing, so the tree must not be modified
+    /// while it is being archived.
+    pub fn append<I, P>(&mut self, kind: Kind, id: &str, references: I, path: P) -> io::Result<()>
+    where
+        I: IntoIterator<Item = String>,
+        P: AsRef<Path>,
+    {
+        let header = EntryHeader {
+            kind,
+            id: id.to_string(),
+            references: references.into_iter().collect(),
+            content_hash: hash_path(path.as_ref())?,
+        };
+
+        let text = toml::to_string(&header).map_err(|e| invalid_data(e.to_string()))?;
+        self.writer.write_all(&[ENTRY])?;
+        write_str(&mut self.writer, &text)?;
+        write_node(&mut self.writer, path.as_ref())
+    }
+
+    /// Terminates the archive and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[END])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Unpacks store objects from an existing archive.
+#[derive(Debug)]
+pub struct ArchiveReader<R> {
+    reader: R,
+    finished: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Opens an archive for reading, validating its preamble.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = vec![0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic.as_slice() != MAGIC {
+            return Err(invalid_data("not a Deck archive"));
+        }
+
+        let mut version = [0u8; 4];
+        reader.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != VERSION {
+            return Err(invalid_data("unsupported Deck archive version"));
+        }
+
+        Ok(ArchiveReader {
+            reader,
+            finished: false,
+        })
+    }
+
+    /// Unpacks the next entry of the archive into `dest`, which must not exist yet.
+    ///
+    /// Returns `Ok(None)` once the end of the archive has been reached. If the unpacked contents do
+    /// not match the content hash recorded in the entry header, an error is returned and `dest`
+    /// is left behind for the caller to clean up.
+    pub fn next_entry<P: AsRef<Path>>(&mut self, dest: P) -> io::Result<Option<EntryHeader>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        match read_u8(&mut self.reader)? {
+            END => {
+                self.finished = true;
+                Ok(None)
+            }
+            ENTRY => {
+                let text = read_str(&mut self.reader, MAX_HEADER_LEN)?;
+                let header: EntryHeader =
+                    toml::from_str(&text).map_err(|e| invalid_data(e.to_string()))?;
+
+                let actual = read_tree(&mut self.reader, dest)?;
+                if actual != header.content_hash {
+                    let msg = format!(
+                        "content hash mismatch for `{}`: expected {}, found {}",
+                        header.id, header.content_hash, actual
+                    );
+                    return Err(invalid_data(msg));
+                }
+
+                Ok(Some(header))
+            }
+            tag => Err(invalid_data(format!("unknown entry tag {:#x}", tag))),
+        }
+    }
+}
+
+/// `Write` sink which feeds everything written to it into a `HashBuilder`.
+struct HashWriter {
+    hasher: Option<HashBuilder>,
+}
+
+impl HashWriter {
+    fn new() -> Self {
+        HashWriter {
+            hasher: Some(Hash::compute()),
+        }
+    }
+
+    fn finish(self) -> Hash {
+        self.hasher.expect("hasher is never empty").finish()
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher = self.hasher.take().map(|h| h.input(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Read` adapter which feeds everything read through it into a `HashBuilder`.
+struct HashingReader<'r, R> {
+    inner: &'r mut R,
+    hasher: Option<HashBuilder>,
+}
+
+impl<'r, R: Read> HashingReader<'r, R> {
+    fn new(inner: &'r mut R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Some(Hash::compute()),
+        }
+    }
+
+    fn finish(self) -> Hash {
+        self.hasher.expect("hasher is never empty").finish()
+    }
+}
+
+impl<'r, R: Read> Read for HashingReader<'r, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher = self.hasher.take().map(|h| h.input(&buf[..len]));
+        Ok(len)
+    }
+}
+
+fn write_node<W: Write>(writer: &mut W, path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| invalid_data("symlink target is not valid UTF-8"))?;
+        writer.write_all(&[SYMLINK])?;
+        write_str(writer, target)
+    } else if file_type.is_dir() {
+        let mut names = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+
+        writer.write_all(&[DIRECTORY])?;
+        write_u64(writer, names.len() as u64)?;
+        for name in names {
+            let name_str = name
+                .to_str()
+                .ok_or_else(|| invalid_data("file name is not valid UTF-8"))?;
+            write_str(writer, name_str)?;
+            write_node(writer, &path.join(&name))?;
+        }
+
+        Ok(())
+    } else {
+        let mut file = File::open(path)?;
+        writer.write_all(&[FILE, is_executable(&metadata) as u8])?;
+        write_u64(writer, metadata.len())?;
+
+        let copied = io::copy(&mut file, writer)?;
+        if copied != metadata.len() {
+            return Err(invalid_data("file was modified while being archived"));
+        }
+
+        Ok(())
+    }
+}
+
+fn diff_node(
+    a: &Path,
+    b: &Path,
+    relative: &Path,
+    limit: usize,
+    differences: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let (a_metadata, b_metadata) = (fs::symlink_metadata(a)?, fs::symlink_metadata(b)?);
+    let (a_type, b_type) = (a_metadata.file_type(), b_metadata.file_type());
+
+    let same = if a_type.is_dir() && b_type.is_dir() {
+        let (a_names, b_names) = (read_names(a)?, read_names(b)?);
+        for name in a_names.union(&b_names) {
+            if differences.len() >= limit {
+                break;
+            }
+
+            let path = relative.join(name);
+            if a_names.contains(name) && b_names.contains(name) {
+                diff_node(&a.join(name), &b.join(name), &path, limit, differences)?;
+            } else {
+                differences.push(path);
+            }
+        }
+
+        true
+    } else if a_type.is_symlink() && b_type.is_symlink() {
+        fs::read_link(a)? == fs::read_link(b)?
+    } else if a_type.is_file() && b_type.is_file() {
+        is_executable(&a_metadata) == is_executable(&b_metadata)
+            && a_metadata.len() == b_metadata.len()
+            && same_contents(a, b)?
+    } else {
+        false
+    };
+
+    if !same && differences.len() < limit {
+        differences.push(relative.to_owned());
+    }
+
+    Ok(())
+}
+
+fn read_names(path: &Path) -> io::Result<BTreeSet<OsString>> {
+    fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.file_name()))
+        .collect()
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+
+    loop {
+        let (a_buf, b_buf) = (a.fill_buf()?, b.fill_buf()?);
+        if a_buf.is_empty() || b_buf.is_empty() {
+            return Ok(a_buf.is_empty() && b_buf.is_empty());
+        }
+
+        let len = a_buf.len().min(b_buf.len());
+        if a_buf[..len] != b_buf[..len] {
+            return Ok(false);
+        }
+
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+fn read_node<R: Read>(reader: &mut R, dest: &Path) -> io::Result<()> {
+    match read_u8(reader)? {
+        FILE => {
+            let executable = read_u8(reader)? != 0;
+            let len = read_u64(reader)?;
+
+            let mut file = File::create(dest)?;
+            let copied = io::copy(&mut reader.by_ref().take(len), &mut file)?;
+            if copied != len {
+                return Err(IoError::new(ErrorKind::UnexpectedEof, "truncated file"));
+            }
+
+            if executable {
+                set_executable(dest)?;
+            }
+
+            Ok(())
+        }
+        DIRECTORY => {
+            fs::create_dir(dest)?;
+            let count = read_u64(reader)?;
+
+            let mut previous: Option<String> = None;
+            for _ in 0..count {
+                let name = read_str(reader, MAX_NAME_LEN)?;
+                if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                    return Err(invalid_data(format!("invalid file name `{}`", name)));
+                } else if previous.as_ref().map(|prev| *prev >= name).unwrap_or(false) {
+                    return Err(invalid_data("directory entries are not sorted"));
+                }
+
+                read_node(reader, &dest.join(&name))?;
+                previous = Some(name);
+            }
+
+            Ok(())
+        }
+        SYMLINK => {
+            let target = read_str(reader, MAX_NAME_LEN)?;
+            symlink(&target, dest)
+        }
+        tag => Err(invalid_data(format!("unknown node tag {:#x}", tag))),
+    }
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_str<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_str<R: Read>(reader: &mut R, max_len: u64) -> io::Result<String> {
+    let len = read_u64(reader)?;
+    if len > max_len {
+        return Err(invalid_data("string exceeds maximum length"));
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid_data("string is not valid UTF-8"))
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> IoError {
+    IoError::new(ErrorKind::InvalidData, error)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_mode(perms.mode() | 0o111);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn symlink(target: &str, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, dest)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn symlink(_target: &str, _dest: &Path) -> io::Result<()> {
+    Err(IoError::new(ErrorKind::Other, "symlinks are not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local::test_util::TempDir;
+
+    fn create_tree(root: &Path) {
+        fs::create_dir_all(root.join("bin")).expect("Failed to create dir");
+        fs::create_dir_all(root.join("share/doc")).expect("Failed to create dir");
+        fs::write(root.join("bin/hello"), b"#!/bin/sh\necho hello\n").expect("Failed to write");
+        fs::write(root.join("share/doc/README"), b"Hello, world!\n").expect("Failed to write");
+        set_executable(&root.join("bin/hello")).expect("Failed to set permissions");
+    }
+
+    #[test]
+    fn hash_is_deterministic() {
+        let temp = TempDir::new("archive");
+        let first = temp.path().join("first");
+        let second = temp.path().join("second");
+        create_tree(&first);
+        create_tree(&second);
+
+        let first_hash = hash_path(&first).expect("Failed to hash first tree");
+        let second_hash = hash_path(&second).expect("Failed to hash second tree");
+        assert_eq!(first_hash, second_hash);
+
+        fs::write(second.join("share/doc/README"), b"Goodbye!\n").expect("Failed to write");
+        let changed_hash = hash_path(&second).expect("Failed to hash changed tree");
+        assert_ne!(first_hash, changed_hash);
+    }
+
+    #[test]
+    fn diff_lists_differing_paths() {
+        let temp = TempDir::new("archive");
+        let first = temp.path().join("first");
+        let second = temp.path().join("second");
+        create_tree(&first);
+        create_tree(&second);
+        assert!(diff_trees(&first, &second, 10)
+            .expect("Failed to diff")
+            .is_empty());
+
+        fs::write(second.join("share/doc/README"), b"Goodbye!\n").expect("Failed to write");
+        fs::write(second.join("share/LICENSE"), b"MIT\n").expect("Failed to write");
+        fs::remove_file(second.join("bin/hello")).expect("Failed to remove file");
+        fs::write(second.join("bin/hello"), b"#!/bin/sh\necho hello\n").expect("Failed to write");
+
+        let differences = diff_trees(&first, &second, 10).expect("Failed to diff");
+        let expected: Vec<PathBuf> = vec![
+            "bin/hello".into(),
+            "share/LICENSE".into(),
+            "share/doc/README".into(),
+        ];
+        assert_eq!(differences, expected);
+
+        let first_only = diff_trees(&first, &second, 1).expect("Failed to diff");
+        assert_eq!(first_only, vec![PathBuf::from("bin/hello")]);
+    }
+
+    #[test]
+    fn tree_size_counts_file_contents() {
+        let temp = TempDir::new("archive");
+        let root = temp.path().join("tree");
+        create_tree(&root);
+        assert_eq!(tree_size(&root).expect("Failed to size tree"), 35);
+
+        symlink("hello", &root.join("bin/hi")).expect("Failed to symlink");
+        assert_eq!(tree_size(&root).expect("Failed to size tree"), 35);
+        assert_eq!(
+            tree_size(root.join("bin/hello")).expect("Failed to size"),
+            21
+        );
+    }
+
+    #[test]
+    fn archive_roundtrip() {
+        let temp = TempDir::new("archive");
+        let source = temp.path().join("source");
+        create_tree(&source);
+
+        let refs = vec!["foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m".to_string()];
+        let mut writer = ArchiveWriter::new(Vec::new()).expect("Failed to start archive");
+        writer
+            .append(Kind::Output, "hello@1.0.0-id", refs.clone(), &source)
+            .expect("Failed to append entry");
+        let bytes = writer.finish().expect("Failed to finish archive");
+
+        let mut reader = ArchiveReader::new(bytes.as_slice()).expect("Failed to open archive");
+        let dest = temp.path().join("dest");
+        let header = reader
+            .next_entry(&dest)
+            .expect("Failed to read entry")
+            .expect("Archive is missing entry");
+
+        assert_eq!(header.kind, Kind::Output);
+        assert_eq!(header.id, "hello@1.0.0-id");
+        assert_eq!(header.references, refs.into_iter().collect::<BTreeSet<_>>());
+        assert_eq!(
+            header.content_hash,
+            hash_path(&dest).expect("Failed to hash")
+        );
+        assert!(reader
+            .next_entry(temp.path().join("none"))
+            .expect("Failed")
+            .is_none());
+
+        let contents = fs::read(dest.join("share/doc/README")).expect("Failed to read file");
+        assert_eq!(contents, b"Hello, world!\n");
+    }
+
+    #[test]
+    fn rejects_corrupted_archive() {
+        let temp = TempDir::new("archive");
+        let source = temp.path().join("source");
+        create_tree(&source);
+
+        let mut writer = ArchiveWriter::new(Vec::new()).expect("Failed to start archive");
+        writer
+            .append(Kind::Source, "hello.tar.gz-id", None, &source)
+            .expect("Failed to append entry");
+        let mut bytes = writer.finish().expect("Failed to finish archive");
+
+        let needle = b"Hello, world!";
+        let offset = bytes
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .expect("Failed to locate file contents");
+        bytes[offset] = b'J';
+
+        let mut reader = ArchiveReader::new(bytes.as_slice()).expect("Failed to open archive");
+        let err = reader
+            .next_entry(temp.path().join("dest"))
+            .expect_err("Failed to reject corrupted entry");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let mut bytes = Vec::new();
+        bytes.push(DIRECTORY);
+        write_u64(&mut bytes, 1).unwrap();
+        write_str(&mut bytes, "..").unwrap();
+        bytes.push(SYMLINK);
+        write_str(&mut bytes, "/etc").unwrap();
+
+        let temp = TempDir::new("archive");
+        let err = read_tree(&mut bytes.as_slice(), temp.path().join("dest"))
+            .expect_err("Failed to reject `..` entry");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
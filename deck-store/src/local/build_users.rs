@@ -0,0 +1,486 @@
+//! Pool of unprivileged users which builders are run as in multi-user mode.
+//!
+//! Every build is leased a distinct user from the pool for as long as it runs, so builds can
+//! neither interfere with each other nor with the store itself. The number of users in the pool
+//! caps the number of concurrent builds. Once a lease is dropped, any processes left behind by the
+//! user are killed and the files it left in the system temporary directory or in the scratch
+//! directories of the build are removed before the user is handed out again. Since killing the
+//! processes blocks, this happens on a thread of its own.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use futures_locks::Mutex;
+use futures_preview::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_preview::channel::oneshot;
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::stream::StreamExt;
+
+use crate::StoreError;
+
+const GROUP_FILE: &str = "/etc/group";
+const PASSWD_FILE: &str = "/etc/passwd";
+/// Number of times processes of a released user are searched for and killed, in case some of
+/// them were busy forking.
+const KILL_ATTEMPTS: usize = 10;
+/// Time to wait for killed processes to exit before searching for them again.
+const KILL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Unprivileged user which a builder is run as.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BuildUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Pool of build users, each of which is leased to at most one build at a time.
+#[derive(Clone, Debug)]
+pub struct BuildUsers {
+    free: UnboundedSender<BuildUser>,
+    queue: Mutex<UnboundedReceiver<BuildUser>>,
+    temp_dirs: Vec<PathBuf>,
+    kill: fn(u32),
+    size: usize,
+}
+
+impl BuildUsers {
+    /// Creates a pool of the given `users`, allowing at most `max_builds` concurrent builds.
+    ///
+    /// If `max_builds` is `None`, every user may be building at the same time.
+    pub fn new<I>(users: I, max_builds: Option<usize>) -> Self
+    where
+        I: IntoIterator<Item = BuildUser>,
+    {
+        let (free, queue) = mpsc::unbounded();
+        let limit = max_builds.unwrap_or(usize::max_value());
+        let mut size = 0;
+        for user in users.into_iter().take(limit) {
+            let _ = free.unbounded_send(user);
+            size += 1;
+        }
+
+        BuildUsers {
+            free,
+            queue: Mutex::new(queue),
+            temp_dirs: vec![std::env::temp_dir()],
+            kill: kill_processes,
+            size,
+        }
+    }
+
+    /// Creates a pool of every non-root user belonging to `group`, as listed in `/etc/group` and
+    /// `/etc/passwd`, allowing at most `max_builds` concurrent builds.
+    pub fn from_group(group: &str, max_builds: Option<usize>) -> Result<Self, StoreError> {
+        let groups = fs::read_to_string(GROUP_FILE).map_err(StoreError::at(GROUP_FILE.as_ref()))?;
+        let passwd =
+            fs::read_to_string(PASSWD_FILE).map_err(StoreError::at(PASSWD_FILE.as_ref()))?;
+
+        let users = find_members(group, &groups, &passwd);
+        if users.is_empty() {
+            return Err(StoreError::NoBuildUsers(group.to_string()));
+        }
+
+        Ok(BuildUsers::new(users, max_builds))
+    }
+
+    /// Returns the maximum number of builds which can run at the same time.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Waits until a build user is free and leases it.
+    ///
+    /// Builds waiting for a user are served in the order they started waiting.
+    pub async fn acquire(&self) -> Lease {
+        // Neither can fail, since the pool holds both the lock and a sender.
+        let mut queue = await!(self.queue.lock().compat()).expect("build user pool was dropped");
+        let user = await!(queue.next()).expect("build user pool was dropped");
+        Lease {
+            user: Some(user),
+            free: self.free.clone(),
+            temp_dirs: self.temp_dirs.clone(),
+            scratch_dirs: Vec::new(),
+            kill: self.kill,
+        }
+    }
+}
+
+/// Build user leased to a single build, which is cleaned up and returned to the pool on drop.
+#[derive(Debug)]
+pub struct Lease {
+    user: Option<BuildUser>,
+    free: UnboundedSender<BuildUser>,
+    temp_dirs: Vec<PathBuf>,
+    scratch_dirs: Vec<PathBuf>,
+    kill: fn(u32),
+}
+
+impl Lease {
+    /// Returns the leased build user.
+    #[inline]
+    pub fn user(&self) -> &BuildUser {
+        self.user.as_ref().expect("lease has already been released")
+    }
+
+    /// Recursively hands ownership of `path` over to the leased build user.
+    ///
+    /// Any file the build user leaves directly inside `path` is removed once the lease ends.
+    pub fn hand_over(&mut self, path: &Path) -> io::Result<()> {
+        let user = self.user();
+        chown_tree(path, user.uid, user.gid)?;
+        self.scratch_dirs.push(path.to_owned());
+        Ok(())
+    }
+
+    /// Returns the build user to the pool and recursively hands ownership of `paths` back to the
+    /// current user, once every process left behind by the build user has been killed.
+    ///
+    /// Killing the processes blocks until they are gone, so the clean-up runs on a thread of its
+    /// own.
+    pub async fn release(mut self, paths: Vec<PathBuf>) -> io::Result<()> {
+        let cleanup = match self.take_cleanup() {
+            Some(cleanup) => cleanup,
+            None => return Ok(()),
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = done_tx.send(cleanup.run(&paths));
+        });
+
+        await!(done_rx).unwrap_or_else(|_| {
+            let message = "build user clean-up thread panicked";
+            Err(io::Error::new(io::ErrorKind::Other, message))
+        })
+    }
+
+    fn take_cleanup(&mut self) -> Option<Cleanup> {
+        let user = self.user.take()?;
+        let mut dirs = self.temp_dirs.clone();
+        dirs.extend(self.scratch_dirs.drain(..));
+        Some(Cleanup {
+            user,
+            dirs,
+            free: self.free.clone(),
+            kill: self.kill,
+        })
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Leases are usually dropped on the executor, which must not block on dying processes.
+        if let Some(cleanup) = self.take_cleanup() {
+            thread::spawn(move || {
+                let _ = cleanup.run(&[]);
+            });
+        }
+    }
+}
+
+/// Clean-up of a build user whose lease has ended.
+#[derive(Debug)]
+struct Cleanup {
+    user: BuildUser,
+    dirs: Vec<PathBuf>,
+    free: UnboundedSender<BuildUser>,
+    /// Kills every process of the given user ID.
+    kill: fn(u32),
+}
+
+impl Cleanup {
+    /// Kills the processes of the build user, hands `keep` back to the current user and removes
+    /// every other file the build user left in the temporary and scratch directories, blocking
+    /// until done.
+    ///
+    /// The build user is returned to the pool even if handing `keep` back fails.
+    fn run(self, keep: &[PathBuf]) -> io::Result<()> {
+        (self.kill)(self.user.uid);
+
+        let (uid, gid) = current_ids();
+        let handed_back = keep.iter().try_for_each(|path| chown_tree(path, uid, gid));
+        for dir in &self.dirs {
+            let _ = remove_files_owned_by(dir, self.user.uid);
+        }
+
+        let _ = self.free.unbounded_send(self.user);
+        handed_back
+    }
+}
+
+/// Lists the non-root users belonging to `group`, sorted by user ID.
+///
+/// A user belongs to the group if it is either listed as a member in `groups`, which has the format
+/// of `/etc/group`, or if the group is its primary group in `passwd`, which has the format of
+/// `/etc/passwd`.
+fn find_members(group: &str, groups: &str, passwd: &str) -> Vec<BuildUser> {
+    let entry = groups
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 4 && fields[0] == group);
+
+    let (gid, members): (u32, Vec<_>) = match entry {
+        Some(fields) => match fields[2].parse() {
+            Ok(gid) => (gid, fields[3].split(',').map(str::trim).collect()),
+            Err(_) => return Vec::new(),
+        },
+        None => return Vec::new(),
+    };
+
+    let mut users: Vec<_> = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4)
+        .filter_map(|fields| {
+            let uid = fields[2].parse().ok()?;
+            let primary_gid: u32 = fields[3].parse().ok()?;
+            if uid == 0 || (primary_gid != gid && !members.contains(&fields[0])) {
+                return None;
+            }
+
+            Some(BuildUser {
+                name: fields[0].to_string(),
+                uid,
+                gid,
+            })
+        })
+        .collect();
+
+    users.sort_by_key(|user| user.uid);
+    users.dedup_by_key(|user| user.uid);
+    users
+}
+
+/// Kills every process whose real or effective user ID is `uid`.
+#[cfg(target_os = "linux")]
+fn kill_processes(uid: u32) {
+    use nix::sys::signal::{self, Signal};
+    use nix::unistd::Pid;
+
+    for _ in 0..KILL_ATTEMPTS {
+        let pids = match processes_of(uid) {
+            Ok(ref pids) if pids.is_empty() => return,
+            Ok(pids) => pids,
+            Err(_) => return,
+        };
+
+        for pid in pids {
+            let _ = signal::kill(Pid::from_raw(pid), Signal::SIGKILL);
+        }
+
+        // Signals are delivered asynchronously, so give the processes a moment to die.
+        thread::sleep(KILL_INTERVAL);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kill_processes(_uid: u32) {}
+
+/// Lists the live processes whose real or effective user ID is `uid`, by reading `/proc`.
+#[cfg(target_os = "linux")]
+fn processes_of(uid: u32) -> io::Result<Vec<i32>> {
+    let mut pids = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        // Processes may exit while we are looking at them.
+        let status = match fs::read_to_string(entry.path().join("status")) {
+            Ok(status) => status,
+            Err(_) => continue,
+        };
+
+        // Zombies are already dead, and only need to be reaped by their parent.
+        if status
+            .lines()
+            .any(|line| line.starts_with("State:") && line.contains("Z"))
+        {
+            continue;
+        }
+
+        let uids = status
+            .lines()
+            .find(|line| line.starts_with("Uid:"))
+            .map(|line| line.split_whitespace().skip(1).take(2).collect::<Vec<_>>());
+
+        if let Some(uids) = uids {
+            if uids.iter().any(|id| id.parse::<u32>().ok() == Some(uid)) {
+                pids.push(pid);
+            }
+        }
+    }
+
+    Ok(pids)
+}
+
+/// Removes every entry directly inside `dir` which is owned by `uid`.
+#[cfg(unix)]
+fn remove_files_owned_by(dir: &Path, uid: u32) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.uid() != uid {
+            continue;
+        } else if metadata.is_dir() {
+            let _ = fs::remove_dir_all(&path);
+        } else {
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively changes the owner of `path` to `uid` and `gid`, leaving symlinks untouched.
+#[cfg(unix)]
+fn chown_tree(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    use nix::unistd::{self, Gid, Uid};
+
+    use crate::local::to_io;
+
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Ok(());
+    }
+
+    let (owner, group) = (Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)));
+    unistd::chown(path, owner, group).map_err(to_io)?;
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn chown_tree(_path: &Path, _uid: u32, _gid: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn current_ids() -> (u32, u32) {
+    use nix::unistd;
+    (unistd::getuid().as_raw(), unistd::getgid().as_raw())
+}
+
+#[cfg(not(unix))]
+fn current_ids() -> (u32, u32) {
+    (0, 0)
+}
+
+#[cfg(not(unix))]
+fn remove_files_owned_by(_dir: &Path, _uid: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_preview::executor::block_on;
+
+    use super::*;
+    use crate::local::test_util::TempDir;
+
+    const GROUP: &'static str = "\
+        root:x:0:\n\
+        wheel:x:10:alice\n\
+        deckbld:x:30000:deckbld1,deckbld2,root\n\
+        users:x:100:\n";
+
+    const PASSWD: &'static str = "\
+        root:x:0:0:root:/root:/bin/sh\n\
+        alice:x:1000:100:Alice:/home/alice:/bin/sh\n\
+        deckbld2:x:30002:65534:Deck build user 2:/var/empty:/sbin/nologin\n\
+        deckbld1:x:30001:65534:Deck build user 1:/var/empty:/sbin/nologin\n\
+        deckbld3:x:30003:30000:Deck build user 3:/var/empty:/sbin/nologin\n\
+        broken:x:nope:30000\n";
+
+    /// Creates a pool which leaves the processes and files of its users alone, since the users of
+    /// these tests may well exist on the machine running them.
+    fn harmless_pool(users: Vec<BuildUser>, max_builds: Option<usize>) -> BuildUsers {
+        let mut pool = BuildUsers::new(users, max_builds);
+        pool.temp_dirs.clear();
+        pool.kill = |_| {};
+        pool
+    }
+
+    fn user(name: &str, uid: u32) -> BuildUser {
+        BuildUser {
+            name: name.to_string(),
+            uid,
+            gid: 30000,
+        }
+    }
+
+    #[test]
+    fn finds_non_root_members() {
+        let members = find_members("deckbld", GROUP, PASSWD);
+        let expected = vec![
+            user("deckbld1", 30001),
+            user("deckbld2", 30002),
+            user("deckbld3", 30003),
+        ];
+        assert_eq!(members, expected);
+    }
+
+    #[test]
+    fn missing_or_root_group_has_no_members() {
+        assert!(find_members("nixbld", GROUP, PASSWD).is_empty());
+        assert!(find_members("root", GROUP, PASSWD).is_empty());
+    }
+
+    #[test]
+    fn caps_concurrent_builds() {
+        let users = vec![user("a", 1), user("b", 2), user("c", 3)];
+        let pool = harmless_pool(users, Some(2));
+        assert_eq!(pool.size(), 2);
+
+        block_on(async {
+            let first = await!(pool.acquire());
+            let second = await!(pool.acquire());
+            assert_ne!(first.user(), second.user());
+
+            let first_uid = first.user().uid;
+            drop(first);
+            let third = await!(pool.acquire());
+            assert_eq!(third.user().uid, first_uid);
+        });
+    }
+
+    #[test]
+    fn removes_only_files_owned_by_user() {
+        let temp = TempDir::new("build-users");
+        let dir = temp.path().join("nested");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("file"), "left behind").unwrap();
+        fs::write(temp.path().join("file"), "left behind").unwrap();
+
+        let (uid, _) = current_ids();
+        remove_files_owned_by(temp.path(), uid + 1).unwrap();
+        assert!(temp.path().join("file").exists());
+        assert!(dir.join("file").exists());
+
+        remove_files_owned_by(temp.path(), uid).unwrap();
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 0);
+    }
+}
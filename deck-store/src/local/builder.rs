@@ -56,93 +56,181 @@
 //! Notice that the `download_*` steps don't have any direct dependencies, meaning they can all run
 //! in parallel, even while the `build_*` steps may block.
 //!
+//! # Scheduling
+//!
+//! How many `download_*` and `build_*` steps actually run at the same time is capped by the
+//! `Limits` of the `Scheduler` in the `Context`, which is shared by every builder using the same
+//! store. Steps waiting for their turn report a `Progress::Blocked` explaining why.
+//!
+//! # Failures
+//!
+//! When a step fails, its package is reported as `Progress::Failed` and, by default, the rest of
+//! the build graph is aborted. With `Builder::keep_going()`, every step which does not depend on
+//! the failed package runs to completion instead, while the `build_*` steps of its dependents are
+//! skipped and reported as failed with `FailureReason::DependencyFailed`. Either way, the
+//! `BuildStream` ends with a `StoreError::BuildsFailed` listing the failed and skipped packages.
+//!
 //! Once the `Builder` is eventually transformed into a `BuildStream`, the whole build graph is
 //! fully self-contained inside the `BuildStream`, ready to be processed with `for_each()` on an
 //! executor.
 
+pub use self::scheduler::{Limits, Scheduler};
+
+pub(crate) use self::job::FetchSource;
+
 use std::collections::BTreeMap;
 
-use deck_core::{Manifest, ManifestId};
+use chrono::Utc;
+use deck_core::{Manifest, ManifestId, OutputId};
 use futures_preview::future::{self, TryFutureExt};
 use futures_preview::stream;
 
-use self::futures::{BuildFuture, BuilderState, InnerFuture, JobFuture};
-use self::job::{BuildManifest, FetchSource, IntoJob};
+use self::futures::{BuildFuture, BuilderState, InnerFuture, JobFuture, Outcome};
+use self::job::{find_substitutes, BuildManifest, FetchOutput, IntoJob};
+use self::scheduler::{Client, Resource};
 use super::context::Context;
-use crate::progress::{self, ProgressReceiver, ProgressSender};
-use crate::BuildStream;
+use crate::progress::{
+    self, Action, FinalStatus, Finished, Progress, ProgressReceiver, ProgressSender, Started,
+};
+use crate::verify::Object;
+use crate::{BuildStream, StoreError};
 
 mod futures;
 mod job;
+mod lookup;
+mod scheduler;
 
-type BuildGraph = BTreeMap<ManifestId, BuildFuture>;
+/// Nodes of the build graph, along with what each of them is going to do with its package.
+type BuildGraph = BTreeMap<ManifestId, (Action, BuildFuture)>;
 
 /// Asynchronous builder for a set of packages.
 #[derive(Debug)]
 pub struct Builder {
     context: Context,
+    client: Client,
     package: ManifestId,
+    manifest: Option<Manifest>,
     graph: BuildGraph,
     progress: (ProgressSender, Option<ProgressReceiver>),
+    keep_going: bool,
+    check: bool,
+    substitute: bool,
 }
 
 impl Builder {
     /// Creates a new `Builder` which will build `package` using data from the given `Context`.
     pub fn new(context: Context, package: ManifestId) -> Self {
         let (tx, rx) = progress::progress_channel(4);
+        let client = context.scheduler.client();
         Builder {
             context,
+            client,
             package,
+            manifest: None,
             graph: BTreeMap::new(),
             progress: (tx, Some(rx)),
+            keep_going: false,
+            check: false,
+            substitute: true,
         }
     }
 
-    /// Same as `Builder::new()`, except it lets you specify a pre-populated `BuildGraph` and a
-    /// progress channel.
+    /// Creates a new `Builder` which will build `manifest` using data from the given `Context`.
+    ///
+    /// Unlike `Builder::new()`, the manifest does not need to be present in the store or in any
+    /// repository, since it is written into the store before the build starts.
+    pub fn from_manifest(context: Context, manifest: Manifest) -> Self {
+        let package = manifest.compute_id();
+        let mut builder = Builder::new(context, package);
+        builder.manifest = Some(manifest);
+        builder
+    }
+
+    /// Sets whether to keep building every package which does not depend on a failed package,
+    /// instead of aborting the build on the first failure.
+    ///
+    /// This is disabled by default.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Sets whether to rebuild the package even though its outputs are already in the store, in
+    /// order to check that the build is reproducible.
+    ///
+    /// The rebuilt outputs are compared with the ones in the store instead of being installed, and
+    /// the build fails with `StoreError::NotReproducible` if they differ. Only the package itself
+    /// is rebuilt, not its dependencies. This is disabled by default.
+    pub fn check(mut self, check: bool) -> Self {
+        self.check = check;
+        self
+    }
+
+    /// Same as `Builder::new()`, except it lets you specify a pre-populated `BuildGraph`, a
+    /// progress channel and the scheduler client of the top-level build.
     ///
     /// This constructor is only called internally, used when recursively building dependencies.
     #[inline]
-    fn new_recursive(ctx: Context, pkg: ManifestId, graph: BuildGraph, tx: ProgressSender) -> Self {
+    fn new_recursive(
+        ctx: Context,
+        client: Client,
+        pkg: ManifestId,
+        graph: BuildGraph,
+        tx: ProgressSender,
+    ) -> Self {
         Builder {
             context: ctx,
+            client,
             package: pkg,
+            manifest: None,
             graph,
             progress: (tx, None),
+            keep_going: false,
+            check: false,
+            substitute: true,
         }
     }
 
     /// Loads and parses the package manifest.
     ///
-    /// If the manifest does not exist in the store, the builder will attempt to fetch it from a
-    /// remote source, if one exists.
+    /// If the manifest does not exist in the store, the builder will attempt to fetch it from the
+    /// configured repositories and remote stores, in that order. A manifest passed to
+    /// `Builder::from_manifest()` is written into the store instead.
     pub fn load_manifest(self) -> ManifestLoaded {
         let context = self.context;
+        let client = self.client;
         let manifest_id = self.package;
+        let given = self.manifest;
         let graph = self.graph;
-        let (tx, rx) = self.progress;
+        let (mut tx, rx) = self.progress;
+        let keep_going = self.keep_going;
+        let check = self.check;
+        let substitute = self.substitute;
 
-        let future = async {
-            // TODO: Implementation needed.
-            // let manifest = await!(context.store.load_manifest(&manifest_id))?;
-            let manifest =
-                Manifest::build("foo", "1.0.0", "fc3j3vub6kodu4jtfoakfs5xhumqi62m", None)
-                    .finish()
-                    .unwrap();
+        let future = async move {
+            let manifest = match given {
+                Some(manifest) => await!(lookup::install_manifest(&context, manifest, &mut tx))?,
+                None => await!(lookup::load_manifest(&context, &manifest_id, &mut tx))?,
+            };
 
             Ok(BuilderState {
                 context,
+                client,
                 manifest,
                 manifest_id,
                 graph,
                 progress: tx,
                 dependencies: Vec::new(),
+                sources: Vec::new(),
+                check,
+                substitute,
             })
         };
 
         ManifestLoaded {
             inner: InnerFuture::new(future),
             progress: rx,
+            keep_going,
         }
     }
 }
@@ -152,36 +240,63 @@ impl Builder {
 pub struct ManifestLoaded {
     inner: InnerFuture,
     progress: Option<ProgressReceiver>,
+    keep_going: bool,
 }
 
 impl ManifestLoaded {
     /// Attempts to substitute this build with a pre-built package, if one is available.
+    ///
+    /// If every output of the package is already in the store, the build is memoized. Otherwise,
+    /// the missing outputs are fetched from the binary caches, but only if all of them can be
+    /// substituted. The package is built from source in every other case.
     pub fn try_substitute(self) -> MaybeSubstituted {
         let inner = self.inner;
 
         let future = async {
             let mut builder = await!(inner)?;
-            let progress = builder.progress.clone();
-
-            // TODO: Implementation needed.
-            // let package_installed = builder
-            //     .manifest
-            //     .outputs()
-            //     .all(|id| builder.context.output_exists(&id));
-            let package_installed = true;
+            if builder.graph.contains_key(&builder.manifest_id) {
+                return Ok(builder);
+            }
 
-            if package_installed {
+            let context = builder.context.clone();
+            let id = builder.manifest_id.clone();
+            let progress = builder.progress.clone();
+            let missing: Vec<OutputId> = builder
+                .manifest
+                .outputs()
+                .filter(|output| !context.store.contains_output(output))
+                .collect();
+
+            if builder.check {
+                // checking a package requires its outputs to compare the rebuilt ones against.
+                if let Some(output) = missing.into_iter().next() {
+                    return Err(StoreError::NotFound(Object::Output(output)));
+                }
+            } else if missing.is_empty() {
                 // package already installed on disk.
-                let job = JobFuture::new(stream::once(future::err(())), progress);
-                let memoized = BuildFuture::new(job);
-                builder.graph.insert(builder.manifest_id.clone(), memoized);
-            // TODO: Implementation needed.
-            // } else if await!(builder.context.substitutes_available(builder.manifest.outputs()))? {
-            } else if await!(future::ok(false))? {
-                // substituted outputs.
-                let job = JobFuture::new(stream::once(future::err(())), progress);
-                let fetched = BuildFuture::new(job);
-                builder.graph.insert(builder.manifest_id.clone(), fetched);
+                let finished = Progress::Finished(Finished {
+                    package_id: id.clone(),
+                    status: FinalStatus::Memoized,
+                    installed_bytes: 0,
+                    timestamp: Utc::now(),
+                });
+
+                let finished = stream::once(future::ok(finished));
+                let job = JobFuture::new(id.clone(), finished, progress);
+                let node = (Action::Memoize, BuildFuture::new(job));
+                builder.graph.insert(id, node);
+            } else if builder.substitute {
+                if let Some(substitutes) = await!(find_substitutes(&context, &missing)) {
+                    // substituted outputs, or built from source if fetching them fails.
+                    let fetch = FetchOutput::new(context.clone(), id.clone(), substitutes);
+                    let client = builder.client.clone();
+                    let scheduled = client.schedule(Resource::Download, id.clone(), fetch);
+                    let fallback = build_instead(context, client, id.clone(), progress.clone());
+                    let job =
+                        future::ok(scheduled).into_job_or_else(id.clone(), progress, fallback);
+                    let node = (Action::Substitute, BuildFuture::new(job));
+                    builder.graph.insert(id, node);
+                }
             }
 
             // building
@@ -191,6 +306,7 @@ impl ManifestLoaded {
         MaybeSubstituted {
             inner: InnerFuture::new(future),
             progress: self.progress,
+            keep_going: self.keep_going,
         }
     }
 }
@@ -200,6 +316,7 @@ impl ManifestLoaded {
 pub struct MaybeSubstituted {
     inner: InnerFuture,
     progress: Option<ProgressReceiver>,
+    keep_going: bool,
 }
 
 impl MaybeSubstituted {
@@ -217,16 +334,14 @@ impl MaybeSubstituted {
                     let target = builder.manifest_id.clone();
                     let source = src.clone();
                     let progress = builder.progress.clone();
-                    jobs.push(
-                        future::ok(FetchSource::new(context, target, source)).into_job(progress),
-                    );
+                    let fetch = FetchSource::new(context, target.clone(), source);
+                    let client = &builder.client;
+                    let scheduled = client.schedule(Resource::Download, target.clone(), fetch);
+                    jobs.push(future::ok(scheduled).into_job(target, progress));
                 }
 
-                let download_sources = BuildFuture::join_all(jobs);
-                builder
-                    .dependencies
-                    .reserve(builder.manifest.dependencies().count() + 1);
-                builder.dependencies.push(download_sources);
+                // sources are not dependencies, since their failures are failures of the package.
+                builder.sources = jobs;
             }
 
             Ok(builder)
@@ -235,6 +350,7 @@ impl MaybeSubstituted {
         SourcesFetched {
             inner: InnerFuture::new(future),
             progress: self.progress,
+            keep_going: self.keep_going,
         }
     }
 }
@@ -244,6 +360,7 @@ impl MaybeSubstituted {
 pub struct SourcesFetched {
     inner: InnerFuture,
     progress: Option<ProgressReceiver>,
+    keep_going: bool,
 }
 
 impl SourcesFetched {
@@ -253,20 +370,19 @@ impl SourcesFetched {
 
         let future = async {
             let mut builder = await!(inner)?;
-            println!("Building deps for {}", builder.manifest_id);
-
             let dependencies = builder.manifest.dependencies();
 
             for dep in dependencies.cloned() {
                 let context = builder.context.clone();
+                let client = builder.client.clone();
                 let progress = builder.progress.clone();
 
-                let child = Builder::new_recursive(context, dep, builder.graph, progress);
+                let child = Builder::new_recursive(context, client, dep, builder.graph, progress);
                 let loaded = child.load_manifest();
                 let maybe_sub = loaded.try_substitute();
                 let sources_done = maybe_sub.fetch_sources();
                 let deps_done = sources_done.build_dependencies();
-                let (built, graph) = await!(deps_done.build_package_recursively())?;
+                let (_, built, graph) = await!(deps_done.build_package_recursively())?;
 
                 builder.dependencies.push(built);
                 builder.graph = graph;
@@ -278,6 +394,7 @@ impl SourcesFetched {
         DependenciesBuilt {
             inner: InnerFuture::new(future),
             progress: self.progress,
+            keep_going: self.keep_going,
         }
     }
 }
@@ -287,42 +404,113 @@ impl SourcesFetched {
 pub struct DependenciesBuilt {
     inner: InnerFuture,
     progress: Option<ProgressReceiver>,
+    keep_going: bool,
 }
 
 impl DependenciesBuilt {
     /// Computes a final `BuildStream` which drives the builder to completion and reports the
     /// progress for each job.
+    ///
+    /// Once the build graph has been computed, the stream reports a `Progress::Started` listing
+    /// what is going to be done with every package in it.
     pub fn build_package(mut self) -> BuildStream {
         let progress = self.progress.take().unwrap();
-        let built = self.build_package_recursively().map_ok(|(built, _)| built);
-        BuildStream::from_future(built, progress)
+        let keep_going = self.keep_going;
+        let built = self
+            .build_package_recursively()
+            .map_ok(|(id, built, graph)| {
+                let started = Started {
+                    package_id: id,
+                    planned: graph
+                        .iter()
+                        .map(|(id, &(action, _))| (id.clone(), action))
+                        .collect(),
+                    timestamp: Utc::now(),
+                };
+
+                (started, built)
+            });
+
+        BuildStream::from_future(built, progress, keep_going)
     }
 
-    /// Builds the package itself, returning it along with the modified `BuildGraph`.
+    /// Computes the build graph without running it, returning what would be done with every
+    /// package in it, in ID order.
+    ///
+    /// Missing manifests are still fetched into the store and the binary caches are still queried
+    /// for substitutes, but nothing else is downloaded or built.
+    pub async fn plan(mut self) -> Result<Vec<(ManifestId, Action)>, StoreError> {
+        // Nobody listens to the progress of a plan, so stop reporting it instead of blocking.
+        self.progress = None;
+        let (_, _, graph) = await!(self.build_package_recursively())?;
+        let planned = graph
+            .into_iter()
+            .map(|(id, (action, _))| (id, action))
+            .collect();
+        Ok(planned)
+    }
+
+    /// Builds the package itself, returning its ID and `BuildFuture` along with the modified
+    /// `BuildGraph`.
     ///
     /// Using the internal `BuildGraph`, package builds are memoized, allowing us to lazily link
     /// `BuildFuture`s together into a directed acyclic graph which can be executed on a `tokio`
     /// runtime.
     ///
     /// This method is only called internally, used when recursively building dependencies.
-    async fn build_package_recursively(self) -> Result<(BuildFuture, BuildGraph), ()> {
+    async fn build_package_recursively(
+        self,
+    ) -> Result<(ManifestId, BuildFuture, BuildGraph), StoreError> {
         let mut builder = await!(self.inner)?;
+        let id = builder.manifest_id.clone();
 
-        match builder.graph.get(&builder.manifest_id).cloned() {
-            Some(node) => Ok((node, builder.graph)),
+        match builder.graph.get(&id).map(|(_, node)| node.clone()) {
+            Some(node) => Ok((id, node, builder.graph)),
             None => {
                 let context = builder.context.clone();
                 let manifest = builder.manifest.clone();
                 let progress = builder.progress.clone();
                 let dependencies = builder.dependencies;
-
-                let job = future::ok(BuildManifest::new(context, manifest)).into_job(progress);
-                let building = BuildFuture::join_all_and_then(dependencies, job);
-                builder.graph.insert(builder.manifest_id.clone(), building);
-                let node = builder.graph[&builder.manifest_id].clone();
-
-                Ok((node, builder.graph))
+                let sources = builder.sources;
+
+                let (action, build) = if builder.check {
+                    (Action::Check, BuildManifest::check(context, manifest))
+                } else {
+                    (Action::Build, BuildManifest::new(context, manifest))
+                };
+                let scheduled = builder.client.schedule(Resource::Build, id.clone(), build);
+                let job = future::ok(scheduled).into_job(id.clone(), progress);
+                let building = BuildFuture::join_all_and_then(dependencies, sources, job);
+                builder.graph.insert(id.clone(), (action, building.clone()));
+
+                Ok((id, building, builder.graph))
             }
         }
     }
 }
+
+/// Builds the package `id` from source after substituting it has failed.
+///
+/// The graph of the original build is complete by then, so the dependencies of the package are
+/// built in a graph of their own, sharing the same scheduler client and progress channel.
+async fn build_instead(
+    context: Context,
+    client: Client,
+    id: ManifestId,
+    progress: ProgressSender,
+) -> Outcome {
+    let tx = progress.clone();
+    let mut builder = Builder::new_recursive(context, client, id.clone(), BTreeMap::new(), tx);
+    builder.substitute = false;
+
+    let building = builder
+        .load_manifest()
+        .try_substitute()
+        .fetch_sources()
+        .build_dependencies();
+
+    match await!(building.build_package_recursively()) {
+        Ok((_, built, _)) => await!(built),
+        Err(err) => await!(future::err::<stream::Empty<_>, _>(err).into_job(id, progress)),
+    }
+}
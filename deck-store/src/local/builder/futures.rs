@@ -1,62 +1,203 @@
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::future::Future;
+use std::mem;
 use std::pin::Pin;
 use std::task::{Poll, Waker};
 
+use chrono::Utc;
 use deck_core::{Manifest, ManifestId};
 use futures_preview::future::{self, FutureExt, TryFutureExt};
 use futures_preview::sink::SinkExt;
 use futures_preview::stream::{self, Stream, StreamExt};
 
+use super::scheduler::Client;
 use super::BuildGraph;
+use crate::cancel::Cancellable;
 use crate::local::context::Context;
-use crate::progress::{Progress, ProgressReceiver, ProgressSender};
-use crate::BuildStream;
+use crate::progress::{
+    Blocked, Failed, FailureReason, Progress, ProgressReceiver, ProgressSender, Started,
+};
+use crate::{BuildStream, CancelHandle, StoreError};
+
+/// Result of a `JobFuture` or `BuildFuture`, which is either success or the ID of the package
+/// which failed to build.
+pub type Outcome = Result<(), ManifestId>;
 
 /// Executes a discrete unit of work during the build process.
 ///
 /// Some examples of discrete units of work might include: fetching a package source, fetching a
 /// package output, and building a package.
+///
+/// If the job fails, its error is reported as a `Progress::Failed` for its package instead.
 #[must_use = "futures do nothing unless polled"]
-pub struct JobFuture(Pin<Box<dyn Future<Output = ()> + Send>>);
+pub struct JobFuture {
+    package_id: ManifestId,
+    tx: ProgressSender,
+    future: Pin<Box<dyn Future<Output = Outcome> + Send>>,
+}
 
 impl JobFuture {
-    /// Creates a new `JobFuture` that forwards the `progress` stream to the given `ProgressSender`.
-    pub fn new<S>(progress: S, tx: ProgressSender) -> Self
+    /// Creates a new `JobFuture` for the package `package_id` that forwards the `progress` stream
+    /// to the given `ProgressSender`.
+    pub fn new<S>(package_id: ManifestId, progress: S, tx: ProgressSender) -> Self
+    where
+        S: Stream<Item = Result<Progress, StoreError>> + Send + Unpin + 'static,
+    {
+        let id = package_id.clone();
+        let mut sink = tx.clone();
+        let future = async move {
+            match await!(forward(progress, &mut sink)) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    let reason = match err {
+                        StoreError::TimedOut { limit, .. } => FailureReason::TimedOut(limit),
+                        err => FailureReason::Error(err.to_string()),
+                    };
+                    await!(report_failure(&mut sink, id.clone(), reason));
+                    Err(id)
+                }
+            }
+        };
+
+        JobFuture {
+            package_id,
+            tx,
+            future: future.boxed(),
+        }
+    }
+
+    /// Same as `JobFuture::new()`, except that `fallback` is run instead if `progress` fails, and
+    /// is responsible for reporting its own failures.
+    ///
+    /// The error which ended `progress` is reported as `Progress::Blocked` before running
+    /// `fallback`, so that it is clear why the package is not being processed as first intended.
+    pub fn new_or_else<S, F>(
+        package_id: ManifestId,
+        progress: S,
+        tx: ProgressSender,
+        fallback: F,
+    ) -> Self
     where
-        S: Stream<Item = Result<Progress, ()>> + Send + Unpin + 'static,
+        S: Stream<Item = Result<Progress, StoreError>> + Send + Unpin + 'static,
+        F: Future<Output = Outcome> + Send + 'static,
     {
-        let future = progress
-            .map(Ok)
-            .forward(tx.sink_map_err(|_| ()))
-            .map(|_| ())
-            .boxed();
+        let id = package_id.clone();
+        let mut sink = tx.clone();
+        let future = async move {
+            match await!(forward(progress, &mut sink)) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    let blocked = Progress::Blocked(Blocked {
+                        package_id: id,
+                        description: format!("falling back after error: {}", err),
+                        timestamp: Utc::now(),
+                    });
+                    let _ = await!(sink.send(Ok(blocked)));
+                    await!(fallback)
+                }
+            }
+        };
+
+        JobFuture {
+            package_id,
+            tx,
+            future: future.boxed(),
+        }
+    }
+
+    /// Replaces this job with one that only reports that its package was skipped, because the
+    /// package `dependency` failed to build.
+    pub fn skip(self, dependency: ManifestId) -> Self {
+        let JobFuture { package_id, tx, .. } = self;
+        let id = package_id.clone();
+        let mut sink = tx.clone();
+        let future = async move {
+            let reason = FailureReason::DependencyFailed(dependency);
+            await!(report_failure(&mut sink, id.clone(), reason));
+            Err(id)
+        };
+
+        JobFuture {
+            package_id,
+            tx,
+            future: future.boxed(),
+        }
+    }
+
+    /// Replaces this job with one that fails without reporting anything, since another job of the
+    /// same package has already reported why the package failed.
+    pub fn abandon(self) -> Self {
+        let JobFuture { package_id, tx, .. } = self;
+        let failed = future::ready(Err(package_id.clone()));
+
+        JobFuture {
+            package_id,
+            tx,
+            future: failed.boxed(),
+        }
+    }
+}
 
-        JobFuture(future)
+impl Debug for JobFuture {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct(stringify!(JobFuture))
+            .field("package_id", &self.package_id)
+            .field("tx", &self.tx)
+            .field("future", &"Pin<Box<dyn Future<Output = Outcome> + Send>>")
+            .finish()
     }
 }
 
 impl Future for JobFuture {
-    type Output = ();
+    type Output = Outcome;
 
     fn poll(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Self::Output> {
-        self.0.as_mut().poll(waker)
+        self.future.as_mut().poll(waker)
+    }
+}
+
+/// Forwards `progress` to `sink` until it ends, returning the error which ended it, if any.
+///
+/// Forwarding stops early without an error once nobody is listening to the progress anymore.
+async fn forward<'a, S>(mut progress: S, sink: &'a mut ProgressSender) -> Result<(), StoreError>
+where
+    S: Stream<Item = Result<Progress, StoreError>> + Unpin + 'a,
+{
+    while let Some(item) = await!(progress.next()) {
+        if await!(sink.send(Ok(item?))).is_err() {
+            break;
+        }
     }
+
+    Ok(())
+}
+
+async fn report_failure<'a>(
+    tx: &'a mut ProgressSender,
+    package_id: ManifestId,
+    reason: FailureReason,
+) {
+    let failed = Progress::Failed(Failed {
+        package_id,
+        reason,
+        timestamp: Utc::now(),
+    });
+    let _ = await!(tx.send(Ok(failed)));
 }
 
 /// A self-contained node in a build graph.
 ///
 /// This future drives the execution of one or more `JobFuture`s.
 ///
-/// `BuildFuture`s do not resolve to any particular output values on their own. They are only used
-/// to execute work on the threadpool and enforce the ordering of build tasks. The current progress
-/// of all `BuildFuture`s in a build graph is aggregated in a single `BuildStream`, which is
-/// returned by a `Builder`.
+/// `BuildFuture`s do not resolve to any particular output values on their own, only to whether
+/// they succeeded. They are only used to execute work on the threadpool and enforce the ordering
+/// of build tasks. The current progress of all `BuildFuture`s in a build graph is aggregated in a
+/// single `BuildStream`, which is returned by a `Builder`.
 ///
 /// This future is intentionally made `Clone` and is safe to poll from multiple threads.
 #[derive(Clone)]
 #[must_use = "futures do nothing unless polled"]
-pub struct BuildFuture(future::Shared<Pin<Box<dyn Future<Output = ()> + Send>>>);
+pub struct BuildFuture(future::Shared<Pin<Box<dyn Future<Output = Outcome> + Send>>>);
 
 impl BuildFuture {
     /// Creates a new `BuildFuture` which executes a single one-off job.
@@ -65,20 +206,25 @@ impl BuildFuture {
         BuildFuture(Pin::from(future).shared())
     }
 
-    /// Creates a new `BuildFuture` which executes the given jobs concurrently, resolving only once
-    /// all of them have completed.
-    pub fn join_all<I: IntoIterator<Item = JobFuture>>(jobs: I) -> Self {
-        let joined = future::join_all(jobs).map(|_| ());
-        let future: Box<dyn Future<Output = _> + Send> = Box::new(joined);
-        BuildFuture(Pin::from(future).shared())
-    }
+    /// Creates a new `BuildFuture` which waits for `deps` and `sources` to complete before
+    /// executing `next`.
+    ///
+    /// `sources` are the jobs fetching the sources of the package built by `next`. If any of them
+    /// fails, `next` is abandoned, since the failure has already been reported for the package.
+    /// Otherwise, if any of `deps` fails, `next` is skipped instead of executed.
+    pub fn join_all_and_then<I, J>(deps: I, sources: J, next: JobFuture) -> Self
+    where
+        I: IntoIterator<Item = BuildFuture>,
+        J: IntoIterator<Item = JobFuture>,
+    {
+        let deps = future::join_all(deps).map(first_failure);
+        let sources = future::join_all(sources).map(first_failure);
+        let joined = deps.join(sources).then(|outcomes| match outcomes {
+            (_, Err(_)) => next.abandon(),
+            (Err(dependency), Ok(())) => next.skip(dependency),
+            (Ok(()), Ok(())) => next,
+        });
 
-    /// Creates a new `BuildFuture` which waits for `deps` to complete before executing `next`.
-    pub fn join_all_and_then<I: IntoIterator<Item = BuildFuture>>(
-        deps: I,
-        next: JobFuture,
-    ) -> Self {
-        let joined = future::join_all(deps).then(|_| next);
         let future: Box<dyn Future<Output = _> + Send> = Box::new(joined);
         BuildFuture(Pin::from(future).shared())
     }
@@ -87,23 +233,29 @@ impl BuildFuture {
 impl Debug for BuildFuture {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         fmt.debug_tuple(stringify!(BuildFuture))
-            .field(&"future::Shared<Pin<Box<dyn Future<Output = ()> + Send>>>")
+            .field(&"future::Shared<Pin<Box<dyn Future<Output = Outcome> + Send>>>")
             .finish()
     }
 }
 
 impl Future for BuildFuture {
-    type Output = ();
+    type Output = Outcome;
 
     fn poll(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Self::Output> {
         Future::poll(Pin::new(&mut self.0), waker)
     }
 }
 
+fn first_failure(outcomes: Vec<Outcome>) -> Outcome {
+    outcomes.into_iter().find(Result::is_err).unwrap_or(Ok(()))
+}
+
 /// Internal state of the builder.
 pub struct BuilderState {
     /// Shared context with access to the store and fetchers.
     pub context: Context,
+    /// Scheduler client of the top-level build, through which all jobs are scheduled.
+    pub client: Client,
     /// Package manifest to build.
     pub manifest: Manifest,
     /// Precomputed ID of the package manifest to build.
@@ -114,15 +266,23 @@ pub struct BuilderState {
     pub progress: ProgressSender,
     /// List of dependent `BuildFuture`s to join on later.
     pub dependencies: Vec<BuildFuture>,
+    /// Jobs fetching the sources of the package, which are joined on along with `dependencies`.
+    pub sources: Vec<JobFuture>,
+    /// Whether to rebuild the package and compare the result with its outputs in the store.
+    pub check: bool,
+    /// Whether the package may be substituted from a binary cache instead of being built.
+    pub substitute: bool,
 }
 
 /// Future which asynchronously constructs a `BuildGraph`, exiting early if any error occurs.
 #[must_use = "futures do nothing unless polled"]
-pub struct InnerFuture(Pin<Box<dyn Future<Output = Result<BuilderState, ()>> + Send>>);
+pub struct InnerFuture(Pin<Box<dyn Future<Output = Result<BuilderState, StoreError>> + Send>>);
 
 impl InnerFuture {
     /// Creates a new `InnerFuture` which represents the intermediate state of the builder.
-    pub fn new<F: Future<Output = Result<BuilderState, ()>> + Send + 'static>(f: F) -> Self {
+    pub fn new<F: Future<Output = Result<BuilderState, StoreError>> + Send + 'static>(
+        f: F,
+    ) -> Self {
         InnerFuture(f.boxed())
     }
 }
@@ -130,13 +290,13 @@ impl InnerFuture {
 impl Debug for InnerFuture {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         fmt.debug_tuple(stringify!(InnerFuture))
-            .field(&"Pin<Box<dyn Future<Output = Result<BuilderState, Error>> + Send>>")
+            .field(&"Pin<Box<dyn Future<Output = Result<BuilderState, StoreError>> + Send>>")
             .finish()
     }
 }
 
 impl Future for InnerFuture {
-    type Output = Result<BuilderState, ()>;
+    type Output = Result<BuilderState, StoreError>;
 
     fn poll(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Self::Output> {
         self.0.as_mut().poll(waker)
@@ -146,18 +306,31 @@ impl Future for InnerFuture {
 impl BuildStream {
     /// Creates a new `BuildStream` which drives the builder to completion.
     ///
-    /// Requires a `BuildFuture` which represents the entire build graph and the receiving half of
-    /// the `ProgressReceiver` used to report progress.
-    pub(super) fn from_future<F>(future: F, rx: ProgressReceiver) -> Self
+    /// Requires a `BuildFuture` which represents the entire build graph, the `Started` event
+    /// describing it, and the receiving half of the `ProgressReceiver` used to report progress.
+    ///
+    /// The `Started` event is yielded before the progress of any job, since the build graph is
+    /// only spawned in the same poll which yields it.
+    ///
+    /// The build graph is spawned onto the executor, and dropped as soon as the `BuildStream` is
+    /// cancelled. This aborts every job still running, which in turn kills their builders and
+    /// releases their scratch directories, locks and build slots. Unless `keep_going` is set, the
+    /// build graph is also aborted as soon as any package fails to build.
+    pub(super) fn from_future<F>(future: F, rx: ProgressReceiver, keep_going: bool) -> Self
     where
-        F: Future<Output = Result<BuildFuture, ()>> + Send + 'static,
+        F: Future<Output = Result<(Started, BuildFuture), StoreError>> + Send + 'static,
     {
+        let cancel = CancelHandle::new();
+        let abort = CancelHandle::new();
+        let (on_cancel, on_failure) = (cancel.clone(), abort.clone());
         let build_started = async move {
             match await!(future) {
                 Err(err) => vec![Err(err)],
-                Ok(build) => {
+                Ok((started, build)) => {
+                    let build = Cancellable::new(build.map(|_| ()), on_failure);
+                    let build = Cancellable::new(build, on_cancel);
                     tokio::spawn(build.map(Ok).compat());
-                    Vec::new()
+                    vec![Ok(Progress::Started(started))]
                 }
             }
         };
@@ -165,9 +338,184 @@ impl BuildStream {
         let progress = build_started
             .map(|res| stream::iter(res))
             .flatten_stream()
-            .select(rx)
-            .boxed();
+            .select(rx);
+
+        let supervised = Supervised::new(progress, abort, keep_going);
+        BuildStream::with_cancel_handle(supervised, cancel)
+    }
+}
+
+/// Stream of build progress which keeps track of the packages that failed to build.
+///
+/// The first failure aborts the rest of the build through `abort`, unless `keep_going` is set.
+/// Either way, the stream ends with a `StoreError::BuildsFailed` summarizing every failure.
+#[must_use = "streams do nothing unless polled"]
+struct Supervised {
+    progress: Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>,
+    abort: CancelHandle,
+    keep_going: bool,
+    failed: Vec<ManifestId>,
+    skipped: Vec<ManifestId>,
+    finished: bool,
+}
+
+impl Supervised {
+    fn new<S>(progress: S, abort: CancelHandle, keep_going: bool) -> Self
+    where
+        S: Stream<Item = Result<Progress, StoreError>> + Send + 'static,
+    {
+        Supervised {
+            progress: progress.boxed(),
+            abort,
+            keep_going,
+            failed: Vec::new(),
+            skipped: Vec::new(),
+            finished: false,
+        }
+    }
 
-        BuildStream(progress)
+    fn record(&mut self, failed: &Failed) {
+        let ids = match failed.reason {
+            FailureReason::Error(_) | FailureReason::TimedOut(_) => &mut self.failed,
+            FailureReason::DependencyFailed(_) => &mut self.skipped,
+        };
+
+        if !ids.contains(&failed.package_id) {
+            ids.push(failed.package_id.clone());
+        }
+
+        if !self.keep_going {
+            self.abort.cancel();
+        }
+    }
+
+    fn summary(&mut self) -> Option<StoreError> {
+        if self.failed.is_empty() && self.skipped.is_empty() {
+            None
+        } else {
+            Some(StoreError::BuildsFailed {
+                failed: mem::replace(&mut self.failed, Vec::new()),
+                skipped: mem::replace(&mut self.skipped, Vec::new()),
+            })
+        }
+    }
+}
+
+impl Stream for Supervised {
+    type Item = Result<Progress, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        match self.progress.as_mut().poll_next(waker) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Some(Ok(Progress::Failed(failed)))) => {
+                self.record(&failed);
+                Poll::Ready(Some(Ok(Progress::Failed(failed))))
+            }
+            Poll::Ready(Some(item)) => Poll::Ready(Some(item)),
+            Poll::Ready(None) => {
+                self.finished = true;
+                Poll::Ready(self.summary().map(Err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_preview::executor::block_on;
+
+    use super::*;
+    use crate::progress::{self, FinalStatus, Finished};
+
+    fn id(name: &str) -> ManifestId {
+        format!("{}@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m", name)
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn dependents_of_failed_jobs_are_skipped() {
+        let (tx, rx) = progress::progress_channel(4);
+
+        let error = StoreError::InvalidSource("foo".to_string());
+        let failing = stream::once(future::err(error));
+        let dependency = BuildFuture::new(JobFuture::new(id("foo"), failing, tx.clone()));
+
+        let built = Progress::Finished(Finished {
+            package_id: id("bar"),
+            status: FinalStatus::Built,
+            installed_bytes: 0,
+            timestamp: Utc::now(),
+        });
+        let next = JobFuture::new(id("bar"), stream::once(future::ok(built)), tx);
+        let build = BuildFuture::join_all_and_then(vec![dependency], Vec::new(), next);
+        assert_eq!(block_on(build), Err(id("bar")));
+
+        let failed: Vec<Failed> = block_on(rx.collect::<Vec<_>>())
+            .into_iter()
+            .map(|progress| match progress {
+                Ok(Progress::Failed(failed)) => failed,
+                other => panic!("expected a failure, got: {:?}", other),
+            })
+            .collect();
+
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].package_id, id("foo"));
+        assert_eq!(failed[1].package_id, id("bar"));
+        match failed[1].reason {
+            FailureReason::DependencyFailed(ref dependency) => assert_eq!(*dependency, id("foo")),
+            ref reason => panic!("expected a dependency failure, got: {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn failed_sources_are_reported_once() {
+        let (tx, rx) = progress::progress_channel(4);
+
+        let error = StoreError::InvalidSource("foo".to_string());
+        let failing = stream::once(future::err(error));
+        let source = JobFuture::new(id("foo"), failing, tx.clone());
+
+        let next = JobFuture::new(id("foo"), stream::empty(), tx);
+        let build = BuildFuture::join_all_and_then(Vec::new(), vec![source], next);
+        assert_eq!(block_on(build), Err(id("foo")));
+
+        let reasons: Vec<FailureReason> = block_on(rx.collect::<Vec<_>>())
+            .into_iter()
+            .map(|progress| match progress {
+                Ok(Progress::Failed(failed)) => failed.reason,
+                other => panic!("expected a failure, got: {:?}", other),
+            })
+            .collect();
+
+        assert_eq!(reasons.len(), 1);
+        match reasons[0] {
+            FailureReason::Error(_) => {}
+            ref reason => panic!("expected an error, got: {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn failures_before_falling_back_are_reported() {
+        let (tx, rx) = progress::progress_channel(4);
+
+        let error = StoreError::Remote("connection reset".to_string());
+        let message = error.to_string();
+        let failing = stream::once(future::err(error));
+        let job = JobFuture::new_or_else(id("foo"), failing, tx, future::ok(()));
+        assert_eq!(block_on(job), Ok(()));
+
+        let progress = block_on(rx.collect::<Vec<_>>());
+        match progress.as_slice() {
+            [Ok(Progress::Blocked(ref blocked))] => {
+                assert_eq!(blocked.package_id, id("foo"));
+                assert!(blocked.description.contains(&message), "{:?}", blocked);
+            }
+            other => panic!("expected the failure to be reported, got: {:?}", other),
+        }
     }
 }
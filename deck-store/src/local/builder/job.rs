@@ -1,48 +1,106 @@
 pub use self::build_manifest::BuildManifest;
-pub use self::fetch_output::FetchOutput;
+pub use self::fetch_output::{find_substitutes, FetchOutput};
 pub use self::fetch_source::FetchSource;
 
 use std::future::Future;
 use std::pin::Pin;
 
 use deck_core::ManifestId;
+use futures_preview::channel::mpsc::{self, UnboundedSender};
 use futures_preview::future::{self, FutureExt, TryFutureExt};
 use futures_preview::stream::{self, Stream, StreamExt};
 
-use super::futures::JobFuture;
+use super::futures::{JobFuture, Outcome};
 use crate::local::context::Context;
 use crate::progress::{Progress, ProgressSender};
+use crate::StoreError;
 
 mod build_manifest;
 mod fetch_output;
 mod fetch_source;
 
 pub trait IntoJob {
-    fn into_job(self, tx: ProgressSender) -> JobFuture;
+    fn into_job(self, package_id: ManifestId, tx: ProgressSender) -> JobFuture;
+
+    /// Same as `IntoJob::into_job()`, except that `fallback` is run instead of reporting the
+    /// failure if the job fails.
+    fn into_job_or_else<F>(
+        self,
+        package_id: ManifestId,
+        tx: ProgressSender,
+        fallback: F,
+    ) -> JobFuture
+    where
+        F: Future<Output = Outcome> + Send + 'static;
 }
 
 impl<S, F> IntoJob for F
 where
-    S: Stream<Item = Result<Progress, ()>> + Send + 'static,
-    F: Future<Output = Result<S, ()>> + Send + 'static,
+    S: Stream<Item = Result<Progress, StoreError>> + Send + 'static,
+    F: Future<Output = Result<S, StoreError>> + Send + 'static,
 {
-    fn into_job(self, tx: ProgressSender) -> JobFuture {
-        let stream = self
-            .map_ok(|stream| Box::pin(stream) as Pin<Box<dyn Stream<Item = _> + Send>>)
-            .unwrap_or_else(|err| {
-                Box::pin(stream::once(future::err(err))) as Pin<Box<dyn Stream<Item = _> + Send>>
-            })
-            .flatten_stream()
-            .boxed();
-
-        JobFuture::new(stream, tx)
+    fn into_job(self, package_id: ManifestId, tx: ProgressSender) -> JobFuture {
+        JobFuture::new(package_id, flatten_job(self), tx)
+    }
+
+    fn into_job_or_else<G>(
+        self,
+        package_id: ManifestId,
+        tx: ProgressSender,
+        fallback: G,
+    ) -> JobFuture
+    where
+        G: Future<Output = Outcome> + Send + 'static,
+    {
+        JobFuture::new_or_else(package_id, flatten_job(self), tx, fallback)
     }
 }
 
+/// Flattens a future resolving to the progress stream of a job into a single stream.
+fn flatten_job<S, F>(job: F) -> Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>
+where
+    S: Stream<Item = Result<Progress, StoreError>> + Send + 'static,
+    F: Future<Output = Result<S, StoreError>> + Send + 'static,
+{
+    job.map_ok(|stream| Box::pin(stream) as Pin<Box<dyn Stream<Item = _> + Send>>)
+        .unwrap_or_else(|err| {
+            Box::pin(stream::once(future::err(err))) as Pin<Box<dyn Stream<Item = _> + Send>>
+        })
+        .flatten_stream()
+        .boxed()
+}
+
+/// Sender through which a running job reports its intermediate progress.
+type JobSender = UnboundedSender<Result<Progress, StoreError>>;
+
+/// Runs the future returned by `job` as a stream, yielding all progress sent by the job followed
+/// by its final result.
+///
+/// The job is only driven while the stream is being polled, and the stream ends once the job has
+/// completed and every clone of its sender has been dropped.
+fn report_from<J, F>(job: J) -> impl Stream<Item = Result<Progress, StoreError>> + Send
+where
+    J: FnOnce(JobSender) -> F,
+    F: Future<Output = Result<Progress, StoreError>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded();
+    let running = job(tx.clone());
+    let driver = async move {
+        let result = await!(running);
+        let _ = tx.unbounded_send(result);
+    };
+
+    let driver = driver
+        .into_stream()
+        .filter_map(|_| future::ready(None::<Result<Progress, StoreError>>));
+
+    rx.select(driver)
+}
+
 trait Job {
     type Args;
-    type Stream: Stream<Item = Result<Progress, ()>> + Send;
-    type Future: Future<Output = Result<Self::Stream, ()>> + Send;
+    type Stream: Stream<Item = Result<Progress, StoreError>> + Send;
+    type Future: Future<Output = Result<Self::Stream, StoreError>> + Send;
 
     fn run(context: Context, id: ManifestId, args: Self::Args) -> Self::Future;
 }
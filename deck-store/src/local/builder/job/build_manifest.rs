@@ -1,55 +1,685 @@
-use std::future::Future;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::process::{Command, ExitStatus, Stdio};
 use std::task::{Poll, Waker};
 use std::time::{Duration, Instant};
 
-use deck_core::Manifest;
-use futures_preview::compat::Future01CompatExt;
-use futures_preview::future::{self, FutureExt};
-use futures_preview::stream::{self, Stream};
+use chrono::Utc;
+use deck_core::{BuildScript, Hash, Manifest, ManifestId, OutputId, Phase};
+use futures::{Async, Future as Future01, Poll as Poll01, Stream as Stream01};
+use futures_preview::compat::Stream01CompatExt;
+use futures_preview::future;
+use futures_preview::stream::{self, Stream, StreamExt};
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio::timer::Delay;
+use tokio_process::CommandExt;
 
+use super::super::Limits;
+use super::{report_from, JobSender};
+use crate::local::build_users::{BuildUser, BuildUsers, Lease};
 use crate::local::context::Context;
-use crate::progress::{BuildStatus, Building, FinalStatus, Finished, Progress};
+use crate::local::dir::StagingPath;
+use crate::local::log;
+#[cfg(target_os = "linux")]
+use crate::local::sandbox::Sandbox;
+use crate::local::sandbox::SandboxConfig;
+use crate::progress::{BuildStatus, Building, FinalStatus, Finished, Progress, TimeLimit};
+use crate::verify::Object;
+use crate::StoreError;
 
+const SOURCES_DIR_NAME: &str = "sources";
+const DEPS_DIR_NAME: &str = "deps";
+const BUILD_DIR_NAME: &str = "build";
+const OUTPUTS_DIR_NAME: &str = "out";
+const TEMP_DIR_NAME: &str = "tmp";
+/// Environment variable holding the number of cores the builder may use.
+const BUILD_CORES_VAR: &str = "DECK_BUILD_CORES";
+/// Timestamp which builders should embed instead of the current time, as seconds since the Unix
+/// epoch. This is the earliest date which can be represented in a ZIP archive.
+const SOURCE_DATE_EPOCH: &str = "315532800";
+/// Home directory of every builder, which never exists so builds cannot depend on its contents.
+const HOME_DIR: &str = "/homeless-shelter";
+/// Location of the scratch directory as seen by sandboxed builders.
+const SANDBOX_SCRATCH_DIR: &str = "/build";
+
+/// Builds a package from source by running each phase of its build script, adding the resulting
+/// outputs to the store.
 #[must_use = "streams do nothing unless polled"]
-pub struct BuildManifest(Pin<Box<dyn Stream<Item = Result<Progress, ()>> + Send>>);
+pub struct BuildManifest(Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>);
 
 impl BuildManifest {
-    pub fn new(_ctx: Context, manifest: Manifest) -> Self {
-        let id = manifest.compute_id();
+    pub fn new(ctx: Context, manifest: Manifest) -> Self {
+        BuildManifest::start(ctx, manifest, false)
+    }
 
-        let building = Progress::Building(Building {
-            package_id: id.clone(),
-            current_task: 3,
-            total_tasks: 5,
-            status: BuildStatus::Compiling,
-            description: "make all".to_string(),
-            stdout: Vec::new(),
-            stderr: Vec::new(),
-        });
+    /// Rebuilds a package whose outputs are already in the store, comparing the rebuilt outputs
+    /// with the existing ones instead of adding them to the store.
+    pub fn check(ctx: Context, manifest: Manifest) -> Self {
+        BuildManifest::start(ctx, manifest, true)
+    }
 
-        let finished = Progress::Finished(Finished {
-            package_id: id,
-            status: FinalStatus::Built,
-        });
+    fn start(ctx: Context, manifest: Manifest, check: bool) -> Self {
+        let id = manifest.compute_id();
+        let logs = ctx.store.logs();
+        let build_log = if check {
+            logs.create_check(&id)
+        } else {
+            logs.create(&id)
+        };
+
+        let build_log = match build_log {
+            Ok(build_log) => build_log,
+            Err(e) => {
+                let failed = stream::once(future::err(StoreError::Io(e)));
+                return BuildManifest(Box::pin(failed));
+            }
+        };
 
-        let when = Instant::now() + Duration::from_millis(1000);
-        let delay = tokio::timer::Delay::new(when);
+        let building = report_from(move |tx| async move {
+            let installed_bytes = await!(build(&ctx, &manifest, check, &tx))?;
+            let status = if check {
+                FinalStatus::Checked
+            } else {
+                FinalStatus::Built
+            };
 
-        let stream = stream::futures_ordered(vec![
-            Box::pin(future::ok(building)) as Pin<Box<dyn Future<Output = _> + Send>>,
-            Box::pin(delay.compat().then(|_| future::ok(finished)))
-                as Pin<Box<dyn Future<Output = _> + Send>>,
-        ]);
+            Ok(Progress::Finished(Finished {
+                package_id: id,
+                status,
+                installed_bytes,
+                timestamp: Utc::now(),
+            }))
+        });
 
-        BuildManifest(Box::pin(stream))
+        BuildManifest(Box::pin(log::record(build_log, building)))
     }
 }
 
 impl Stream for BuildManifest {
-    type Item = Result<Progress, ()>;
+    type Item = Result<Progress, StoreError>;
 
     fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
         self.0.as_mut().poll_next(waker)
     }
 }
+
+/// Scratch directory in which a single build takes place.
+///
+/// ```text
+/// build-<random>/
+/// ├── sources/   Links to the sources of the package.
+/// ├── deps/      Links to the outputs of every dependency and build dependency.
+/// ├── build/     Working directory of the builder.
+/// ├── out/       One directory per output, to be filled by the builder.
+/// └── tmp/       Temporary files.
+/// ```
+///
+/// Entries are created in sorted order, so their order does not vary between builds even on file
+/// systems which list directory entries in the order they were created.
+#[derive(Debug)]
+struct Scratch {
+    root: PathBuf,
+    path: Vec<PathBuf>,
+    outputs: Vec<(OutputId, PathBuf)>,
+    store_paths: BTreeSet<PathBuf>,
+}
+
+impl Scratch {
+    /// Returns the clean environment the builder is run with, for which the scratch directory is
+    /// located at `base`.
+    ///
+    /// The environment is kept deterministic: builds run in the UTC time zone with the `C.UTF-8`
+    /// locale, see a fixed `SOURCE_DATE_EPOCH` and have a `HOME` which does not exist. Variables
+    /// declared by the manifest override these defaults, but are in turn overridden by `HOME` and
+    /// the paths of the scratch directory.
+    fn env(
+        &self,
+        manifest: &Manifest,
+        base: &Path,
+    ) -> Result<BTreeMap<String, OsString>, StoreError> {
+        let mut env = BTreeMap::new();
+        env.insert("SOURCE_DATE_EPOCH".into(), SOURCE_DATE_EPOCH.into());
+        env.insert("TZ".into(), "UTC".into());
+        env.insert("LANG".into(), "C.UTF-8".into());
+        env.extend(
+            manifest
+                .env()
+                .map(|(key, value)| (key.clone(), OsString::from(value))),
+        );
+
+        env.insert("sources".into(), base.join(SOURCES_DIR_NAME).into());
+        env.insert("deps".into(), base.join(DEPS_DIR_NAME).into());
+        env.insert("TMPDIR".into(), base.join(TEMP_DIR_NAME).into());
+        env.insert("HOME".into(), HOME_DIR.into());
+
+        let path = std::env::join_paths(&self.path)
+            .map_err(|e| StoreError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        env.insert("PATH".into(), path);
+
+        for (id, path) in &self.outputs {
+            let key = match id.output() {
+                Some(name) => format!("out_{}", name.replace('-', "_")),
+                None => "out".to_string(),
+            };
+            let relative = path.strip_prefix(&self.root).unwrap_or(path);
+            env.insert(key, base.join(relative).into());
+        }
+
+        Ok(env)
+    }
+}
+
+/// Runs the build script of `manifest` and adds its outputs to the store, returning their total
+/// size in bytes.
+///
+/// If `check` is set, the outputs are compared with the ones already in the store instead, and
+/// nothing is added.
+async fn build<'a>(
+    ctx: &'a Context,
+    manifest: &'a Manifest,
+    check: bool,
+    tx: &'a JobSender,
+) -> Result<u64, StoreError> {
+    let id = manifest.compute_id();
+    let script = manifest.build_script();
+    if script.is_empty() {
+        let reason = format!("`{}` does not declare a builder", id);
+        return Err(StoreError::InvalidManifest(reason));
+    }
+
+    let name = format!("build-{}", Hash::random());
+    let scratch_dir = await!(ctx.store.scratch_dir(&name))?;
+    let scratch = await!(materialize(ctx, manifest, scratch_dir.as_path()))?;
+
+    let lease = match ctx.build_users {
+        Some(ref users) => Some(await!(lease_user(users, &scratch))?),
+        None => None,
+    };
+
+    let (mut env, confined) = match ctx.sandbox {
+        Some(ref config) => {
+            let user = lease.as_ref().map(Lease::user);
+            let confined = await!(Confined::new(ctx, config, &scratch, user))?;
+            let env = scratch.env(manifest, Path::new(SANDBOX_SCRATCH_DIR))?;
+            (env, Some(confined))
+        }
+        None => (scratch.env(manifest, &scratch.root)?, None),
+    };
+
+    let limits = ctx.scheduler.limits();
+    env.insert(
+        BUILD_CORES_VAR.into(),
+        limits.build_cores.to_string().into(),
+    );
+
+    let phases: Vec<_> = script.phases().collect();
+    let total_tasks = phases.len() as u32;
+    let mut progress = Building {
+        package_id: id.clone(),
+        status: BuildStatus::Started,
+        current_task: 0,
+        total_tasks,
+        description: format!("building `{}`", id),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        timestamp: Utc::now(),
+    };
+
+    let _ = tx.unbounded_send(Ok(Progress::Building(progress.clone())));
+
+    let time_limits = TimeLimits::start(script, limits);
+    for (index, (phase, text)) in phases.into_iter().enumerate() {
+        progress.status = to_status(phase);
+        progress.current_task = index as u32 + 1;
+        progress.description = phase.to_string();
+
+        let mut command = Command::new(script.builder());
+        command
+            .args(script.args())
+            .arg(text)
+            .current_dir(scratch.root.join(BUILD_DIR_NAME))
+            .env_clear()
+            .envs(&env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if let Some(ref lease) = lease {
+            run_as(&mut command, lease.user());
+        }
+
+        new_session(&mut command);
+        fixed_umask(&mut command);
+
+        if let Some(ref confined) = confined {
+            confined.apply(&mut command);
+        }
+
+        await!(run_phase(
+            &id,
+            phase,
+            command,
+            time_limits,
+            &mut progress,
+            tx
+        ))?;
+    }
+
+    if let Some(lease) = lease {
+        let outputs = scratch
+            .outputs
+            .iter()
+            .map(|(_, path)| path.clone())
+            .collect();
+        await!(lease.release(outputs)).map_err(StoreError::Io)?;
+    }
+
+    let mut installed_bytes = 0;
+    for (output, path) in &scratch.outputs {
+        if check {
+            ctx.store.compare_output(output, path)?;
+        } else {
+            await!(ctx.store.add_output(output, path))?;
+            installed_bytes += ctx.store.output_size(output)?;
+        }
+    }
+
+    Ok(installed_bytes)
+}
+
+/// Lays out the scratch directory of a build of `manifest` at `root`.
+///
+/// Every source and every output of the package's dependencies must already be present in the
+/// store.
+async fn materialize<'a>(
+    ctx: &'a Context,
+    manifest: &'a Manifest,
+    root: &'a Path,
+) -> Result<Scratch, StoreError> {
+    for dir in &[
+        SOURCES_DIR_NAME,
+        DEPS_DIR_NAME,
+        BUILD_DIR_NAME,
+        OUTPUTS_DIR_NAME,
+        TEMP_DIR_NAME,
+    ] {
+        let path = root.join(dir);
+        fs::create_dir(&path).map_err(StoreError::at(&path))?;
+    }
+
+    let mut store_paths = BTreeSet::new();
+    for source in manifest.sources() {
+        let id = match source.expected_id() {
+            Some(id) => id,
+            None => return Err(StoreError::InvalidSource(format!("{:?}", source))),
+        };
+
+        let target = match ctx.store.source_path(&id) {
+            Some(target) => target,
+            None => return Err(StoreError::NotFound(Object::Source(id))),
+        };
+
+        let link = root.join(SOURCES_DIR_NAME).join(id.to_string());
+        symlink(&target, &link).map_err(StoreError::at(&link))?;
+        store_paths.insert(target);
+    }
+
+    let mut path = Vec::new();
+    let deps: BTreeSet<_> = manifest
+        .dependencies()
+        .chain(manifest.build_dependencies())
+        .collect();
+    for dep in deps {
+        let closure = match await!(ctx.store.compute_closure(dep.clone())) {
+            Some(closure) => closure,
+            None => return Err(StoreError::NotFound(Object::Manifest(dep.clone()))),
+        };
+
+        for output in closure.manifests().flat_map(|(_, m)| m.outputs()) {
+            if !ctx.store.contains_output(&output) {
+                return Err(StoreError::NotFound(Object::Output(output)));
+            }
+            store_paths.insert(ctx.store.output_path(&output));
+        }
+
+        for output in closure.target_manifest().outputs() {
+            let target = ctx.store.output_path(&output);
+            let link = root.join(DEPS_DIR_NAME).join(output.to_string());
+            symlink(&target, &link).map_err(StoreError::at(&link))?;
+
+            let bin = target.join("bin");
+            if bin.is_dir() {
+                path.push(bin);
+            }
+        }
+    }
+
+    let mut outputs = Vec::new();
+    for output in manifest.outputs() {
+        let dir = root.join(OUTPUTS_DIR_NAME).join(output.to_string());
+        fs::create_dir(&dir).map_err(StoreError::at(&dir))?;
+        outputs.push((output, dir));
+    }
+
+    Ok(Scratch {
+        root: root.to_owned(),
+        path,
+        outputs,
+        store_paths,
+    })
+}
+
+/// Waits for a free build user and hands the writable parts of `scratch` over to it.
+async fn lease_user<'a>(users: &'a BuildUsers, scratch: &'a Scratch) -> Result<Lease, StoreError> {
+    let mut lease = await!(users.acquire());
+    for dir in &[BUILD_DIR_NAME, OUTPUTS_DIR_NAME, TEMP_DIR_NAME] {
+        let path = scratch.root.join(dir);
+        lease.hand_over(&path).map_err(StoreError::at(&path))?;
+    }
+
+    Ok(lease)
+}
+
+/// Sandbox which the builder is confined to, along with its root directory.
+#[derive(Debug)]
+struct Confined {
+    #[cfg(target_os = "linux")]
+    sandbox: Sandbox,
+    _root: StagingPath,
+}
+
+impl Confined {
+    /// Lays out a sandbox containing the scratch directory and every store path used by the build,
+    /// which is run as `user` if one was leased.
+    #[cfg(target_os = "linux")]
+    async fn new<'a>(
+        ctx: &'a Context,
+        config: &'a SandboxConfig,
+        scratch: &'a Scratch,
+        user: Option<&'a BuildUser>,
+    ) -> Result<Self, StoreError> {
+        let name = format!("sandbox-{}", Hash::random());
+        let root = await!(ctx.store.scratch_dir(&name))?;
+
+        let temp = scratch.root.join(TEMP_DIR_NAME);
+        let mut sandbox = Sandbox::new(root.as_path(), &scratch.root, &temp);
+        sandbox.current_dir(Path::new(SANDBOX_SCRATCH_DIR).join(BUILD_DIR_NAME));
+        if let Some(user) = user {
+            sandbox.host_ids(user.uid, user.gid);
+        }
+
+        for path in scratch.store_paths.iter().chain(&config.extra_paths) {
+            sandbox.bind_read_only(path);
+        }
+
+        sandbox.prepare().map_err(StoreError::at(root.as_path()))?;
+        Ok(Confined {
+            sandbox,
+            _root: root,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn new<'a>(
+        _ctx: &'a Context,
+        _config: &'a SandboxConfig,
+        _scratch: &'a Scratch,
+        _user: Option<&'a BuildUser>,
+    ) -> Result<Self, StoreError> {
+        let message = "sandboxed builds are only supported on Linux";
+        Err(StoreError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            message,
+        )))
+    }
+
+    /// Confines `command` to the sandbox once it is spawned.
+    #[cfg(target_os = "linux")]
+    fn apply(&self, command: &mut Command) {
+        self.sandbox.apply(command);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply(&self, _command: &mut Command) {}
+}
+
+/// Time limits of a single build, which apply across all of its phases.
+#[derive(Clone, Copy, Debug)]
+struct TimeLimits {
+    deadline: Option<(Duration, Instant)>,
+    max_silent_time: Option<Duration>,
+}
+
+impl TimeLimits {
+    /// Starts the clock on a build of `script`, falling back to the defaults in `limits`.
+    fn start(script: &BuildScript, limits: &Limits) -> Self {
+        let timeout = script.timeout().or(limits.build_timeout);
+        TimeLimits {
+            deadline: timeout.map(|timeout| (timeout, Instant::now() + timeout)),
+            max_silent_time: script.max_silent_time().or(limits.max_silent_time),
+        }
+    }
+}
+
+/// Event reported by a running builder.
+#[derive(Debug)]
+enum Event {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    /// The builder has exited with the given status.
+    Exited(ExitStatus),
+    /// The builder has exceeded the given time limit.
+    Expired(TimeLimit),
+}
+
+/// Spawns `command` for a single phase, forwarding its output to `tx` until it exits.
+///
+/// The builder is killed along with its process group as soon as it exceeds `limits`.
+async fn run_phase<'a>(
+    id: &'a ManifestId,
+    phase: Phase,
+    mut command: Command,
+    limits: TimeLimits,
+    progress: &'a mut Building,
+    tx: &'a JobSender,
+) -> Result<(), StoreError> {
+    let mut child = command.spawn_async()?;
+    let _group = ProcessGroup(child.id());
+    let stdout = child.stdout().take().map(|out| {
+        let chunks = FramedRead::new(out, BytesCodec::new());
+        chunks.map(|chunk| Event::Stdout(chunk.to_vec()))
+    });
+    let stderr = child.stderr().take().map(|err| {
+        let chunks = FramedRead::new(err, BytesCodec::new());
+        chunks.map(|chunk| Event::Stderr(chunk.to_vec()))
+    });
+
+    let output: Box<dyn Stream01<Item = _, Error = _> + Send> = match (stdout, stderr) {
+        (Some(stdout), Some(stderr)) => Box::new(stdout.select(stderr)),
+        _ => Box::new(futures::stream::empty()),
+    };
+
+    let exited = child.into_stream().map(Event::Exited);
+    let mut events = Watchdog::new(output.chain(exited), limits).compat();
+    while let Some(event) = await!(events.next()) {
+        match event? {
+            Event::Stdout(bytes) => progress.stdout = bytes,
+            Event::Stderr(bytes) => progress.stderr = bytes,
+            Event::Exited(status) if status.success() => return Ok(()),
+            Event::Exited(status) => {
+                return Err(StoreError::BuildFailed {
+                    package: id.clone(),
+                    phase,
+                    status,
+                });
+            }
+            Event::Expired(limit) => {
+                return Err(StoreError::TimedOut {
+                    package: id.clone(),
+                    phase,
+                    limit,
+                });
+            }
+        }
+
+        progress.timestamp = Utc::now();
+        let _ = tx.unbounded_send(Ok(Progress::Building(progress.clone())));
+        progress.stdout.clear();
+        progress.stderr.clear();
+    }
+
+    let message = "builder exited without a status";
+    Err(StoreError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        message,
+    )))
+}
+
+/// Stream of events from a running builder, which also reports when it exceeds its time limits.
+struct Watchdog<S> {
+    events: S,
+    deadline: Option<(Duration, Delay)>,
+    silence: Option<(Duration, Delay)>,
+}
+
+impl<S> Watchdog<S> {
+    fn new(events: S, limits: TimeLimits) -> Self {
+        let silence = limits
+            .max_silent_time
+            .map(|max| (max, Delay::new(Instant::now() + max)));
+        Watchdog {
+            events,
+            deadline: limits
+                .deadline
+                .map(|(timeout, at)| (timeout, Delay::new(at))),
+            silence,
+        }
+    }
+}
+
+impl<S: Stream01<Item = Event, Error = io::Error>> Stream01 for Watchdog<S> {
+    type Item = Event;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll01<Option<Self::Item>, Self::Error> {
+        let expired = |delay: &mut Delay| {
+            delay
+                .poll()
+                .map(|ready| ready.is_ready())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        };
+
+        // The deadline is checked before any output, or a builder which never stops writing
+        // would never time out.
+        if let Some((timeout, ref mut delay)) = self.deadline {
+            if expired(delay)? {
+                let limit = TimeLimit::Timeout(timeout);
+                return Ok(Async::Ready(Some(Event::Expired(limit))));
+            }
+        }
+
+        if let Async::Ready(event) = self.events.poll()? {
+            // Any output from the builder restarts the clock on its silence.
+            if let Some((max, ref mut delay)) = self.silence {
+                delay.reset(Instant::now() + max);
+            }
+
+            return Ok(Async::Ready(event));
+        }
+
+        if let Some((max, ref mut delay)) = self.silence {
+            if expired(delay)? {
+                let limit = TimeLimit::MaxSilentTime(max);
+                return Ok(Async::Ready(Some(Event::Expired(limit))));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Switches `command` to the given build user once it is spawned.
+#[cfg(unix)]
+fn run_as(command: &mut Command, user: &BuildUser) {
+    use std::os::unix::process::CommandExt;
+    command.uid(user.uid).gid(user.gid);
+}
+
+#[cfg(not(unix))]
+fn run_as(_command: &mut Command, _user: &BuildUser) {}
+
+/// Starts `command` in a new session, so its whole process tree can be killed at once.
+#[cfg(unix)]
+fn new_session(command: &mut Command) {
+    use nix::unistd;
+    use std::os::unix::process::CommandExt;
+
+    use crate::local::to_io;
+
+    command.before_exec(|| unistd::setsid().map(|_| ()).map_err(to_io));
+}
+
+#[cfg(not(unix))]
+fn new_session(_command: &mut Command) {}
+
+/// Starts `command` with a umask of `022`, so the permissions of the files created by the builder
+/// do not depend on the umask of the daemon.
+#[cfg(unix)]
+fn fixed_umask(command: &mut Command) {
+    use nix::sys::stat::{self, Mode};
+    use std::os::unix::process::CommandExt;
+
+    command.before_exec(|| {
+        stat::umask(Mode::S_IWGRP | Mode::S_IWOTH);
+        Ok(())
+    });
+}
+
+#[cfg(not(unix))]
+fn fixed_umask(_command: &mut Command) {}
+
+/// Process group of a running builder, which is killed when dropped.
+///
+/// This takes care of any processes left behind by the builder once it exits, as well as the
+/// builder itself if the build is cancelled while it is still running.
+#[derive(Debug)]
+struct ProcessGroup(u32);
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        use nix::sys::signal::{self, Signal};
+        use nix::unistd::Pid;
+
+        // A negative PID addresses every process in the group led by that PID.
+        let group = Pid::from_raw(-(self.0 as i32));
+        let _ = signal::kill(group, Signal::SIGKILL);
+    }
+}
+
+fn to_status(phase: Phase) -> BuildStatus {
+    match phase {
+        Phase::Prepare => BuildStatus::Preparing,
+        Phase::Configure => BuildStatus::Configuring,
+        Phase::Compile => BuildStatus::Compiling,
+        Phase::Test => BuildStatus::Testing,
+        Phase::Finalize => BuildStatus::Finalizing,
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "symlinks are not supported",
+    ))
+}
@@ -1,25 +1,153 @@
 use std::pin::Pin;
 use std::task::{Poll, Waker};
 
-use deck_core::ManifestId;
-use futures_preview::stream::Stream;
+use chrono::Utc;
+use deck_binary_cache::CacheError;
+use deck_core::{ManifestId, OutputId};
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::stream::{Stream, StreamExt};
 
-use crate::local::context::Context;
-use crate::progress::Progress;
+use super::{report_from, JobSender};
+use crate::local::context::{Context, Substitutes};
+use crate::progress::{FinalStatus, Finished, Progress, Substituted, Substituting};
+use crate::StoreError;
 
+/// Output which can be fetched from a binary cache instead of being built.
+#[derive(Clone, Debug)]
+pub struct Substitute {
+    /// ID of the missing output.
+    pub output: OutputId,
+    /// Index of the binary cache providing the output.
+    pub cache: usize,
+}
+
+/// Finds a binary cache for each of the given `outputs`, in order of priority.
+///
+/// Returns `None` if any one of the outputs cannot be substituted, since the package must then be
+/// built from source regardless. With `Substitutes::Verified`, this is the case for every output
+/// whose content hash is not registered in the store.
+pub async fn find_substitutes<'a>(
+    ctx: &'a Context,
+    outputs: &'a [OutputId],
+) -> Option<Vec<Substitute>> {
+    let mut substitutes = Vec::with_capacity(outputs.len());
+
+    for output in outputs {
+        if ctx.substitutes == Substitutes::Verified {
+            match ctx.store.registered_output_hash(output) {
+                Ok(Some(_)) => {}
+                Ok(None) | Err(_) => return None,
+            }
+        }
+
+        let mut found = None;
+        for (index, cache) in ctx.binary_caches.iter().enumerate() {
+            let mut cache = match await!(cache.lock().compat()) {
+                Ok(cache) => cache,
+                Err(_) => continue,
+            };
+
+            if let Ok(true) = await!(cache.query_outputs(output)) {
+                found = Some(index);
+                break;
+            }
+        }
+
+        match found {
+            Some(cache) => substitutes.push(Substitute {
+                output: output.clone(),
+                cache,
+            }),
+            None => return None,
+        }
+    }
+
+    Some(substitutes)
+}
+
+/// Fetches the outputs of a package from binary caches, adding them to the store.
 #[must_use = "streams do nothing unless polled"]
-pub struct FetchOutput(Pin<Box<dyn Stream<Item = Result<Progress, ()>> + Send>>);
+pub struct FetchOutput(Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>);
 
 impl FetchOutput {
-    pub fn new(ctx: Context, id: ManifestId) -> Self {
-        unimplemented!()
+    pub fn new(ctx: Context, id: ManifestId, substitutes: Vec<Substitute>) -> Self {
+        let fetching = report_from(move |tx| async move {
+            let installed_bytes = await!(fetch_all(&ctx, &id, substitutes, &tx))?;
+            Ok(Progress::Finished(Finished {
+                package_id: id,
+                status: FinalStatus::Downloaded,
+                installed_bytes,
+                timestamp: Utc::now(),
+            }))
+        });
+
+        FetchOutput(Box::pin(fetching))
     }
 }
 
 impl Stream for FetchOutput {
-    type Item = Result<Progress, ()>;
+    type Item = Result<Progress, StoreError>;
 
     fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
         self.0.as_mut().poll_next(waker)
     }
 }
+
+/// Fetches each substitute in turn, verifying and registering its tree before moving on.
+///
+/// Each tree is unpacked while it is being downloaded, and must match the registered hash of its
+/// output. With `Substitutes::Any`, outputs which are not registered are trusted as-is.
+///
+/// Returns the total size of the fetched outputs in the store, in bytes.
+async fn fetch_all<'a>(
+    ctx: &'a Context,
+    id: &'a ManifestId,
+    substitutes: Vec<Substitute>,
+    tx: &'a JobSender,
+) -> Result<u64, StoreError> {
+    let mut total_bytes = 0;
+    for Substitute { output, cache } in substitutes {
+        let trusted = ctx.store.registered_output_hash(&output)?;
+        if trusted.is_none() && ctx.substitutes == Substitutes::Verified {
+            let reason = format!("output `{}` has no registered hash to verify", output);
+            return Err(StoreError::PermissionDenied(reason));
+        }
+
+        let mut progress = Substituting {
+            package_id: id.clone(),
+            output: output.clone(),
+            cache,
+            downloaded_bytes: 0,
+            total_bytes: None,
+            timestamp: Utc::now(),
+        };
+
+        let mut cache = match await!(ctx.binary_caches[cache].lock().compat()) {
+            Ok(cache) => cache,
+            Err(_) => return Err(StoreError::Cache(CacheError::NotFound(output))),
+        };
+
+        let _ = tx.unbounded_send(Ok(Progress::Substituting(progress.clone())));
+
+        let chunks = cache.fetch_output(&output).inspect(|chunk| {
+            if let Ok(ref chunk) = *chunk {
+                progress.downloaded_bytes += chunk.len() as u64;
+                progress.timestamp = Utc::now();
+                let _ = tx.unbounded_send(Ok(Progress::Substituting(progress.clone())));
+            }
+        });
+        await!(ctx.store.replace_output(&output, chunks, trusted))?;
+        drop(cache);
+
+        let installed_bytes = ctx.store.output_size(&output)?;
+        total_bytes += installed_bytes;
+        let _ = tx.unbounded_send(Ok(Progress::Substituted(Substituted {
+            package_id: id.clone(),
+            output,
+            installed_bytes,
+            timestamp: Utc::now(),
+        })));
+    }
+
+    Ok(total_bytes)
+}
@@ -1,87 +1,275 @@
+use std::mem;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Poll, Waker};
 
-use deck_core::{ManifestId, Source};
-use futures_preview::compat::{Future01CompatExt, Stream01CompatExt};
+use chrono::Utc;
+use deck_core::{Hash, HashBuilder, ManifestId, Source, SourceId};
+use futures_preview::compat::{Compat01As03, Future01CompatExt, Stream01CompatExt};
 use futures_preview::future::{self, FutureExt, TryFutureExt};
-use futures_preview::stream::{self, Stream, StreamExt, TryStreamExt};
+use futures_preview::stream::{self, Stream, StreamExt};
 use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Chunk};
 
-use crate::local::context::Context;
-use crate::progress::{Blocked, Downloading, Progress};
+use crate::local::context::{Context, HttpsClient};
+use crate::local::dir::{Claim, Subscription, Writer};
+use crate::local::file::LockedFile;
+use crate::local::store_dir::SourcesDir;
+use crate::progress::{Blocked, Downloading, Progress, Reusing};
+use crate::verify::Object;
+use crate::StoreError;
+
+type ProgressStream = Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>;
 
 #[must_use = "streams do nothing unless polled"]
-pub struct FetchSource(Pin<Box<dyn Stream<Item = Result<Progress, ()>> + Send>>);
+pub struct FetchSource(Pin<Box<dyn Stream<Item = Result<Progress, StoreError>> + Send>>);
 
 impl FetchSource {
     pub fn new(ctx: Context, id: ManifestId, source: Source) -> Self {
         match source {
             Source::Git => fetch_git(ctx, id),
             Source::Path { ref path, ref hash } => unimplemented!(),
-            Source::Uri { uri, hash } => fetch_uri(ctx, id, uri, hash),
+            Source::Uri { ref uri, .. } => {
+                let expected = source.expected_id();
+                fetch_uri(ctx, id, uri.clone(), expected)
+            }
         }
     }
 
-    fn from_stream<S: Stream<Item = Result<Progress, ()>> + Send + 'static>(inner: S) -> Self {
+    fn from_stream<S: Stream<Item = Result<Progress, StoreError>> + Send + 'static>(
+        inner: S,
+    ) -> Self {
         FetchSource(Box::pin(inner))
     }
 }
 
 impl Stream for FetchSource {
-    type Item = Result<Progress, ()>;
+    type Item = Result<Progress, StoreError>;
 
     fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
         self.0.as_mut().poll_next(waker)
     }
 }
 
-fn fetch_uri(ctx: Context, id: ManifestId, uri: String, _hash: String) -> FetchSource {
+fn fetch_uri(ctx: Context, id: ManifestId, uri: String, expected: Option<SourceId>) -> FetchSource {
     let future = async move {
-        let get = ctx.client.get(uri.parse().unwrap()).compat();
-        let response = await!(get).map_err(|e| eprintln!("failed to connect to URI: {}", e))?;
+        let source_id = match expected {
+            Some(source_id) => source_id,
+            None => return Err(StoreError::InvalidSource(uri)),
+        };
+
+        let stream: ProgressStream = match await!(ctx.store.claim_source(source_id.clone()))? {
+            Claim::Existing => {
+                let progress = Progress::Blocked(Blocked {
+                    package_id: id,
+                    description: format!("source `{}` already in store", source_id),
+                    timestamp: Utc::now(),
+                });
+                Box::pin(stream::once(future::ok(progress)))
+            }
+            Claim::Wait(subscription) => follow(ctx, id, uri, source_id, subscription),
+            Claim::Write(mut writer) => match ctx.store.reuse_source(&mut writer, &source_id) {
+                Some(existing) => await!(reuse(id, source_id, existing, writer))?,
+                None => {
+                    let client = ctx.client.clone();
+                    await!(download(client, id, uri, source_id, writer))?
+                }
+            },
+        };
+
+        Ok(stream)
+    };
+
+    let stream = future
+        .unwrap_or_else(|err| Box::pin(stream::once(future::err(err))) as ProgressStream)
+        .flatten_stream();
+
+    FetchSource::from_stream(stream)
+}
+
+/// Follows the progress of another job fetching the same source.
+///
+/// If that job gives up on the source, e.g. because its build was cancelled, the fetch is taken
+/// over and performed here instead.
+fn follow(
+    ctx: Context,
+    id: ManifestId,
+    uri: String,
+    source_id: SourceId,
+    subscription: Subscription,
+) -> ProgressStream {
+    let stream = subscription
+        .map(move |item| -> ProgressStream {
+            match item {
+                Ok(progress) => {
+                    Box::pin(stream::once(future::ok(progress.for_package(id.clone()))))
+                }
+                Err(StoreError::WriteFailed(_)) => {
+                    let expected = Some(source_id.clone());
+                    Box::pin(fetch_uri(ctx.clone(), id.clone(), uri.clone(), expected))
+                }
+                Err(err) => Box::pin(stream::once(future::err(err))),
+            }
+        })
+        .flatten();
+
+    Box::pin(stream)
+}
+
+/// Adds the source `source_id`, which has been filled in from the source `existing`, to the store.
+async fn reuse(
+    id: ManifestId,
+    source_id: SourceId,
+    existing: SourceId,
+    writer: Writer<SourcesDir>,
+) -> Result<ProgressStream, StoreError> {
+    let progress = Progress::Reusing(Reusing {
+        package_id: id,
+        source: source_id,
+        existing,
+        timestamp: Utc::now(),
+    });
+
+    writer.publish(&progress);
+    await!(writer.commit())?;
+    Ok(Box::pin(stream::once(future::ok(progress))))
+}
+
+/// Downloads `uri` into the store as `source_id`.
+///
+/// Progress is published through `writer` as well, so any other job fetching the same source
+/// while the download is in progress can follow along.
+async fn download(
+    client: Arc<HttpsClient>,
+    id: ManifestId,
+    uri: String,
+    source_id: SourceId,
+    mut writer: Writer<SourcesDir>,
+) -> Result<ProgressStream, StoreError> {
+    let target = match uri.parse() {
+        Ok(target) => target,
+        Err(_) => return Err(StoreError::InvalidSource(uri)),
+    };
+
+    let get = client.get(target).compat();
+    let response = match await!(get) {
+        Ok(response) => response,
+        Err(e) => return Err(StoreError::Http(uri, e)),
+    };
+
+    if !response.status().is_success() {
+        let status = response.status().as_u16();
+        return Err(StoreError::HttpStatus(uri, status));
+    }
 
-        let len = response
-            .headers()
-            .get(CONTENT_LENGTH)
-            .and_then(|len| len.to_str().ok())
-            .and_then(|len| len.parse::<u64>().ok());
+    let len = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
 
-        let mut progress = Downloading {
-            package_id: id.clone(),
+    let file = await!(writer.path().create_file())?;
+    let download = Download {
+        chunks: response.into_body().compat(),
+        file: Some(file),
+        hasher: Hash::compute(),
+        progress: Downloading {
+            package_id: id,
             downloaded_bytes: 0,
             total_bytes: len,
-            source: uri.clone(),
+            source: uri,
+            timestamp: Utc::now(),
+        },
+        source_id,
+        writer,
+    };
+
+    let stream = stream::unfold(Some(download), |download| async move {
+        let mut download = match download {
+            Some(download) => download,
+            None => return None,
         };
 
-        let downloading = response
-            .into_body()
-            .compat()
-            .map_err(|_| ())
-            .map_ok(move |chunk| {
-                progress.downloaded_bytes += chunk.len() as u64;
-                Progress::Downloading(progress.clone())
-            });
+        match await!(download.chunks.next()) {
+            Some(Ok(chunk)) => match await!(download.write(chunk)) {
+                Ok(progress) => Some((Ok(progress), Some(download))),
+                Err(err) => Some((Err(err), None)),
+            },
+            Some(Err(e)) => {
+                let uri = download.progress.source.clone();
+                Some((Err(StoreError::Http(uri, e)), None))
+            }
+            None => Some((await!(download.finish()), None)),
+        }
+    });
 
-        let progress = Progress::Blocked(Blocked {
-            package_id: id,
-            description: format!("fetched source from `{}`", uri),
-        });
+    Ok(Box::pin(stream))
+}
 
-        let done = downloading.chain(stream::once(future::ok(progress)));
-        Ok(done)
-    };
+/// State of a source download in progress.
+struct Download {
+    chunks: Compat01As03<Body>,
+    file: Option<LockedFile>,
+    hasher: HashBuilder,
+    progress: Downloading,
+    source_id: SourceId,
+    writer: Writer<SourcesDir>,
+}
 
-    let stream = future
-        .map_ok(|stream| Box::pin(stream) as Pin<Box<dyn Stream<Item = _> + Send>>)
-        .unwrap_or_else(|err| Box::pin(stream::once(future::err(err))))
-        .flatten_stream();
+impl Download {
+    async fn write(&mut self, chunk: Chunk) -> Result<Progress, StoreError> {
+        let file = self.file.take().expect("file is only taken while writing");
+        let write = tokio::io::write_all(file, chunk).compat();
+        let (file, chunk) = await!(write)?;
+        self.file = Some(file);
 
-    FetchSource::from_stream(stream)
+        let hasher = mem::replace(&mut self.hasher, Hash::compute());
+        self.hasher = hasher.input(&chunk);
+        self.progress.downloaded_bytes += chunk.len() as u64;
+        self.progress.timestamp = Utc::now();
+
+        let progress = Progress::Downloading(self.progress.clone());
+        self.writer.publish(&progress);
+        Ok(progress)
+    }
+
+    async fn finish(self) -> Result<Progress, StoreError> {
+        let Download {
+            file,
+            hasher,
+            progress,
+            source_id,
+            writer,
+            ..
+        } = self;
+
+        drop(file);
+        let expected = *source_id.hash();
+        let found = hasher.finish();
+        if found != expected {
+            return Err(StoreError::HashMismatch {
+                object: Object::Source(source_id),
+                expected,
+                found,
+            });
+        }
+
+        let fetched = Progress::Blocked(Blocked {
+            package_id: progress.package_id,
+            description: format!("fetched source from `{}`", progress.source),
+            timestamp: Utc::now(),
+        });
+
+        writer.publish(&fetched);
+        await!(writer.commit())?;
+        Ok(fetched)
+    }
 }
 
 fn fetch_git(_ctx: Context, id: ManifestId) -> FetchSource {
     FetchSource::from_stream(stream::once(future::ok(Progress::Blocked(Blocked {
         package_id: id,
         description: "checked out repository".to_string(),
+        timestamp: Utc::now(),
     }))))
 }
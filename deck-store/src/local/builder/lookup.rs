@@ -0,0 +1,292 @@
+//! Lookup of package manifests which may be missing from the local store.
+
+use chrono::Utc;
+use deck_core::{Manifest, ManifestId};
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::sink::SinkExt;
+use futures_preview::stream::StreamExt;
+
+use crate::local::context::Context;
+use crate::progress::{LoadSource, Loading, Progress, ProgressSender};
+use crate::verify::Object;
+use crate::StoreError;
+
+/// Loads the manifest `id`, fetching it into the store first if it is missing.
+///
+/// The store itself is searched first, followed by each repository and then each remote store in
+/// order of priority. Fetched manifests are only accepted if their computed ID matches `id`, in
+/// which case they are written into the store before being returned. Every location searched is
+/// reported to `tx`, as is the progress of writing the manifest. If no location has the manifest,
+/// any failure to query them is reported in the returned error.
+pub async fn load_manifest<'a>(
+    ctx: &'a Context,
+    id: &'a ManifestId,
+    tx: &'a mut ProgressSender,
+) -> Result<Manifest, StoreError> {
+    await!(report(tx, id, LoadSource::Store));
+    if let Some(manifest) = await!(ctx.store.read_manifest(id))? {
+        return Ok(manifest);
+    }
+
+    let manifest = await!(query_sources(ctx, id, tx))?;
+    await!(install_manifest(ctx, manifest, tx))
+}
+
+/// Writes `manifest` into the store, reporting the progress of writing it to `tx`.
+pub async fn install_manifest<'a>(
+    ctx: &'a Context,
+    manifest: Manifest,
+    tx: &'a mut ProgressSender,
+) -> Result<Manifest, StoreError> {
+    let mut installing = Box::pin(ctx.store.install_manifest(manifest.clone()));
+    while let Some(progress) = await!(installing.next()) {
+        let _ = await!(tx.send(Ok(progress?)));
+    }
+
+    Ok(manifest)
+}
+
+/// Queries the repositories and then the remote stores for the manifest `id`, returning the
+/// first manifest whose computed ID matches.
+///
+/// Sources which fail to be queried or which return a mismatched manifest are skipped. If none of
+/// the sources can provide the manifest, their failures are collected in
+/// `StoreError::LookupFailed`, or `StoreError::NotFound` is returned if every source simply lacks
+/// it.
+async fn query_sources<'a>(
+    ctx: &'a Context,
+    id: &'a ManifestId,
+    tx: &'a mut ProgressSender,
+) -> Result<Manifest, StoreError> {
+    let mut errors = Vec::new();
+
+    for (index, repository) in ctx.repositories.iter().enumerate() {
+        await!(report(tx, id, LoadSource::Repository(index)));
+        let mut repository = match await!(repository.lock().compat()) {
+            Ok(repository) => repository,
+            Err(_) => {
+                errors.push(format!("repository #{} is unavailable", index));
+                continue;
+            }
+        };
+
+        // Repositories do not distinguish missing manifests from failed queries.
+        if let Ok(manifest) = await!(repository.query(id)) {
+            match check_id(manifest, id) {
+                Ok(manifest) => return Ok(manifest),
+                Err(found) => errors.push(format!("repository #{} {}", index, found)),
+            }
+        }
+    }
+
+    for (index, remote) in ctx.remotes.iter().enumerate() {
+        await!(report(tx, id, LoadSource::RemoteStore(index)));
+        let mut remote = match await!(remote.lock().compat()) {
+            Ok(remote) => remote,
+            Err(_) => {
+                errors.push(format!("remote store #{} is unavailable", index));
+                continue;
+            }
+        };
+
+        match await!(remote.query_manifest(id)) {
+            Ok(manifest) => match check_id(manifest, id) {
+                Ok(manifest) => return Ok(manifest),
+                Err(found) => errors.push(format!("remote store #{} {}", index, found)),
+            },
+            Err(StoreError::NotFound(_)) => {}
+            Err(err) => errors.push(format!("remote store #{}: {}", index, err)),
+        }
+    }
+
+    if errors.is_empty() {
+        Err(StoreError::NotFound(Object::Manifest(id.clone())))
+    } else {
+        Err(StoreError::LookupFailed {
+            manifest: id.clone(),
+            errors,
+        })
+    }
+}
+
+/// Returns `manifest` if its computed ID matches `id`, or describes the mismatch otherwise.
+fn check_id(manifest: Manifest, id: &ManifestId) -> Result<Manifest, String> {
+    let found = manifest.compute_id();
+    if found == *id {
+        Ok(manifest)
+    } else {
+        Err(format!("returned manifest `{}` instead", found))
+    }
+}
+
+async fn report<'a>(tx: &'a mut ProgressSender, id: &'a ManifestId, source: LoadSource) {
+    let progress = Progress::Loading(Loading {
+        package_id: id.clone(),
+        source,
+        timestamp: Utc::now(),
+    });
+
+    // The receiving end is only gone if the build has been abandoned, which is not our concern.
+    let _ = await!(tx.send(Ok(progress)));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error as IoError, ErrorKind};
+
+    use deck_binary_cache::{BinaryCache, BinaryCacheFuture, CacheError, OutputStream};
+    use deck_core::{OutputId, Platform};
+    use deck_repository::{Repository, RepositoryFuture};
+    use futures_preview::future::{self, FutureExt};
+    use futures_preview::stream;
+
+    use super::*;
+    use crate::local::test_util::{run, TempDir};
+    use crate::local::LocalStore;
+    use crate::progress::{self, ProgressReceiver};
+    use crate::{
+        BuildStream, CheckContents, LogOptions, LogStream, Repair, Store, StoreFuture, VerifyStream,
+    };
+
+    const HASH: &str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+    /// Repository which returns the same manifest for every query, if any.
+    #[derive(Debug)]
+    struct FixedRepository(Option<Manifest>);
+
+    impl Repository for FixedRepository {
+        fn query<'a>(&'a mut self, _: &'a ManifestId) -> RepositoryFuture<'a, Manifest> {
+            future::ready(self.0.clone().ok_or(())).boxed()
+        }
+    }
+
+    /// Remote store which fails every query.
+    #[derive(Debug)]
+    struct BrokenRemote;
+
+    fn reset() -> StoreError {
+        StoreError::Remote("connection reset".into())
+    }
+
+    fn cache_reset() -> CacheError {
+        CacheError::Io(IoError::new(ErrorKind::ConnectionReset, "connection reset"))
+    }
+
+    impl BinaryCache for BrokenRemote {
+        fn query_outputs<'a>(&'a mut self, _: &'a OutputId) -> BinaryCacheFuture<'a, bool> {
+            future::err(cache_reset()).boxed()
+        }
+
+        fn fetch_output<'a>(&'a mut self, _: &'a OutputId) -> OutputStream<'a> {
+            Box::pin(stream::once(future::err(cache_reset())))
+        }
+    }
+
+    impl Store for BrokenRemote {
+        fn supported_platforms<'a>(&'a self) -> StoreFuture<'a, Vec<Platform>> {
+            future::err(reset()).boxed()
+        }
+
+        fn query_manifest<'a>(&'a mut self, _: &'a ManifestId) -> StoreFuture<'a, Manifest> {
+            future::err(reset()).boxed()
+        }
+
+        fn build_manifest(&mut self, _: Manifest) -> BuildStream {
+            BuildStream::new(stream::once(future::err(reset())))
+        }
+
+        fn get_build_log<'a>(&'a mut self, _: &'a ManifestId, _: LogOptions) -> LogStream<'a> {
+            LogStream::new(stream::once(future::err(reset())))
+        }
+
+        fn verify<'a>(&'a mut self, _: CheckContents, _: Repair) -> VerifyStream<'a> {
+            VerifyStream::new(stream::once(future::err(reset())))
+        }
+    }
+
+    fn manifest(name: &str) -> Manifest {
+        Manifest::build(name, "1.0.0", HASH, None)
+            .finish()
+            .expect("Failed to create manifest")
+    }
+
+    /// Looks up `id` in `store`, keeping the progress receiver alive until the lookup is done.
+    fn lookup(store: LocalStore, id: ManifestId) -> (Result<Manifest, StoreError>, LocalStore) {
+        run(async move {
+            let (mut tx, _rx): (_, ProgressReceiver) = progress::progress_channel(32);
+            let loaded = await!(load_manifest(&store.context, &id, &mut tx));
+            (loaded, store)
+        })
+    }
+
+    #[test]
+    fn skips_mismatched_manifests() {
+        let temp = TempDir::new("lookup");
+        let mut store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let (foo, bar) = (manifest("foo"), manifest("bar"));
+        let id = foo.compute_id();
+
+        let store = run(async move {
+            await!(store.add_repository(FixedRepository(Some(bar)))).unwrap();
+            await!(store.add_repository(FixedRepository(Some(foo)))).unwrap();
+            store
+        });
+
+        let (loaded, store) = lookup(store, id.clone());
+        assert_eq!(loaded.expect("Failed to look up manifest").compute_id(), id);
+        assert!(
+            run(async move { await!(store.context.store.read_manifest(&id)) })
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn reports_failed_lookups() {
+        let temp = TempDir::new("lookup");
+        let mut store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let (foo, bar) = (manifest("foo"), manifest("bar"));
+        let (id, bar_id) = (foo.compute_id(), bar.compute_id());
+
+        let store = run(async move {
+            await!(store.add_repository(FixedRepository(Some(bar)))).unwrap();
+            await!(store.add_repository(FixedRepository(None))).unwrap();
+            await!(store.add_remote_store(BrokenRemote)).unwrap();
+            store
+        });
+
+        match lookup(store, id.clone()).0 {
+            Err(StoreError::LookupFailed { manifest, errors }) => {
+                assert_eq!(manifest, id);
+                assert_eq!(
+                    errors,
+                    vec![
+                        format!("repository #0 returned manifest `{}` instead", bar_id),
+                        "remote store #0: remote store error: connection reset".to_string(),
+                    ]
+                );
+            }
+            other => panic!("expected failed lookups, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_manifests_are_not_found() {
+        let temp = TempDir::new("lookup");
+        let remote_temp = TempDir::new("lookup-remote");
+        let mut store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let remote = LocalStore::open(remote_temp.path().to_owned()).expect("Failed to open store");
+        let id = manifest("foo").compute_id();
+
+        let store = run(async move {
+            await!(store.add_repository(FixedRepository(None))).unwrap();
+            await!(store.add_remote_store(remote)).unwrap();
+            store
+        });
+
+        match lookup(store, id.clone()).0 {
+            Err(StoreError::NotFound(Object::Manifest(ref missing))) => assert_eq!(*missing, id),
+            other => panic!("expected a missing manifest, got: {:?}", other),
+        }
+    }
+}
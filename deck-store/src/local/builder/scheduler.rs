@@ -0,0 +1,582 @@
+//! Resource-aware scheduling of jobs in the build graph.
+//!
+//! Every job which builds a package or downloads something must hold a slot of the corresponding
+//! `Resource` while it runs, and the number of slots of each resource is capped by `Limits`. Jobs
+//! waiting for a slot are queued per client, where each client is a single top-level build, and
+//! freed slots are handed out to the clients in turn. This keeps one large build from starving
+//! every other build sharing the same store.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Poll, Waker};
+use std::time::Duration;
+
+use chrono::Utc;
+use deck_core::ManifestId;
+use futures_preview::stream::Stream;
+
+use crate::progress::{Blocked, Progress};
+use crate::StoreError;
+
+/// Default maximum number of concurrent downloads.
+const DEFAULT_MAX_DOWNLOADS: usize = 8;
+
+/// Limits on the resources used by the jobs in a build graph.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Maximum number of packages which may be built at the same time.
+    pub max_builds: usize,
+    /// Maximum number of sources and outputs which may be downloaded at the same time.
+    pub max_downloads: usize,
+    /// Number of cores each builder may use, exposed to builders as `DECK_BUILD_CORES`.
+    pub build_cores: usize,
+    /// How long a build may take before it is killed, unless its manifest says otherwise.
+    pub build_timeout: Option<Duration>,
+    /// How long a builder may go without writing any output before it is killed, unless its
+    /// manifest says otherwise.
+    pub max_silent_time: Option<Duration>,
+}
+
+impl Limits {
+    /// Returns the number of slots of `resource`, which is always at least one.
+    fn of(&self, resource: Resource) -> usize {
+        let limit = match resource {
+            Resource::Build => self.max_builds,
+            Resource::Download => self.max_downloads,
+        };
+
+        limit.max(1)
+    }
+}
+
+impl Default for Limits {
+    /// Builds one package at a time, allowing it to use every core for as long as it needs.
+    fn default() -> Self {
+        Limits {
+            max_builds: 1,
+            max_downloads: DEFAULT_MAX_DOWNLOADS,
+            build_cores: num_cpus::get(),
+            build_timeout: None,
+            max_silent_time: None,
+        }
+    }
+}
+
+/// Kind of resource a job holds a slot of while it runs.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Resource {
+    /// Building a package from source.
+    Build,
+    /// Downloading a source or fetching outputs from a binary cache.
+    Download,
+}
+
+impl Display for Resource {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Resource::Build => write!(fmt, "build"),
+            Resource::Download => write!(fmt, "download"),
+        }
+    }
+}
+
+/// Scheduler shared by every build using the same store.
+#[derive(Clone)]
+pub struct Scheduler {
+    limits: Limits,
+    state: Arc<Mutex<State>>,
+    clients: Arc<AtomicUsize>,
+}
+
+impl Scheduler {
+    /// Creates a new `Scheduler` enforcing the given `limits`.
+    ///
+    /// Limits of zero are treated as one, so every job eventually gets to run.
+    pub fn new(limits: Limits) -> Self {
+        let state = State {
+            builds: Slots::new(limits.of(Resource::Build)),
+            downloads: Slots::new(limits.of(Resource::Download)),
+            tickets: 0,
+        };
+
+        Scheduler {
+            limits,
+            state: Arc::new(Mutex::new(state)),
+            clients: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the limits enforced by this scheduler.
+    #[inline]
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Registers a new client, whose jobs are queued separately from those of every other client.
+    pub(crate) fn client(&self) -> Client {
+        Client {
+            scheduler: self.clone(),
+            id: self.clients.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Debug for Scheduler {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct(stringify!(Scheduler))
+            .field("limits", &self.limits)
+            .finish()
+    }
+}
+
+/// Handle through which a single top-level build schedules its jobs.
+#[derive(Clone, Debug)]
+pub struct Client {
+    scheduler: Scheduler,
+    id: usize,
+}
+
+impl Client {
+    /// Runs `job` for the package `package_id` once a slot of `resource` is free, holding on to the
+    /// slot until the job completes.
+    ///
+    /// If the job has to wait, a `Progress::Blocked` explaining why is yielded first.
+    pub fn schedule<S>(&self, resource: Resource, package_id: ManifestId, job: S) -> Scheduled<S> {
+        let acquire = Acquire {
+            scheduler: self.scheduler.clone(),
+            resource,
+            client: self.id,
+            ticket: None,
+        };
+
+        Scheduled {
+            package_id,
+            resource,
+            limit: self.scheduler.limits.of(resource),
+            state: JobState::Waiting {
+                acquire,
+                job,
+                blocked: false,
+            },
+        }
+    }
+}
+
+/// Stream which runs a job once a slot of its resource is free.
+#[must_use = "streams do nothing unless polled"]
+pub struct Scheduled<S> {
+    package_id: ManifestId,
+    resource: Resource,
+    limit: usize,
+    state: JobState<S>,
+}
+
+/// Progress of a `Scheduled` job.
+enum JobState<S> {
+    Waiting {
+        acquire: Acquire,
+        job: S,
+        blocked: bool,
+    },
+    Running {
+        _permit: Permit,
+        job: S,
+    },
+    Done,
+}
+
+impl<S> Scheduled<S> {
+    fn blocked(&self) -> Progress {
+        let description = format!(
+            "waiting for a free {} slot, all {} are in use",
+            self.resource, self.limit
+        );
+
+        Progress::Blocked(Blocked {
+            package_id: self.package_id.clone(),
+            description,
+            timestamp: Utc::now(),
+        })
+    }
+}
+
+impl<S> Debug for Scheduled<S> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct(stringify!(Scheduled))
+            .field("package_id", &self.package_id)
+            .field("resource", &self.resource)
+            .finish()
+    }
+}
+
+impl<S> Stream for Scheduled<S>
+where
+    S: Stream<Item = Result<Progress, StoreError>> + Unpin,
+{
+    type Item = Result<Progress, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            match this.state {
+                JobState::Waiting {
+                    ref mut acquire,
+                    ref mut blocked,
+                    ..
+                } => match Pin::new(acquire).poll(waker) {
+                    Poll::Ready(permit) => {
+                        if let JobState::Waiting { job, .. } =
+                            mem::replace(&mut this.state, JobState::Done)
+                        {
+                            this.state = JobState::Running {
+                                _permit: permit,
+                                job,
+                            };
+                        }
+                    }
+                    Poll::Pending if !*blocked => {
+                        *blocked = true;
+                        return Poll::Ready(Some(Ok(this.blocked())));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                JobState::Running { ref mut job, .. } => match Pin::new(job).poll_next(waker) {
+                    Poll::Ready(None) => {
+                        // Frees the slot as soon as the job is done.
+                        this.state = JobState::Done;
+                        return Poll::Ready(None);
+                    }
+                    poll => return poll,
+                },
+                JobState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Future which resolves to a `Permit` once a slot of `resource` is granted to it.
+struct Acquire {
+    scheduler: Scheduler,
+    resource: Resource,
+    client: usize,
+    ticket: Option<usize>,
+}
+
+impl Acquire {
+    fn permit(&mut self) -> Permit {
+        self.ticket = None;
+        Permit {
+            scheduler: self.scheduler.clone(),
+            resource: self.resource,
+        }
+    }
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let granted = {
+            let mut state = this.scheduler.lock();
+            let ticket = match this.ticket {
+                Some(ticket) => ticket,
+                None => {
+                    let ticket = state.next_ticket();
+                    this.ticket = Some(ticket);
+                    ticket
+                }
+            };
+
+            let slots = state.slots_mut(this.resource);
+            if slots.granted.remove(&ticket) {
+                true
+            } else if !slots.is_queued(ticket) && slots.try_take(this.client) {
+                true
+            } else {
+                slots.enqueue(this.client, ticket, waker.clone());
+                false
+            }
+        };
+
+        if granted {
+            Poll::Ready(this.permit())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            let mut state = self.scheduler.lock();
+            let slots = state.slots_mut(self.resource);
+            if slots.granted.remove(&ticket) {
+                slots.release();
+            } else {
+                slots.dequeue(ticket);
+            }
+        }
+    }
+}
+
+/// Slot of a resource held by a running job, which is freed on drop.
+struct Permit {
+    scheduler: Scheduler,
+    resource: Resource,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.lock().slots_mut(self.resource).release();
+    }
+}
+
+/// Shared state of a `Scheduler`.
+struct State {
+    builds: Slots,
+    downloads: Slots,
+    tickets: usize,
+}
+
+impl State {
+    fn next_ticket(&mut self) -> usize {
+        self.tickets = self.tickets.wrapping_add(1);
+        self.tickets
+    }
+
+    fn slots_mut(&mut self, resource: Resource) -> &mut Slots {
+        match resource {
+            Resource::Build => &mut self.builds,
+            Resource::Download => &mut self.downloads,
+        }
+    }
+}
+
+/// Slots of a single resource, along with the jobs waiting for one.
+struct Slots {
+    limit: usize,
+    in_use: usize,
+    /// Tickets of waiting jobs, queued per client.
+    waiting: BTreeMap<usize, VecDeque<usize>>,
+    /// Wakers of waiting jobs, keyed by ticket.
+    wakers: HashMap<usize, Waker>,
+    /// Tickets of jobs which have been granted a slot but have not claimed it yet.
+    granted: HashSet<usize>,
+    /// Client which was last granted a slot.
+    last_client: Option<usize>,
+}
+
+impl Slots {
+    fn new(limit: usize) -> Self {
+        Slots {
+            limit,
+            in_use: 0,
+            waiting: BTreeMap::new(),
+            wakers: HashMap::new(),
+            granted: HashSet::new(),
+            last_client: None,
+        }
+    }
+
+    fn is_queued(&self, ticket: usize) -> bool {
+        self.wakers.contains_key(&ticket)
+    }
+
+    /// Takes a free slot for `client` if one is available and no other job is waiting for it.
+    fn try_take(&mut self, client: usize) -> bool {
+        if self.in_use < self.limit && self.waiting.is_empty() {
+            self.in_use += 1;
+            self.last_client = Some(client);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn enqueue(&mut self, client: usize, ticket: usize, waker: Waker) {
+        if self.wakers.insert(ticket, waker).is_none() {
+            self.waiting.entry(client).or_default().push_back(ticket);
+        }
+    }
+
+    fn dequeue(&mut self, ticket: usize) {
+        self.wakers.remove(&ticket);
+        for queue in self.waiting.values_mut() {
+            queue.retain(|&t| t != ticket);
+        }
+        self.waiting.retain(|_, queue| !queue.is_empty());
+    }
+
+    /// Frees a slot and hands it out to the next waiting job, if any.
+    fn release(&mut self) {
+        self.in_use = self.in_use.saturating_sub(1);
+        while self.in_use < self.limit {
+            match self.next_waiting() {
+                Some(ticket) => {
+                    self.in_use += 1;
+                    self.granted.insert(ticket);
+                    if let Some(waker) = self.wakers.remove(&ticket) {
+                        waker.wake();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Pops the next waiting job, taking turns between clients.
+    fn next_waiting(&mut self) -> Option<usize> {
+        let client = {
+            let mut after = self
+                .waiting
+                .keys()
+                .skip_while(|&&c| Some(c) <= self.last_client);
+            after
+                .next()
+                .or_else(|| self.waiting.keys().next())
+                .cloned()?
+        };
+
+        let ticket = self.waiting.get_mut(&client)?.pop_front();
+        if self.waiting.get(&client).map_or(false, VecDeque::is_empty) {
+            self.waiting.remove(&client);
+        }
+
+        self.last_client = Some(client);
+        ticket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_preview::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use futures_preview::stream::StreamExt;
+    use futures_preview::task::noop_waker_ref;
+
+    use super::*;
+    use crate::progress::{FinalStatus, Finished};
+
+    type Job = Scheduled<UnboundedReceiver<Result<Progress, StoreError>>>;
+
+    fn limits(max_builds: usize) -> Limits {
+        Limits {
+            max_builds,
+            max_downloads: 1,
+            build_cores: 1,
+            build_timeout: None,
+            max_silent_time: None,
+        }
+    }
+
+    /// Schedules a build which reports that it has finished as soon as it starts running, but only
+    /// completes once the returned sender is dropped.
+    fn build(client: &Client) -> (UnboundedSender<Result<Progress, StoreError>>, Job) {
+        let id: ManifestId = "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        let finished = Progress::Finished(Finished {
+            package_id: id.clone(),
+            status: FinalStatus::Built,
+            installed_bytes: 0,
+            timestamp: Utc::now(),
+        });
+
+        tx.unbounded_send(Ok(finished)).unwrap();
+        (tx, client.schedule(Resource::Build, id, rx))
+    }
+
+    fn is_blocked(job: &mut Job) -> bool {
+        match job.poll_next_unpin(noop_waker_ref()) {
+            Poll::Ready(Some(Ok(Progress::Blocked(_)))) => true,
+            Poll::Pending => false,
+            _ => panic!("job is not waiting"),
+        }
+    }
+
+    fn is_running(job: &mut Job) -> bool {
+        match job.poll_next_unpin(noop_waker_ref()) {
+            Poll::Ready(Some(Ok(Progress::Finished(_)))) => true,
+            Poll::Pending => false,
+            _ => panic!("job has already completed"),
+        }
+    }
+
+    fn complete(tx: UnboundedSender<Result<Progress, StoreError>>, mut job: Job) {
+        drop(tx);
+        match job.poll_next_unpin(noop_waker_ref()) {
+            Poll::Ready(None) => {}
+            _ => panic!("job did not complete"),
+        }
+    }
+
+    #[test]
+    fn caps_concurrent_jobs() {
+        let client = Scheduler::new(limits(2)).client();
+        let (first_tx, mut first) = build(&client);
+        let (_second_tx, mut second) = build(&client);
+        let (_third_tx, mut third) = build(&client);
+
+        assert!(is_running(&mut first));
+        assert!(is_running(&mut second));
+        assert!(is_blocked(&mut third));
+        assert!(!is_running(&mut third));
+
+        complete(first_tx, first);
+        assert!(is_running(&mut third));
+    }
+
+    #[test]
+    fn takes_turns_between_clients() {
+        let scheduler = Scheduler::new(limits(1));
+        let (greedy, fair) = (scheduler.client(), scheduler.client());
+
+        let (first_tx, mut first) = build(&greedy);
+        let (second_tx, mut second) = build(&greedy);
+        let (_third_tx, mut third) = build(&greedy);
+        let (other_tx, mut other) = build(&fair);
+
+        assert!(is_running(&mut first));
+        assert!(is_blocked(&mut second));
+        assert!(is_blocked(&mut third));
+        assert!(is_blocked(&mut other));
+
+        complete(first_tx, first);
+        assert!(!is_running(&mut second));
+        assert!(is_running(&mut other));
+
+        complete(other_tx, other);
+        assert!(!is_running(&mut third));
+        assert!(is_running(&mut second));
+
+        complete(second_tx, second);
+        assert!(is_running(&mut third));
+    }
+
+    #[test]
+    fn dropped_jobs_give_up_their_turn() {
+        let client = Scheduler::new(limits(1)).client();
+        let (first_tx, mut first) = build(&client);
+        let (_second_tx, mut second) = build(&client);
+        let (_third_tx, mut third) = build(&client);
+
+        assert!(is_running(&mut first));
+        assert!(is_blocked(&mut second));
+        assert!(is_blocked(&mut third));
+
+        drop(second);
+        complete(first_tx, first);
+        assert!(is_running(&mut third));
+    }
+}
@@ -1,20 +1,98 @@
 use std::sync::Arc;
 
+use deck_binary_cache::BinaryCache;
+use deck_repository::Repository;
+use futures_locks::Mutex;
 use hyper::{client::HttpConnector, Client};
 use hyper_tls::HttpsConnector;
 
+use super::build_users::BuildUsers;
+use super::builder::{Limits, Scheduler};
+use super::sandbox::SandboxConfig;
 use super::store_dir::StoreDir;
+use crate::Store;
 
 pub(crate) type HttpsClient = Client<HttpsConnector<HttpConnector>>;
 
+/// Binary caches to query for missing outputs, in order of priority.
+pub(crate) type BinaryCaches = Vec<Mutex<Box<dyn BinaryCache + Send>>>;
+
+/// Repositories to query for missing manifests, in order of priority.
+pub(crate) type Repositories = Vec<Mutex<Box<dyn Repository + Send>>>;
+
+/// Remote stores to query for missing manifests, in order of priority.
+pub(crate) type RemoteStores = Vec<Mutex<Box<dyn Store + Send>>>;
+
+/// Which outputs may be substituted from binary caches instead of being built.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Substitutes {
+    /// Any output offered by a binary cache is added to the store as-is.
+    Any,
+    /// Only outputs whose content hash is already registered in the store are substituted, so that
+    /// their contents can be verified before they are added. Other packages are built instead.
+    ///
+    /// Binary caches are not signed, so there is no other way to tell whether a cache can be
+    /// trusted with an output. In practice, this only allows restoring outputs whose hash was
+    /// recorded by an earlier build or a trusted import, and every new package is built.
+    Verified,
+}
+
 #[derive(Clone, Debug)]
 pub struct Context {
     pub client: Arc<HttpsClient>,
     pub store: Arc<StoreDir>,
+    pub binary_caches: Arc<BinaryCaches>,
+    pub repositories: Arc<Repositories>,
+    pub remotes: Arc<RemoteStores>,
+    pub scheduler: Scheduler,
+    pub sandbox: Option<Arc<SandboxConfig>>,
+    pub build_users: Option<BuildUsers>,
+    pub substitutes: Substitutes,
 }
 
 impl Context {
-    pub fn new(store: Arc<StoreDir>, client: Arc<HttpsClient>) -> Self {
-        Context { store, client }
+    pub fn new(
+        store: Arc<StoreDir>,
+        client: Arc<HttpsClient>,
+        binary_caches: Arc<BinaryCaches>,
+        repositories: Arc<Repositories>,
+        remotes: Arc<RemoteStores>,
+    ) -> Self {
+        Context {
+            store,
+            client,
+            binary_caches,
+            repositories,
+            remotes,
+            scheduler: Scheduler::new(Limits::default()),
+            sandbox: None,
+            build_users: None,
+            substitutes: Substitutes::Any,
+        }
+    }
+
+    /// Caps the resources used by builds according to `limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.scheduler = Scheduler::new(limits);
+        self
+    }
+
+    /// Runs every builder inside a sandbox configured by `config`.
+    pub fn with_sandbox(mut self, config: SandboxConfig) -> Self {
+        self.sandbox = Some(Arc::new(config));
+        self
+    }
+
+    /// Runs every builder as a distinct user leased from `users`, which also caps the number of
+    /// concurrent builds.
+    pub fn with_build_users(mut self, users: BuildUsers) -> Self {
+        self.build_users = Some(users);
+        self
+    }
+
+    /// Restricts which outputs may be substituted from binary caches.
+    pub fn with_substitutes(mut self, substitutes: Substitutes) -> Self {
+        self.substitutes = substitutes;
+        self
     }
 }
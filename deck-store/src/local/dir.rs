@@ -1,20 +1,67 @@
-pub use self::path::{ReadPath, WritePath};
-pub use self::state::State;
+pub use self::in_flight::Subscription;
+pub use self::path::{ReadPath, StagingPath, WritePath};
+pub use self::recovery::{recover, Recovered};
+pub use self::state::{Claim, State, Writer};
 
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::fs::{self, File as StdFile};
 use std::future::Future;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 
 use deck_core::FilesystemId;
+use futures_preview::compat::Compat01As03;
+use futures_preview::io::AsyncWriteExt;
+use futures_preview::stream::{self, Stream, StreamExt};
+use tokio::io::AsyncWrite;
 
+use super::archive;
+use crate::StoreError;
+
+mod in_flight;
+mod lock;
 mod path;
+mod recovery;
 mod state;
 
 // NOTE: All this noise has been to work fine with a simple `async fn`, with no need for associated
 // types, this type alias, or `Pin<Box<_>>`. Replace _immediately_ once `async fn` in traits is
 // stabilized in Rust.
 
-pub type DirFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, ()>> + Send + 'a>>;
+pub type DirFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StoreError>> + Send + 'a>>;
+pub type WriteStream<'a, T> =
+    Pin<Box<dyn Stream<Item = Result<WriteState<T>, StoreError>> + Send + 'a>>;
+
+/// Size of the chunks copied by `copy_chunked()`.
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of bytes written to the store so far.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WriteProgress {
+    pub written_bytes: u64,
+    pub total_bytes: Option<u64>,
+}
+
+/// Item yielded by a `WriteStream`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WriteState<T> {
+    /// The write is still in progress.
+    Progress(WriteProgress),
+    /// The write has completed, producing the given value. This is always the last item.
+    Done(T),
+}
+
+impl<T> WriteState<T> {
+    /// Maps the value of a completed write, leaving progress untouched.
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> WriteState<U> {
+        match self {
+            WriteState::Progress(progress) => WriteState::Progress(progress),
+            WriteState::Done(value) => WriteState::Done(f(value)),
+        }
+    }
+}
 
 pub trait Directory: Debug + Send + Sync {
     type Id: FilesystemId;
@@ -30,5 +77,247 @@ pub trait Directory: Debug + Send + Sync {
         &'a self,
         path: &'a mut WritePath,
         input: Self::Input,
-    ) -> DirFuture<'a, Self::Output>;
+    ) -> WriteStream<'a, Self::Output>;
+}
+
+/// Writes each chunk yielded by `chunks` to `file` as soon as it arrives, reporting the total
+/// number of bytes written after each one.
+///
+/// `total_bytes` is the expected size of the file, if known ahead of time.
+pub(crate) fn write_chunked<W, S>(
+    file: W,
+    chunks: S,
+    total_bytes: Option<u64>,
+) -> impl Stream<Item = Result<WriteProgress, StoreError>> + Send
+where
+    W: AsyncWrite + Send,
+    S: Stream<Item = Result<Vec<u8>, StoreError>> + Send + Unpin,
+{
+    let file = Some(Compat01As03::new(file));
+    let progress = WriteProgress {
+        written_bytes: 0,
+        total_bytes,
+    };
+
+    stream::unfold(
+        (file, chunks, progress),
+        |(file, mut chunks, progress)| async move {
+            let mut file = match file {
+                Some(file) => file,
+                None => return None,
+            };
+
+            let chunk = match await!(chunks.next()) {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => return Some((Err(e), (None, chunks, progress))),
+                None => return None,
+            };
+
+            if let Err(e) = await!(file.write_all(&chunk)) {
+                return Some((Err(StoreError::Io(e)), (None, chunks, progress)));
+            }
+
+            let progress = WriteProgress {
+                written_bytes: progress.written_bytes + chunk.len() as u64,
+                total_bytes,
+            };
+
+            Some((Ok(progress), (Some(file), chunks, progress)))
+        },
+    )
+}
+
+/// Copies the file or directory tree located at `source` to `dest`, which must not exist yet.
+///
+/// Files are copied one chunk at a time, reporting the total number of bytes copied so far after
+/// each chunk. Executable bits and symlinks are preserved, so the copy has the same content hash
+/// as `source`.
+pub(crate) fn copy_chunked(
+    source: PathBuf,
+    dest: PathBuf,
+) -> impl Stream<Item = Result<WriteProgress, StoreError>> + Send {
+    let copy = TreeCopy::new(&source, dest).map_err(StoreError::at(&source));
+    stream::unfold(Some(copy), |copy| async move {
+        let mut copy = match copy? {
+            Ok(copy) => copy,
+            Err(err) => return Some((Err(err), None)),
+        };
+
+        match copy.next_chunk() {
+            Ok(Some(progress)) => Some((Ok(progress), Some(Ok(copy)))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+}
+
+/// State of a file or directory tree being copied by `copy_chunked()`.
+struct TreeCopy {
+    /// Entries left to copy, as pairs of source and destination paths, in the order they are
+    /// created.
+    entries: VecDeque<(PathBuf, PathBuf)>,
+    /// File currently being copied, along with the path of its destination.
+    current: Option<(StdFile, StdFile, PathBuf)>,
+    buf: Vec<u8>,
+    progress: WriteProgress,
+}
+
+impl TreeCopy {
+    fn new(source: &Path, dest: PathBuf) -> io::Result<Self> {
+        let mut entries = VecDeque::new();
+        list_entries(source.to_owned(), dest, &mut entries)?;
+
+        Ok(TreeCopy {
+            entries,
+            current: None,
+            buf: vec![0; WRITE_CHUNK_SIZE],
+            progress: WriteProgress {
+                written_bytes: 0,
+                total_bytes: Some(archive::tree_size(source)?),
+            },
+        })
+    }
+
+    /// Copies the next chunk of the tree, creating any directories and symlinks which come before
+    /// it. Returns `Ok(None)` once the whole tree has been copied.
+    fn next_chunk(&mut self) -> Result<Option<WriteProgress>, StoreError> {
+        loop {
+            if let Some((ref mut src, ref mut dst, ref path)) = self.current {
+                let len = src.read(&mut self.buf).map_err(StoreError::at(path))?;
+                if len > 0 {
+                    dst.write_all(&self.buf[..len])
+                        .map_err(StoreError::at(path))?;
+                    self.progress.written_bytes += len as u64;
+                    return Ok(Some(self.progress));
+                }
+            }
+
+            self.current = None;
+            let (source, dest) = match self.entries.pop_front() {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            let metadata = fs::symlink_metadata(&source).map_err(StoreError::at(&source))?;
+            let file_type = metadata.file_type();
+            if file_type.is_dir() {
+                fs::create_dir(&dest).map_err(StoreError::at(&dest))?;
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(&source).map_err(StoreError::at(&source))?;
+                let target = target.to_str().ok_or_else(|| {
+                    let error =
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid symlink target");
+                    StoreError::Path(source.clone(), error)
+                })?;
+                archive::symlink(target, &dest).map_err(StoreError::at(&dest))?;
+            } else {
+                let src = StdFile::open(&source).map_err(StoreError::at(&source))?;
+                let dst = StdFile::create(&dest).map_err(StoreError::at(&dest))?;
+                let permissions = metadata.permissions();
+                fs::set_permissions(&dest, permissions).map_err(StoreError::at(&dest))?;
+                self.current = Some((src, dst, dest));
+            }
+        }
+    }
+}
+
+/// Lists `source` and everything below it in sorted order, along with their paths under `dest`.
+fn list_entries(
+    source: PathBuf,
+    dest: PathBuf,
+    entries: &mut VecDeque<(PathBuf, PathBuf)>,
+) -> io::Result<()> {
+    let is_dir = fs::symlink_metadata(&source)?.is_dir();
+    entries.push_back((source.clone(), dest.clone()));
+
+    if is_dir {
+        let mut names = fs::read_dir(&source)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+
+        for name in names {
+            list_entries(source.join(&name), dest.join(&name), entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_preview::compat::Future01CompatExt;
+    use futures_preview::stream::TryStreamExt;
+
+    use crate::local::test_util::{run, TempDir};
+
+    #[test]
+    fn written_chunks_are_reported_as_they_arrive() {
+        let temp = TempDir::new("write-chunked");
+        let path = temp.path().join("file");
+        let chunks: Vec<Result<_, StoreError>> = vec![Ok(vec![1; 3]), Ok(vec![2; 5])];
+
+        let target = path.clone();
+        let progress = run(async move {
+            let file = await!(tokio::fs::File::create(target).compat()).unwrap();
+            let writing = write_chunked(file, stream::iter(chunks), Some(8));
+            await!(writing.try_collect::<Vec<_>>())
+        });
+
+        let written: Vec<_> = progress
+            .expect("Failed to write chunks")
+            .into_iter()
+            .map(|p| (p.written_bytes, p.total_bytes))
+            .collect();
+        assert_eq!(written, vec![(3, Some(8)), (8, Some(8))]);
+        assert_eq!(fs::read(&path).unwrap(), [1, 1, 1, 2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn failed_chunks_end_the_write() {
+        let temp = TempDir::new("write-chunked-error");
+        let path = temp.path().join("file");
+        let error = io::Error::new(io::ErrorKind::Other, "connection reset");
+        let chunks = vec![Ok(vec![1; 3]), Err(StoreError::Io(error)), Ok(vec![2; 5])];
+
+        let progress = run(async move {
+            let file = await!(tokio::fs::File::create(path).compat()).unwrap();
+            let writing = write_chunked(file, stream::iter(chunks), None);
+            await!(writing.collect::<Vec<_>>())
+        });
+
+        match progress.as_slice() {
+            [Ok(_), Err(StoreError::Io(_))] => {}
+            other => panic!("expected write to end at the error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn copied_trees_are_streamed_chunk_by_chunk() {
+        let temp = TempDir::new("copy-chunked");
+        let source = temp.path().join("source");
+        fs::create_dir_all(source.join("bin")).unwrap();
+        fs::write(source.join("big"), vec![7; WRITE_CHUNK_SIZE * 2 + 1]).unwrap();
+        fs::write(source.join("bin").join("small"), b"small").unwrap();
+        archive::symlink("big", &source.join("link")).unwrap();
+
+        let dest = temp.path().join("dest");
+        let copying = copy_chunked(source.clone(), dest.clone());
+        let progress = run(copying.try_collect::<Vec<_>>()).expect("Failed to copy tree");
+
+        let total = WRITE_CHUNK_SIZE as u64 * 2 + 1 + 5;
+        assert_eq!(progress.len(), 4);
+        assert!(progress.iter().all(|p| p.total_bytes == Some(total)));
+        assert!(progress
+            .windows(2)
+            .all(|w| w[0].written_bytes < w[1].written_bytes));
+        assert_eq!(progress.last().unwrap().written_bytes, total);
+
+        assert_eq!(
+            archive::hash_path(&dest).unwrap(),
+            archive::hash_path(&source).unwrap()
+        );
+    }
 }
@@ -0,0 +1,246 @@
+//! In-process registry of objects which are currently being written to the store.
+//!
+//! When several jobs in the same process request the same object at once, only the first one
+//! (the leader) actually writes it. Every other job (a follower) subscribes to the progress of the
+//! leader instead and is notified once the write has completed. Coordination with other processes
+//! is left to the lock files, which the leader acquires as usual.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+use futures_preview::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_preview::stream::Stream;
+
+use crate::progress::Progress;
+use crate::StoreError;
+
+type Writes<K> = Arc<Mutex<HashMap<K, Arc<Mutex<Broadcast>>>>>;
+
+/// Registry of in-flight writes, keyed by object ID.
+#[derive(Debug)]
+pub struct InFlight<K: Eq + Hash> {
+    writes: Writes<K>,
+}
+
+impl<K: Clone + Debug + Display + Eq + Hash> InFlight<K> {
+    pub fn new() -> Self {
+        InFlight {
+            writes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers a new write of `key`, or subscribes to the write of `key` already in progress.
+    pub fn claim(&self, key: K) -> Claimed<K> {
+        let mut writes = self.writes.lock().expect("in-flight registry was poisoned");
+
+        if let Some(broadcast) = writes.get(&key) {
+            return Claimed::Follower(Subscription::new(broadcast));
+        }
+
+        let broadcast = Arc::new(Mutex::new(Broadcast::default()));
+        writes.insert(key.clone(), broadcast.clone());
+
+        Claimed::Leader(Publisher {
+            key,
+            writes: self.writes.clone(),
+            broadcast,
+            finished: false,
+        })
+    }
+}
+
+impl<K: Clone + Debug + Display + Eq + Hash> Default for InFlight<K> {
+    fn default() -> Self {
+        InFlight::new()
+    }
+}
+
+/// Result of claiming an in-flight write with `InFlight::claim()`.
+#[derive(Debug)]
+pub enum Claimed<K: Clone + Debug + Display + Eq + Hash> {
+    /// No write was in progress, so the caller must perform it.
+    Leader(Publisher<K>),
+    /// Another job is already performing the write.
+    Follower(Subscription),
+}
+
+#[derive(Debug, Default)]
+struct Broadcast {
+    latest: Option<Progress>,
+    subscribers: Vec<UnboundedSender<Result<Progress, StoreError>>>,
+}
+
+/// Handle held by the leader of an in-flight write.
+///
+/// Dropping the `Publisher` without calling `Publisher::finish()` reports the write as failed to
+/// all subscribers.
+#[derive(Debug)]
+pub struct Publisher<K: Clone + Debug + Display + Eq + Hash> {
+    key: K,
+    writes: Writes<K>,
+    broadcast: Arc<Mutex<Broadcast>>,
+    finished: bool,
+}
+
+impl<K: Clone + Debug + Display + Eq + Hash> Publisher<K> {
+    /// Forwards `progress` to every subscriber.
+    pub fn publish(&self, progress: &Progress) {
+        let mut broadcast = self.broadcast.lock().expect("in-flight write was poisoned");
+        broadcast.latest = Some(progress.clone());
+        broadcast
+            .subscribers
+            .retain(|tx| tx.unbounded_send(Ok(progress.clone())).is_ok());
+    }
+
+    /// Marks the write as completed successfully, ending all subscriptions.
+    pub fn finish(mut self) {
+        self.close(true);
+    }
+
+    fn close(&mut self, succeeded: bool) {
+        self.finished = true;
+
+        {
+            let mut writes = self.writes.lock().expect("in-flight registry was poisoned");
+            let is_ours = writes
+                .get(&self.key)
+                .map(|current| Arc::ptr_eq(current, &self.broadcast))
+                .unwrap_or(false);
+
+            if is_ours {
+                writes.remove(&self.key);
+            }
+        }
+
+        let mut broadcast = self.broadcast.lock().expect("in-flight write was poisoned");
+        for tx in broadcast.subscribers.drain(..) {
+            if !succeeded {
+                let error = StoreError::WriteFailed(self.key.to_string());
+                let _ = tx.unbounded_send(Err(error));
+            }
+        }
+    }
+}
+
+impl<K: Clone + Debug + Display + Eq + Hash> Drop for Publisher<K> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.close(false);
+        }
+    }
+}
+
+/// Stream of progress reported by the leader of an in-flight write.
+///
+/// The most recent progress is replayed upon subscribing. The stream ends once the write has
+/// completed successfully, or yields an error if the leader failed.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Subscription(UnboundedReceiver<Result<Progress, StoreError>>);
+
+impl Subscription {
+    fn new(broadcast: &Arc<Mutex<Broadcast>>) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let mut broadcast = broadcast.lock().expect("in-flight write was poisoned");
+
+        if let Some(ref latest) = broadcast.latest {
+            let _ = tx.unbounded_send(Ok(latest.clone()));
+        }
+
+        broadcast.subscribers.push(tx);
+        Subscription(rx)
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Progress, StoreError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(waker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use deck_core::ManifestId;
+    use futures_preview::executor::block_on;
+    use futures_preview::stream::StreamExt;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::progress::{Blocked, Progress};
+
+    fn progress(description: &str) -> Progress {
+        let id: ManifestId = "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .unwrap();
+        Progress::Blocked(Blocked {
+            package_id: id,
+            description: description.to_string(),
+            timestamp: Utc::now(),
+        })
+    }
+
+    fn descriptions(items: Vec<Result<Progress, StoreError>>) -> Vec<Result<String, String>> {
+        items
+            .into_iter()
+            .map(|item| match item {
+                Ok(Progress::Blocked(blocked)) => Ok(blocked.description),
+                Ok(_) => panic!("unexpected progress"),
+                Err(err) => Err(err.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn followers_share_progress_of_leader() {
+        let in_flight = InFlight::new();
+        let leader = match in_flight.claim("foo") {
+            Claimed::Leader(publisher) => publisher,
+            Claimed::Follower(_) => panic!("first claim must lead"),
+        };
+
+        leader.publish(&progress("first"));
+        let early = match in_flight.claim("foo") {
+            Claimed::Follower(subscription) => subscription,
+            Claimed::Leader(_) => panic!("second claim must follow"),
+        };
+
+        leader.publish(&progress("second"));
+        leader.finish();
+
+        let items = block_on(early.collect::<Vec<_>>());
+        assert_eq!(
+            descriptions(items),
+            vec![Ok("first".to_string()), Ok("second".to_string())]
+        );
+
+        match in_flight.claim("foo") {
+            Claimed::Leader(_) => {}
+            Claimed::Follower(_) => panic!("finished writes must be removed"),
+        }
+    }
+
+    #[test]
+    fn followers_are_notified_of_failure() {
+        let in_flight = InFlight::new();
+        let leader = in_flight.claim("foo");
+        let follower = match in_flight.claim("foo") {
+            Claimed::Follower(subscription) => subscription,
+            Claimed::Leader(_) => panic!("second claim must follow"),
+        };
+
+        drop(leader);
+
+        let items = block_on(follower.collect::<Vec<_>>());
+        assert_eq!(
+            descriptions(items),
+            vec![Err("concurrent write of `foo` failed".to_string())]
+        );
+    }
+}
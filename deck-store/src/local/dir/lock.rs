@@ -0,0 +1,135 @@
+//! Ownership information recorded in lock files.
+//!
+//! Lock files are guarded with advisory file locks, which the operating system releases as soon
+//! as the owning process exits. In addition, every lock file records the process ID and hostname
+//! of its owner along with the time it was acquired, which allows telling apart a lock whose owner
+//! has crashed from one held over a network filesystem where advisory locks are unreliable.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use std::process;
+
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+/// Marker appended to a lock file by its owner right before the lock is released.
+pub const MARK_LOCK_AS_STALE: &[u8] = b"stale";
+
+/// Process which acquired a lock file.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub hostname: String,
+    pub started: DateTime<Utc>,
+}
+
+impl LockOwner {
+    /// Returns the `LockOwner` describing the current process.
+    pub fn current() -> Self {
+        LockOwner {
+            pid: process::id(),
+            hostname: current_hostname(),
+            started: Utc::now(),
+        }
+    }
+
+    /// Serializes the owner in the format written to lock files.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        toml::to_string(self).unwrap_or_default().into_bytes()
+    }
+
+    /// Returns whether the owner is known to no longer be running.
+    ///
+    /// Only processes on the current host can be checked. Owners on any other host are assumed to
+    /// still be alive.
+    pub fn is_dead(&self) -> bool {
+        self.hostname == current_hostname() && !is_process_alive(self.pid)
+    }
+}
+
+/// Lock file which has been successfully locked by the current process.
+#[derive(Debug)]
+pub struct AcquiredLock {
+    file: File,
+    contents: Vec<u8>,
+}
+
+impl AcquiredLock {
+    /// Attempts to lock the lock file at `path` without blocking, creating it if it doesn't exist.
+    ///
+    /// Returns `Ok(None)` if the lock is currently held by someone else.
+    pub fn try_acquire(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+
+        match file.try_lock_exclusive() {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(Some(AcquiredLock { file, contents }))
+    }
+
+    /// Returns whether the previous owner of the lock has abandoned it.
+    ///
+    /// A lock is considered abandoned if it is empty, was explicitly marked as stale by its owner,
+    /// cannot be parsed, or belongs to a process on this host which is no longer running.
+    pub fn is_abandoned(&self) -> bool {
+        if self.contents.is_empty() || self.contents.ends_with(MARK_LOCK_AS_STALE) {
+            return true;
+        }
+
+        std::str::from_utf8(&self.contents)
+            .ok()
+            .and_then(|text| toml::from_str::<LockOwner>(text).ok())
+            .map(|owner| owner.is_dead())
+            .unwrap_or(true)
+    }
+
+    /// Deletes the lock file at `path` and releases the lock.
+    ///
+    /// Any process waiting on the deleted lock file will notice that it has been replaced and
+    /// try again.
+    pub fn remove(self, path: &Path) -> io::Result<()> {
+        let removed = match std::fs::remove_file(path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+
+        let _ = self.file.unlock();
+        removed
+    }
+}
+
+fn current_hostname() -> String {
+    hostname::get_hostname().unwrap_or_default()
+}
+
+#[cfg(target_os = "linux")]
+fn is_process_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_process_alive(pid: u32) -> bool {
+    process::Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(true)
+}
+
+#[cfg(not(unix))]
+fn is_process_alive(_pid: u32) -> bool {
+    true
+}
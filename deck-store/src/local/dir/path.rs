@@ -1,17 +1,27 @@
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Display, Path, PathBuf};
 
 use deck_core::FilesystemId;
+use filetime::FileTime;
 use futures::future::poll_fn;
 use futures_preview::compat::Future01CompatExt;
 use futures_preview::future::{FutureExt, TryFutureExt};
 use tokio::fs::{self, File, OpenOptions};
 
+use super::lock::{LockOwner, MARK_LOCK_AS_STALE};
 use crate::local::file::{FileFutureExt, LockedFile};
 use crate::local::{TEMP_DIR_NAME, VAR_DIR_NAME};
+use crate::StoreError;
 
-const LOCK_FILE_EXT: &str = "lock";
-const MARK_LOCK_AS_STALE: &[u8] = "stale".as_bytes();
+pub(super) const LOCK_FILE_EXT: &str = "lock";
+
+/// Returns the path of the lock file guarding the entry `name` in `tmp`.
+pub(super) fn lock_path_for<P: AsRef<Path>>(prefix: &Path, name: P) -> PathBuf {
+    let mut file_name = name.as_ref().as_os_str().to_owned();
+    file_name.push(".");
+    file_name.push(LOCK_FILE_EXT);
+    prefix.join(VAR_DIR_NAME).join(file_name)
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum LockedPath {
@@ -29,18 +39,16 @@ pub struct DirectoryPath<I> {
 
 impl<I: FilesystemId> DirectoryPath<I> {
     pub fn new<P: AsRef<Path>, S: AsRef<str>>(prefix: P, directory: S, id: I) -> Self {
-        let mut lock_path = prefix.as_ref().join(VAR_DIR_NAME).join(id.to_path());
-        lock_path.set_extension(LOCK_FILE_EXT);
-
+        let prefix = prefix.as_ref();
         DirectoryPath {
-            root: prefix.as_ref().join(directory.as_ref()).join(id.to_path()),
-            temp_path: prefix.as_ref().join(TEMP_DIR_NAME).join(id.to_path()),
-            lock_path,
+            root: prefix.join(directory.as_ref()).join(id.to_path()),
+            temp_path: prefix.join(TEMP_DIR_NAME).join(id.to_path()),
+            lock_path: lock_path_for(prefix, id.to_path()),
             id,
         }
     }
 
-    pub async fn lock_reading(self) -> Result<Option<ReadPath>, ()> {
+    pub async fn lock_reading(self) -> Result<Option<ReadPath>, StoreError> {
         if self.root.exists() {
             Ok(Some(ReadPath::new(self.root, self.id, None)))
         } else {
@@ -53,7 +61,7 @@ impl<I: FilesystemId> DirectoryPath<I> {
         }
     }
 
-    pub async fn lock_writing(self) -> Result<LockedPath, ()> {
+    pub async fn lock_writing(self) -> Result<LockedPath, StoreError> {
         if self.root.exists() {
             let should_read = ReadPath::new(self.root, self.id, None);
             Ok(LockedPath::ReadExisting(should_read))
@@ -103,16 +111,31 @@ impl WritePath {
         self.temp_path.display()
     }
 
-    pub async fn create_file(&mut self) -> Result<LockedFile, ()> {
+    /// Returns the path this object is moved to once it has been written.
+    #[inline]
+    pub fn final_path(&self) -> &Path {
+        &self.final_path
+    }
+
+    pub async fn create_file(&mut self) -> Result<LockedFile, StoreError> {
         await!(File::create(self.temp_path.clone())
             .lock_exclusive()
             .compat()
             .boxed()
-            .map_err(|_| ()))
+            .map_err(StoreError::at(&self.temp_path)))
+    }
+
+    pub fn copy_from<P: AsRef<Path>>(&mut self, source: P) -> Result<u64, StoreError> {
+        std::fs::copy(source, &self.temp_path).map_err(StoreError::at(&self.temp_path))
     }
 
-    pub fn copy_from<P: AsRef<Path>>(&mut self, source: P) -> Result<u64, ()> {
-        std::fs::copy(source, self.temp_path.clone()).map_err(|_| ())
+    /// Hard-links the file at `source` into this path, falling back to copying it if linking is
+    /// not possible, e.g. because `source` resides on another filesystem.
+    pub fn link_or_copy_from<P: AsRef<Path>>(&mut self, source: P) -> Result<(), StoreError> {
+        let source = source.as_ref();
+        std::fs::hard_link(source, &self.temp_path)
+            .or_else(|_| std::fs::copy(source, &self.temp_path).map(|_| ()))
+            .map_err(StoreError::at(&self.temp_path))
     }
 
     pub fn to_read_only(&self) -> ReadPath {
@@ -123,12 +146,11 @@ impl WritePath {
         }
     }
 
-    pub async fn normalize_and_rename(self) -> Result<(), ()> {
+    pub async fn normalize_and_rename(self) -> Result<(), StoreError> {
         if self.temp_path.exists() {
-            // TODO: Need to normalize permissions here.
-            await!(fs::rename(self.temp_path, self.final_path)
-                .compat()
-                .map_err(|_| ()))?;
+            normalize(&self.temp_path).map_err(StoreError::at(&self.temp_path))?;
+            let error = StoreError::at(&self.final_path);
+            await!(fs::rename(self.temp_path, self.final_path).compat()).map_err(error)?;
         }
 
         Ok(())
@@ -166,12 +188,12 @@ impl ReadPath {
         self.path.exists()
     }
 
-    pub async fn open_file(&self) -> Result<LockedFile, ()> {
+    pub async fn open_file(&self) -> Result<LockedFile, StoreError> {
         await!(File::open(self.path.clone())
             .lock_shared()
             .compat()
             .boxed()
-            .map_err(|_| ()))
+            .map_err(StoreError::at(&self.path)))
     }
 }
 
@@ -181,6 +203,44 @@ impl Drop for ReadPath {
     }
 }
 
+/// Private scratch path in `tmp`, which is removed when dropped.
+///
+/// The path is guarded by a lock file for as long as it exists, so it is never mistaken for an
+/// abandoned entry during crash recovery.
+#[derive(Debug)]
+pub struct StagingPath {
+    path: PathBuf,
+    _guard: LockFileGuard,
+}
+
+impl StagingPath {
+    /// Locks a new staging path named `name` in the `tmp` directory of the store at `prefix`.
+    ///
+    /// The path itself is not created.
+    pub async fn new<'a>(prefix: &'a Path, name: &'a str) -> Result<Self, StoreError> {
+        let guard = await!(LockFileGuard::new(lock_path_for(prefix, name)))?;
+        Ok(StagingPath {
+            path: prefix.join(TEMP_DIR_NAME).join(name),
+            _guard: guard,
+        })
+    }
+
+    #[inline]
+    pub fn as_path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingPath {
+    fn drop(&mut self) {
+        if self.path.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.path);
+        } else {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
 #[derive(Debug)]
 struct LockFileGuard {
     file: LockedFile,
@@ -188,31 +248,46 @@ struct LockFileGuard {
 }
 
 impl LockFileGuard {
-    async fn new(path: PathBuf) -> Result<Self, ()> {
-        let opening = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path.clone())
-            .lock_exclusive()
-            .compat()
-            .boxed()
-            .map_err(|_| ());
+    async fn new(path: PathBuf) -> Result<Self, StoreError> {
+        loop {
+            let opening = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(path.clone())
+                .lock_exclusive()
+                .compat()
+                .boxed()
+                .map_err(StoreError::at(&path));
 
-        let file = await!(opening)?;
-        let (mut file, metadata) = await!(file.metadata().compat().map_err(|_| ()))?;
+            let file = await!(opening)?;
+            let metadata = file.metadata().compat().map_err(StoreError::at(&path));
+            let (mut file, metadata) = await!(metadata)?;
 
-        if !metadata.len() == 0 {
-            await!(poll_fn(|| file.poll_set_len(0)).compat()).map_err(|_| ())?;
-        }
+            // The previous owner deletes the lock file right before releasing it, so we may have
+            // locked a file which is no longer reachable. If so, try again with the new one.
+            if !is_same_file(&metadata, &path) {
+                continue;
+            }
 
-        Ok(LockFileGuard { file, path })
+            if metadata.len() != 0 {
+                let truncating = poll_fn(|| file.poll_set_len(0)).compat();
+                await!(truncating).map_err(StoreError::at(&path))?;
+            }
+
+            file.write_all(&LockOwner::current().to_bytes())
+                .and_then(|_| file.flush())
+                .map_err(StoreError::at(&path))?;
+
+            return Ok(LockFileGuard { file, path });
+        }
     }
 }
 
 impl Drop for LockFileGuard {
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.path);
+        // Mark the lock as stale first, in case the lock file cannot be removed.
         let _ = self.file.write_all(MARK_LOCK_AS_STALE);
+        let _ = std::fs::remove_file(&self.path);
     }
 }
 
@@ -223,3 +298,108 @@ impl PartialEq for LockFileGuard {
         self.path == other.path
     }
 }
+
+/// Modification time given to every normalized store path, one second past the Unix epoch.
+const NORMALIZED_MTIME: i64 = 1;
+
+/// Strips write permissions and timestamps from the file tree at `path`.
+///
+/// Files become read-only, keeping only whether they are executable, since that is the only part
+/// of their mode which is covered by the content hash. Directories remain writable by the owner so
+/// that objects can still be removed from the store. Symlinks are left untouched.
+fn normalize(path: &Path) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        return Ok(());
+    } else if file_type.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            normalize(&entry?.path())?;
+        }
+    }
+
+    set_normalized_mode(path, &metadata)?;
+    let mtime = FileTime::from_unix_time(NORMALIZED_MTIME, 0);
+    filetime::set_file_times(path, mtime, mtime)
+}
+
+#[cfg(unix)]
+fn set_normalized_mode(path: &Path, metadata: &std::fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = if metadata.is_dir() {
+        0o755
+    } else if metadata.permissions().mode() & 0o111 != 0 {
+        0o555
+    } else {
+        0o444
+    };
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_normalized_mode(path: &Path, metadata: &std::fs::Metadata) -> io::Result<()> {
+    let mut perms = metadata.permissions();
+    perms.set_readonly(!metadata.is_dir());
+    std::fs::set_permissions(path, perms)
+}
+
+#[cfg(unix)]
+fn is_same_file(metadata: &std::fs::Metadata, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::metadata(path)
+        .map(|current| current.dev() == metadata.dev() && current.ino() == metadata.ino())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_metadata: &std::fs::Metadata, path: &Path) -> bool {
+    path.exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use deck_core::Hash;
+
+    use super::*;
+    use crate::local::archive;
+
+    #[test]
+    #[cfg(unix)]
+    fn normalize_preserves_hash() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let root = env::temp_dir().join(format!("deck-normalize-test-{}", Hash::random()));
+        fs::create_dir_all(root.join("bin")).expect("Failed to create dir");
+        fs::write(root.join("bin/hello"), b"#!/bin/sh\necho hello\n").expect("Failed to write");
+        fs::write(root.join("README"), b"Hello, world!\n").expect("Failed to write");
+        let perms = fs::Permissions::from_mode(0o775);
+        fs::set_permissions(root.join("bin/hello"), perms).expect("Failed to set permissions");
+        symlink("bin/hello", root.join("hello")).expect("Failed to create symlink");
+
+        let before = archive::hash_path(&root).expect("Failed to hash tree");
+        normalize(&root).expect("Failed to normalize tree");
+        let after = archive::hash_path(&root).expect("Failed to hash tree");
+        assert_eq!(before, after);
+
+        let mode = |path: &str| {
+            let metadata = fs::metadata(root.join(path)).expect("Failed to read metadata");
+            let mtime = FileTime::from_last_modification_time(&metadata);
+            assert_eq!(mtime.unix_seconds(), NORMALIZED_MTIME);
+            metadata.permissions().mode() & 0o777
+        };
+
+        assert_eq!(mode("bin/hello"), 0o555);
+        assert_eq!(mode("README"), 0o444);
+        assert_eq!(mode("bin"), 0o755);
+        assert!(fs::symlink_metadata(root.join("hello")).is_ok());
+
+        fs::remove_dir_all(&root).expect("Failed to clean up");
+    }
+}
@@ -0,0 +1,220 @@
+//! Cleanup of state left behind by crashed writers.
+//!
+//! Every entry in `tmp` is owned by the process holding the lock file of the same name in `var`.
+//! If that process crashes, its half-written entry and lock file are left behind forever. This
+//! module finds such entries and removes them, while leaving alone anything whose owner might
+//! still be running.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use super::lock::AcquiredLock;
+use super::path::{lock_path_for, LOCK_FILE_EXT};
+use crate::local::{TEMP_DIR_NAME, VAR_DIR_NAME};
+use crate::StoreError;
+
+/// Summary of the state cleaned up by `recover()`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Recovered {
+    /// Abandoned entries in `tmp` which have been removed.
+    pub temp_entries: Vec<PathBuf>,
+    /// Stale lock files in `var` which have been removed.
+    pub stale_locks: Vec<PathBuf>,
+}
+
+/// Removes abandoned temporary entries and stale lock files from the store at `prefix`.
+///
+/// Recovery is best-effort. Entries which cannot be inspected or removed are skipped and left as
+/// they are.
+pub fn recover(prefix: &Path) -> Result<Recovered, StoreError> {
+    let mut recovered = Recovered::default();
+
+    for name in entry_names(&prefix.join(TEMP_DIR_NAME))? {
+        let temp_path = prefix.join(TEMP_DIR_NAME).join(&name);
+        let lock_path = lock_path_for(prefix, &name);
+        let lock_existed = lock_path.exists();
+
+        let lock = match AcquiredLock::try_acquire(&lock_path) {
+            Ok(Some(lock)) => lock,
+            Ok(None) | Err(_) => continue,
+        };
+
+        if !lock.is_abandoned() {
+            continue;
+        }
+
+        if remove_entry(&temp_path).is_ok() {
+            recovered.temp_entries.push(temp_path);
+        }
+
+        if lock.remove(&lock_path).is_ok() && lock_existed {
+            recovered.stale_locks.push(lock_path);
+        }
+    }
+
+    for name in entry_names(&prefix.join(VAR_DIR_NAME))? {
+        let lock_path = prefix.join(VAR_DIR_NAME).join(&name);
+        if !lock_path.is_file() || lock_path.extension() != Some(LOCK_FILE_EXT.as_ref()) {
+            continue;
+        }
+
+        if let Ok(Some(lock)) = AcquiredLock::try_acquire(&lock_path) {
+            if lock.is_abandoned() && lock.remove(&lock_path).is_ok() {
+                recovered.stale_locks.push(lock_path);
+            }
+        }
+    }
+
+    Ok(recovered)
+}
+
+fn entry_names(dir: &Path) -> Result<Vec<String>, StoreError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StoreError::Path(dir.to_owned(), e)),
+    };
+
+    let names = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+
+    Ok(names)
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::process::Command;
+
+    use chrono::Utc;
+    use fs2::FileExt;
+
+    use super::super::lock::{LockOwner, MARK_LOCK_AS_STALE};
+    use super::*;
+    use crate::local::test_util::TempDir;
+
+    struct TempStore(TempDir);
+
+    impl TempStore {
+        fn new() -> Self {
+            let temp = TempDir::new("recovery");
+            let path = temp.path();
+            fs::create_dir_all(path.join(TEMP_DIR_NAME)).expect("Failed to create tmp dir");
+            fs::create_dir_all(path.join(VAR_DIR_NAME)).expect("Failed to create var dir");
+            TempStore(temp)
+        }
+
+        fn path(&self) -> &Path {
+            self.0.path()
+        }
+
+        /// Simulates a writer which was interrupted while writing to `tmp/<name>`.
+        fn crashed_writer(&self, name: &str, owner: &LockOwner) -> (PathBuf, PathBuf) {
+            let temp_path = self.path().join(TEMP_DIR_NAME).join(name);
+            fs::create_dir_all(temp_path.join("bin")).expect("Failed to create temp entry");
+            fs::write(temp_path.join("bin/partial"), b"\x7fELF").expect("Failed to write file");
+
+            let lock_path = lock_path_for(self.path(), name);
+            fs::write(&lock_path, owner.to_bytes()).expect("Failed to write lock file");
+            (temp_path, lock_path)
+        }
+    }
+
+    fn dead_owner() -> LockOwner {
+        let mut child = Command::new("true")
+            .spawn()
+            .expect("Failed to spawn process");
+        child.wait().expect("Failed to wait for process");
+
+        LockOwner {
+            pid: child.id(),
+            ..LockOwner::current()
+        }
+    }
+
+    #[test]
+    fn removes_entries_of_dead_writers() {
+        let store = TempStore::new();
+        let (temp_path, lock_path) = store.crashed_writer("foo-1.0.0", &dead_owner());
+
+        let recovered = recover(store.path()).expect("Failed to recover");
+        assert_eq!(recovered.temp_entries, vec![temp_path.clone()]);
+        assert_eq!(recovered.stale_locks, vec![lock_path.clone()]);
+        assert!(!temp_path.exists());
+        assert!(!lock_path.exists());
+    }
+
+    #[test]
+    fn removes_entries_without_locks_and_released_locks() {
+        let store = TempStore::new();
+        let orphan = store.path().join(TEMP_DIR_NAME).join("orphan");
+        fs::write(&orphan, b"half-written").expect("Failed to write orphan");
+
+        let released = lock_path_for(store.path(), "released");
+        let mut contents = LockOwner::current().to_bytes();
+        contents.extend_from_slice(MARK_LOCK_AS_STALE);
+        fs::write(&released, contents).expect("Failed to write lock file");
+
+        let recovered = recover(store.path()).expect("Failed to recover");
+        assert_eq!(recovered.temp_entries, vec![orphan.clone()]);
+        assert_eq!(recovered.stale_locks, vec![released.clone()]);
+        assert!(!orphan.exists());
+        assert!(!released.exists());
+        assert!(!lock_path_for(store.path(), "orphan").exists());
+    }
+
+    #[test]
+    fn keeps_entries_of_running_writers() {
+        let store = TempStore::new();
+        let (temp_path, lock_path) = store.crashed_writer("held", &dead_owner());
+        let held = File::open(&lock_path).expect("Failed to open lock file");
+        held.lock_exclusive().expect("Failed to lock");
+
+        let recovered = recover(store.path()).expect("Failed to recover");
+        assert_eq!(recovered, Recovered::default());
+        assert!(temp_path.exists());
+        assert!(lock_path.exists());
+    }
+
+    #[test]
+    fn keeps_entries_of_writers_on_other_hosts() {
+        let store = TempStore::new();
+        let owner = LockOwner {
+            pid: dead_owner().pid,
+            hostname: "some-other-host".to_string(),
+            started: Utc::now(),
+        };
+        let (temp_path, lock_path) = store.crashed_writer("remote", &owner);
+
+        let recovered = recover(store.path()).expect("Failed to recover");
+        assert_eq!(recovered, Recovered::default());
+        assert!(temp_path.exists());
+        assert!(lock_path.exists());
+    }
+
+    #[test]
+    fn removes_stale_locks_without_entries() {
+        let store = TempStore::new();
+        let lock_path = lock_path_for(store.path(), "stale");
+        fs::write(&lock_path, dead_owner().to_bytes()).expect("Failed to write lock file");
+        let unrelated = store.path().join(VAR_DIR_NAME).join("db");
+        fs::create_dir_all(&unrelated).expect("Failed to create dir");
+
+        let recovered = recover(store.path()).expect("Failed to recover");
+        assert_eq!(recovered.stale_locks, vec![lock_path.clone()]);
+        assert!(!lock_path.exists());
+        assert!(unrelated.exists());
+    }
+}
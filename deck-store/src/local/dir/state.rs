@@ -1,13 +1,96 @@
-use std::path::Path;
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Poll, Waker};
 
-use deck_core::FilesystemId;
+use deck_core::{FilesystemId, Hash};
+use futures_preview::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_preview::stream::{Stream, StreamExt};
 
-use super::path::{DirectoryPath, LockedPath};
-use super::Directory;
+use super::in_flight::{Claimed, InFlight, Publisher, Subscription};
+use super::path::{DirectoryPath, LockedPath, StagingPath, WritePath};
+use super::{Directory, WriteState, WriteStream};
+use crate::local::registry::{Registration, Registry};
+use crate::local::{archive, VAR_DIR_NAME};
+use crate::progress::Progress;
+use crate::verify::Problem;
+use crate::{CheckContents, StoreError};
+
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+type WriteSender<T> = UnboundedSender<Result<WriteState<T>, StoreError>>;
+
+/// Outcome of claiming an object for writing with `State::claim()`.
+#[derive(Debug)]
+pub enum Claim<D: Directory> {
+    /// The object is already present in the store.
+    Existing,
+    /// The object is missing, and the caller is now responsible for writing it.
+    Write(Writer<D>),
+    /// The object is currently being written by another job in this process.
+    ///
+    /// The subscription yields the progress of that job, and ends once the object is in the store.
+    /// If the job gives up on the object instead, e.g. because its build was cancelled, the
+    /// subscription yields `StoreError::WriteFailed` and the object may be claimed again.
+    Wait(Subscription),
+}
+
+/// Exclusive handle for writing a new object into the store.
+///
+/// Any other job which claims the same object while it is being written is notified of the
+/// progress published here. Dropping the `Writer` without calling `Writer::commit()` discards the
+/// object and reports the write as failed to everyone waiting on it.
+#[derive(Debug)]
+pub struct Writer<D: Directory> {
+    prefix: PathBuf,
+    id: D::Id,
+    path: WritePath,
+    publisher: Publisher<D::Id>,
+}
+
+impl<D: Directory> Writer<D> {
+    #[inline]
+    pub fn path(&mut self) -> &mut WritePath {
+        &mut self.path
+    }
+
+    /// Forwards `progress` to every job waiting on this object.
+    #[inline]
+    pub fn publish(&self, progress: &Progress) {
+        self.publisher.publish(progress);
+    }
+
+    /// Registers the written object and moves it into the store, waking up every job waiting on
+    /// it.
+    pub async fn commit(self) -> Result<(), StoreError> {
+        let id = self.id.clone();
+        await!(self.commit_as(id))
+    }
+
+    async fn commit_as(self, id: D::Id) -> Result<(), StoreError> {
+        let Writer {
+            prefix,
+            path,
+            publisher,
+            ..
+        } = self;
+
+        let content_hash =
+            archive::hash_path(path.as_path()).map_err(StoreError::at(path.as_path()))?;
+        await!(register_and_rename::<D>(&prefix, &id, content_hash, path))?;
+        publisher.finish();
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
-pub struct State<D> {
+pub struct State<D: Directory> {
     directory: D,
+    in_flight: InFlight<D::Id>,
 }
 
 impl<D> State<D>
@@ -18,7 +101,10 @@ where
     D::Output: 'static,
 {
     pub fn new(directory: D) -> Self {
-        State { directory }
+        State {
+            directory,
+            in_flight: InFlight::new(),
+        }
     }
 
     pub fn contains(&self, prefix: &Path, id: &D::Id) -> bool {
@@ -30,7 +116,7 @@ where
         &'a self,
         prefix: &'a Path,
         id: &'a D::Id,
-    ) -> Result<Option<D::Output>, ()> {
+    ) -> Result<Option<D::Output>, StoreError> {
         let path = DirectoryPath::new(prefix, D::NAME, id.clone());
 
         if let Some(read_path) = await!(path.lock_reading())? {
@@ -40,33 +126,342 @@ where
         }
     }
 
-    pub async fn write<'a>(
+    /// Claims the object `id` for writing.
+    ///
+    /// Only one job at a time may write a given object. Jobs within this process which claim an
+    /// object already being written are handed a subscription to the progress of the writer
+    /// instead, while other processes are kept out by the lock file of the object.
+    pub async fn claim<'a>(&'a self, prefix: &'a Path, id: D::Id) -> Result<Claim<D>, StoreError> {
+        let publisher = match self.in_flight.claim(id.clone()) {
+            Claimed::Leader(publisher) => publisher,
+            Claimed::Follower(subscription) => return Ok(Claim::Wait(subscription)),
+        };
+
+        let path = DirectoryPath::new(prefix, D::NAME, id.clone());
+        match await!(path.lock_writing())? {
+            LockedPath::ReadExisting(_) => {
+                publisher.finish();
+                Ok(Claim::Existing)
+            }
+            LockedPath::WriteNew(path) => Ok(Claim::Write(Writer {
+                prefix: prefix.to_owned(),
+                id,
+                path,
+                publisher,
+            })),
+        }
+    }
+
+    /// Writes `input` into the store, yielding progress as it goes and finally the ID of the
+    /// new object along with its output.
+    pub fn write<'a>(
+        &'a self,
+        prefix: &'a Path,
+        input: D::Input,
+    ) -> WriteStream<'a, (D::Id, D::Output)> {
+        let (tx, rx) = mpsc::unbounded();
+        let writing = async move {
+            let result = await!(self.write_to(prefix, input, &tx));
+            let _ = tx.unbounded_send(result.map(WriteState::Done));
+        };
+
+        Box::pin(Driven {
+            items: rx,
+            driver: Some(Box::pin(writing)),
+        })
+    }
+
+    async fn write_to<'a>(
         &'a self,
         prefix: &'a Path,
         input: D::Input,
-    ) -> Result<(D::Id, D::Output), ()> {
+        tx: &'a WriteSender<(D::Id, D::Output)>,
+    ) -> Result<(D::Id, D::Output), StoreError> {
         // Since the `D::Id` of a given `D::Input` is not known ahead of time, we compute a
         // temporary one here and use it to mark ourselves as writing. A new `D::Id`, which may be
         // different from the temporary one, will be returned from `Directory::write()` along with
         // the `D::Output`.
         let temp_id = await!(self.directory.precompute_id(&input))?;
-        let path = DirectoryPath::new(prefix, D::NAME, temp_id.clone());
-        let locked = await!(path.lock_writing())?;
-
-        match locked {
-            LockedPath::ReadExisting(path) => {
-                println!("already in store");
-                let output = await!(self.directory.read(&path))?;
-                Ok((temp_id, output.unwrap()))
+
+        let mut writer = loop {
+            match await!(self.claim(prefix, temp_id.clone()))? {
+                Claim::Existing => return await!(self.read_existing(prefix, temp_id)),
+                Claim::Wait(subscription) => {
+                    if await!(follow(subscription))? {
+                        return await!(self.read_existing(prefix, temp_id));
+                    }
+                }
+                Claim::Write(writer) => break writer,
             }
-            LockedPath::WriteNew(mut path) => {
-                let output = await!(self.directory.write(&mut path, input))?;
-                let read_only = path.to_read_only();
-                let new_id = await!(self.directory.compute_id(&read_only))?;
-                // TODO: Register paths in database transaction here.
-                await!(path.normalize_and_rename())?;
-                Ok((new_id, output))
+        };
+
+        let mut output = None;
+        let mut writing = self.directory.write(writer.path(), input);
+        while let Some(state) = await!(writing.next()) {
+            match state? {
+                WriteState::Progress(p) => {
+                    let _ = tx.unbounded_send(Ok(WriteState::Progress(p)));
+                }
+                WriteState::Done(out) => output = Some(out),
+            }
+        }
+
+        drop(writing);
+        let output = match output {
+            Some(output) => output,
+            None => return Err(StoreError::WriteFailed(temp_id.to_string())),
+        };
+
+        let read_only = writer.path().to_read_only();
+        let new_id = await!(self.directory.compute_id(&read_only))?;
+        await!(writer.commit_as(new_id.clone()))?;
+        Ok((new_id, output))
+    }
+
+    async fn read_existing<'a>(
+        &'a self,
+        prefix: &'a Path,
+        id: D::Id,
+    ) -> Result<(D::Id, D::Output), StoreError> {
+        match await!(self.read(prefix, &id))? {
+            Some(output) => Ok((id, output)),
+            None => Err(StoreError::WriteFailed(id.to_string())),
+        }
+    }
+
+    /// Moves an already verified object located at `staged` into the store as `id`, registering
+    /// it with the given content hash.
+    ///
+    /// Returns `Ok(false)` without touching `staged` if the object is already present in the store.
+    pub async fn insert<'a>(
+        &'a self,
+        prefix: &'a Path,
+        id: D::Id,
+        staged: &'a Path,
+        content_hash: Hash,
+    ) -> Result<bool, StoreError> {
+        let path = DirectoryPath::new(prefix, D::NAME, id.clone());
+
+        match await!(path.lock_writing())? {
+            LockedPath::ReadExisting(_) => Ok(false),
+            LockedPath::WriteNew(path) => {
+                fs::rename(staged, path.as_path()).map_err(StoreError::at(staged))?;
+                await!(register_and_rename::<D>(prefix, &id, content_hash, path))?;
+                Ok(true)
             }
         }
     }
+
+    /// Replaces the object `id` with the already verified object located at `staged`, registering
+    /// it with the given content hash.
+    ///
+    /// The existing object is only moved aside while the new one is inserted, and is put back
+    /// along with its registration if inserting fails, so a failed replacement leaves the store
+    /// as it was.
+    pub async fn replace<'a>(
+        &'a self,
+        prefix: &'a Path,
+        id: D::Id,
+        staged: &'a Path,
+        content_hash: Hash,
+    ) -> Result<(), StoreError> {
+        let path = prefix.join(D::NAME).join(id.to_path());
+        let name = format!("replaced-{}", Hash::random());
+        let aside = await!(StagingPath::new(prefix, &name))?;
+        let registry = Registry::open(prefix);
+        let registration = registry.get(D::NAME, &id.to_string())?;
+
+        let moved = match fs::rename(&path, aside.as_path()) {
+            Ok(()) => true,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(StoreError::Path(path, e)),
+        };
+
+        let inserted = await!(self.insert(prefix, id.clone(), staged, content_hash));
+        if inserted.is_err() && moved {
+            fs::rename(aside.as_path(), &path).map_err(StoreError::at(&path))?;
+            if let Some(ref registration) = registration {
+                registry.register(D::NAME, &id.to_string(), registration)?;
+            }
+        }
+
+        inserted.map(|_| ())
+    }
+
+    /// Removes the object `id` and its registration from the store, if it exists.
+    pub fn remove(&self, prefix: &Path, id: &D::Id) -> Result<(), StoreError> {
+        let path = prefix.join(D::NAME).join(id.to_path());
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+
+        match removed {
+            Ok(_) => Registry::open(prefix).unregister(D::NAME, &id.to_string()),
+            Err(_) if !path.exists() => Registry::open(prefix).unregister(D::NAME, &id.to_string()),
+            Err(e) => Err(StoreError::Path(path, e)),
+        }
+    }
+
+    /// Lists the IDs of all objects which are either present on disk or registered, in no
+    /// particular order.
+    ///
+    /// Entries whose names cannot be parsed as a `D::Id` are ignored.
+    pub fn ids(&self, prefix: &Path) -> Result<Vec<D::Id>, StoreError>
+    where
+        D::Id: FromStr,
+    {
+        let mut ids = HashSet::new();
+
+        let dir = prefix.join(D::NAME);
+        match fs::read_dir(&dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    let name = entry.map_err(StoreError::at(&dir))?.file_name();
+                    if let Ok(id) = D::Id::from_path(name) {
+                        ids.insert(id);
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(StoreError::Path(dir, e)),
+        }
+
+        for id in Registry::open(prefix).ids(D::NAME)? {
+            if let Ok(id) = id.parse() {
+                ids.insert(id);
+            }
+        }
+
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Checks whether the object `id` is consistent with its registration.
+    ///
+    /// If `check` is `CheckContents::Enabled`, the contents of the object are rehashed and
+    /// compared against the registered hash as well. Returns `Ok(None)` if no problem was found.
+    pub async fn check<'a>(
+        &'a self,
+        prefix: &'a Path,
+        id: &'a D::Id,
+        check: CheckContents,
+    ) -> Result<Option<Problem>, StoreError> {
+        // Objects are registered before being moved into place while the write lock is held, so
+        // the registration must only be read after any pending writer has released the lock.
+        let path = DirectoryPath::new(prefix, D::NAME, id.clone());
+        let read_path = await!(path.lock_reading())?;
+        let registration = Registry::open(prefix).get(D::NAME, &id.to_string())?;
+
+        match (read_path, registration) {
+            (None, None) => Ok(None),
+            (None, Some(_)) => Ok(Some(Problem::Missing)),
+            (Some(_), None) => Ok(Some(Problem::Unregistered)),
+            (Some(path), Some(registration)) => {
+                if check == CheckContents::Enabled {
+                    let hash = archive::hash_path(path.as_path())
+                        .map_err(StoreError::at(path.as_path()))?;
+                    if hash != registration.content_hash {
+                        return Ok(Some(Problem::Corrupted));
+                    }
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Registers the object `id` using the current contents found on disk, replacing any existing
+    /// registration.
+    pub fn reregister(&self, prefix: &Path, id: &D::Id) -> Result<(), StoreError> {
+        let path = prefix.join(D::NAME).join(id.to_path());
+        let content_hash = archive::hash_path(&path).map_err(StoreError::at(&path))?;
+        let registration = Registration { content_hash };
+        Registry::open(prefix).register(D::NAME, &id.to_string(), &registration)
+    }
+
+    /// Removes the registration of the object `id` without touching the object itself.
+    #[inline]
+    pub fn deregister(&self, prefix: &Path, id: &D::Id) -> Result<(), StoreError> {
+        Registry::open(prefix).unregister(D::NAME, &id.to_string())
+    }
+
+    /// Moves the object `id` out of the store and into `var/quarantine`, removing its
+    /// registration.
+    ///
+    /// Quarantined objects are kept around for inspection and are never read by the store again.
+    pub fn quarantine(&self, prefix: &Path, id: &D::Id) -> Result<(), StoreError> {
+        let path = prefix.join(D::NAME).join(id.to_path());
+        let quarantine_dir = prefix
+            .join(VAR_DIR_NAME)
+            .join(QUARANTINE_DIR_NAME)
+            .join(D::NAME);
+        fs::create_dir_all(&quarantine_dir).map_err(StoreError::at(&quarantine_dir))?;
+
+        let target = quarantine_dir.join(format!("{}.{}", id, Hash::random()));
+        fs::rename(&path, &target).map_err(StoreError::at(&path))?;
+        self.deregister(prefix, id)
+    }
+}
+
+/// Registers the object `id` and moves it from `path` into its final location in the store.
+///
+/// The registration is written while `path` is still locked, ensuring that anyone who finds the
+/// object on disk will also find its registration.
+async fn register_and_rename<'a, D: Directory>(
+    prefix: &'a Path,
+    id: &'a D::Id,
+    content_hash: Hash,
+    path: WritePath,
+) -> Result<(), StoreError> {
+    let registry = Registry::open(prefix);
+    let id = id.to_string();
+    registry.register(D::NAME, &id, &Registration { content_hash })?;
+
+    if let Err(e) = await!(path.normalize_and_rename()) {
+        let _ = registry.unregister(D::NAME, &id);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Stream of the items sent through a channel by `driver`, which runs as the stream is polled.
+#[must_use = "streams do nothing unless polled"]
+struct Driven<'a, T> {
+    items: UnboundedReceiver<T>,
+    driver: Option<Pin<Box<dyn Future<Output = ()> + Send + 'a>>>,
+}
+
+impl<'a, T> Stream for Driven<'a, T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, waker: &Waker) -> Poll<Option<Self::Item>> {
+        let finished = match self.driver {
+            Some(ref mut driver) => driver.as_mut().poll(waker).is_ready(),
+            None => false,
+        };
+
+        if finished {
+            self.driver = None;
+        }
+
+        Pin::new(&mut self.items).poll_next(waker)
+    }
+}
+
+/// Follows the progress of another job writing the same object until it is done.
+///
+/// Returns `Ok(true)` if the object was written, or `Ok(false)` if the other job gave up on it,
+/// e.g. because its build was cancelled, in which case the caller should claim it again.
+async fn follow(mut subscription: Subscription) -> Result<bool, StoreError> {
+    while let Some(progress) = await!(subscription.next()) {
+        match progress {
+            Ok(_) => {}
+            Err(StoreError::WriteFailed(_)) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
 }
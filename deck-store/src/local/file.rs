@@ -4,11 +4,19 @@ use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::fs::{File as StdFile, Metadata};
 use std::io::{Read, Write};
 use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
 
 use fs2::FileExt;
-use futures::{try_ready, Async, Future, Poll};
+use futures::{task, try_ready, Async, Future, Poll};
 use tokio::fs::{file, File};
-use tokio::io::{AsyncRead, AsyncWrite, Error as IoError};
+use tokio::io::{AsyncRead, AsyncWrite, Error as IoError, ErrorKind};
+use tokio::timer::Delay;
+
+/// Interval at which a contended lock is polled again.
+///
+/// Advisory file locks provide no way to be notified when they are released, particularly when
+/// held by another process, so there is no choice but to try again periodically.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Trait adding file locking functionality to `tokio::File`.
 ///
@@ -149,7 +157,8 @@ impl Future for LockedFuture {
                 let file = try_ready!(inner.poll());
                 file.into_std()
             }
-            State::Blocking(ref mut inner) => {
+            State::Blocking(ref mut inner, ref mut retry) => {
+                try_ready!(retry.poll().map_err(|e| IoError::new(ErrorKind::Other, e)));
                 inner.take().expect("inner `std::fs::File` was empty")
             }
             State::Locked => {
@@ -169,7 +178,13 @@ impl Future for LockedFuture {
                 Ok(Async::Ready(file))
             }
             Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
-                self.state = State::Blocking(Some(std_file));
+                let mut retry = Delay::new(Instant::now() + LOCK_RETRY_INTERVAL);
+                match retry.poll() {
+                    Ok(Async::NotReady) => {}
+                    _ => task::current().notify(),
+                }
+
+                self.state = State::Blocking(Some(std_file), retry);
                 Ok(Async::NotReady)
             }
             Err(e) => Err(e),
@@ -185,7 +200,7 @@ enum Kind {
 
 enum State {
     Pending(Box<dyn Future<Item = File, Error = IoError> + Send>),
-    Blocking(Option<StdFile>),
+    Blocking(Option<StdFile>, Delay),
     Locked,
 }
 
@@ -197,7 +212,7 @@ impl Debug for State {
             State::Pending(_) => debug
                 .field(&"Box<dyn Future<Item = tokio::fs::File, Error = tokio::io::Error> + Send>")
                 .finish(),
-            State::Blocking(ref inner) => debug.field(inner).finish(),
+            State::Blocking(ref inner, _) => debug.field(inner).finish(),
             State::Locked => debug.finish(),
         }
     }
@@ -0,0 +1,750 @@
+//! Persistent build logs, located at `var/log`.
+//!
+//! While a package is being built, its output is appended to a plain text log named after its
+//! `ManifestId`. Every line is prefixed with a timestamp, and changes in the build phase are
+//! recorded as marker lines surrounded by `===`. Once the build completes, the log is compressed
+//! with gzip and the plain text log is removed. The plain text log is locked for as long as it is
+//! being written to, so a build in progress can always be detected by the presence of a locked
+//! uncompressed log, while one left behind by a crashed daemon is treated as finished. Logs of
+//! cancelled builds are compressed as well, after recording that the build was cancelled.
+//!
+//! Starting a new log removes the compressed log of the previous build, and the plain text log is
+//! always preferred while it exists, so only the log of the latest build is ever served.
+//!
+//! Rebuilding an installed package to check that it is reproducible writes a separate check log,
+//! suffixed with `.check`, so the log of the build which produced the installed outputs is kept.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{SecondsFormat, Utc};
+use deck_core::{Hash, ManifestId};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use fs2::FileExt;
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::stream::{self, Stream, StreamExt};
+use tokio::timer::Delay;
+
+use super::VAR_DIR_NAME;
+use crate::progress::{BuildStatus, FinalStatus, Progress};
+use crate::verify::Object;
+use crate::{LogOptions, StoreError};
+
+const LOG_DIR_NAME: &str = "log";
+const PLAIN_FILE_EXT: &str = "log";
+const COMPRESSED_FILE_EXT: &str = "log.gz";
+const CHECK_PLAIN_FILE_EXT: &str = "check.log";
+const CHECK_COMPRESSED_FILE_EXT: &str = "check.log.gz";
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Collection of build logs belonging to a store.
+#[derive(Clone, Debug)]
+pub struct BuildLogs {
+    root: PathBuf,
+}
+
+impl BuildLogs {
+    /// Opens the build logs of the store located at `prefix`.
+    pub fn open<P: AsRef<Path>>(prefix: P) -> Self {
+        BuildLogs {
+            root: prefix.as_ref().join(VAR_DIR_NAME).join(LOG_DIR_NAME),
+        }
+    }
+
+    /// Starts a new log for a build of `id`, replacing any log left over from a previous build.
+    pub fn create(&self, id: &ManifestId) -> io::Result<BuildLog> {
+        let plain_path = self.plain_path(id);
+        let compressed_path = self.compressed_path(id);
+        self.start(
+            plain_path,
+            compressed_path,
+            &format!("build of {} started", id),
+        )
+    }
+
+    /// Starts a new log for a check build of `id`, replacing any check log left over from a
+    /// previous check but leaving the log of the original build untouched.
+    pub fn create_check(&self, id: &ManifestId) -> io::Result<BuildLog> {
+        let plain_path = self.check_plain_path(id);
+        let compressed_path = self.check_compressed_path(id);
+        self.start(
+            plain_path,
+            compressed_path,
+            &format!("check of {} started", id),
+        )
+    }
+
+    fn start(
+        &self,
+        plain_path: PathBuf,
+        compressed_path: PathBuf,
+        marker: &str,
+    ) -> io::Result<BuildLog> {
+        fs::create_dir_all(&self.root)?;
+
+        // The log is only truncated once it is locked, in case it is still being written to.
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&plain_path)?;
+        file.try_lock_exclusive()?;
+        file.set_len(0)?;
+
+        match fs::remove_file(&compressed_path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            removed => removed?,
+        }
+
+        let mut log = BuildLog {
+            file,
+            plain_path,
+            compressed_path,
+            status: None,
+            finished: false,
+        };
+
+        log.write_marker(marker)?;
+        Ok(log)
+    }
+
+    /// Returns whether a build of `id` is currently writing to its log.
+    ///
+    /// A plain text log which is not locked has no live writer, and is therefore not considered
+    /// to be in progress.
+    pub fn is_building(&self, id: &ManifestId) -> bool {
+        match File::open(self.plain_path(id)) {
+            Ok(file) => match file.try_lock_shared() {
+                Ok(()) => false,
+                Err(ref e) => e.kind() == fs2::lock_contended_error().kind(),
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Reads the log of `id`, returning `Ok(None)` if no log exists.
+    ///
+    /// The first `options.offset` bytes of the log are skipped. If `options.tail` is set, only
+    /// that many lines are returned from the end of the remaining log. `options.follow` is
+    /// ignored.
+    pub fn read(&self, id: &ManifestId, options: &LogOptions) -> io::Result<Option<Vec<u8>>> {
+        let read = self.read_with_end(id, options)?;
+        Ok(read.map(|(text, _)| text))
+    }
+
+    /// Same as `BuildLogs::read()`, but also returns the offset of the end of the log.
+    fn read_with_end(
+        &self,
+        id: &ManifestId,
+        options: &LogOptions,
+    ) -> io::Result<Option<(Vec<u8>, u64)>> {
+        let mut text = match self.read_from(id, options.offset)? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+
+        let end = options.offset + text.len() as u64;
+        if let Some(lines) = options.tail {
+            let start = tail_start(&text, lines);
+            text.drain(..start);
+        }
+
+        Ok(Some((text, end)))
+    }
+
+    /// Returns a stream which yields the log of `id` as described by `options`, and then keeps
+    /// yielding newly written output until the build finishes, if it is still in progress.
+    pub fn follow(
+        &self,
+        id: &ManifestId,
+        options: &LogOptions,
+    ) -> impl Stream<Item = Result<Vec<u8>, StoreError>> + Send {
+        let state = Follow {
+            logs: self.clone(),
+            id: id.clone(),
+            options: *options,
+            position: None,
+        };
+
+        stream::unfold(Some(state), |state| async move {
+            let mut state = match state {
+                Some(state) => state,
+                None => return None,
+            };
+
+            loop {
+                if state.position.is_some() {
+                    let when = Instant::now() + FOLLOW_POLL_INTERVAL;
+                    let _ = await!(Delay::new(when).compat());
+                }
+
+                match state.poll_log() {
+                    Err(err) => return Some((Err(err), None)),
+                    Ok((ref text, true)) if text.is_empty() => return None,
+                    Ok((text, true)) => return Some((Ok(text), None)),
+                    Ok((ref text, false)) if text.is_empty() => continue,
+                    Ok((text, false)) => return Some((Ok(text), Some(state))),
+                }
+            }
+        })
+    }
+
+    /// Reads the log of `id` starting from byte `offset`, preferring the plain text log while it
+    /// exists.
+    fn read_from(&self, id: &ManifestId, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        read_log(&self.plain_path(id), &self.compressed_path(id), offset)
+    }
+
+    fn plain_path(&self, id: &ManifestId) -> PathBuf {
+        self.root.join(format!("{}.{}", id, PLAIN_FILE_EXT))
+    }
+
+    fn compressed_path(&self, id: &ManifestId) -> PathBuf {
+        self.root.join(format!("{}.{}", id, COMPRESSED_FILE_EXT))
+    }
+
+    fn check_plain_path(&self, id: &ManifestId) -> PathBuf {
+        self.root.join(format!("{}.{}", id, CHECK_PLAIN_FILE_EXT))
+    }
+
+    fn check_compressed_path(&self, id: &ManifestId) -> PathBuf {
+        self.root
+            .join(format!("{}.{}", id, CHECK_COMPRESSED_FILE_EXT))
+    }
+}
+
+/// Log of a single build in progress.
+///
+/// The log is compressed once `BuildLog::finish()` is called. If it is dropped without being
+/// finished, e.g. because its build was cancelled, the log is marked as cancelled and compressed
+/// anyway, so that anyone following it is not left waiting for a build which will never finish.
+#[derive(Debug)]
+pub struct BuildLog {
+    file: File,
+    plain_path: PathBuf,
+    compressed_path: PathBuf,
+    status: Option<(String, String)>,
+    finished: bool,
+}
+
+impl BuildLog {
+    /// Records the given build progress in the log.
+    pub fn append(&mut self, progress: &Progress) -> io::Result<()> {
+        match *progress {
+            Progress::Blocked(ref p) => self.write_marker(&format!("blocked: {}", p.description)),
+            Progress::Started(_)
+            | Progress::Loading(_)
+            | Progress::Downloading(_)
+            | Progress::Substituting(_)
+            | Progress::Substituted(_) => Ok(()),
+            Progress::Reusing(ref p) => {
+                self.write_marker(&format!("reusing: `{}` as `{}`", p.existing, p.source))
+            }
+            Progress::Building(ref p) => {
+                let status = (status_name(&p.status).to_string(), p.description.clone());
+                if self.status.as_ref() != Some(&status) {
+                    let task = format!("{}/{}", p.current_task, p.total_tasks);
+                    let marker = format!("{} ({}): {}", status.0, task, status.1);
+                    self.write_marker(&marker)?;
+                    self.status = Some(status);
+                }
+
+                self.write_output("stdout", &p.stdout)?;
+                self.write_output("stderr", &p.stderr)
+            }
+            Progress::Installing(ref p) => {
+                // Byte-level progress is not worth logging, so only the first event of each
+                // installation step is recorded.
+                let status = ("installing".to_string(), p.description.clone());
+                if self.status.as_ref() != Some(&status) {
+                    self.write_marker(&format!("installing: {}", p.description))?;
+                    self.status = Some(status);
+                }
+
+                Ok(())
+            }
+            Progress::Finished(ref p) => {
+                self.write_marker(&format!("finished: {}", final_status_name(&p.status)))
+            }
+            Progress::Failed(ref p) => self.write_marker(&format!("failed: {}", p.reason)),
+        }
+    }
+
+    /// Compresses the finished log, removing the plain text version.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        self.compress()
+    }
+
+    fn compress(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let compressed_path = &self.compressed_path;
+        let temp_path = compressed_path.with_extension(format!("gz.{}", Hash::random()));
+        let compressed = File::open(&self.plain_path).and_then(|mut plain| {
+            let file = File::create(&temp_path)?;
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            io::copy(&mut plain, &mut encoder)?;
+            encoder.finish()?.sync_all()?;
+            fs::rename(&temp_path, compressed_path)
+        });
+
+        if let Err(e) = compressed {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+
+        fs::remove_file(&self.plain_path)
+    }
+
+    fn write_marker(&mut self, text: &str) -> io::Result<()> {
+        writeln!(self.file, "[{}] === {} ===", timestamp(), text)
+    }
+
+    fn write_output(&mut self, stream: &str, output: &[u8]) -> io::Result<()> {
+        if output.is_empty() {
+            return Ok(());
+        }
+
+        for line in trim_newline(output).split(|&b| b == b'\n') {
+            write!(self.file, "[{}] {} | ", timestamp(), stream)?;
+            self.file.write_all(line)?;
+            self.file.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for BuildLog {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let _ = self.write_marker("cancelled");
+        if self.compress().is_err() {
+            // The build is over either way, and a plain text log would signal otherwise.
+            let _ = fs::remove_file(&self.plain_path);
+        }
+    }
+}
+
+/// Wraps a stream of build `progress`, recording every event in `log`.
+///
+/// The log is compressed once `progress` has been exhausted. Failing to write to the log is
+/// reported as an error in the returned stream.
+pub fn record<S>(
+    log: BuildLog,
+    progress: S,
+) -> impl Stream<Item = Result<Progress, StoreError>> + Send
+where
+    S: Stream<Item = Result<Progress, StoreError>> + Send + Unpin,
+{
+    stream::unfold(Some((progress, log)), |state| async move {
+        let (mut progress, mut log) = match state {
+            Some(state) => state,
+            None => return None,
+        };
+
+        match await!(progress.next()) {
+            Some(Ok(event)) => match log.append(&event) {
+                Ok(_) => Some((Ok(event), Some((progress, log)))),
+                Err(e) => Some((Err(StoreError::Io(e)), None)),
+            },
+            Some(Err(err)) => {
+                let _ = log.write_marker(&format!("failed: {}", err));
+                Some((Err(err), Some((progress, log))))
+            }
+            None => match log.finish() {
+                Ok(_) => None,
+                Err(e) => Some((Err(StoreError::Io(e)), None)),
+            },
+        }
+    })
+}
+
+/// State of a `BuildLogs::follow()` stream.
+#[derive(Debug)]
+struct Follow {
+    logs: BuildLogs,
+    id: ManifestId,
+    options: LogOptions,
+    position: Option<u64>,
+}
+
+impl Follow {
+    /// Reads any output written since the last poll, returning it along with whether the build
+    /// has finished.
+    fn poll_log(&mut self) -> Result<(Vec<u8>, bool), StoreError> {
+        let finished = !self.logs.is_building(&self.id);
+
+        let (text, end) = match self.position {
+            None => match self.logs.read_with_end(&self.id, &self.options)? {
+                Some(read) => read,
+                None => return Err(StoreError::NotFound(Object::Manifest(self.id.clone()))),
+            },
+            Some(position) => {
+                let text = self.logs.read_from(&self.id, position)?;
+                let text = text.unwrap_or_default();
+                let end = position + text.len() as u64;
+                (text, end)
+            }
+        };
+
+        self.position = Some(end);
+        Ok((text, finished))
+    }
+}
+
+/// Reads a log starting from byte `offset`, preferring the plain text log at `plain_path` while it
+/// exists over its compressed version at `compressed_path`.
+///
+/// The compressed log is moved into place before the plain text log is removed, so one of them
+/// can always be found.
+fn read_log(plain_path: &Path, compressed_path: &Path, offset: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut text = Vec::new();
+
+    match File::open(plain_path) {
+        Ok(mut file) => {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_to_end(&mut text)?;
+            return Ok(Some(text));
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    match File::open(compressed_path) {
+        Ok(file) => {
+            let mut decoder = GzDecoder::new(BufReader::new(file));
+            io::copy(&mut decoder.by_ref().take(offset), &mut io::sink())?;
+            decoder.read_to_end(&mut text)?;
+            Ok(Some(text))
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Strips a single trailing newline from `text`, if there is one.
+fn trim_newline(text: &[u8]) -> &[u8] {
+    match text.last() {
+        Some(&b'\n') => &text[..text.len() - 1],
+        _ => text,
+    }
+}
+
+/// Returns the byte offset at which the last `lines` lines of `text` begin.
+fn tail_start(text: &[u8], lines: usize) -> usize {
+    if lines == 0 {
+        return text.len();
+    }
+
+    trim_newline(text)
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|&(_, &b)| b == b'\n')
+        .nth(lines - 1)
+        .map(|(i, _)| i + 1)
+        .unwrap_or(0)
+}
+
+fn timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn status_name(status: &BuildStatus) -> &'static str {
+    match *status {
+        BuildStatus::Started => "started",
+        BuildStatus::Preparing => "preparing",
+        BuildStatus::Configuring => "configuring",
+        BuildStatus::Compiling => "compiling",
+        BuildStatus::Testing => "testing",
+        BuildStatus::Finalizing => "finalizing",
+    }
+}
+
+fn final_status_name(status: &FinalStatus) -> &'static str {
+    match *status {
+        FinalStatus::Memoized => "memoized",
+        FinalStatus::Reinstalled => "reinstalled",
+        FinalStatus::Downloaded => "downloaded",
+        FinalStatus::Built => "built",
+        FinalStatus::Checked => "checked",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::local::test_util::{run, TempDir};
+    use crate::progress::{Building, Finished};
+
+    fn manifest_id() -> ManifestId {
+        "foo@1.0.0-fc3j3vub6kodu4jtfoakfs5xhumqi62m"
+            .parse()
+            .expect("Failed to parse manifest ID")
+    }
+
+    fn building(id: &ManifestId, status: BuildStatus, stdout: &[u8]) -> Progress {
+        Progress::Building(Building {
+            package_id: id.clone(),
+            status,
+            current_task: 1,
+            total_tasks: 2,
+            description: "make all".to_string(),
+            stdout: stdout.to_vec(),
+            stderr: Vec::new(),
+            timestamp: Utc::now(),
+        })
+    }
+
+    fn lines(text: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(text)
+            .lines()
+            .map(|line| line.splitn(2, "] ").nth(1).unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn tail_start_of_text() {
+        let text = b"one\ntwo\nthree\n";
+        assert_eq!(tail_start(text, 0), text.len());
+        assert_eq!(&text[tail_start(text, 1)..], b"three\n");
+        assert_eq!(&text[tail_start(text, 2)..], b"two\nthree\n");
+        assert_eq!(tail_start(text, 5), 0);
+        assert_eq!(
+            &b"no newline"[tail_start(b"no newline", 1)..],
+            b"no newline"
+        );
+    }
+
+    #[test]
+    fn records_phases_and_output() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        let mut log = logs.create(&id).expect("Failed to create log");
+        log.append(&building(&id, BuildStatus::Compiling, b"cc -c foo.c\n"))
+            .expect("Failed to append");
+        log.append(&building(
+            &id,
+            BuildStatus::Compiling,
+            b"cc -c bar.c\ncc -o foo\n",
+        ))
+        .expect("Failed to append");
+        assert!(logs.is_building(&id));
+
+        let in_progress = logs
+            .read(&id, &LogOptions::default())
+            .expect("Failed to read");
+        assert_eq!(lines(&in_progress.expect("Log is missing")).len(), 5);
+
+        log.append(&Progress::Finished(Finished {
+            package_id: id.clone(),
+            status: FinalStatus::Built,
+            installed_bytes: 0,
+            timestamp: Utc::now(),
+        }))
+        .expect("Failed to append");
+        log.finish().expect("Failed to finish log");
+        assert!(!logs.is_building(&id));
+
+        let text = logs
+            .read(&id, &LogOptions::default())
+            .expect("Failed to read");
+        assert_eq!(
+            lines(&text.expect("Log is missing")),
+            vec![
+                format!("=== build of {} started ===", id),
+                "=== compiling (1/2): make all ===".to_string(),
+                "stdout | cc -c foo.c".to_string(),
+                "stdout | cc -c bar.c".to_string(),
+                "stdout | cc -o foo".to_string(),
+                "=== finished: built ===".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_log_keeps_original_log() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        let mut log = logs.create(&id).expect("Failed to create log");
+        log.append(&building(&id, BuildStatus::Compiling, b"original\n"))
+            .expect("Failed to append");
+        log.finish().expect("Failed to finish log");
+        let original = logs.read(&id, &LogOptions::default()).unwrap().unwrap();
+
+        let mut check = logs.create_check(&id).expect("Failed to create check log");
+        check
+            .append(&building(&id, BuildStatus::Compiling, b"rebuilt\n"))
+            .expect("Failed to append");
+        assert!(!logs.is_building(&id));
+        check.finish().expect("Failed to finish check log");
+
+        assert_eq!(
+            logs.read(&id, &LogOptions::default()).unwrap().unwrap(),
+            original
+        );
+        let checked = read_log(
+            &logs.check_plain_path(&id),
+            &logs.check_compressed_path(&id),
+            0,
+        );
+        assert_eq!(
+            lines(&checked.unwrap().expect("Check log is missing")),
+            vec![
+                format!("=== check of {} started ===", id),
+                "=== compiling (1/2): make all ===".to_string(),
+                "stdout | rebuilt".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_log_is_marked_as_cancelled() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        let mut log = logs.create(&id).expect("Failed to create log");
+        log.append(&building(&id, BuildStatus::Compiling, b"partial\n"))
+            .expect("Failed to append");
+        drop(log);
+        assert!(!logs.is_building(&id));
+
+        let text = logs.read(&id, &LogOptions::default()).unwrap();
+        assert_eq!(
+            lines(&text.expect("Log is missing")),
+            vec![
+                format!("=== build of {} started ===", id),
+                "=== compiling (1/2): make all ===".to_string(),
+                "stdout | partial".to_string(),
+                "=== cancelled ===".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn following_ends_when_build_is_cancelled() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        let log = logs.create(&id).expect("Failed to create log");
+        let cancelling = thread::spawn(move || {
+            thread::sleep(FOLLOW_POLL_INTERVAL * 2);
+            drop(log);
+        });
+
+        let following = logs.follow(&id, &LogOptions::default());
+        let text: Vec<u8> = run(following
+            .map(|chunk| chunk.expect("Failed to follow"))
+            .concat());
+        cancelling.join().expect("Failed to cancel build");
+
+        let lines = lines(&text);
+        assert_eq!(
+            lines.first(),
+            Some(&format!("=== build of {} started ===", id))
+        );
+        assert_eq!(lines.last().map(String::as_str), Some("=== cancelled ==="));
+    }
+
+    #[test]
+    fn reads_with_offset_and_tail() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        let mut log = logs.create(&id).expect("Failed to create log");
+        log.append(&building(
+            &id,
+            BuildStatus::Testing,
+            b"first\nsecond\nthird\n",
+        ))
+        .expect("Failed to append");
+        log.finish().expect("Failed to finish log");
+
+        let full = logs.read(&id, &LogOptions::default()).unwrap().unwrap();
+
+        let tail = LogOptions {
+            tail: Some(2),
+            ..LogOptions::default()
+        };
+        let text = logs.read(&id, &tail).unwrap().unwrap();
+        assert_eq!(lines(&text), vec!["stdout | second", "stdout | third"]);
+
+        let offset = LogOptions {
+            offset: full.len() as u64 - text.len() as u64,
+            ..LogOptions::default()
+        };
+        assert_eq!(logs.read(&id, &offset).unwrap().unwrap(), text);
+    }
+
+    #[test]
+    fn missing_log() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let read = logs.read(&manifest_id(), &LogOptions::default());
+        assert_eq!(read.expect("Failed to read"), None);
+    }
+
+    #[test]
+    fn rebuilds_replace_the_previous_log() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        let mut log = logs.create(&id).expect("Failed to create log");
+        log.append(&building(&id, BuildStatus::Compiling, b"old\n"))
+            .expect("Failed to append");
+        log.finish().expect("Failed to finish log");
+
+        let mut log = logs.create(&id).expect("Failed to create log");
+        log.append(&building(&id, BuildStatus::Compiling, b"new\n"))
+            .expect("Failed to append");
+        assert!(!logs.compressed_path(&id).exists());
+
+        let expected = vec![
+            format!("=== build of {} started ===", id),
+            "=== compiling (1/2): make all ===".to_string(),
+            "stdout | new".to_string(),
+        ];
+        let in_progress = logs.read(&id, &LogOptions::default()).unwrap();
+        assert_eq!(lines(&in_progress.expect("Log is missing")), expected);
+
+        log.finish().expect("Failed to finish log");
+        let finished = logs.read(&id, &LogOptions::default()).unwrap();
+        assert_eq!(lines(&finished.expect("Log is missing")), expected);
+    }
+
+    #[test]
+    fn logs_without_a_writer_are_finished() {
+        let temp = TempDir::new("log");
+        let logs = BuildLogs::open(temp.path());
+        let id = manifest_id();
+
+        // A crashed daemon leaves its plain text log behind, without a lock.
+        drop(logs.create(&id).expect("Failed to create log"));
+        fs::write(logs.plain_path(&id), "[0] === build started ===\n").unwrap();
+        assert!(!logs.is_building(&id));
+
+        let following = logs.follow(&id, &LogOptions::default());
+        let text: Vec<u8> = run(following
+            .map(|chunk| chunk.expect("Failed to follow"))
+            .concat());
+        assert_eq!(lines(&text), vec!["=== build started ==="]);
+    }
+}
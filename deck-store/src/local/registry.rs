@@ -0,0 +1,129 @@
+//! Record of valid objects in the store and their content hashes.
+//!
+//! Every object which has been fully written to the store is registered here, keyed by the name of
+//! its `Directory` and its ID. An object which exists on disk without a registration was never
+//! committed properly, and a registration without an object on disk indicates the store has been
+//! tampered with.
+
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use deck_core::Hash;
+use serde::{Deserialize, Serialize};
+
+use super::VAR_DIR_NAME;
+use crate::StoreError;
+
+const REGISTRY_DIR_NAME: &str = "db";
+const ENTRY_FILE_EXT: &str = "toml";
+
+/// Metadata recorded for a single registered store object.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Registration {
+    /// Hash of the serialized contents of the object, as computed by `archive::hash_path()`.
+    pub content_hash: Hash,
+}
+
+/// Record of valid objects in the store, located at `var/db`.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    root: PathBuf,
+}
+
+impl Registry {
+    /// Opens the registry belonging to the store located at `prefix`.
+    pub fn open<P: AsRef<Path>>(prefix: P) -> Self {
+        Registry {
+            root: prefix.as_ref().join(VAR_DIR_NAME).join(REGISTRY_DIR_NAME),
+        }
+    }
+
+    /// Looks up the registration for the object `id` in the directory `dir`.
+    pub fn get(&self, dir: &str, id: &str) -> Result<Option<Registration>, StoreError> {
+        let path = self.entry_path(dir, id);
+        match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map(Some)
+                .map_err(|e| StoreError::Path(path, IoError::new(ErrorKind::InvalidData, e))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StoreError::Path(path, e)),
+        }
+    }
+
+    /// Returns whether the object `id` in the directory `dir` has been registered.
+    #[inline]
+    pub fn contains(&self, dir: &str, id: &str) -> bool {
+        self.entry_path(dir, id).exists()
+    }
+
+    /// Registers the object `id` in the directory `dir`, replacing any existing registration.
+    ///
+    /// The entry is written to a temporary file first and atomically renamed into place, so
+    /// readers never observe a partially written registration.
+    pub fn register(
+        &self,
+        dir: &str,
+        id: &str,
+        registration: &Registration,
+    ) -> Result<(), StoreError> {
+        let dir_path = self.root.join(dir);
+        fs::create_dir_all(&dir_path).map_err(StoreError::at(&dir_path))?;
+
+        let text =
+            toml::to_string(registration).map_err(|e| IoError::new(ErrorKind::InvalidData, e))?;
+        let final_path = self.entry_path(dir, id);
+        let temp_path = final_path.with_extension(format!("{}.{}", ENTRY_FILE_EXT, Hash::random()));
+
+        let written = fs::File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(text.as_bytes())
+                    .and_then(|_| file.sync_all())
+            })
+            .and_then(|_| fs::rename(&temp_path, &final_path));
+
+        written.map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            StoreError::Path(final_path, e)
+        })
+    }
+
+    /// Removes the registration of the object `id` in the directory `dir`, if one exists.
+    pub fn unregister(&self, dir: &str, id: &str) -> Result<(), StoreError> {
+        let path = self.entry_path(dir, id);
+        match fs::remove_file(&path) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(StoreError::Path(path, e)),
+        }
+    }
+
+    /// Lists the IDs of all objects registered in the directory `dir`, in no particular order.
+    pub fn ids(&self, dir: &str) -> Result<Vec<String>, StoreError> {
+        let dir_path = self.root.join(dir);
+        let entries = match fs::read_dir(&dir_path) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(StoreError::Path(dir_path, e)),
+        };
+
+        let mut ids = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(StoreError::at(&dir_path))?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(ENTRY_FILE_EXT) {
+                if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    ids.push(stem.to_string());
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn entry_path(&self, dir: &str, id: &str) -> PathBuf {
+        self.root
+            .join(dir)
+            .join(format!("{}.{}", id, ENTRY_FILE_EXT))
+    }
+}
@@ -0,0 +1,41 @@
+//! Linux namespace sandbox for builders.
+//!
+//! Sandboxed builders run as an unprivileged user inside fresh user, mount, PID, network, IPC and
+//! UTS namespaces. Their root directory is a minimal tree containing only:
+//!
+//! * The store paths in the closure of the build, bind-mounted read-only at their usual location.
+//! * Any extra host paths configured through `SandboxConfig`, also read-only.
+//! * A private writable `/build` and `/tmp`, bind-mounted from the scratch directory of the build.
+//! * A handful of device nodes in `/dev`, a fresh `/proc`, and a minimal `/etc`.
+//!
+//! Hosts which mask parts of their own `/proc`, as container runtimes often do, do not permit
+//! mounting a fresh one, so sandboxed builds fail on them.
+//!
+//! The network namespace contains nothing but a loopback interface which is down, so builders
+//! have no network access, and the hostname is always `localhost`.
+//!
+//! No privileges are required, but the kernel must allow unprivileged user namespaces.
+
+#[cfg(target_os = "linux")]
+pub use self::linux::Sandbox;
+
+use std::path::PathBuf;
+
+#[cfg(target_os = "linux")]
+mod linux;
+
+/// Options for running builders inside a sandbox.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SandboxConfig {
+    /// Host paths which are made available read-only inside the sandbox at the same location, e.g.
+    /// `/bin/sh` for builders relying on a shell.
+    pub extra_paths: Vec<PathBuf>,
+}
+
+impl SandboxConfig {
+    /// Returns whether builds can be sandboxed on the current platform.
+    #[inline]
+    pub fn is_supported() -> bool {
+        cfg!(target_os = "linux")
+    }
+}
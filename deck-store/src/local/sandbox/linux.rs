@@ -0,0 +1,322 @@
+//! Sandbox implementation based on Linux namespaces.
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use nix::fcntl::{self, OFlag};
+use nix::mount::{self, MntFlags, MsFlags};
+use nix::sched::{self, CloneFlags};
+use nix::sys::stat::Mode;
+use nix::sys::statvfs::{self, FsFlags};
+use nix::sys::wait::{self, WaitStatus};
+use nix::unistd::{self, ForkResult};
+
+use crate::local::to_io;
+
+/// Hostname reported inside the sandbox.
+const HOSTNAME: &str = "localhost";
+/// User ID of the builder inside the sandbox.
+const BUILD_UID: u32 = 1000;
+/// Group ID of the builder inside the sandbox.
+const BUILD_GID: u32 = 100;
+/// Directory under the sandbox root where the old root is placed by `pivot_root(2)`.
+const OLD_ROOT_DIR_NAME: &str = ".old-root";
+/// Device nodes which are bind-mounted from the host.
+const DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+];
+/// Highest file descriptor closed by the intermediate process, if `OPEN_MAX` is unknown.
+const FALLBACK_MAX_FD: i32 = 1024;
+
+/// Bind mount performed when entering the sandbox.
+#[derive(Clone, Debug)]
+struct BindMount {
+    source: PathBuf,
+    target: PathBuf,
+    read_only: bool,
+}
+
+/// Sandbox which a builder process can be confined to.
+///
+/// The root directory of the sandbox is laid out by `Sandbox::prepare()`, after which any number
+/// of commands can be confined to it with `Sandbox::apply()`.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    root: PathBuf,
+    current_dir: PathBuf,
+    host_ids: Option<(u32, u32)>,
+    mounts: Vec<BindMount>,
+}
+
+impl Sandbox {
+    /// Creates a new sandbox rooted at `root`, in which `build_dir` and `temp_dir` appear as
+    /// `/build` and `/tmp`, respectively.
+    ///
+    /// `root` must be an empty or nonexistent directory, and commands start out in `/build`.
+    pub fn new<P: Into<PathBuf>>(root: P, build_dir: &Path, temp_dir: &Path) -> Self {
+        let mut sandbox = Sandbox {
+            root: root.into(),
+            current_dir: PathBuf::from("/build"),
+            host_ids: None,
+            mounts: Vec::new(),
+        };
+
+        sandbox.bind(build_dir, Path::new("/build"), false);
+        sandbox.bind(temp_dir, Path::new("/tmp"), false);
+        for device in DEVICES {
+            sandbox.bind(Path::new(device), Path::new(device), false);
+        }
+
+        sandbox
+    }
+
+    /// Makes the absolute host path `path` available read-only inside the sandbox at the same
+    /// location.
+    pub fn bind_read_only<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        self.bind(path, path, true);
+    }
+
+    /// Sets the working directory of commands inside the sandbox.
+    pub fn current_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.current_dir = path.into();
+    }
+
+    /// Sets the host user and group IDs which the builder is mapped to.
+    ///
+    /// Commands must already be switched to this user and group, e.g. with `CommandExt::uid()`
+    /// and `CommandExt::gid()`. Defaults to the IDs of the current process.
+    pub fn host_ids(&mut self, uid: u32, gid: u32) {
+        self.host_ids = Some((uid, gid));
+    }
+
+    /// Lays out the root directory of the sandbox, creating an empty mount point for every bind
+    /// mount along with a minimal `/etc`.
+    pub fn prepare(&self) -> io::Result<()> {
+        for dir in &["etc", "proc", OLD_ROOT_DIR_NAME] {
+            fs::create_dir_all(self.root.join(dir))?;
+        }
+
+        let passwd = format!(
+            "build:x:{}:{}:Build user:/build:/noshell\n",
+            BUILD_UID, BUILD_GID
+        );
+        let group = format!("build:x:{}:\n", BUILD_GID);
+        let hosts = format!("127.0.0.1 {}\n::1 {}\n", HOSTNAME, HOSTNAME);
+        fs::write(self.root.join("etc/passwd"), passwd)?;
+        fs::write(self.root.join("etc/group"), group)?;
+        fs::write(self.root.join("etc/hosts"), hosts)?;
+
+        for mount in &self.mounts {
+            let mount_point = self.mount_point(mount);
+            if fs::metadata(&mount.source)?.is_dir() {
+                fs::create_dir_all(&mount_point)?;
+            } else {
+                if let Some(parent) = mount_point.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                File::create(&mount_point)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Confines `command` to this sandbox once it is spawned.
+    ///
+    /// The sandbox must have been laid out with `Sandbox::prepare()` beforehand. If the sandbox
+    /// cannot be entered, e.g. because unprivileged user namespaces are disabled, spawning the
+    /// command fails.
+    pub fn apply(&self, command: &mut Command) {
+        // Everything is computed up front, since allocating between `fork()` and `exec()` is unsafe
+        // in a multithreaded process.
+        let (uid, gid) = self
+            .host_ids
+            .unwrap_or_else(|| (unistd::getuid().as_raw(), unistd::getgid().as_raw()));
+        let setup = Setup {
+            uid_map: format!("{} {} 1\n", BUILD_UID, uid),
+            gid_map: format!("{} {} 1\n", BUILD_GID, gid),
+            max_fd: unistd::sysconf(unistd::SysconfVar::OPEN_MAX)
+                .ok()
+                .and_then(|max| max)
+                .map(|max| max as i32)
+                .unwrap_or(FALLBACK_MAX_FD),
+            root: self.root.clone(),
+            proc_dir: self.root.join("proc"),
+            put_old: self.root.join(OLD_ROOT_DIR_NAME),
+            old_root: Path::new("/").join(OLD_ROOT_DIR_NAME),
+            current_dir: self.current_dir.clone(),
+            mounts: self.sorted_mounts(),
+        };
+
+        command.before_exec(move || setup.enter());
+    }
+
+    /// Returns where the mount point of `mount` must be created ahead of time.
+    ///
+    /// A path nested inside a writable bind mount, e.g. a store located in `/tmp`, is hidden by
+    /// that mount, so its mount point is created within the source of the enclosing mount instead.
+    fn mount_point(&self, mount: &BindMount) -> PathBuf {
+        self.mounts
+            .iter()
+            .filter(|m| !m.read_only && m.target != mount.target)
+            .filter(|m| mount.target.starts_with(&m.target))
+            .max_by_key(|m| m.target.components().count())
+            .and_then(|m| {
+                let relative = mount.target.strip_prefix(&m.target).ok()?;
+                Some(m.source.join(relative))
+            })
+            .unwrap_or_else(|| mount.target.clone())
+    }
+
+    /// Returns the bind mounts ordered such that enclosing mounts are performed first.
+    fn sorted_mounts(&self) -> Vec<BindMount> {
+        let mut mounts = self.mounts.clone();
+        mounts.sort_by_key(|m| m.target.components().count());
+        mounts
+    }
+
+    fn bind(&mut self, source: &Path, target: &Path, read_only: bool) {
+        let relative = target.strip_prefix("/").unwrap_or(target);
+        self.mounts.push(BindMount {
+            source: source.to_owned(),
+            target: self.root.join(relative),
+            read_only,
+        });
+    }
+}
+
+/// Precomputed state used by the child process to enter the sandbox.
+#[derive(Debug)]
+struct Setup {
+    uid_map: String,
+    gid_map: String,
+    max_fd: i32,
+    root: PathBuf,
+    proc_dir: PathBuf,
+    put_old: PathBuf,
+    old_root: PathBuf,
+    current_dir: PathBuf,
+    mounts: Vec<BindMount>,
+}
+
+impl Setup {
+    /// Moves the calling process into the sandbox, right before it executes the builder.
+    ///
+    /// Since a process cannot move itself into a new PID namespace, the calling process forks
+    /// once more and only waits for the builder, which runs as PID 1 inside the sandbox.
+    fn enter(&self) -> io::Result<()> {
+        let namespaces = CloneFlags::CLONE_NEWUSER
+            | CloneFlags::CLONE_NEWNS
+            | CloneFlags::CLONE_NEWPID
+            | CloneFlags::CLONE_NEWNET
+            | CloneFlags::CLONE_NEWIPC
+            | CloneFlags::CLONE_NEWUTS;
+
+        sched::unshare(namespaces).map_err(to_io)?;
+        write_file("/proc/self/setgroups", b"deny")?;
+        write_file("/proc/self/uid_map", self.uid_map.as_bytes())?;
+        write_file("/proc/self/gid_map", self.gid_map.as_bytes())?;
+
+        if let ForkResult::Parent { child } = unistd::fork().map_err(to_io)? {
+            // The spawning process only learns that `exec()` succeeded once every copy of its
+            // close-on-exec status pipe is closed, including the one held by this process.
+            for fd in 3..self.max_fd {
+                let _ = unistd::close(fd);
+            }
+
+            let code = match wait::waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => code,
+                Ok(WaitStatus::Signaled(_, signal, _)) => 128 + signal as i32,
+                _ => 1,
+            };
+
+            unistd::_exit(code);
+        }
+
+        self.mount_root()?;
+        unistd::sethostname(HOSTNAME).map_err(to_io)?;
+        unistd::pivot_root(self.root.as_path(), self.put_old.as_path()).map_err(to_io)?;
+        unistd::chdir("/").map_err(to_io)?;
+        mount::umount2(self.old_root.as_path(), MntFlags::MNT_DETACH).map_err(to_io)?;
+        unistd::chdir(self.current_dir.as_path()).map_err(to_io)
+    }
+
+    fn mount_root(&self) -> io::Result<()> {
+        let none = None::<&str>;
+
+        // Keep the mounts below from propagating back into the namespace of the host.
+        let private = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+        mount::mount(none, "/", none, private, none).map_err(to_io)?;
+
+        // `pivot_root(2)` requires the new root to be a mount point.
+        let bind = MsFlags::MS_BIND | MsFlags::MS_REC;
+        let root = self.root.as_path();
+        mount::mount(Some(root), root, none, bind, none).map_err(to_io)?;
+
+        for BindMount {
+            source,
+            target,
+            read_only,
+        } in &self.mounts
+        {
+            mount::mount(Some(source.as_path()), target.as_path(), none, bind, none)
+                .map_err(to_io)?;
+            if *read_only {
+                remount_read_only(target)?;
+            }
+        }
+
+        // Mounting `/proc` is refused if the host has parts of its own `/proc` masked, as is common
+        // inside containers. The builder would then silently run in a different sandbox than the
+        // one configured, so the build fails instead.
+        let proc_flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+        let proc_dir = self.proc_dir.as_path();
+        mount::mount(Some("proc"), proc_dir, Some("proc"), proc_flags, none).map_err(to_io)
+    }
+}
+
+/// Remounts the bind mount at `target` read-only.
+///
+/// Flags which are locked on the original mount must be kept, or the kernel refuses to remount
+/// it from inside a user namespace.
+fn remount_read_only(target: &Path) -> io::Result<()> {
+    let current = statvfs::statvfs(target).map_err(to_io)?.flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    let locked = [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+
+    for (fs_flag, ms_flag) in &locked {
+        if current.contains(*fs_flag) {
+            flags |= *ms_flag;
+        }
+    }
+
+    let none = None::<&str>;
+    mount::mount(none, target, none, flags, none).map_err(to_io)
+}
+
+/// Writes `contents` to the existing file at `path` without allocating.
+fn write_file(path: &str, contents: &[u8]) -> io::Result<()> {
+    let fd = fcntl::open(path, OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty()).map_err(to_io)?;
+    let written = unistd::write(fd, contents).map_err(to_io);
+    let _ = unistd::close(fd);
+
+    match written? {
+        n if n == contents.len() => Ok(()),
+        _ => Err(io::Error::from(ErrorKind::WriteZero)),
+    }
+}
@@ -1,18 +1,60 @@
+use std::collections::{BTreeSet, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
 
-use deck_core::{Manifest, ManifestId, OutputId, Source, SourceId};
+use chrono::Utc;
+use deck_core::{FilesystemId, Hash, Manifest, ManifestId, OutputId, Source, SourceId};
+use futures_preview::channel::{mpsc, oneshot};
+use futures_preview::executor;
+use futures_preview::sink::SinkExt;
+use futures_preview::stream::{Stream, StreamExt, TryStreamExt};
+
+pub use self::sources::SourcesDir;
 
 use self::manifests::{ManifestsDir, ManifestsInput};
 use self::outputs::OutputsDir;
-use self::sources::SourcesDir;
-use super::dir::State;
+use super::archive::{self, ArchiveReader, ArchiveWriter, Kind};
+use super::dir::{
+    self, Claim, Directory, StagingPath, State, WriteProgress, WriteState, WriteStream, Writer,
+};
+use super::log::BuildLogs;
+use super::registry::Registry;
 use crate::closure::Closure;
+use crate::progress::{Installing, Progress};
+use crate::verify::{Action, Object, Problem};
+use crate::{CheckContents, StoreError};
 
 mod manifests;
 mod outputs;
 mod sources;
 
+/// Maximum number of differing paths reported when a rebuilt output does not match.
+const MAX_REPORTED_DIFFERENCES: usize = 10;
+/// Number of downloaded chunks which may be waiting to be unpacked at a time.
+const UNPACK_BUFFER_CHUNKS: usize = 4;
+
+/// Sets whether package sources should be included when exporting a closure.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IncludeSources {
+    /// Sources present in the store are exported alongside manifests and outputs.
+    Enabled,
+    /// Only manifests and outputs are exported.
+    Disabled,
+}
+
+/// Store objects which were added to the store by `StoreDir::import()`.
+///
+/// Objects contained in the archive which were already present in the store are not listed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Imported {
+    pub manifests: Vec<ManifestId>,
+    pub sources: Vec<SourceId>,
+    pub outputs: Vec<OutputId>,
+}
+
 #[derive(Debug)]
 pub struct StoreDir {
     prefix: PathBuf,
@@ -22,10 +64,19 @@ pub struct StoreDir {
 }
 
 impl StoreDir {
-    pub fn open(path: PathBuf) -> Result<Self, ()> {
+    pub fn open(path: PathBuf) -> Result<Self, StoreError> {
         let prefix = fs::read_dir(&path)
-            .map_err(|_| ())
-            .and_then(|_| fs::canonicalize(path).map_err(|_| ()))?;
+            .and_then(|_| fs::canonicalize(&path))
+            .map_err(StoreError::at(&path))?;
+
+        // Entries which cannot be cleaned up are already skipped by `recover()`. Failing to list
+        // `tmp` or `var` is only tolerated if the current user lacks the permission to do so,
+        // since such a store may still be perfectly readable.
+        match dir::recover(&prefix) {
+            Ok(_) => {}
+            Err(StoreError::Path(_, ref e)) if e.kind() == io::ErrorKind::PermissionDenied => {}
+            Err(e) => return Err(e),
+        }
 
         Ok(StoreDir {
             prefix,
@@ -35,8 +86,51 @@ impl StoreDir {
         })
     }
 
-    pub async fn compute_closure(&self, _id: ManifestId) -> Option<Closure> {
-        unimplemented!()
+    /// Computes the runtime closure of `id` from the manifests present in the store.
+    ///
+    /// Returns `None` if the manifest of `id` or any of its transitive dependencies is missing.
+    pub async fn compute_closure(&self, id: ManifestId) -> Option<Closure> {
+        let mut packages = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![id.clone()];
+
+        while let Some(next) = pending.pop() {
+            if !visited.insert(next.clone()) {
+                continue;
+            }
+
+            let manifest = await!(self.manifests.read(&self.prefix, &next)).ok()??;
+            pending.extend(manifest.dependencies().cloned());
+            packages.insert(manifest);
+        }
+
+        Closure::new(id, packages).ok()
+    }
+
+    /// Reads the manifest `id` from the store, returning `None` if it is not present.
+    pub async fn read_manifest<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> Result<Option<Manifest>, StoreError> {
+        await!(self.manifests.read(&self.prefix, id))
+    }
+
+    /// Lists the IDs of every manifest which is either present on disk or registered, in no
+    /// particular order.
+    pub fn manifest_ids(&self) -> Result<Vec<ManifestId>, StoreError> {
+        self.manifests.ids(&self.prefix)
+    }
+
+    /// Returns the build logs belonging to this store.
+    #[inline]
+    pub(crate) fn logs(&self) -> BuildLogs {
+        BuildLogs::open(&self.prefix)
+    }
+
+    /// Returns the content hash the output `id` is registered with, if it is registered.
+    pub fn registered_output_hash(&self, id: &OutputId) -> Result<Option<Hash>, StoreError> {
+        let registration = Registry::open(&self.prefix).get(OutputsDir::NAME, &id.to_string())?;
+        Ok(registration.map(|registration| registration.content_hash))
     }
 
     pub fn contains_output(&self, id: &OutputId) -> bool {
@@ -44,10 +138,949 @@ impl StoreDir {
         self.outputs.contains(prefix, id)
     }
 
-    pub async fn write_manifest(&self, manifest: Manifest) -> Result<Manifest, ()> {
-        let prefix = &self.prefix;
+    /// Returns the path of the output `id`, whether or not it is present in the store.
+    #[inline]
+    pub fn output_path(&self, id: &OutputId) -> PathBuf {
+        self.object_path::<OutputsDir>(id)
+    }
+
+    /// Returns the total size of the files in the output `id`, in bytes.
+    pub fn output_size(&self, id: &OutputId) -> Result<u64, StoreError> {
+        let path = self.output_path(id);
+        archive::tree_size(&path).map_err(StoreError::at(&path))
+    }
+
+    /// Returns the path of the source `id`, or `None` if it is not present in the store.
+    pub fn source_path(&self, id: &SourceId) -> Option<PathBuf> {
+        if self.sources.contains(&self.prefix, id) {
+            Some(self.object_path::<SourcesDir>(id))
+        } else {
+            None
+        }
+    }
+
+    /// Creates a private scratch directory named `name` in `tmp`, which is removed when dropped.
+    pub async fn scratch_dir<'a>(&'a self, name: &'a str) -> Result<StagingPath, StoreError> {
+        let scratch = await!(StagingPath::new(&self.prefix, name))?;
+        fs::create_dir_all(scratch.as_path()).map_err(StoreError::at(scratch.as_path()))?;
+        Ok(scratch)
+    }
+
+    /// Moves the freshly built output located at `built` into the store as `id`.
+    ///
+    /// The output is registered with the content hash of `built` and has its permissions and
+    /// timestamps normalized on the way in. Returns `Ok(false)` without touching `built` if the
+    /// output is already present in the store.
+    pub async fn add_output<'a>(
+        &'a self,
+        id: &'a OutputId,
+        built: &'a Path,
+    ) -> Result<bool, StoreError> {
+        let hash = archive::hash_path(built).map_err(StoreError::at(built))?;
+        await!(self.outputs.insert(&self.prefix, id.clone(), built, hash))
+    }
+
+    /// Compares the rebuilt output located at `built` with the output `id` already in the store.
+    ///
+    /// Succeeds if the content hash of `built` matches the registered hash of `id`. Otherwise, the
+    /// first paths which differ between the two are reported in `StoreError::NotReproducible`.
+    pub fn compare_output(&self, id: &OutputId, built: &Path) -> Result<(), StoreError> {
+        let registry = Registry::open(&self.prefix);
+        let registration = match registry.get(OutputsDir::NAME, &id.to_string())? {
+            Some(registration) => registration,
+            None => return Err(StoreError::NotFound(Object::Output(id.clone()))),
+        };
+
+        let hash = archive::hash_path(built).map_err(StoreError::at(built))?;
+        if hash == registration.content_hash {
+            return Ok(());
+        }
+
+        let existing = self.output_path(id);
+        let differences = archive::diff_trees(&existing, built, MAX_REPORTED_DIFFERENCES)
+            .map_err(StoreError::at(built))?;
+
+        Err(StoreError::NotReproducible {
+            output: id.clone(),
+            differences,
+        })
+    }
+
+    /// Claims the source `id` for writing.
+    ///
+    /// If another job is already fetching the same source, the returned claim subscribes to the
+    /// progress of that job instead of starting a second download.
+    pub async fn claim_source(&self, id: SourceId) -> Result<Claim<SourcesDir>, StoreError> {
+        await!(self.sources.claim(&self.prefix, id))
+    }
+
+    /// Fills `writer` with an existing source whose contents are identical to those of `id`, but
+    /// which is stored under a different name.
+    ///
+    /// The existing source is hard-linked if possible and copied otherwise, and its contents are
+    /// rehashed before being reused, so a corrupted source is never passed on. Returns the ID of
+    /// the reused source, or `None` if no such source exists, in which case `id` must be fetched.
+    pub fn reuse_source(&self, writer: &mut Writer<SourcesDir>, id: &SourceId) -> Option<SourceId> {
+        // Source IDs embed the hash of their contents, so candidates are found by scanning the IDs
+        // of every source in the store, without having to read any of them.
+        let mut candidates: Vec<_> = self
+            .sources
+            .ids(&self.prefix)
+            .ok()?
+            .into_iter()
+            .filter(|existing| existing.hash() == id.hash() && existing != id)
+            .filter(|existing| self.object_path::<SourcesDir>(existing).is_file())
+            .collect();
+
+        candidates.sort();
+        candidates.into_iter().find(|existing| {
+            let path = self.object_path::<SourcesDir>(existing);
+            if writer.path().link_or_copy_from(path).is_err() {
+                return false;
+            }
+
+            let reused = writer.path().as_path();
+            match sources::hash_source(reused) {
+                Ok(ref hash) if hash == id.hash() => true,
+                _ => {
+                    let _ = fs::remove_file(reused);
+                    false
+                }
+            }
+        })
+    }
+
+    pub fn write_manifest<'a>(&'a self, manifest: Manifest) -> WriteStream<'a, Manifest> {
         let input = ManifestsInput::Manifest(manifest);
-        let (_, out) = await!(self.manifests.write(prefix, input))?;
-        Ok(out)
+        let writing = self.manifests.write(&self.prefix, input);
+        Box::pin(writing.map_ok(|state| state.map(|(_, manifest)| manifest)))
+    }
+
+    /// Writes `manifest` into the store, reporting the bytes written as `Progress::Installing`.
+    pub fn install_manifest<'a>(
+        &'a self,
+        manifest: Manifest,
+    ) -> impl Stream<Item = Result<Progress, StoreError>> + Send + 'a {
+        let package_id = manifest.compute_id();
+        let description = format!("writing manifest `{}`", package_id);
+        let mut latest = WriteProgress::default();
+
+        self.write_manifest(manifest).map_ok(move |state| {
+            if let WriteState::Progress(progress) = state {
+                latest = progress;
+            }
+
+            Progress::Installing(Installing {
+                package_id: package_id.clone(),
+                description: description.clone(),
+                written_bytes: latest.written_bytes,
+                total_bytes: latest.total_bytes,
+                timestamp: Utc::now(),
+            })
+        })
+    }
+
+    /// Exports every manifest and output in `closure` to `writer` as a portable archive.
+    ///
+    /// If `sources` is `IncludeSources::Enabled`, the sources of each manifest are exported as
+    /// well, skipping any which are not present in the store. Missing manifests and outputs are
+    /// treated as an error.
+    pub async fn export<'a, W: Write + 'a>(
+        &'a self,
+        closure: &'a Closure,
+        sources: IncludeSources,
+        writer: W,
+    ) -> Result<W, StoreError> {
+        let mut archive = ArchiveWriter::new(writer)?;
+
+        for (id, manifest) in closure.manifests() {
+            let path = self.object_path::<ManifestsDir>(id);
+            let refs = manifest.dependencies().map(ToString::to_string);
+            archive.append(Kind::Manifest, &id.to_string(), refs, path)?;
+        }
+
+        if sources == IncludeSources::Enabled {
+            let ids: BTreeSet<_> = closure
+                .manifests()
+                .flat_map(|(_, manifest)| manifest.sources().filter_map(Source::expected_id))
+                .filter(|id| self.sources.contains(&self.prefix, id))
+                .collect();
+
+            for id in ids {
+                let path = self.object_path::<SourcesDir>(&id);
+                archive.append(Kind::Source, &id.to_string(), None, path)?;
+            }
+        }
+
+        for (_, manifest) in closure.manifests() {
+            for id in manifest.outputs() {
+                let path = self.object_path::<OutputsDir>(&id);
+                let refs = manifest.output_references(&id).map(ToString::to_string);
+                archive.append(Kind::Output, &id.to_string(), refs, path)?;
+            }
+        }
+
+        Ok(archive.finish()?)
+    }
+
+    /// Imports all store objects contained in the archive read from `reader`.
+    ///
+    /// Every object is unpacked into a private staging directory under `tmp` and has its content
+    /// hash verified before anything is added to the store. Manifest IDs are recomputed from their
+    /// contents, sources are rehashed against the hash in their IDs, and all references must
+    /// resolve either to other objects in the archive or to objects already present in the store.
+    /// Only once the entire archive has been validated are the objects moved into place and
+    /// registered. If any object fails to be registered, the objects added so far are removed
+    /// again, leaving the store unchanged.
+    pub async fn import<R: Read>(&self, reader: R) -> Result<Imported, StoreError> {
+        let name = format!("import-{}", Hash::random());
+        let staging = await!(StagingPath::new(&self.prefix, &name))?;
+        fs::create_dir_all(staging.as_path()).map_err(StoreError::at(staging.as_path()))?;
+        await!(self.import_staged(reader, staging.as_path()))
+    }
+
+    async fn import_staged<'a, R: Read>(
+        &'a self,
+        reader: R,
+        staging: &'a Path,
+    ) -> Result<Imported, StoreError> {
+        let mut archive = ArchiveReader::new(reader)?;
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+
+        while let Some(header) = archive.next_entry(staged_path_for(staging, entries.len()))? {
+            if !seen.insert((header.kind, header.id.clone())) {
+                let reason = format!("duplicate entry `{}`", header.id);
+                return Err(StoreError::InvalidArchive(reason));
+            }
+
+            let staged = staged_path_for(staging, entries.len());
+            let entry = match header.kind {
+                Kind::Manifest => {
+                    let id = parse_id::<ManifestId>(&header.id)?;
+                    let text = fs::read_to_string(&staged).map_err(StoreError::at(&staged))?;
+                    let manifest: Manifest = text
+                        .parse()
+                        .map_err(|e| StoreError::InvalidManifest(format!("{}", e)))?;
+                    if manifest.compute_id() != id {
+                        let reason = format!("manifest does not match its ID `{}`", id);
+                        return Err(StoreError::InvalidManifest(reason));
+                    }
+
+                    let deps: BTreeSet<_> =
+                        manifest.dependencies().map(ToString::to_string).collect();
+                    if deps != header.references {
+                        let reason = format!("references of `{}` do not match manifest", id);
+                        return Err(StoreError::InvalidArchive(reason));
+                    }
+
+                    StagedEntry::Manifest(id)
+                }
+                Kind::Source => {
+                    // The content hash is supplied by the archive itself, so it says nothing about
+                    // whether the source is the one its ID refers to.
+                    let id = parse_id::<SourceId>(&header.id)?;
+                    let found = sources::hash_source(&staged).map_err(StoreError::at(&staged))?;
+                    if found != *id.hash() {
+                        return Err(StoreError::HashMismatch {
+                            expected: *id.hash(),
+                            object: Object::Source(id),
+                            found,
+                        });
+                    }
+
+                    StagedEntry::Source(id)
+                }
+                Kind::Output => StagedEntry::Output(parse_id(&header.id)?),
+            };
+
+            entries.push((entry, staged, header));
+        }
+
+        for (entry, _, header) in &entries {
+            for reference in &header.references {
+                let resolved = match entry {
+                    StagedEntry::Manifest(_) => {
+                        seen.contains(&(Kind::Manifest, reference.clone()))
+                            || ManifestId::from_str(reference)
+                                .map(|id| self.manifests.contains(&self.prefix, &id))
+                                .unwrap_or(false)
+                    }
+                    StagedEntry::Output(_) => {
+                        seen.contains(&(Kind::Output, reference.clone()))
+                            || OutputId::from_str(reference)
+                                .map(|id| self.outputs.contains(&self.prefix, &id))
+                                .unwrap_or(false)
+                    }
+                    StagedEntry::Source(_) => false,
+                };
+
+                if !resolved {
+                    let reason = format!("unresolved reference `{}`", reference);
+                    return Err(StoreError::InvalidArchive(reason));
+                }
+            }
+        }
+
+        let mut imported = Imported::default();
+        for (entry, staged, header) in entries {
+            let hash = header.content_hash;
+            let inserted = match entry {
+                StagedEntry::Manifest(ref id) => {
+                    await!(self
+                        .manifests
+                        .insert(&self.prefix, id.clone(), &staged, hash))
+                }
+                StagedEntry::Source(ref id) => {
+                    await!(self.sources.insert(&self.prefix, id.clone(), &staged, hash))
+                }
+                StagedEntry::Output(ref id) => {
+                    await!(self.outputs.insert(&self.prefix, id.clone(), &staged, hash))
+                }
+            };
+
+            match (inserted, entry) {
+                (Ok(false), _) => {}
+                (Ok(true), StagedEntry::Manifest(id)) => imported.manifests.push(id),
+                (Ok(true), StagedEntry::Source(id)) => imported.sources.push(id),
+                (Ok(true), StagedEntry::Output(id)) => imported.outputs.push(id),
+                (Err(e), _) => {
+                    self.rollback_import(&imported);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(imported)
+    }
+
+    /// Lists every object which is either present on disk or registered in the store.
+    pub fn objects(&self) -> Result<Vec<Object>, StoreError> {
+        let prefix = &self.prefix;
+        let manifests = self
+            .manifests
+            .ids(prefix)?
+            .into_iter()
+            .map(Object::Manifest);
+        let sources = self.sources.ids(prefix)?.into_iter().map(Object::Source);
+        let outputs = self.outputs.ids(prefix)?.into_iter().map(Object::Output);
+        Ok(manifests.chain(sources).chain(outputs).collect())
+    }
+
+    /// Checks whether `object` is consistent with its registration.
+    ///
+    /// If `check` is `CheckContents::Enabled`, the contents of `object` are rehashed and manifests
+    /// additionally have their IDs recomputed. Returns `Ok(None)` if no problem was found.
+    pub async fn check_object<'a>(
+        &'a self,
+        object: &'a Object,
+        check: CheckContents,
+    ) -> Result<Option<Problem>, StoreError> {
+        let prefix = &self.prefix;
+        match *object {
+            Object::Manifest(ref id) => {
+                let problem = await!(self.manifests.check(prefix, id, check))?;
+                let checking_id = check == CheckContents::Enabled && problem.is_none();
+                if checking_id && !self.is_valid_manifest(id) {
+                    Ok(Some(Problem::Corrupted))
+                } else {
+                    Ok(problem)
+                }
+            }
+            Object::Output(ref id) => await!(self.outputs.check(prefix, id, check)),
+            Object::Source(ref id) => await!(self.sources.check(prefix, id, check)),
+        }
+    }
+
+    /// Repairs `object` using only the contents of the store, returning the action taken.
+    ///
+    /// Stale registrations are removed, and manifests which still match their IDs are registered
+    /// again with their current hash. Everything else is moved into quarantine.
+    pub fn repair_object(&self, object: &Object, problem: Problem) -> Result<Action, StoreError> {
+        let prefix = &self.prefix;
+        match (object, problem) {
+            (Object::Manifest(id), Problem::Missing) => self.manifests.deregister(prefix, id)?,
+            (Object::Output(id), Problem::Missing) => self.outputs.deregister(prefix, id)?,
+            (Object::Source(id), Problem::Missing) => self.sources.deregister(prefix, id)?,
+            (Object::Manifest(id), _) if self.is_valid_manifest(id) => {
+                self.manifests.reregister(prefix, id)?;
+                return Ok(Action::Registered);
+            }
+            (Object::Manifest(id), _) => self.manifests.quarantine(prefix, id)?,
+            (Object::Output(id), _) => self.outputs.quarantine(prefix, id)?,
+            (Object::Source(id), _) => self.sources.quarantine(prefix, id)?,
+        }
+
+        match problem {
+            Problem::Missing => Ok(Action::Deregistered),
+            Problem::Unregistered | Problem::Corrupted => Ok(Action::Quarantined),
+        }
+    }
+
+    /// Replaces the output `id` with the tree streamed from `chunks`, as produced by
+    /// `archive::write_tree()`.
+    ///
+    /// The tree is unpacked while it is being received, and is only added to the store if it
+    /// matches the `trusted` content hash, as well as the registered hash of `id` if there is one.
+    /// If `trusted` is `None`, the source of the tree is trusted to provide the right contents.
+    /// Any existing copy of the output is kept until the new tree has taken its place.
+    pub async fn replace_output<'a, S, E>(
+        &'a self,
+        id: &'a OutputId,
+        chunks: S,
+        trusted: Option<Hash>,
+    ) -> Result<(), StoreError>
+    where
+        S: Stream<Item = Result<Vec<u8>, E>> + Unpin + 'a,
+        StoreError: From<E>,
+    {
+        let name = format!("fetch-{}", Hash::random());
+        let staging = await!(StagingPath::new(&self.prefix, &name))?;
+        let hash = await!(unpack_tree(chunks, staging.as_path()))?;
+
+        let registry = Registry::open(&self.prefix);
+        let registered = registry.get(OutputsDir::NAME, &id.to_string())?;
+        let expected = registered.map(|registration| registration.content_hash);
+        for expected in expected.into_iter().chain(trusted) {
+            if expected != hash {
+                return Err(StoreError::HashMismatch {
+                    object: Object::Output(id.clone()),
+                    expected,
+                    found: hash,
+                });
+            }
+        }
+
+        await!(self
+            .outputs
+            .replace(&self.prefix, id.clone(), staging.as_path(), hash))
+    }
+
+    /// Returns whether the manifest stored as `id` still parses and hashes to `id`.
+    fn is_valid_manifest(&self, id: &ManifestId) -> bool {
+        let path = self.object_path::<ManifestsDir>(id);
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|text| text.parse::<Manifest>().ok())
+            .map(|manifest| manifest.compute_id() == *id)
+            .unwrap_or(false)
+    }
+
+    /// Removes all objects which were added by a failed import.
+    fn rollback_import(&self, imported: &Imported) {
+        for id in &imported.outputs {
+            let _ = self.outputs.remove(&self.prefix, id);
+        }
+
+        for id in &imported.sources {
+            let _ = self.sources.remove(&self.prefix, id);
+        }
+
+        for id in &imported.manifests {
+            let _ = self.manifests.remove(&self.prefix, id);
+        }
+    }
+
+    fn object_path<D: Directory>(&self, id: &D::Id) -> PathBuf {
+        self.prefix.join(D::NAME).join(id.to_path())
+    }
+}
+
+/// Store object which has been unpacked and verified, but not yet added to the store.
+#[derive(Debug)]
+enum StagedEntry {
+    Manifest(ManifestId),
+    Source(SourceId),
+    Output(OutputId),
+}
+
+/// Unpacks the tree streamed from `chunks` into `dest` while it is being received, returning the
+/// hash of its contents.
+///
+/// Unpacking blocks on I/O, so it runs on a thread of its own, fed through a bounded channel so
+/// that only a few chunks are held in memory at a time.
+async fn unpack_tree<S, E>(mut chunks: S, dest: &Path) -> Result<Hash, StoreError>
+where
+    S: Stream<Item = Result<Vec<u8>, E>> + Unpin,
+    StoreError: From<E>,
+{
+    let (mut tx, rx) = mpsc::channel(UNPACK_BUFFER_CHUNKS);
+    let (done_tx, done_rx) = oneshot::channel();
+    let path = dest.to_owned();
+    thread::spawn(move || {
+        let mut reader = ChunkReader::new(executor::block_on_stream(rx));
+        let unpacked = archive::read_tree(&mut reader, &path).map_err(StoreError::at(&path));
+        let _ = done_tx.send(unpacked);
+    });
+
+    let mut failed = None;
+    while let Some(chunk) = await!(chunks.next()) {
+        match chunk {
+            // the unpacking thread stops receiving once it has failed, and reports why below.
+            Ok(chunk) => {
+                if await!(tx.send(chunk)).is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                failed = Some(StoreError::from(err));
+                break;
+            }
+        }
+    }
+
+    // the thread must be done writing into `dest` before returning, even if the download failed.
+    drop(tx);
+    let unpacked = await!(done_rx);
+    if let Some(err) = failed {
+        return Err(err);
+    }
+
+    unpacked.unwrap_or_else(|_| {
+        let err = io::Error::new(io::ErrorKind::Other, "unpacking thread panicked");
+        Err(StoreError::Path(dest.to_owned(), err))
+    })
+}
+
+/// Reader over a sequence of chunks of bytes.
+struct ChunkReader<I> {
+    chunks: I,
+    current: Cursor<Vec<u8>>,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> ChunkReader<I> {
+    fn new(chunks: I) -> Self {
+        ChunkReader {
+            chunks,
+            current: Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.chunks.next() {
+                Some(chunk) => self.current = Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+fn staged_path_for(staging: &Path, index: usize) -> PathBuf {
+    staging.join(index.to_string())
+}
+
+fn parse_id<T: FromStr>(id: &str) -> Result<T, StoreError> {
+    T::from_str(id).map_err(|_| StoreError::InvalidId(id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_preview::stream;
+
+    use super::sources::SourceInput;
+    use super::*;
+    use crate::local::test_util::{run, temp_store, TempDir};
+
+    const HASH: &str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+    /// Adds a package named `name` which depends on `deps` to `store`, with a default output
+    /// containing a single file. The output is first built in `scratch`.
+    async fn add_package<'a>(
+        store: &'a StoreDir,
+        scratch: &'a Path,
+        name: &'a str,
+        deps: Vec<ManifestId>,
+    ) -> ManifestId {
+        let manifest = deps
+            .into_iter()
+            .fold(Manifest::build(name, "1.0.0", HASH, None), |m, dep| {
+                m.dependency(dep)
+            })
+            .finish()
+            .expect("Failed to create manifest");
+
+        let id = manifest.compute_id();
+        let mut writing = store.write_manifest(manifest.clone());
+        while let Some(state) = await!(writing.next()) {
+            state.expect("Failed to write manifest");
+        }
+
+        for output in manifest.outputs() {
+            let built = scratch.join(output.to_string());
+            fs::create_dir_all(built.join("bin")).expect("Failed to create output");
+            fs::write(built.join("bin").join(name), name).expect("Failed to write output");
+            await!(store.add_output(&output, &built)).expect("Failed to add output");
+        }
+
+        id
+    }
+
+    /// Packs a tree containing a single file, returning its output ID, its hash and the tree
+    /// split into small chunks.
+    fn packed_tree(scratch: &Path) -> (OutputId, Hash, Vec<Vec<u8>>) {
+        let manifest = Manifest::build("foo", "1.0.0", HASH, None)
+            .finish()
+            .expect("Failed to create manifest");
+        let output = manifest.outputs().next().expect("Manifest has no outputs");
+
+        let tree = scratch.join("tree");
+        fs::create_dir_all(tree.join("bin")).expect("Failed to create tree");
+        fs::write(tree.join("bin").join("foo"), "#!/bin/sh\necho foo\n").expect("Failed to write");
+
+        let hash = archive::hash_path(&tree).expect("Failed to hash tree");
+        let mut packed = Vec::new();
+        archive::write_tree(&mut packed, &tree).expect("Failed to pack tree");
+        let chunks = packed.chunks(7).map(<[u8]>::to_vec).collect();
+        (output, hash, chunks)
+    }
+
+    #[test]
+    fn fails_to_open_stores_which_cannot_be_recovered() {
+        let temp = TempDir::new("unrecoverable");
+        let tmp = temp.path().join(crate::local::TEMP_DIR_NAME);
+        fs::write(&tmp, b"not a directory").expect("Failed to create file");
+
+        match StoreDir::open(temp.path().to_owned()) {
+            Err(StoreError::Path(path, _)) => assert!(path.ends_with(crate::local::TEMP_DIR_NAME)),
+            other => panic!("expected recovery to fail, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replaces_outputs_from_streamed_chunks() {
+        let (_temp, store) = temp_store("replace");
+        let scratch = TempDir::new("replace-scratch");
+        let (output, hash, chunks) = packed_tree(scratch.path());
+
+        run(async move {
+            let chunks = stream::iter(chunks.into_iter().map(Ok::<_, io::Error>));
+            await!(store.replace_output(&output, chunks, Some(hash))).expect("Failed to replace");
+
+            assert_eq!(store.registered_output_hash(&output).unwrap(), Some(hash));
+            let file = store.output_path(&output).join("bin").join("foo");
+            assert_eq!(fs::read_to_string(file).unwrap(), "#!/bin/sh\necho foo\n");
+        });
+    }
+
+    #[test]
+    fn rejects_trees_which_do_not_match_trusted_hash() {
+        let (_temp, store) = temp_store("replace");
+        let scratch = TempDir::new("replace-scratch");
+        let (output, hash, chunks) = packed_tree(scratch.path());
+
+        run(async move {
+            let trusted = Hash::random();
+            let chunks = stream::iter(chunks.into_iter().map(Ok::<_, io::Error>));
+            match await!(store.replace_output(&output, chunks, Some(trusted))) {
+                Err(StoreError::HashMismatch {
+                    expected, found, ..
+                }) => {
+                    assert_eq!(expected, trusted);
+                    assert_eq!(found, hash);
+                }
+                other => panic!("expected a hash mismatch, got: {:?}", other),
+            }
+
+            assert!(!store.contains_output(&output));
+            assert_eq!(store.registered_output_hash(&output).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn failed_downloads_are_not_added() {
+        let (_temp, store) = temp_store("replace");
+        let scratch = TempDir::new("replace-scratch");
+        let (output, hash, chunks) = packed_tree(scratch.path());
+
+        run(async move {
+            let first = chunks.into_iter().next().expect("Tree is empty");
+            let broken = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset");
+            let chunks = stream::iter(vec![Ok(first), Err(broken)]);
+            let replaced = await!(store.replace_output(&output, chunks, Some(hash)));
+            assert!(replaced.is_err(), "{:?}", replaced);
+            assert!(!store.contains_output(&output));
+
+            let tmp = store.prefix.join(crate::local::TEMP_DIR_NAME);
+            let leftovers = fs::read_dir(&tmp).map(Iterator::count).unwrap_or(0);
+            assert_eq!(leftovers, 0);
+        });
+    }
+
+    #[test]
+    fn failed_replacements_keep_the_existing_output() {
+        let (_temp, store) = temp_store("replace");
+        let scratch = TempDir::new("replace-scratch");
+        let (output, hash, chunks) = packed_tree(scratch.path());
+        let missing = scratch.path().join("missing");
+
+        run(async move {
+            let chunks = stream::iter(chunks.into_iter().map(Ok::<_, io::Error>));
+            await!(store.replace_output(&output, chunks, Some(hash))).expect("Failed to replace");
+
+            let replaced =
+                await!(store
+                    .outputs
+                    .replace(&store.prefix, output.clone(), &missing, hash));
+            assert!(replaced.is_err(), "{:?}", replaced);
+
+            assert_eq!(store.registered_output_hash(&output).unwrap(), Some(hash));
+            let file = store.output_path(&output).join("bin").join("foo");
+            assert_eq!(fs::read_to_string(file).unwrap(), "#!/bin/sh\necho foo\n");
+        });
+    }
+
+    /// Returns the bytes written after each progress item in `states`, and the final value.
+    fn progress_of<T>(states: Vec<Result<WriteState<T>, StoreError>>) -> (Vec<u64>, T) {
+        let mut written = Vec::new();
+        let mut done = None;
+        for state in states {
+            match state.expect("Failed to write object") {
+                WriteState::Progress(progress) => written.push(progress.written_bytes),
+                WriteState::Done(value) => done = Some(value),
+            }
+        }
+
+        (written, done.expect("Write did not finish"))
+    }
+
+    #[test]
+    fn writes_sources_with_streamed_progress() {
+        let (temp, store) = temp_store("write-source");
+        let contents = vec![42u8; 200_000];
+        let path = temp.path().join("foo.tar.gz");
+        fs::write(&path, &contents).expect("Failed to write source");
+
+        let hash = Hash::compute().input(&contents).finish();
+        let source = Source::Path {
+            path: path.clone(),
+            hash: hash.to_string(),
+        };
+        let expected = source.expected_id().expect("Source has no ID");
+
+        let (states, store) = run(async move {
+            let input = SourceInput::Path(source, path);
+            let states = await!(store
+                .sources
+                .write(&store.prefix, input)
+                .collect::<Vec<_>>());
+            (states, store)
+        });
+
+        let (written, (id, output)) = progress_of(states);
+        assert!(written.len() > 1, "{:?}", written);
+        assert!(written.windows(2).all(|w| w[0] < w[1]), "{:?}", written);
+        assert_eq!(written.last(), Some(&(contents.len() as u64)));
+
+        assert_eq!(id, expected);
+        assert_eq!(store.source_path(&id), Some(output.clone()));
+        assert_eq!(fs::read(output).unwrap(), contents);
+    }
+
+    #[test]
+    fn rejects_sources_which_do_not_match_their_hash() {
+        let (temp, store) = temp_store("write-source");
+        let path = temp.path().join("foo.tar.gz");
+        fs::write(&path, b"foo").expect("Failed to write source");
+
+        let source = Source::Path {
+            path: path.clone(),
+            hash: HASH.to_string(),
+        };
+        let expected = source.expected_id().expect("Source has no ID");
+
+        let (states, store) = run(async move {
+            let input = SourceInput::Path(source, path);
+            let states = await!(store
+                .sources
+                .write(&store.prefix, input)
+                .collect::<Vec<_>>());
+            (states, store)
+        });
+
+        match states.last() {
+            Some(Err(StoreError::HashMismatch { .. })) => {}
+            other => panic!("expected a hash mismatch, got: {:?}", other),
+        }
+
+        assert_eq!(store.source_path(&expected), None);
+    }
+
+    #[test]
+    fn writes_outputs_with_streamed_progress() {
+        let (temp, store) = temp_store("write-output");
+        let (output, hash, _) = packed_tree(temp.path());
+        let tree = temp.path().join("tree");
+        let size = archive::tree_size(&tree).expect("Failed to measure tree");
+
+        let (states, store) = run(async move {
+            let input = (output.clone(), tree);
+            let states = await!(store
+                .outputs
+                .write(&store.prefix, input)
+                .collect::<Vec<_>>());
+            (states, store)
+        });
+
+        let (written, (id, path)) = progress_of(states);
+        assert_eq!(written.last(), Some(&size));
+        assert_eq!(path, store.output_path(&id));
+        assert_eq!(store.registered_output_hash(&id).unwrap(), Some(hash));
+    }
+
+    /// Writes a source named `name` containing `text` into `store`.
+    async fn add_text_source<'a>(store: &'a StoreDir, name: &'a str, text: &'a str) -> SourceId {
+        let input = SourceInput::Text(name.to_string(), text.to_string());
+        let mut writing = store.sources.write(&store.prefix, input);
+        let mut id = None;
+        while let Some(state) = await!(writing.next()) {
+            if let WriteState::Done((written, _)) = state.expect("Failed to write source") {
+                id = Some(written);
+            }
+        }
+
+        id.expect("Source was not written")
+    }
+
+    /// Claims the source named `name` with the same hash as `existing` and tries to reuse
+    /// `existing` for it, returning the reused ID and whether the claimed source was filled in.
+    async fn reuse_as<'a>(
+        store: &'a StoreDir,
+        existing: &'a SourceId,
+        name: &'a str,
+    ) -> (Option<SourceId>, bool) {
+        let id = SourceId::new(name.to_string(), *existing.hash()).expect("Invalid source ID");
+        match await!(store.claim_source(id.clone())).expect("Failed to claim source") {
+            Claim::Write(mut writer) => {
+                let reused = store.reuse_source(&mut writer, &id);
+                (reused, writer.path().as_path().is_file())
+            }
+            _ => panic!("expected `{}` to be missing", id),
+        }
+    }
+
+    #[test]
+    fn reuses_sources_with_the_same_hash() {
+        let (_temp, store) = temp_store("reuse-source");
+
+        run(async move {
+            let foo = await!(add_text_source(&store, "foo.tar.gz", "contents"));
+            let (reused, filled) = await!(reuse_as(&store, &foo, "bar.tar.gz"));
+            assert_eq!(reused, Some(foo));
+            assert!(filled);
+        });
+    }
+
+    #[test]
+    fn corrupted_sources_are_not_reused() {
+        let (_temp, store) = temp_store("reuse-source");
+
+        run(async move {
+            let foo = await!(add_text_source(&store, "foo.tar.gz", "contents"));
+            let path = store.source_path(&foo).expect("Source is missing");
+            let mut permissions = fs::metadata(&path).unwrap().permissions();
+            permissions.set_readonly(false);
+            fs::set_permissions(&path, permissions).unwrap();
+            fs::write(&path, "tampered").unwrap();
+
+            let (reused, filled) = await!(reuse_as(&store, &foo, "bar.tar.gz"));
+            assert_eq!(reused, None);
+            assert!(!filled);
+        });
+    }
+
+    /// Exports the closure of a package `bar` depending on `foo` from `store`.
+    async fn export_closure<'a>(store: &'a StoreDir) -> (Vec<ManifestId>, Vec<u8>) {
+        let scratch = TempDir::new("export-scratch");
+        let foo = await!(add_package(store, scratch.path(), "foo", Vec::new()));
+        let bar = await!(add_package(store, scratch.path(), "bar", vec![foo.clone()]));
+
+        let closure = await!(store.compute_closure(bar.clone())).expect("Closure is missing");
+        let archive = await!(store.export(&closure, IncludeSources::Disabled, Vec::new()));
+        let mut ids = vec![foo, bar];
+        ids.sort();
+        (ids, archive.expect("Failed to export closure"))
+    }
+
+    #[test]
+    fn exported_closure_round_trips() {
+        let (_source_dir, source) = temp_store("export");
+        let (_dest_dir, dest) = temp_store("import");
+
+        run(async move {
+            let (ids, archive) = await!(export_closure(&source));
+            let imported = await!(dest.import(archive.as_slice())).expect("Failed to import");
+
+            let mut manifests = imported.manifests.clone();
+            manifests.sort();
+            assert_eq!(manifests, ids);
+            for id in &ids {
+                let manifest = await!(dest.read_manifest(id)).expect("Failed to read manifest");
+                assert_eq!(manifest.map(|m| m.compute_id()).as_ref(), Some(id));
+            }
+
+            assert_eq!(imported.outputs.len(), 2);
+            for output in &imported.outputs {
+                let expected = source.registered_output_hash(output).unwrap();
+                assert!(expected.is_some());
+                assert_eq!(dest.registered_output_hash(output).unwrap(), expected);
+                assert!(dest.output_path(output).join("bin").is_dir());
+            }
+
+            let again = await!(dest.import(archive.as_slice())).expect("Failed to import again");
+            assert_eq!(again, Imported::default());
+        });
+    }
+
+    #[test]
+    fn failed_import_is_rolled_back() {
+        let (_source_dir, source) = temp_store("export");
+        let (dest_dir, dest) = temp_store("import");
+
+        // Outputs are imported after manifests, so an unusable outputs directory makes the import
+        // fail halfway through.
+        fs::write(dest_dir.path().join(OutputsDir::NAME), b"").expect("Failed to block outputs");
+
+        run(async move {
+            let (ids, archive) = await!(export_closure(&source));
+            let result = await!(dest.import(archive.as_slice()));
+            assert!(result.is_err());
+
+            assert!(dest.manifest_ids().unwrap().is_empty());
+            for id in &ids {
+                assert!(!dest.manifests.contains(&dest.prefix, id));
+            }
+        });
+    }
+
+    #[test]
+    fn rejects_archives_with_tampered_sources() {
+        let (temp, store) = temp_store("import");
+        let hash = Hash::compute().input("contents").finish();
+        let id = SourceId::new("foo.tar.gz".to_string(), hash).expect("Invalid source ID");
+        let tampered = temp.path().join("foo.tar.gz");
+        fs::write(&tampered, "tampered").expect("Failed to write source");
+
+        let mut archive = ArchiveWriter::new(Vec::new()).expect("Failed to start archive");
+        archive
+            .append(Kind::Source, &id.to_string(), None, &tampered)
+            .expect("Failed to append source");
+        let archive = archive.finish().expect("Failed to finish archive");
+
+        run(async move {
+            match await!(store.import(archive.as_slice())) {
+                Err(StoreError::HashMismatch { expected, .. }) => assert_eq!(expected, hash),
+                other => panic!("expected a hash mismatch, got: {:?}", other),
+            }
+
+            assert_eq!(store.source_path(&id), None);
+        });
     }
 }
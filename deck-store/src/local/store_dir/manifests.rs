@@ -1,14 +1,15 @@
 use std::path::PathBuf;
 
 use deck_core::{Manifest, ManifestId};
-use futures_preview::compat::{Compat01As03, Future01CompatExt};
-use futures_preview::future::FutureExt;
-use futures_preview::io::AsyncWriteExt;
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::future::{self, FutureExt, TryFutureExt};
+use futures_preview::stream::{self, StreamExt, TryStreamExt};
 use tokio::fs::File;
 use tokio::io::{ErrorKind, Read};
 
-use crate::local::dir::{DirFuture, Directory, ReadPath, WritePath};
+use crate::local::dir::{self, DirFuture, Directory, ReadPath, WritePath, WriteState, WriteStream};
 use crate::local::file::FileFutureExt;
+use crate::StoreError;
 
 #[derive(Clone, Debug)]
 pub enum ManifestsInput {
@@ -33,14 +34,16 @@ impl Directory for ManifestsDir {
                 ManifestsInput::Manifest(ref manifest) => Ok(manifest.compute_id()),
                 ManifestsInput::Path(ref path) => {
                     let p = path.to_owned();
-                    let mut file = await!(File::open(p).lock_shared().compat()).map_err(|_| ())?;
+                    let locked = File::open(p).lock_shared().compat();
+                    let mut file = await!(locked).map_err(StoreError::at(path))?;
                     let mut text = String::new();
-                    file.read_to_string(&mut text).map_err(|_| ())?;
-                    let manifest: Manifest = text.parse().map_err(|_| ())?;
+                    file.read_to_string(&mut text)
+                        .map_err(StoreError::at(path))?;
+                    let manifest = parse_manifest(&text)?;
                     Ok(manifest.compute_id())
                 }
                 ManifestsInput::Text(ref text) => {
-                    let manifest: Manifest = text.parse().map_err(|_| ())?;
+                    let manifest = parse_manifest(text)?;
                     Ok(manifest.compute_id())
                 }
             }
@@ -53,8 +56,9 @@ impl Directory for ManifestsDir {
         let future = async move {
             let mut file = await!(path.open_file())?;
             let mut s = String::new();
-            file.read_to_string(&mut s).map_err(|_| ())?;
-            let manifest: Manifest = s.parse().map_err(|_| ())?;
+            file.read_to_string(&mut s)
+                .map_err(StoreError::at(path.as_path()))?;
+            let manifest = parse_manifest(&s)?;
             Ok(manifest.compute_id())
         };
 
@@ -65,11 +69,12 @@ impl Directory for ManifestsDir {
         let future = async move {
             match await!(path.open_file()) {
                 // Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
-                Err(_) => Err(()),
+                Err(e) => Err(e),
                 Ok(mut file) => {
                     let mut s = String::new();
-                    file.read_to_string(&mut s).map_err(|_| ())?;
-                    let manifest = s.parse().map_err(|_| ())?;
+                    file.read_to_string(&mut s)
+                        .map_err(StoreError::at(path.as_path()))?;
+                    let manifest = parse_manifest(&s)?;
                     Ok(Some(manifest))
                 }
             }
@@ -82,37 +87,49 @@ impl Directory for ManifestsDir {
         &'a self,
         path: &'a mut WritePath,
         input: Self::Input,
-    ) -> DirFuture<'a, Self::Output> {
+    ) -> WriteStream<'a, Self::Output> {
         let future = async move {
-            println!("doing the thing... {}", path.display());
-            let mut file = Compat01As03::new(await!(path.create_file())?);
-            println!("succeeded");
-            match input {
+            let (manifest, toml) = match input {
                 ManifestsInput::Manifest(manifest) => {
                     let toml = manifest.to_string();
-                    await!(file.write_all(toml.as_bytes())).map_err(|_| ())?;
-                    Ok(manifest)
+                    (manifest, toml)
                 }
                 ManifestsInput::Path(p) => {
                     let toml = {
-                        let mut src =
-                            await!(File::open(p).lock_shared().compat()).map_err(|_| ())?;
+                        let locked = File::open(p.clone()).lock_shared().compat();
+                        let mut src = await!(locked).map_err(StoreError::at(&p))?;
                         let mut toml = String::new();
-                        src.read_to_string(&mut toml).map_err(|_| ())?;
+                        src.read_to_string(&mut toml).map_err(StoreError::at(&p))?;
                         toml
                     };
-                    let manifest = toml.parse().map_err(|_| ())?;
-                    await!(file.write_all(toml.as_bytes())).map_err(|_| ())?;
-                    Ok(manifest)
+                    let manifest = parse_manifest(&toml)?;
+                    (manifest, toml)
                 }
                 ManifestsInput::Text(text) => {
-                    let manifest = text.parse().map_err(|_| ())?;
-                    await!(file.write_all(text.as_bytes())).map_err(|_| ())?;
-                    Ok(manifest)
+                    let manifest = parse_manifest(&text)?;
+                    (manifest, text)
                 }
-            }
+            };
+
+            let file = await!(path.create_file())?;
+            let bytes = toml.into_bytes();
+            let total_bytes = Some(bytes.len() as u64);
+            let chunks = stream::once(future::ok(bytes));
+            let written = dir::write_chunked(file, chunks, total_bytes);
+            let done = stream::once(future::ok(WriteState::Done(manifest)));
+            Ok(written.map_ok(WriteState::Progress).chain(done))
         };
 
-        future.boxed()
+        let stream = future
+            .map_ok(|stream| Box::pin(stream) as WriteStream<'a, Self::Output>)
+            .unwrap_or_else(|err| Box::pin(stream::once(future::err(err))))
+            .flatten_stream();
+
+        Box::pin(stream)
     }
 }
+
+fn parse_manifest(text: &str) -> Result<Manifest, StoreError> {
+    text.parse()
+        .map_err(|e| StoreError::InvalidManifest(format!("{}", e)))
+}
@@ -1,36 +1,53 @@
 use std::path::PathBuf;
 
 use deck_core::OutputId;
+use futures_preview::future::{self, FutureExt};
+use futures_preview::stream::{self, StreamExt, TryStreamExt};
 
-use crate::local::dir::{DirFuture, Directory, ReadPath, WritePath};
+use crate::local::dir::{self, DirFuture, Directory, ReadPath, WritePath, WriteState, WriteStream};
+use crate::StoreError;
 
 #[derive(Debug)]
 pub struct OutputsDir;
 
 impl Directory for OutputsDir {
     type Id = OutputId;
-    type Input = PathBuf;
+    /// Output to write, along with the path of the tree it was built in.
+    type Input = (OutputId, PathBuf);
     type Output = PathBuf;
 
     const NAME: &'static str = "outputs";
 
-    fn precompute_id<'a>(&'a self, _input: &'a Self::Input) -> DirFuture<'a, Self::Id> {
-        unimplemented!()
+    fn precompute_id<'a>(&'a self, input: &'a Self::Input) -> DirFuture<'a, Self::Id> {
+        future::ok(input.0.clone()).boxed()
     }
 
-    fn compute_id<'a>(&'a self, _path: &'a ReadPath) -> DirFuture<'a, Self::Id> {
-        unimplemented!()
+    /// Output IDs are derived from the manifest which builds them rather than from their contents,
+    /// so the ID an output was written under is final.
+    fn compute_id<'a>(&'a self, path: &'a ReadPath) -> DirFuture<'a, Self::Id> {
+        let id = path
+            .as_id()
+            .parse()
+            .map_err(|_| StoreError::InvalidId(path.as_id().to_string()));
+        future::ready(id).boxed()
     }
 
-    fn read<'a>(&'a self, _path: &'a ReadPath) -> DirFuture<'a, Option<Self::Output>> {
-        unimplemented!()
+    fn read<'a>(&'a self, path: &'a ReadPath) -> DirFuture<'a, Option<Self::Output>> {
+        if path.exists() {
+            future::ok(Some(path.as_path().to_owned())).boxed()
+        } else {
+            future::ok(None).boxed()
+        }
     }
 
     fn write<'a>(
         &'a self,
-        _path: &'a mut WritePath,
-        _input: Self::Input,
-    ) -> DirFuture<'a, Self::Output> {
-        unimplemented!()
+        path: &'a mut WritePath,
+        (_, built): Self::Input,
+    ) -> WriteStream<'a, Self::Output> {
+        let output = path.final_path().to_owned();
+        let done = stream::once(future::ok(WriteState::Done(output)));
+        let copied = dir::copy_chunked(built, path.as_path().to_owned());
+        Box::pin(copied.map_ok(WriteState::Progress).chain(done))
     }
 }
@@ -1,9 +1,18 @@
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
 use deck_core::{Hash, Source, SourceId};
-use futures_preview::future::{self, FutureExt};
+use futures_preview::future::{self, FutureExt, TryFutureExt};
+use futures_preview::stream::{self, StreamExt, TryStreamExt};
 
-use crate::local::dir::{DirFuture, Directory, ReadPath, WritePath};
+use crate::local::archive;
+use crate::local::dir::{self, DirFuture, Directory, ReadPath, WritePath, WriteState, WriteStream};
+use crate::verify::Object;
+use crate::StoreError;
+
+/// Size of the chunks read while hashing a source file.
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Clone, Debug)]
 pub enum SourceInput {
@@ -24,10 +33,13 @@ impl Directory for SourcesDir {
     fn precompute_id<'a>(&'a self, input: &'a Self::Input) -> DirFuture<'a, Self::Id> {
         let future = async move {
             match input {
-                SourceInput::Path(_, _) => unimplemented!(),
+                SourceInput::Path(ref source, ref path) => source
+                    .expected_id()
+                    .ok_or_else(|| StoreError::InvalidSource(path.display().to_string())),
                 SourceInput::Text(ref name, ref text) => {
                     let hash = Hash::compute().input(&text).finish();
-                    let id = SourceId::new(name.clone(), hash)?;
+                    let id = SourceId::new(name.clone(), hash)
+                        .map_err(|_| StoreError::InvalidSource(name.clone()))?;
                     Ok(id)
                 }
             }
@@ -36,8 +48,27 @@ impl Directory for SourcesDir {
         future.boxed()
     }
 
-    fn compute_id<'a>(&'a self, _path: &'a ReadPath) -> DirFuture<'a, Self::Id> {
-        unimplemented!()
+    /// Rehashes the written source, which must match the hash of the ID it was written under.
+    fn compute_id<'a>(&'a self, path: &'a ReadPath) -> DirFuture<'a, Self::Id> {
+        let future = async move {
+            let id: SourceId = path
+                .as_id()
+                .parse()
+                .map_err(|_| StoreError::InvalidId(path.as_id().to_string()))?;
+
+            let found = hash_source(path.as_path()).map_err(StoreError::at(path.as_path()))?;
+            if found != *id.hash() {
+                return Err(StoreError::HashMismatch {
+                    expected: *id.hash(),
+                    object: Object::Source(id),
+                    found,
+                });
+            }
+
+            Ok(id)
+        };
+
+        future.boxed()
     }
 
     fn read<'a>(&'a self, path: &'a ReadPath) -> DirFuture<'a, Option<Self::Output>> {
@@ -50,9 +81,55 @@ impl Directory for SourcesDir {
 
     fn write<'a>(
         &'a self,
-        _path: &'a mut WritePath,
-        _input: Self::Input,
-    ) -> DirFuture<'a, Self::Output> {
-        unimplemented!()
+        path: &'a mut WritePath,
+        input: Self::Input,
+    ) -> WriteStream<'a, Self::Output> {
+        let output = path.final_path().to_owned();
+        let done = stream::once(future::ok(WriteState::Done(output)));
+
+        match input {
+            SourceInput::Path(_, source) => {
+                let dest = path.as_path().to_owned();
+                let copied = dir::copy_chunked(source, dest);
+                Box::pin(copied.map_ok(WriteState::Progress).chain(done))
+            }
+            SourceInput::Text(_, text) => {
+                let future = async move {
+                    let file = await!(path.create_file())?;
+                    let bytes = text.into_bytes();
+                    let total_bytes = Some(bytes.len() as u64);
+                    let chunks = stream::once(future::ok(bytes));
+                    let written = dir::write_chunked(file, chunks, total_bytes);
+                    Ok(written.map_ok(WriteState::Progress).chain(done))
+                };
+
+                let stream = future
+                    .map_ok(|stream| Box::pin(stream) as WriteStream<'a, Self::Output>)
+                    .unwrap_or_else(|err| Box::pin(stream::once(future::err(err))))
+                    .flatten_stream();
+
+                Box::pin(stream)
+            }
+        }
+    }
+}
+
+/// Computes the hash embedded in the ID of the source located at `path`.
+///
+/// Source files are hashed by their raw contents, the same way they are hashed when downloaded,
+/// while directories are hashed the same way as any other store object.
+pub(super) fn hash_source(path: &Path) -> io::Result<Hash> {
+    if path.is_dir() {
+        return archive::hash_path(path);
+    }
+
+    let mut file = File::open(path)?;
+    let mut hasher = Hash::compute();
+    let mut buf = vec![0; HASH_CHUNK_SIZE];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finish()),
+            len => hasher = hasher.input(&buf[..len]),
+        }
     }
 }
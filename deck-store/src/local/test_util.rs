@@ -0,0 +1,54 @@
+//! Helpers shared by the unit tests of the local store.
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use deck_core::Hash;
+use futures_preview::future::{FutureExt, TryFutureExt};
+use tokio::runtime::Runtime;
+
+use super::store_dir::StoreDir;
+
+/// Temporary directory which is removed along with its contents when dropped.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a new empty directory in the system temporary directory, named after `name`.
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("deck-{}-test-{}", name, Hash::random()));
+        fs::create_dir_all(&path).expect("Failed to create temp dir");
+        TempDir(path)
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Opens a new empty store in a temporary directory named after `name`.
+pub fn temp_store(name: &str) -> (TempDir, StoreDir) {
+    let temp = TempDir::new(name);
+    let store = StoreDir::open(temp.path().to_owned()).expect("Failed to open store");
+    (temp, store)
+}
+
+/// Runs `future` to completion on a new `tokio` runtime, which file system operations require.
+pub fn run<F, T>(future: F) -> T
+where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut runtime = Runtime::new().expect("Failed to start runtime");
+    let future = future.map(Ok::<T, ()>).boxed().compat();
+    runtime.block_on(future).expect("Future failed")
+}
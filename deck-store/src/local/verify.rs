@@ -0,0 +1,447 @@
+//! Verification and repair of the objects in a local store.
+//!
+//! Every object found on disk or in the registry is checked in turn, and the result for each one
+//! is reported through a `VerifyStream` as soon as it is known. When repairing, missing or corrupt
+//! outputs are fetched again from the configured binary caches if possible, and verified against
+//! their registered hash. Inconsistent sources are quarantined and downloaded again from the URI
+//! declared by any manifest in the store which needs them. Any other inconsistent object is either
+//! registered again, if it can be proven valid, or moved into quarantine.
+
+use deck_core::{OutputId, SourceId};
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::future;
+use futures_preview::stream::{self, StreamExt};
+
+use super::builder::FetchSource;
+use super::context::Context;
+use crate::verify::{Action, Invalid, Object, Repaired, Verified};
+use crate::{CheckContents, Repair, StoreError, VerifyStream};
+
+/// Verifies every object in the store of `ctx`, using its binary caches and HTTP client to repair
+/// corrupt outputs and sources.
+pub fn verify<'a>(ctx: &'a Context, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+    let objects = match ctx.store.objects() {
+        Ok(objects) => objects,
+        Err(err) => return VerifyStream::new(stream::once(future::err(err))),
+    };
+
+    let results = stream::unfold(objects.into_iter(), move |mut objects| async move {
+        let object = match objects.next() {
+            Some(object) => object,
+            None => return None,
+        };
+
+        let result = await!(verify_object(ctx, object, check, repair));
+        Some((result, objects))
+    });
+
+    VerifyStream::new(results)
+}
+
+async fn verify_object<'a>(
+    ctx: &'a Context,
+    object: Object,
+    check: CheckContents,
+    repair: Repair,
+) -> Result<Verified, StoreError> {
+    let store = &ctx.store;
+    let problem = match await!(store.check_object(&object, check))? {
+        Some(problem) => problem,
+        None => return Ok(Verified::Checked(object)),
+    };
+
+    if repair == Repair::Disabled {
+        return Ok(Verified::Invalid(Invalid { object, problem }));
+    }
+
+    let action = match object {
+        Object::Output(ref id) if await!(refetch_output(ctx, id)) => Action::Refetched,
+        Object::Source(ref id) => {
+            // The source must be out of the way before it can be downloaded again.
+            let action = store.repair_object(&object, problem)?;
+            if await!(refetch_source(ctx, id)) {
+                Action::Refetched
+            } else {
+                action
+            }
+        }
+        _ => store.repair_object(&object, problem)?,
+    };
+
+    Ok(Verified::Repaired(Repaired {
+        object,
+        problem,
+        action,
+    }))
+}
+
+/// Attempts to replace the output `id` with a valid copy from one of the binary caches.
+///
+/// Returns `true` if the output was replaced successfully, or `false` if no cache could provide
+/// a copy matching the registered hash. Outputs which are not registered are never refetched,
+/// since there is no hash to verify their copies against.
+async fn refetch_output<'a>(ctx: &'a Context, id: &'a OutputId) -> bool {
+    let trusted = match ctx.store.registered_output_hash(id) {
+        Ok(Some(hash)) => hash,
+        Ok(None) | Err(_) => return false,
+    };
+
+    for cache in ctx.binary_caches.iter() {
+        let mut cache = match await!(cache.lock().compat()) {
+            Ok(cache) => cache,
+            Err(_) => continue,
+        };
+
+        let chunks = cache.fetch_output(id);
+        if await!(ctx.store.replace_output(id, chunks, Some(trusted))).is_ok() {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Attempts to download the source `id` again, which must no longer be present in the store.
+///
+/// The URI of the source is looked up in the manifests in the store, and the download is verified
+/// against the hash embedded in `id`. Returns `true` if the source was downloaded successfully, or
+/// `false` if no manifest declares it or the download failed.
+async fn refetch_source<'a>(ctx: &'a Context, id: &'a SourceId) -> bool {
+    let manifest_ids = match ctx.store.manifest_ids() {
+        Ok(ids) => ids,
+        Err(_) => return false,
+    };
+
+    for manifest_id in manifest_ids {
+        let manifest = match await!(ctx.store.read_manifest(&manifest_id)) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) | Err(_) => continue,
+        };
+
+        let source = manifest
+            .sources()
+            .find(|source| source.expected_id().as_ref() == Some(id))
+            .cloned();
+
+        if let Some(source) = source {
+            let mut fetching = FetchSource::new(ctx.clone(), manifest_id, source);
+            while let Some(progress) = await!(fetching.next()) {
+                if progress.is_err() {
+                    return false;
+                }
+            }
+
+            return ctx.store.source_path(id).is_some();
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+
+    use deck_binary_cache::{BinaryCache, BinaryCacheFuture, OutputStream};
+    use deck_core::{FilesystemId, Hash, Manifest, ManifestId, Source};
+    use futures_preview::future::FutureExt;
+
+    use super::*;
+    use crate::local::dir::Claim;
+    use crate::local::test_util::{run, TempDir};
+    use crate::local::{archive, LocalStore};
+    use crate::verify::Problem;
+
+    const HASH: &str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+    /// Binary cache serving a single output tree.
+    #[derive(Debug)]
+    struct TreeCache(Vec<u8>);
+
+    impl BinaryCache for TreeCache {
+        fn query_outputs<'a>(&'a mut self, _: &'a OutputId) -> BinaryCacheFuture<'a, bool> {
+            future::ok(true).boxed()
+        }
+
+        fn fetch_output<'a>(&'a mut self, _: &'a OutputId) -> OutputStream<'a> {
+            stream::once(future::ok(self.0.clone())).boxed()
+        }
+    }
+
+    /// Serves `body` over HTTP to the first client connecting to the returned URI.
+    fn serve_once(name: &str, body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+        let uri = format!("http://{}/{}", listener.local_addr().unwrap(), name);
+
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().expect("Failed to accept client");
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match socket.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => request.extend_from_slice(&buf[..len]),
+                }
+            }
+
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = socket.write_all(header.as_bytes());
+            let _ = socket.write_all(&body);
+        });
+
+        uri
+    }
+
+    /// Verifies every object in `store` and returns the results.
+    fn verify_all(store: LocalStore, check: CheckContents, repair: Repair) -> Vec<Verified> {
+        run(async move {
+            let results = await!(verify(&store.context, check, repair).collect::<Vec<_>>());
+            results
+                .into_iter()
+                .map(|r| r.expect("Failed to verify"))
+                .collect()
+        })
+    }
+
+    /// Makes the file at `path` writable and replaces its contents with `contents`.
+    fn tamper(path: &Path, contents: &str) {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn repaired(object: Object, problem: Problem, action: Action) -> Verified {
+        Verified::Repaired(Repaired {
+            object,
+            problem,
+            action,
+        })
+    }
+
+    /// Writes `manifest` into `store`, returning its ID.
+    fn add_manifest(store: &LocalStore, manifest: Manifest) -> ManifestId {
+        let store = store.context.store.clone();
+        run(async move {
+            let mut writing = store.write_manifest(manifest.clone());
+            while let Some(state) = await!(writing.next()) {
+                state.expect("Failed to write manifest");
+            }
+
+            manifest.compute_id()
+        })
+    }
+
+    /// Adds an output containing a single file to `store`, returning its ID and the packed tree.
+    fn add_output(store: &LocalStore, scratch: &Path) -> (OutputId, Vec<u8>) {
+        let manifest = Manifest::build("foo", "1.0.0", HASH, None)
+            .finish()
+            .expect("Failed to create manifest");
+        let output = manifest.outputs().next().expect("Manifest has no outputs");
+
+        let built = scratch.join("foo");
+        fs::create_dir_all(&built).expect("Failed to create output");
+        fs::write(built.join("foo"), "foo").expect("Failed to write output");
+        let mut packed = Vec::new();
+        archive::write_tree(&mut packed, &built).expect("Failed to pack output");
+
+        let (store, id) = (store.context.store.clone(), output.clone());
+        run(async move { await!(store.add_output(&id, &built)).expect("Failed to add output") });
+        (output, packed)
+    }
+
+    /// Adds a source named `name` containing `contents` to `store`, returning its ID.
+    fn add_source(store: &LocalStore, name: &str, contents: &str) -> SourceId {
+        let hash = Hash::compute().input(contents).finish();
+        let id = SourceId::new(name.to_string(), hash).expect("Invalid source ID");
+
+        let store = store.context.store.clone();
+        let (source, contents) = (id.clone(), contents.to_string());
+        run(async move {
+            match await!(store.claim_source(source)).expect("Failed to claim source") {
+                Claim::Write(mut writer) => {
+                    fs::write(writer.path().as_path(), contents).expect("Failed to write source");
+                    await!(writer.commit()).expect("Failed to commit source");
+                }
+                _ => panic!("expected source to be missing"),
+            }
+        });
+
+        id
+    }
+
+    #[test]
+    fn reports_problems_without_repairing() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let scratch = TempDir::new("verify-scratch");
+        let (output, _) = add_output(&store, scratch.path());
+        tamper(&store.context.store.output_path(&output).join("foo"), "bar");
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Disabled);
+        let invalid = Verified::Invalid(Invalid {
+            object: Object::Output(output.clone()),
+            problem: Problem::Corrupted,
+        });
+        assert_eq!(results, vec![invalid]);
+        assert!(temp.path().join("outputs").join(output.to_path()).exists());
+    }
+
+    #[test]
+    fn deregisters_missing_objects() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let manifest = Manifest::build("bar", "1.0.0", HASH, None)
+            .finish()
+            .expect("Failed to create manifest");
+        let id = add_manifest(&store, manifest);
+        fs::remove_file(temp.path().join("manifests").join(id.to_path())).unwrap();
+
+        let results = verify_all(store, CheckContents::Disabled, Repair::Enabled);
+        let expected = repaired(Object::Manifest(id), Problem::Missing, Action::Deregistered);
+        assert_eq!(results, vec![expected]);
+    }
+
+    #[test]
+    fn registers_valid_manifests() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let manifest = Manifest::build("bar", "1.0.0", HASH, None)
+            .finish()
+            .expect("Failed to create manifest");
+        let id = manifest.compute_id();
+        let path = temp.path().join("manifests").join(id.to_path());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, manifest.to_string()).unwrap();
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Enabled);
+        let expected = repaired(
+            Object::Manifest(id),
+            Problem::Unregistered,
+            Action::Registered,
+        );
+        assert_eq!(results, vec![expected]);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn quarantines_corrupted_manifests() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let manifest = Manifest::build("bar", "1.0.0", HASH, None)
+            .finish()
+            .expect("Failed to create manifest");
+        let id = add_manifest(&store, manifest);
+        let path = temp.path().join("manifests").join(id.to_path());
+        tamper(&path, "not a manifest");
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Enabled);
+        let expected = repaired(
+            Object::Manifest(id),
+            Problem::Corrupted,
+            Action::Quarantined,
+        );
+        assert_eq!(results, vec![expected]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn quarantines_corrupted_outputs_without_caches() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let scratch = TempDir::new("verify-scratch");
+        let (output, _) = add_output(&store, scratch.path());
+        let path = store.context.store.output_path(&output);
+        tamper(&path.join("foo"), "bar");
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Enabled);
+        let expected = repaired(
+            Object::Output(output),
+            Problem::Corrupted,
+            Action::Quarantined,
+        );
+        assert_eq!(results, vec![expected]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refetches_corrupted_outputs_from_caches() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let scratch = TempDir::new("verify-scratch");
+        let (output, packed) = add_output(&store, scratch.path());
+        let path = store.context.store.output_path(&output);
+        tamper(&path.join("foo"), "bar");
+
+        let store = run(async move {
+            let mut store = store;
+            await!(store.add_binary_cache(TreeCache(packed))).expect("Failed to add cache");
+            store
+        });
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Enabled);
+        let expected = repaired(
+            Object::Output(output),
+            Problem::Corrupted,
+            Action::Refetched,
+        );
+        assert_eq!(results, vec![expected]);
+        assert_eq!(fs::read_to_string(path.join("foo")).unwrap(), "foo");
+    }
+
+    #[test]
+    fn refetches_corrupted_sources() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let id = add_source(&store, "foo.tar.gz", "contents");
+        let uri = serve_once("foo.tar.gz", b"contents".to_vec());
+        let manifest = Manifest::build("bar", "1.0.0", HASH, None)
+            .source(Source::Uri {
+                uri,
+                hash: id.hash().to_string(),
+            })
+            .finish()
+            .expect("Failed to create manifest");
+        let manifest_id = add_manifest(&store, manifest);
+
+        let path = store
+            .context
+            .store
+            .source_path(&id)
+            .expect("Source is missing");
+        tamper(&path, "tampered");
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Enabled);
+        let expected = vec![
+            Verified::Checked(Object::Manifest(manifest_id)),
+            repaired(Object::Source(id), Problem::Corrupted, Action::Refetched),
+        ];
+        assert_eq!(results, expected);
+        assert_eq!(fs::read_to_string(path).unwrap(), "contents");
+    }
+
+    #[test]
+    fn quarantines_sources_which_cannot_be_refetched() {
+        let temp = TempDir::new("verify");
+        let store = LocalStore::open(temp.path().to_owned()).expect("Failed to open store");
+        let id = add_source(&store, "foo.tar.gz", "contents");
+        let path = store
+            .context
+            .store
+            .source_path(&id)
+            .expect("Source is missing");
+        tamper(&path, "tampered");
+
+        let results = verify_all(store, CheckContents::Enabled, Repair::Enabled);
+        let expected = repaired(Object::Source(id), Problem::Corrupted, Action::Quarantined);
+        assert_eq!(results, vec![expected]);
+        assert!(!path.exists());
+    }
+}
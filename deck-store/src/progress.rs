@@ -1,37 +1,187 @@
-use deck_core::ManifestId;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use deck_core::{ManifestId, OutputId, SourceId};
 use futures_preview::channel::mpsc::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+
+use crate::StoreError;
 
-pub(crate) type ProgressSender = Sender<Result<Progress, ()>>;
-pub(crate) type ProgressReceiver = Receiver<Result<Progress, ()>>;
+pub(crate) type ProgressSender = Sender<Result<Progress, StoreError>>;
+pub(crate) type ProgressReceiver = Receiver<Result<Progress, StoreError>>;
 
 pub(crate) fn progress_channel(buffer: usize) -> (ProgressSender, ProgressReceiver) {
     mpsc::channel(buffer)
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Progress {
+    Started(Started),
     Blocked(Blocked),
+    Loading(Loading),
     Downloading(Downloading),
+    Substituting(Substituting),
+    Substituted(Substituted),
+    Reusing(Reusing),
     Building(Building),
     Installing(Installing),
     Finished(Finished),
+    Failed(Failed),
+}
+
+impl Progress {
+    /// Returns the ID of the package this progress is reported for.
+    pub fn package_id(&self) -> &ManifestId {
+        match *self {
+            Progress::Started(ref p) => &p.package_id,
+            Progress::Blocked(ref p) => &p.package_id,
+            Progress::Loading(ref p) => &p.package_id,
+            Progress::Downloading(ref p) => &p.package_id,
+            Progress::Substituting(ref p) => &p.package_id,
+            Progress::Substituted(ref p) => &p.package_id,
+            Progress::Reusing(ref p) => &p.package_id,
+            Progress::Building(ref p) => &p.package_id,
+            Progress::Installing(ref p) => &p.package_id,
+            Progress::Finished(ref p) => &p.package_id,
+            Progress::Failed(ref p) => &p.package_id,
+        }
+    }
+
+    /// Returns the time at which this progress was reported.
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match *self {
+            Progress::Started(ref p) => p.timestamp,
+            Progress::Blocked(ref p) => p.timestamp,
+            Progress::Loading(ref p) => p.timestamp,
+            Progress::Downloading(ref p) => p.timestamp,
+            Progress::Substituting(ref p) => p.timestamp,
+            Progress::Substituted(ref p) => p.timestamp,
+            Progress::Reusing(ref p) => p.timestamp,
+            Progress::Building(ref p) => p.timestamp,
+            Progress::Installing(ref p) => p.timestamp,
+            Progress::Finished(ref p) => p.timestamp,
+            Progress::Failed(ref p) => p.timestamp,
+        }
+    }
+
+    /// Reports this progress for the package `id` instead.
+    ///
+    /// This is used when work is shared between the builds of several packages.
+    pub(crate) fn for_package(mut self, id: ManifestId) -> Self {
+        match self {
+            Progress::Started(ref mut p) => p.package_id = id,
+            Progress::Blocked(ref mut p) => p.package_id = id,
+            Progress::Loading(ref mut p) => p.package_id = id,
+            Progress::Downloading(ref mut p) => p.package_id = id,
+            Progress::Substituting(ref mut p) => p.package_id = id,
+            Progress::Substituted(ref mut p) => p.package_id = id,
+            Progress::Reusing(ref mut p) => p.package_id = id,
+            Progress::Building(ref mut p) => p.package_id = id,
+            Progress::Installing(ref mut p) => p.package_id = id,
+            Progress::Finished(ref mut p) => p.package_id = id,
+            Progress::Failed(ref mut p) => p.package_id = id,
+        }
+
+        self
+    }
+}
+
+/// What a build is going to do with a package.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Action {
+    /// Every output of the package is already in the store.
+    Memoize,
+    /// The missing outputs of the package are fetched from binary caches.
+    Substitute,
+    /// The package is built from source.
+    Build,
+    /// The package is rebuilt to check that its build is reproducible.
+    Check,
+}
+
+/// The build graph has been computed and its jobs are about to run.
+///
+/// This is reported once per build, before any job reports progress of its own. Only the loading
+/// of manifests while computing the build graph is reported earlier.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Started {
+    /// ID of the package being built.
+    pub package_id: ManifestId,
+    /// Every package in the build graph, including the package itself, in the order of their IDs.
+    pub planned: Vec<(ManifestId, Action)>,
+    pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Blocked {
     pub package_id: ManifestId,
     pub description: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Location being searched for the manifest of a package.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum LoadSource {
+    /// The local store.
+    Store,
+    /// The repository with the given priority, starting from zero.
+    Repository(usize),
+    /// The remote store with the given priority, starting from zero.
+    RemoteStore(usize),
+}
+
+/// The manifest of a package is being looked up.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Loading {
+    pub package_id: ManifestId,
+    pub source: LoadSource,
+    pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Downloading {
     pub package_id: ManifestId,
     pub source: String,
     pub downloaded_bytes: u64,
     pub total_bytes: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// An output of a package is being fetched from a binary cache.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Substituting {
+    pub package_id: ManifestId,
+    pub output: OutputId,
+    /// Priority of the binary cache the output is fetched from, starting from zero.
+    pub cache: usize,
+    pub downloaded_bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// An output of a package has been fetched from a binary cache and added to the store.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Substituted {
+    pub package_id: ManifestId,
+    pub output: OutputId,
+    /// Size of the output in the store, in bytes.
+    pub installed_bytes: u64,
+    pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug)]
+/// A source is being filled in from an existing source in the store with identical contents.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Reusing {
+    pub package_id: ManifestId,
+    /// Source requested by the package.
+    pub source: SourceId,
+    /// Existing source whose contents are being reused.
+    pub existing: SourceId,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum BuildStatus {
     Started,
     Preparing,
@@ -41,7 +191,7 @@ pub enum BuildStatus {
     Finalizing,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Building {
     pub package_id: ManifestId,
     pub status: BuildStatus,
@@ -50,24 +200,82 @@ pub struct Building {
     pub description: String,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
+    pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Installing {
     pub package_id: ManifestId,
     pub description: String,
+    pub written_bytes: u64,
+    pub total_bytes: Option<u64>,
+    pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum FinalStatus {
     Memoized,
     Reinstalled,
     Downloaded,
     Built,
+    /// The package was rebuilt and its outputs were identical to those in the store.
+    Checked,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Finished {
     pub package_id: ManifestId,
     pub status: FinalStatus,
+    /// Total size of the outputs added to the store, in bytes.
+    pub installed_bytes: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Reason why a package could not be built.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum FailureReason {
+    /// One of the jobs of the package failed with the given error.
+    Error(String),
+    /// The build of the package was killed for exceeding the given time limit.
+    TimedOut(TimeLimit),
+    /// The package was not built because its dependency with the given ID failed.
+    DependencyFailed(ManifestId),
+}
+
+impl Display for FailureReason {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            FailureReason::Error(ref err) => write!(fmt, "{}", err),
+            FailureReason::TimedOut(ref limit) => write!(fmt, "build killed: {}", limit),
+            FailureReason::DependencyFailed(ref id) => write!(fmt, "dependency `{}` failed", id),
+        }
+    }
+}
+
+/// A package could not be built.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Failed {
+    pub package_id: ManifestId,
+    pub reason: FailureReason,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Time limit of a build, which is killed once the limit is exceeded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum TimeLimit {
+    /// The build may take at most this long as a whole.
+    Timeout(Duration),
+    /// The builder may go at most this long without writing to stdout or stderr.
+    MaxSilentTime(Duration),
+}
+
+impl Display for TimeLimit {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            TimeLimit::Timeout(ref limit) => write!(fmt, "timed out after {}s", limit.as_secs()),
+            TimeLimit::MaxSilentTime(ref limit) => {
+                write!(fmt, "no output for {}s", limit.as_secs())
+            }
+        }
+    }
 }
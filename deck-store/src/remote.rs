@@ -1,3 +1,246 @@
+//! Stores on other machines or in other processes.
+//!
+//! A `RemoteStore` spawns a `deck-store-serve` process, either locally or on another host through
+//! `ssh`, and talks to it over the stdin and stdout of that process using the protocol described
+//! in the `protocol` module.
+
+pub use self::serve::serve;
+
+use std::io;
+use std::ops::DerefMut;
+
+use deck_binary_cache::{BinaryCache, BinaryCacheFuture, CacheError, OutputStream};
+use deck_core::{Manifest, ManifestId, OutputId, Platform};
+use futures_locks::Mutex;
+use futures_preview::compat::Future01CompatExt;
+use futures_preview::future::FutureExt;
+use futures_preview::stream::{self, Stream, StreamExt};
+use url::Url;
+
+use self::connection::{unexpected, Connection, ServeCommand};
+use self::protocol::{protocol_error, Frame, Request, Response};
+use super::{
+    BuildStream, CheckContents, LogOptions, LogStream, Repair, Store, StoreError, StoreFuture,
+    StoreId, VerifyStream,
+};
+
+mod connection;
 mod docker;
+mod protocol;
+mod serve;
 #[cfg(feature = "ssh")]
 mod ssh;
+
+/// Name of the program which serves a store over its stdin and stdout.
+pub const SERVE_PROGRAM: &str = "deck-store-serve";
+
+/// Store served by a `deck-store-serve` process over its stdin and stdout.
+///
+/// Requests are sent one at a time over a single connection, except for builds, which each get a
+/// connection of their own so that other requests can be made while they run. Dropping the
+/// `BuildStream` of a build closes its connection, which cancels the build on the remote end.
+#[derive(Debug)]
+pub struct RemoteStore {
+    command: ServeCommand,
+    connection: Mutex<Connection>,
+}
+
+impl RemoteStore {
+    /// Opens the remote store identified by `id`.
+    ///
+    /// `ssh+` stores run `deck-store-serve` on the remote host, while `command+` stores run the
+    /// program given by the URL path locally, passing it each `arg` query pair as an argument. The
+    /// process is not spawned until the first request is made.
+    pub fn open(id: &StoreId) -> Result<Self, StoreError> {
+        if id.is_command() {
+            Ok(RemoteStore::from_command(local_command(id.as_url())?))
+        } else if id.is_ssh() {
+            ssh_command(id.as_url()).map(RemoteStore::from_command)
+        } else {
+            Err(StoreError::UnsupportedStore(id.to_string()))
+        }
+    }
+
+    fn from_command(command: ServeCommand) -> Self {
+        RemoteStore {
+            connection: Mutex::new(Connection::new(command.clone())),
+            command,
+        }
+    }
+
+    /// Sends `request` over the shared connection and waits for the single message answering it.
+    async fn call<'a>(&'a self, request: Request) -> Result<Response, StoreError> {
+        let mut connection = await!(self.connection.lock().compat()).expect("connection dropped");
+        await!(connection.call(request))
+    }
+
+    /// Sends `request` over the shared connection and streams the frames answering it.
+    fn stream<'a>(
+        &'a self,
+        request: Request,
+    ) -> impl Stream<Item = Result<Frame<Response>, StoreError>> + Send + 'a {
+        stream::once(self.connection.lock().compat())
+            .map(move |connection| {
+                let connection = connection.expect("connection dropped");
+                responses(connection, request.clone())
+            })
+            .flatten()
+    }
+}
+
+impl BinaryCache for RemoteStore {
+    fn query_outputs<'a>(&'a mut self, id: &'a OutputId) -> BinaryCacheFuture<'a, bool> {
+        let future = async move {
+            match await!(self.call(Request::QueryOutputs(id.clone()))) {
+                Ok(Response::Exists(exists)) => Ok(exists),
+                Ok(ref response) => Err(to_cache_error(unexpected(response))),
+                Err(err) => Err(to_cache_error(err)),
+            }
+        };
+
+        future.boxed()
+    }
+
+    fn fetch_output<'a>(&'a mut self, id: &'a OutputId) -> OutputStream<'a> {
+        self.stream(Request::FetchOutput(id.clone()))
+            .map(|frame| match frame {
+                Ok(Frame::Data(data)) => Ok(data),
+                Ok(Frame::Message(ref response)) => Err(to_cache_error(unexpected(response))),
+                Err(err) => Err(to_cache_error(err)),
+            })
+            .boxed()
+    }
+}
+
+impl Store for RemoteStore {
+    fn supported_platforms<'a>(&'a self) -> StoreFuture<'a, Vec<Platform>> {
+        let future = async move {
+            match await!(self.call(Request::SupportedPlatforms))? {
+                Response::Platforms(platforms) => Ok(platforms),
+                ref response => Err(unexpected(response)),
+            }
+        };
+
+        future.boxed()
+    }
+
+    fn query_manifest<'a>(&'a mut self, id: &'a ManifestId) -> StoreFuture<'a, Manifest> {
+        let future = async move {
+            let manifest: Manifest = match await!(self.call(Request::QueryManifest(id.clone())))? {
+                Response::Manifest(text) => text
+                    .parse()
+                    .map_err(|e| StoreError::InvalidManifest(format!("{}", e)))?,
+                ref response => return Err(unexpected(response)),
+            };
+
+            if manifest.compute_id() != *id {
+                let reason = format!("remote store returned a different manifest for `{}`", id);
+                return Err(StoreError::InvalidManifest(reason));
+            }
+
+            Ok(manifest)
+        };
+
+        future.boxed()
+    }
+
+    fn build_manifest(&mut self, manifest: Manifest) -> BuildStream {
+        let connection = Box::new(Connection::new(self.command.clone()));
+        let request = Request::BuildManifest(manifest.to_string());
+        let progress = responses(connection, request).map(|frame| match frame? {
+            Frame::Message(Response::Progress(progress)) => Ok(progress),
+            Frame::Message(ref response) => Err(unexpected(response)),
+            Frame::Data(_) => Err(protocol_error("unexpected data frame in build")),
+        });
+
+        BuildStream::new(progress)
+    }
+
+    fn get_build_log<'a>(&'a mut self, id: &'a ManifestId, options: LogOptions) -> LogStream<'a> {
+        let chunks =
+            self.stream(Request::GetBuildLog(id.clone(), options))
+                .map(|frame| match frame? {
+                    Frame::Data(data) => Ok(data),
+                    Frame::Message(ref response) => Err(unexpected(response)),
+                });
+
+        LogStream::new(chunks)
+    }
+
+    fn verify<'a>(&'a mut self, check: CheckContents, repair: Repair) -> VerifyStream<'a> {
+        let results = self
+            .stream(Request::Verify(check, repair))
+            .map(|frame| match frame? {
+                Frame::Message(Response::Verified(verified)) => Ok(verified),
+                Frame::Message(ref response) => Err(unexpected(response)),
+                Frame::Data(_) => Err(protocol_error("unexpected data frame in verify")),
+            });
+
+        VerifyStream::new(results)
+    }
+}
+
+/// Sends `request` over `connection` and streams the frames answering it, up to but excluding the
+/// final `Response::Done`.
+fn responses<C>(
+    connection: C,
+    request: Request,
+) -> impl Stream<Item = Result<Frame<Response>, StoreError>> + Send
+where
+    C: DerefMut<Target = Connection> + Send,
+{
+    stream::unfold(Some((connection, Some(request))), |state| async move {
+        let (mut connection, request) = match state {
+            Some(state) => state,
+            None => return None,
+        };
+
+        if let Some(request) = request {
+            if let Err(err) = await!(connection.send(request)) {
+                return Some((Err(err), None));
+            }
+        }
+
+        match await!(connection.recv()) {
+            Ok(Frame::Message(Response::Done)) => None,
+            Ok(Frame::Message(Response::Error(msg))) => Some((Err(StoreError::Remote(msg)), None)),
+            Ok(frame) => Some((Ok(frame), Some((connection, None)))),
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+}
+
+/// Returns the command which runs the program at the path of a `command+` store URL.
+fn local_command(url: &Url) -> Result<ServeCommand, StoreError> {
+    let program = url
+        .to_file_path()
+        .map_err(|_| StoreError::UnsupportedStore(url.to_string()))?;
+    let args = url
+        .query_pairs()
+        .filter(|(key, _)| key == "arg")
+        .map(|(_, value)| value.into_owned().into())
+        .collect();
+
+    Ok(ServeCommand {
+        program: program.into_os_string(),
+        args,
+    })
+}
+
+#[cfg(feature = "ssh")]
+fn ssh_command(url: &Url) -> Result<ServeCommand, StoreError> {
+    Ok(ssh::serve_command(url))
+}
+
+#[cfg(not(feature = "ssh"))]
+fn ssh_command(url: &Url) -> Result<ServeCommand, StoreError> {
+    Err(StoreError::UnsupportedStore(url.to_string()))
+}
+
+fn to_cache_error(err: StoreError) -> CacheError {
+    match err {
+        StoreError::Io(e) => CacheError::Io(e),
+        StoreError::Cache(e) => e,
+        err => CacheError::Io(io::Error::new(io::ErrorKind::Other, err.to_string())),
+    }
+}
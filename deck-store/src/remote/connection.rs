@@ -0,0 +1,181 @@
+use std::ffi::OsString;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io;
+use std::process::{Command, Stdio};
+
+use futures::Sink;
+use futures_preview::compat::{Compat01As03, Future01CompatExt, Stream01CompatExt};
+use futures_preview::stream::StreamExt;
+use tokio::codec::{FramedRead, FramedWrite};
+use tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
+
+use super::protocol::{protocol_error, Frame, FrameCodec, Request, Response};
+use crate::StoreError;
+
+/// Command which starts a `deck-store-serve` process speaking the protocol over its stdio.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServeCommand {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+}
+
+impl ServeCommand {
+    fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        command
+    }
+}
+
+/// Connection to a remote store, which spawns the serving process on demand.
+///
+/// If the responses to a request are not read to the end, e.g. because the stream returned to
+/// the caller was dropped, the process is abandoned and a fresh one is spawned for the next
+/// request.
+pub struct Connection {
+    command: ServeCommand,
+    pipe: Option<Pipe>,
+}
+
+impl Connection {
+    pub fn new(command: ServeCommand) -> Self {
+        Connection {
+            command,
+            pipe: None,
+        }
+    }
+
+    /// Sends `request` to the remote store, spawning the serving process first if needed.
+    pub async fn send(&mut self, request: Request) -> Result<(), StoreError> {
+        let pipe = match self.pipe.take() {
+            Some(ref pipe) if pipe.busy => Pipe::spawn(&self.command)?,
+            Some(pipe) => pipe,
+            None => Pipe::spawn(&self.command)?,
+        };
+
+        let pipe = self.pipe.get_or_insert(pipe);
+        pipe.busy = true;
+        let sending = (&mut pipe.requests).send(Frame::Message(request)).compat();
+        if let Err(err) = await!(sending) {
+            self.pipe = None;
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Receives the next frame answering the last request.
+    pub async fn recv(&mut self) -> Result<Frame<Response>, StoreError> {
+        let received = match self.pipe {
+            Some(ref mut pipe) => await!(pipe.responses.next()),
+            None => return Err(protocol_error("no request was sent to the remote store")),
+        };
+
+        match received {
+            Some(Ok(frame)) => {
+                if let Frame::Message(ref response) = frame {
+                    if let Some(ref mut pipe) = self.pipe {
+                        pipe.busy = !response.is_final();
+                    }
+                }
+
+                Ok(frame)
+            }
+            Some(Err(err)) => {
+                self.pipe = None;
+                Err(err.into())
+            }
+            None => {
+                self.pipe = None;
+                let closed = io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "remote store closed the connection",
+                );
+                Err(closed.into())
+            }
+        }
+    }
+
+    /// Sends `request` and waits for the single message answering it.
+    pub async fn call(&mut self, request: Request) -> Result<Response, StoreError> {
+        await!(self.send(request))?;
+        match await!(self.recv())? {
+            Frame::Message(Response::Error(msg)) => Err(StoreError::Remote(msg)),
+            Frame::Message(ref response) if !response.is_final() => {
+                self.pipe = None;
+                Err(unexpected(response))
+            }
+            Frame::Message(response) => Ok(response),
+            Frame::Data(_) => {
+                self.pipe = None;
+                Err(protocol_error("unexpected data frame from remote store"))
+            }
+        }
+    }
+}
+
+impl Debug for Connection {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct(stringify!(Connection))
+            .field("command", &self.command)
+            .field(
+                "pid",
+                &self
+                    .pipe
+                    .as_ref()
+                    .and_then(|p| p.child.as_ref())
+                    .map(Child::id),
+            )
+            .finish()
+    }
+}
+
+/// Returns the error reported when the remote store answers with an unexpected message.
+pub fn unexpected(response: &Response) -> StoreError {
+    protocol_error(format!(
+        "unexpected response from remote store: {:?}",
+        response
+    ))
+}
+
+/// Stdio of a running `deck-store-serve` process.
+struct Pipe {
+    child: Option<Child>,
+    requests: FramedWrite<ChildStdin, FrameCodec<Request>>,
+    responses: Compat01As03<FramedRead<ChildStdout, FrameCodec<Response>>>,
+    /// Whether the responses to the last request have not been read to the end.
+    busy: bool,
+}
+
+impl Pipe {
+    fn spawn(command: &ServeCommand) -> Result<Self, StoreError> {
+        let mut child = command.to_command().spawn_async()?;
+        let stdin = child.stdin().take();
+        let stdout = child.stdout().take();
+        let (stdin, stdout) = match (stdin, stdout) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => return Err(protocol_error("remote store has no stdin or stdout")),
+        };
+
+        Ok(Pipe {
+            child: Some(child),
+            requests: FramedWrite::new(stdin, FrameCodec::new()),
+            responses: FramedRead::new(stdout, FrameCodec::new()).compat(),
+            busy: false,
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // Closing stdin lets the server cancel whatever it is doing and exit by itself, which
+        // killing it outright would not. The exited process is reaped in the background.
+        if let Some(child) = self.child.take() {
+            child.forget();
+        }
+    }
+}
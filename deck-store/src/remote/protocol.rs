@@ -0,0 +1,204 @@
+//! Framed protocol spoken between a `RemoteStore` and `deck-store-serve`.
+//!
+//! Every frame starts with the length of its body as a 32-bit big-endian integer. The first byte
+//! of the body tags the frame: messages are encoded as JSON, while data frames carrying build logs
+//! and output trees are sent as-is.
+//!
+//! The client sends one request at a time, and the server answers it with either a single message
+//! or a stream of frames terminated by `Response::Done` or `Response::Error`.
+
+use std::io;
+use std::marker::PhantomData;
+
+use bytes::{Bytes, BytesMut};
+use deck_core::{ManifestId, OutputId, Platform};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use crate::progress::Progress;
+use crate::verify::Verified;
+use crate::{CheckContents, LogOptions, Repair, StoreError};
+
+/// Largest data frame sent by the server, in bytes.
+pub const MAX_DATA_LEN: usize = 64 * 1024;
+
+/// Largest frame accepted by either side, in bytes.
+const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+const MESSAGE_TAG: u8 = b'M';
+const DATA_TAG: u8 = b'D';
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Request {
+    SupportedPlatforms,
+    QueryManifest(ManifestId),
+    /// Uploads the given manifest, in TOML format, and builds it.
+    BuildManifest(String),
+    GetBuildLog(ManifestId, LogOptions),
+    Verify(CheckContents, Repair),
+    QueryOutputs(OutputId),
+    FetchOutput(OutputId),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Response {
+    Platforms(Vec<Platform>),
+    /// The requested manifest, in TOML format.
+    Manifest(String),
+    Progress(Progress),
+    Verified(Verified),
+    Exists(bool),
+    /// The stream of frames answering the last request has ended.
+    Done,
+    /// The last request failed with the given message.
+    Error(String),
+}
+
+impl Response {
+    /// Returns whether no more frames follow this response to the current request.
+    pub fn is_final(&self) -> bool {
+        match *self {
+            Response::Progress(_) | Response::Verified(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// A single frame of the protocol.
+#[derive(Clone, Debug)]
+pub enum Frame<T> {
+    Message(T),
+    Data(Vec<u8>),
+}
+
+/// Encodes and decodes frames carrying messages of type `T`.
+#[derive(Debug)]
+pub struct FrameCodec<T> {
+    frames: LengthDelimitedCodec,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> FrameCodec<T> {
+    pub fn new() -> Self {
+        let mut frames = LengthDelimitedCodec::new();
+        frames.set_max_frame_length(MAX_FRAME_LEN);
+        FrameCodec {
+            frames,
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for FrameCodec<T> {
+    fn default() -> Self {
+        FrameCodec::new()
+    }
+}
+
+impl<T: Serialize> Encoder for FrameCodec<T> {
+    type Item = Frame<T>;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Self::Item, dst: &mut BytesMut) -> io::Result<()> {
+        let mut body = Vec::new();
+        match frame {
+            Frame::Message(message) => {
+                body.push(MESSAGE_TAG);
+                serde_json::to_writer(&mut body, &message).map_err(invalid_data)?;
+            }
+            Frame::Data(data) => {
+                body.push(DATA_TAG);
+                body.extend(data);
+            }
+        }
+
+        self.frames.encode(Bytes::from(body), dst)
+    }
+}
+
+impl<T: DeserializeOwned> Decoder for FrameCodec<T> {
+    type Item = Frame<T>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Self::Item>> {
+        let mut body = match self.frames.decode(src)? {
+            Some(body) => body,
+            None => return Ok(None),
+        };
+
+        if body.is_empty() {
+            return Err(invalid_data("received an empty frame"));
+        }
+
+        let payload = body.split_off(1);
+        match body[0] {
+            MESSAGE_TAG => serde_json::from_slice(&payload)
+                .map(|message| Some(Frame::Message(message)))
+                .map_err(invalid_data),
+            DATA_TAG => Ok(Some(Frame::Data(payload.to_vec()))),
+            tag => Err(invalid_data(format!("unknown frame tag {:#04x}", tag))),
+        }
+    }
+}
+
+/// Returns the error reported when the other side does not follow the protocol.
+pub fn protocol_error<T: Into<String>>(reason: T) -> StoreError {
+    StoreError::Io(invalid_data(reason.into()))
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut codec = FrameCodec::<Response>::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Frame::Message(Response::Exists(true)), &mut buf)
+            .expect("Failed to encode message");
+        codec
+            .encode(Frame::Data(b"hello".to_vec()), &mut buf)
+            .expect("Failed to encode data");
+
+        let mut partial = buf.split_to(3);
+        assert!(codec
+            .decode(&mut partial)
+            .expect("Failed to decode")
+            .is_none());
+        partial.unsplit(buf);
+        let mut buf = partial;
+
+        match codec.decode(&mut buf).expect("Failed to decode") {
+            Some(Frame::Message(Response::Exists(true))) => {}
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        match codec.decode(&mut buf).expect("Failed to decode") {
+            Some(Frame::Data(ref data)) if data == b"hello" => {}
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        assert!(codec.decode(&mut buf).expect("Failed to decode").is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        let mut codec = FrameCodec::<Response>::new();
+        let mut buf = BytesMut::new();
+        codec
+            .frames
+            .encode(Bytes::from(&b"X{}"[..]), &mut buf)
+            .expect("Failed to encode frame");
+
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use deck_core::Manifest;
+use std::io;
+
+use futures::Sink;
+use futures_preview::channel::mpsc::{self, UnboundedReceiver};
+use futures_preview::compat::{Future01CompatExt, Stream01CompatExt};
+use futures_preview::future::{self, FutureExt, TryFutureExt};
+use futures_preview::stream::{self, Stream, StreamExt};
+use tokio::codec::{FramedRead, FramedWrite};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::protocol::{protocol_error, Frame, FrameCodec, Request, Response, MAX_DATA_LEN};
+use crate::progress::Progress;
+use crate::{BuildStream, Store, StoreError};
+
+type Requests = UnboundedReceiver<io::Result<Frame<Request>>>;
+type Responses<W> = FramedWrite<W, FrameCodec<Response>>;
+
+/// Serves `store` to a single client, reading requests from `input` and writing the responses to
+/// `output` until `input` is closed.
+///
+/// This is what `deck-store-serve` runs over its stdin and stdout. Closing `input` while a build
+/// is running cancels the build.
+///
+/// Requests are read by a separate task, since reading from stdin blocks the reading task until
+/// input arrives, and builds must make progress while the client is being watched for hangups.
+pub async fn serve<S, R, W>(mut store: S, input: R, output: W) -> Result<(), StoreError>
+where
+    S: Store,
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send,
+{
+    let (tx, mut requests) = mpsc::unbounded();
+    let reading = async move {
+        let mut frames = FramedRead::new(input, FrameCodec::new()).compat();
+        while let Some(frame) = await!(frames.next()) {
+            if tx.unbounded_send(frame).is_err() {
+                break;
+            }
+        }
+
+        Ok::<(), ()>(())
+    };
+    tokio::spawn(reading.boxed().compat());
+
+    let mut responses = FramedWrite::new(output, FrameCodec::new());
+
+    while let Some(frame) = await!(requests.next()) {
+        let request = match frame? {
+            Frame::Message(request) => request,
+            Frame::Data(_) => return Err(protocol_error("unexpected data frame from client")),
+        };
+
+        match request {
+            Request::SupportedPlatforms => {
+                let reply = await!(store.supported_platforms()).map(Response::Platforms);
+                await!(reply_with(&mut responses, reply))?;
+            }
+            Request::QueryManifest(id) => {
+                let manifest = await!(store.query_manifest(&id));
+                let reply = manifest.map(|manifest| Response::Manifest(manifest.to_string()));
+                await!(reply_with(&mut responses, reply))?;
+            }
+            Request::BuildManifest(text) => {
+                let manifest = match text.parse::<Manifest>() {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        let err = StoreError::InvalidManifest(e.to_string());
+                        await!(reply_with(&mut responses, Err(err)))?;
+                        continue;
+                    }
+                };
+
+                let build = store.build_manifest(manifest);
+                if !await!(forward_build(build, &mut requests, &mut responses))? {
+                    break;
+                }
+            }
+            Request::GetBuildLog(id, options) => {
+                let chunks = store
+                    .get_build_log(&id, options)
+                    .map(|chunk| chunk.map(Frame::Data));
+                await!(forward(chunks, &mut responses))?;
+            }
+            Request::Verify(check, repair) => {
+                let results = store.verify(check, repair);
+                let results = results.map(|res| res.map(|v| Frame::Message(Response::Verified(v))));
+                await!(forward(results, &mut responses))?;
+            }
+            Request::QueryOutputs(id) => {
+                let exists = await!(store.query_outputs(&id)).map_err(StoreError::Cache);
+                await!(reply_with(&mut responses, exists.map(Response::Exists)))?;
+            }
+            Request::FetchOutput(id) => {
+                let chunks = store.fetch_output(&id).map(|chunk| match chunk {
+                    Ok(data) => Ok(Frame::Data(data)),
+                    Err(err) => Err(StoreError::Cache(err)),
+                });
+                await!(forward(chunks, &mut responses))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Forwards the progress of `build` until it finishes, cancelling it if the client closes the
+/// connection or sends another request in the meantime.
+///
+/// Returns `false` if the client hung up or broke the protocol, after which it is not served any
+/// further.
+async fn forward_build<'a, W>(
+    build: BuildStream,
+    requests: &'a mut Requests,
+    responses: &'a mut Responses<W>,
+) -> Result<bool, StoreError>
+where
+    W: AsyncWrite + Send,
+{
+    enum Event {
+        Progress(Option<Result<Progress, StoreError>>),
+        Hangup,
+    }
+
+    let progress = build.map(Some).chain(stream::once(future::ready(None)));
+    let hangup = requests.map(|_| Event::Hangup);
+    let hangup = hangup.chain(stream::once(future::ready(Event::Hangup)));
+    let mut events = progress.map(Event::Progress).select(hangup);
+
+    while let Some(event) = await!(events.next()) {
+        match event {
+            Event::Progress(Some(Ok(progress))) => {
+                let response = Response::Progress(progress);
+                await!(send(responses, Frame::Message(response)))?;
+            }
+            Event::Progress(Some(Err(err))) => {
+                await!(reply_with(responses, Err(err)))?;
+                return Ok(true);
+            }
+            Event::Progress(None) => {
+                await!(send(responses, Frame::Message(Response::Done)))?;
+                return Ok(true);
+            }
+            Event::Hangup => return Ok(false),
+        }
+    }
+
+    Ok(false)
+}
+
+/// Writes every frame yielded by `frames`, followed by `Response::Done`, or by `Response::Error`
+/// if the stream fails.
+async fn forward<'a, S, W>(mut frames: S, responses: &'a mut Responses<W>) -> Result<(), StoreError>
+where
+    S: Stream<Item = Result<Frame<Response>, StoreError>> + Unpin,
+    W: AsyncWrite + Send,
+{
+    while let Some(frame) = await!(frames.next()) {
+        match frame {
+            Ok(Frame::Data(data)) => {
+                for chunk in data.chunks(MAX_DATA_LEN) {
+                    await!(send(responses, Frame::Data(chunk.to_vec())))?;
+                }
+            }
+            Ok(frame) => await!(send(responses, frame))?,
+            Err(err) => return await!(reply_with(responses, Err(err))),
+        }
+    }
+
+    await!(send(responses, Frame::Message(Response::Done)))
+}
+
+/// Answers a request with `reply`, or with `Response::Error` if it failed.
+async fn reply_with<'a, W>(
+    responses: &'a mut Responses<W>,
+    reply: Result<Response, StoreError>,
+) -> Result<(), StoreError>
+where
+    W: AsyncWrite + Send,
+{
+    let response = reply.unwrap_or_else(|err| Response::Error(err.to_string()));
+    await!(send(responses, Frame::Message(response)))
+}
+
+async fn send<'a, W>(
+    responses: &'a mut Responses<W>,
+    frame: Frame<Response>,
+) -> Result<(), StoreError>
+where
+    W: AsyncWrite + Send,
+{
+    await!((&mut *responses).send(frame).compat())?;
+    Ok(())
+}
@@ -1 +1,80 @@
+use std::ffi::OsString;
 
+use url::Url;
+
+use super::connection::ServeCommand;
+use super::SERVE_PROGRAM;
+
+const SSH_PROGRAM: &str = "ssh";
+
+/// Returns the command which runs `deck-store-serve` on the host of `url` through `ssh`.
+///
+/// X11 and agent forwarding are disabled, and no terminal is allocated, since the session
+/// carries nothing but the binary protocol. The host is passed after `--`, so that it can never be
+/// read as an option.
+pub fn serve_command(url: &Url) -> ServeCommand {
+    let mut args: Vec<OsString> = vec!["-x".into(), "-a".into(), "-T".into()];
+
+    if let Some(port) = url.port() {
+        args.push("-p".into());
+        args.push(port.to_string().into());
+    }
+
+    if !url.username().is_empty() {
+        args.push("-l".into());
+        args.push(url.username().into());
+    }
+
+    args.push("--".into());
+    args.push(url.host_str().unwrap_or_default().into());
+    args.push(SERVE_PROGRAM.into());
+
+    ServeCommand {
+        program: SSH_PROGRAM.into(),
+        args,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_serve_program_on_host() {
+        let url = "ssh://user@server:2222"
+            .parse()
+            .expect("Failed to parse URL");
+        let command = serve_command(&url);
+        let args: Vec<_> = command
+            .args
+            .iter()
+            .map(|arg| arg.to_str().unwrap())
+            .collect();
+
+        assert_eq!(command.program, "ssh");
+        assert_eq!(
+            args,
+            &[
+                "-x",
+                "-a",
+                "-T",
+                "-p",
+                "2222",
+                "-l",
+                "user",
+                "--",
+                "server",
+                SERVE_PROGRAM
+            ]
+        );
+
+        let url = "ssh://server".parse().expect("Failed to parse URL");
+        let command = serve_command(&url);
+        let args: Vec<_> = command
+            .args
+            .iter()
+            .map(|arg| arg.to_str().unwrap())
+            .collect();
+        assert_eq!(args, &["-x", "-a", "-T", "--", "server", SERVE_PROGRAM]);
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use deck_core::{ManifestId, OutputId, SourceId};
+use serde::{Deserialize, Serialize};
+
+/// Result of verifying a single object in the store.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum Verified {
+    /// The object is registered and its contents are intact.
+    Checked(Object),
+    /// The object is inconsistent and was left untouched.
+    Invalid(Invalid),
+    /// The object was inconsistent and has been repaired.
+    Repaired(Repaired),
+}
+
+/// Identifies an object in the store.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub enum Object {
+    Manifest(ManifestId),
+    Output(OutputId),
+    Source(SourceId),
+}
+
+impl Display for Object {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Object::Manifest(ref id) => write!(fmt, "{}", id),
+            Object::Output(ref id) => write!(fmt, "{}", id),
+            Object::Source(ref id) => write!(fmt, "{}", id),
+        }
+    }
+}
+
+/// Kind of inconsistency detected in the store.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub enum Problem {
+    /// The object is registered, but is not present on disk.
+    Missing,
+    /// The object is present on disk, but was never registered.
+    Unregistered,
+    /// The contents of the object do not match its registered hash.
+    Corrupted,
+}
+
+/// Action taken in order to repair an inconsistent object.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+pub enum Action {
+    /// The object was found to be valid and has been registered with its current hash.
+    Registered,
+    /// The stale registration of the object has been removed.
+    Deregistered,
+    /// A valid copy of the object has been fetched from a binary cache.
+    Refetched,
+    /// The object has been moved into quarantine and is no longer part of the store.
+    Quarantined,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Invalid {
+    pub object: Object,
+    pub problem: Problem,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Repaired {
+    pub object: Object,
+    pub problem: Problem,
+    pub action: Action,
+}
@@ -0,0 +1,495 @@
+//! Builds of several interdependent packages in a temporary local store.
+
+#![feature(async_await, await_macro, futures_api)]
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use deck_binary_cache::{BinaryCache, BinaryCacheFuture, CacheError, OutputStream};
+use deck_store::core::{BuildScript, FilesystemId, Hash, Manifest, ManifestId, OutputId, Phase};
+use deck_store::local::LocalStore;
+use deck_store::progress::{FailureReason, FinalStatus, Progress, TimeLimit};
+use deck_store::{BuildOptions, LogOptions, Store, StoreError};
+use futures_preview::future::{self, FutureExt, TryFutureExt};
+use futures_preview::stream::{self, StreamExt};
+use tokio::runtime::Runtime;
+
+const HASH: &str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+/// Empty store in a temporary directory, which is removed when dropped.
+struct TempStore(PathBuf);
+
+impl TempStore {
+    fn new() -> Self {
+        let dir = env::temp_dir().join(format!("deck-build-test-{}", Hash::random()));
+        fs::create_dir_all(&dir).expect("Failed to create store");
+        TempStore(dir)
+    }
+
+    fn open(&self) -> LocalStore {
+        LocalStore::open(self.0.clone()).expect("Failed to open store")
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Binary cache which claims to have every output, but fails to send any of them.
+#[derive(Debug)]
+struct BrokenCache;
+
+impl BinaryCache for BrokenCache {
+    fn query_outputs<'a>(&'a mut self, _: &'a OutputId) -> BinaryCacheFuture<'a, bool> {
+        future::ok(true).boxed()
+    }
+
+    fn fetch_output<'a>(&'a mut self, _: &'a OutputId) -> OutputStream<'a> {
+        let chunks = vec![Ok(b"garbage".to_vec()), Err(CacheError::HttpStatus(500))];
+        stream::iter(chunks).boxed()
+    }
+}
+
+fn run<F, T>(future: F) -> T
+where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut runtime = Runtime::new().expect("Failed to start runtime");
+    let future = future.map(Ok::<T, ()>).boxed().compat();
+    runtime.block_on(future).expect("Future failed")
+}
+
+/// Creates a manifest for the package `name` which depends on `deps` and runs `script` to build.
+fn manifest(name: &str, script: &str, deps: &[&Manifest]) -> Manifest {
+    manifest_with(name, script, deps, |script| script)
+}
+
+/// Same as `manifest()`, but lets `configure` set further options on the build script, such as
+/// its time limits.
+fn manifest_with<F>(name: &str, script: &str, deps: &[&Manifest], configure: F) -> Manifest
+where
+    F: FnOnce(BuildScript) -> BuildScript,
+{
+    let script = BuildScript::new("/bin/sh")
+        .arg("-c")
+        .phase(Phase::Compile, script);
+    let script = configure(script);
+    deps.iter()
+        .fold(Manifest::build(name, "1.0.0", HASH, None), |m, dep| {
+            m.dependency(dep.compute_id())
+        })
+        .build_script(script)
+        .finish()
+        .expect("Failed to create manifest")
+}
+
+/// Builds `target` after adding it and `others` to `store`, returning every reported progress.
+fn build(
+    store: LocalStore,
+    target: Manifest,
+    others: Vec<Manifest>,
+    options: BuildOptions,
+) -> Vec<Result<Progress, StoreError>> {
+    run(async move {
+        for manifest in others {
+            await!(store.add_manifest(manifest)).expect("Failed to add manifest");
+        }
+
+        let id = await!(store.add_manifest(target)).expect("Failed to add manifest");
+        await!(store.build_package(id, options).collect::<Vec<_>>())
+    })
+}
+
+#[test]
+fn keeps_building_independent_packages() {
+    let temp = TempStore::new();
+    let good = manifest("good", "echo good > \"$out/good\"", &[]);
+    let bad = manifest("bad", "exit 1", &[]);
+    let top = manifest("top", "echo top > \"$out/top\"", &[&good, &bad]);
+    let (good_id, bad_id, top_id) = (good.compute_id(), bad.compute_id(), top.compute_id());
+    let good_output = good.outputs().next().expect("Manifest has no outputs");
+
+    let options = BuildOptions {
+        keep_going: true,
+        ..BuildOptions::default()
+    };
+    let progress = build(temp.open(), top, vec![good, bad], options);
+
+    let built: Vec<&ManifestId> = progress
+        .iter()
+        .filter_map(|progress| match progress {
+            Ok(Progress::Finished(ref finished)) => match finished.status {
+                FinalStatus::Built => Some(&finished.package_id),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    assert_eq!(built, vec![&good_id]);
+
+    let failed: Vec<(&ManifestId, &FailureReason)> = progress
+        .iter()
+        .filter_map(|progress| match progress {
+            Ok(Progress::Failed(ref failed)) => Some((&failed.package_id, &failed.reason)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(failed.len(), 2, "{:?}", failed);
+    match failed[0] {
+        (id, FailureReason::Error(_)) => assert_eq!(*id, bad_id),
+        other => panic!("expected `bad` to fail, got: {:?}", other),
+    }
+    match failed[1] {
+        (id, FailureReason::DependencyFailed(dep)) => {
+            assert_eq!(*id, top_id);
+            assert_eq!(*dep, bad_id);
+        }
+        other => panic!("expected `top` to be skipped, got: {:?}", other),
+    }
+
+    match progress.last() {
+        Some(Err(StoreError::BuildsFailed { failed, skipped })) => {
+            assert_eq!(*failed, vec![bad_id]);
+            assert_eq!(*skipped, vec![top_id]);
+        }
+        last => panic!("expected failed builds, got: {:?}", last),
+    }
+
+    let good_path = temp.0.join("outputs").join(good_output.to_path());
+    assert!(good_path.join("good").is_file());
+}
+
+#[test]
+fn reports_failed_packages_once() {
+    let temp = TempStore::new();
+    let bad = manifest("bad", "exit 1", &[]);
+    let bad_id = bad.compute_id();
+
+    let progress = build(temp.open(), bad, Vec::new(), BuildOptions::default());
+
+    let failed = progress.iter().filter(|progress| match progress {
+        Ok(Progress::Failed(_)) => true,
+        _ => false,
+    });
+    assert_eq!(failed.count(), 1);
+
+    match progress.last() {
+        Some(Err(StoreError::BuildsFailed { failed, skipped })) => {
+            assert_eq!(*failed, vec![bad_id]);
+            assert!(skipped.is_empty());
+        }
+        last => panic!("expected failed builds, got: {:?}", last),
+    }
+}
+
+/// Returns the final status of every package which finished building in `progress`.
+fn finished(progress: &[Result<Progress, StoreError>]) -> Vec<(&ManifestId, &FinalStatus)> {
+    progress
+        .iter()
+        .filter_map(|progress| match progress {
+            Ok(Progress::Finished(ref finished)) => Some((&finished.package_id, &finished.status)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn checks_installed_packages() {
+    let temp = TempStore::new();
+    let foo = manifest("foo", "echo foo > \"$out/foo\"", &[]);
+    let id = foo.compute_id();
+
+    let built = build(
+        temp.open(),
+        foo.clone(),
+        Vec::new(),
+        BuildOptions::default(),
+    );
+    match finished(&built).as_slice() {
+        [(_, FinalStatus::Built)] => {}
+        other => panic!("expected `foo` to be built, got: {:?}", other),
+    }
+
+    let options = BuildOptions {
+        check: true,
+        ..BuildOptions::default()
+    };
+    let checked = build(temp.open(), foo, Vec::new(), options);
+    match finished(&checked).as_slice() {
+        [(checked_id, FinalStatus::Checked)] => assert_eq!(**checked_id, id),
+        other => panic!("expected `foo` to be checked, got: {:?}", other),
+    }
+    assert!(checked.iter().all(Result::is_ok), "{:?}", checked);
+
+    // the check is logged separately, so the log of the original build is kept.
+    let log_dir = temp.0.join("var").join("log");
+    assert!(log_dir.join(format!("{}.check.log.gz", id)).is_file());
+
+    let mut store = temp.open();
+    let log = run(async move {
+        let mut log = store.get_build_log(&id, LogOptions::default());
+        let mut text = Vec::new();
+        while let Some(chunk) = await!(log.next()) {
+            text.extend(chunk.expect("Failed to read log"));
+        }
+        String::from_utf8(text).expect("Log is not UTF-8")
+    });
+    assert!(log.contains("=== build of "), "{}", log);
+    assert!(!log.contains("=== check of "), "{}", log);
+}
+
+#[test]
+fn detects_unreproducible_packages() {
+    let temp = TempStore::new();
+    let marker = temp.0.join("built-once");
+    let script = format!(
+        "if [ -e '{0}' ]; then echo 2 > \"$out/n\"; else echo 1 > \"$out/n\"; : > '{0}'; fi",
+        marker.display()
+    );
+    let foo = manifest("foo", &script, &[]);
+    let id = foo.compute_id();
+
+    let built = build(
+        temp.open(),
+        foo.clone(),
+        Vec::new(),
+        BuildOptions::default(),
+    );
+    assert!(built.iter().all(Result::is_ok), "{:?}", built);
+
+    let options = BuildOptions {
+        check: true,
+        ..BuildOptions::default()
+    };
+    let checked = build(temp.open(), foo, Vec::new(), options);
+    let failure = checked.iter().find_map(|progress| match progress {
+        Ok(Progress::Failed(ref failed)) => Some(failed),
+        _ => None,
+    });
+    match failure.map(|failed| (&failed.package_id, &failed.reason)) {
+        Some((failed_id, FailureReason::Error(ref message))) => {
+            assert_eq!(*failed_id, id);
+            assert!(message.contains("is not reproducible"), "{}", message);
+        }
+        other => panic!("expected the check to fail, got: {:?}", other),
+    }
+    assert!(finished(&checked).is_empty(), "{:?}", checked);
+}
+
+#[test]
+fn checking_requires_installed_outputs() {
+    let temp = TempStore::new();
+    let foo = manifest("foo", "echo foo > \"$out/foo\"", &[]);
+
+    let options = BuildOptions {
+        check: true,
+        ..BuildOptions::default()
+    };
+    let checked = build(temp.open(), foo, Vec::new(), options);
+    match checked.last() {
+        Some(Err(StoreError::NotFound(_))) => {}
+        last => panic!("expected missing outputs, got: {:?}", last),
+    }
+}
+
+#[test]
+fn builds_packages_which_fail_to_substitute() {
+    let temp = TempStore::new();
+    let foo = manifest("foo", "echo foo > \"$out/foo\"", &[]);
+    let id = foo.compute_id();
+    let output = foo.outputs().next().expect("Manifest has no outputs");
+
+    let mut store = temp.open();
+    let progress = run(async move {
+        await!(store.add_binary_cache(BrokenCache)).expect("Failed to add cache");
+        let id = await!(store.add_manifest(foo)).expect("Failed to add manifest");
+        await!(store
+            .build_package(id, BuildOptions::default())
+            .collect::<Vec<_>>())
+    });
+
+    let substituting = progress.iter().any(|progress| match progress {
+        Ok(Progress::Substituting(ref substituting)) => substituting.package_id == id,
+        _ => false,
+    });
+    assert!(substituting, "{:?}", progress);
+    assert!(progress.iter().all(Result::is_ok), "{:?}", progress);
+
+    match finished(&progress).as_slice() {
+        [(built_id, FinalStatus::Built)] => assert_eq!(**built_id, id),
+        other => panic!("expected `foo` to be built, got: {:?}", other),
+    }
+
+    let output_path = temp.0.join("outputs").join(output.to_path());
+    assert!(output_path.join("foo").is_file());
+}
+
+/// Returns the time limit which the package `id` was killed for exceeding in `progress`.
+fn expired_limit(progress: &[Result<Progress, StoreError>], id: &ManifestId) -> TimeLimit {
+    let expired = progress.iter().find_map(|progress| match progress {
+        Ok(Progress::Failed(ref failed)) if failed.package_id == *id => match failed.reason {
+            FailureReason::TimedOut(limit) => Some(limit),
+            _ => None,
+        },
+        _ => None,
+    });
+
+    expired.unwrap_or_else(|| panic!("expected `{}` to time out, got: {:?}", id, progress))
+}
+
+/// Waits for the process whose ID is written in the file at `pid_path` to be gone.
+fn assert_killed(pid_path: &Path) {
+    let pid = fs::read_to_string(pid_path).expect("Failed to read PID");
+    let process = Path::new("/proc").join(pid.trim());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while process.exists() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(
+        !process.exists(),
+        "builder process {} is still running",
+        pid.trim()
+    );
+}
+
+#[test]
+fn kills_builders_which_exceed_their_timeout() {
+    let temp = TempStore::new();
+    let pid_path = temp.0.join("sleeper.pid");
+    let script = format!("/bin/sleep 30 & echo $! > '{}'; wait", pid_path.display());
+    let timeout = Duration::from_secs(1);
+    let slow = manifest_with("slow", &script, &[], |s| s.with_timeout(timeout));
+    let id = slow.compute_id();
+
+    let started = Instant::now();
+    let progress = build(temp.open(), slow, Vec::new(), BuildOptions::default());
+    assert!(
+        started.elapsed() < Duration::from_secs(20),
+        "build was not killed"
+    );
+
+    assert_eq!(expired_limit(&progress, &id), TimeLimit::Timeout(timeout));
+    match progress.last() {
+        Some(Err(StoreError::BuildsFailed { failed, .. })) => assert_eq!(*failed, vec![id]),
+        last => panic!("expected failed builds, got: {:?}", last),
+    }
+
+    assert_killed(&pid_path);
+}
+
+#[test]
+fn kills_builders_which_never_stop_writing() {
+    let temp = TempStore::new();
+    let timeout = Duration::from_secs(1);
+    let spammy = manifest_with("spammy", "while :; do echo spam; done", &[], |s| {
+        s.with_timeout(timeout)
+    });
+    let id = spammy.compute_id();
+
+    let store = temp.open();
+    let started = Instant::now();
+    let progress = run(async move {
+        let id = await!(store.add_manifest(spammy)).expect("Failed to add manifest");
+        let building = store.build_package(id, BuildOptions::default());
+
+        // The builder writes far too much output to keep all of it around.
+        let progress = building.filter(|progress| match progress {
+            Ok(Progress::Building(_)) => future::ready(false),
+            _ => future::ready(true),
+        });
+        await!(progress.collect::<Vec<_>>())
+    });
+
+    assert!(
+        started.elapsed() < Duration::from_secs(20),
+        "build was not killed"
+    );
+    assert_eq!(expired_limit(&progress, &id), TimeLimit::Timeout(timeout));
+}
+
+#[test]
+fn kills_builders_which_stay_silent_for_too_long() {
+    let temp = TempStore::new();
+    let pid_path = temp.0.join("sleeper.pid");
+    let script = format!(
+        "echo starting; /bin/sleep 30 & echo $! > '{}'; wait",
+        pid_path.display()
+    );
+    let max_silent_time = Duration::from_secs(1);
+    let silent = manifest_with("silent", &script, &[], |s| {
+        s.with_max_silent_time(max_silent_time)
+    });
+    let id = silent.compute_id();
+
+    let progress = build(temp.open(), silent, Vec::new(), BuildOptions::default());
+    assert_eq!(
+        expired_limit(&progress, &id),
+        TimeLimit::MaxSilentTime(max_silent_time)
+    );
+    assert_killed(&pid_path);
+}
+
+#[test]
+fn output_restarts_the_silence_clock() {
+    let temp = TempStore::new();
+    let script = "for i in 1 2 3 4; do echo $i; /bin/sleep 0.5; done; echo done > \"$out/done\"";
+    let chatty = manifest_with("chatty", script, &[], |s| {
+        s.with_max_silent_time(Duration::from_secs(1))
+    });
+    let id = chatty.compute_id();
+
+    let progress = build(temp.open(), chatty, Vec::new(), BuildOptions::default());
+    match finished(&progress).as_slice() {
+        [(built_id, FinalStatus::Built)] => assert_eq!(**built_id, id),
+        other => panic!("expected `chatty` to be built, got: {:?}", other),
+    }
+}
+
+#[test]
+fn dropping_the_build_kills_the_builder_and_removes_its_scratch_dir() {
+    let temp = TempStore::new();
+    let pid_path = temp.0.join("sleeper.pid");
+    let script = format!(
+        "/bin/sleep 30 & echo $! > '{}'; echo started; wait",
+        pid_path.display()
+    );
+    let abandoned = manifest("abandoned", &script, &[]);
+
+    let store = temp.open();
+    let started = pid_path.clone();
+    run(async move {
+        let id = await!(store.add_manifest(abandoned)).expect("Failed to add manifest");
+        let mut building = store.build_package(id, BuildOptions::default());
+        while let Some(progress) = await!(building.next()) {
+            progress.expect("Build failed before it was abandoned");
+            if started.exists() {
+                break;
+            }
+        }
+    });
+
+    assert_killed(&pid_path);
+
+    let scratch_dirs = || {
+        fs::read_dir(temp.0.join("tmp"))
+            .expect("Failed to read temporary directory")
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("build-"))
+            .count()
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while scratch_dirs() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert_eq!(scratch_dirs(), 0, "scratch directory was not removed");
+}
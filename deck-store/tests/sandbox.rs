@@ -0,0 +1,373 @@
+//! Builds which try to escape the sandbox, and must fail to do so.
+//!
+//! Every escape is attempted by the builder of a package built in a sandboxed local store, which
+//! only succeeds if every attempt failed. These tests fail if the kernel does not allow
+//! unprivileged user namespaces, unless the `DECK_SKIP_SANDBOX_TESTS` environment variable is set,
+//! in which case they are skipped. Tests which lease build users additionally require root, and
+//! are ignored unless requested.
+
+#![cfg(target_os = "linux")]
+#![feature(async_await, await_macro, futures_api)]
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use deck_store::core::{BuildScript, FilesystemId, Hash, Manifest, ManifestId, Phase};
+use deck_store::local::build_users::{BuildUser, BuildUsers};
+use deck_store::local::sandbox::{Sandbox, SandboxConfig};
+use deck_store::local::LocalStore;
+use deck_store::progress::{FinalStatus, Progress};
+use deck_store::{Store, StoreError};
+use futures_preview::future::{FutureExt, TryFutureExt};
+use futures_preview::stream::StreamExt;
+use tokio::runtime::Runtime;
+
+const HASH: &str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+/// Host directories needed to run `/bin/sh` and coreutils inside the sandbox.
+const HOST_PATHS: &[&str] = &["/bin", "/usr", "/lib", "/lib64"];
+/// Environment variable which skips these tests on machines that cannot create namespaces.
+const SKIP_VAR: &str = "DECK_SKIP_SANDBOX_TESTS";
+
+/// Empty sandboxed store in a temporary directory, which is removed when dropped.
+struct TempStore(PathBuf);
+
+impl TempStore {
+    /// Creates a new store, returning `None` if sandboxes cannot be entered on this machine and
+    /// `SKIP_VAR` is set.
+    ///
+    /// Panics if sandboxes cannot be entered otherwise, so the tests never pass without running.
+    fn new() -> Option<Self> {
+        let dir = env::temp_dir().join(format!("deck-sandbox-test-{}", Hash::random()));
+        fs::create_dir_all(&dir).expect("Failed to create store");
+        let dir = fs::canonicalize(&dir).expect("Failed to resolve store");
+        let store = TempStore(dir);
+
+        match store.probe() {
+            Ok(()) => Some(store),
+            Err(ref e)
+                if e.kind() == ErrorKind::PermissionDenied && env::var_os(SKIP_VAR).is_some() =>
+            {
+                eprintln!("skipping sandbox test, cannot enter sandbox: {}", e);
+                None
+            }
+            Err(e) => panic!(
+                "Failed to enter sandbox: {} (set `{}` to skip the sandbox tests)",
+                e, SKIP_VAR
+            ),
+        }
+    }
+
+    /// Enters an empty sandbox, to tell whether this machine supports them at all.
+    fn probe(&self) -> std::io::Result<()> {
+        let probe = self.0.join("probe");
+        for dir in &["build", "tmp"] {
+            fs::create_dir_all(probe.join(dir))?;
+        }
+
+        let mut sandbox =
+            Sandbox::new(probe.join("root"), &probe.join("build"), &probe.join("tmp"));
+        for path in HOST_PATHS.iter().map(Path::new).filter(|p| p.exists()) {
+            sandbox.bind_read_only(path);
+        }
+
+        sandbox.prepare()?;
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg("true").env_clear();
+        sandbox.apply(&mut command);
+        let status = command.status();
+        let _ = fs::remove_dir_all(&probe);
+        status.map(|_| ())
+    }
+
+    fn open(&self) -> LocalStore {
+        let config = SandboxConfig {
+            extra_paths: HOST_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .filter(|p| p.exists())
+                .collect(),
+        };
+
+        LocalStore::open(self.0.clone())
+            .expect("Failed to open store")
+            .with_sandbox(config)
+    }
+
+    /// Returns the path of the single output of `manifest` in the store.
+    fn output_path(&self, manifest: &Manifest) -> PathBuf {
+        let output = manifest.outputs().next().expect("Manifest has no outputs");
+        self.0.join("outputs").join(output.to_path())
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run<F, T>(future: F) -> T
+where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut runtime = Runtime::new().expect("Failed to start runtime");
+    let future = future.map(Ok::<T, ()>).boxed().compat();
+    runtime.block_on(future).expect("Future failed")
+}
+
+/// Creates a manifest for the package `name` depending on `deps`, whose builder runs each of
+/// `checks` in turn and fails as soon as one of them does.
+///
+/// Builders only find the `bin` directories of their dependencies in `PATH`, so the host paths are
+/// added to it first.
+fn manifest(name: &str, checks: &[&str], deps: &[&Manifest]) -> Manifest {
+    let mut script = String::from("export PATH=/bin:/usr/bin\n");
+    for (index, check) in checks.iter().enumerate() {
+        let failed = format!("echo 'check #{} failed' >&2; exit 1", index);
+        script.push_str(&format!("{{ {}\n}} || {{ {}; }}\n", check, failed));
+    }
+    script.push_str("echo ok > \"$out/ok\"\n");
+
+    let script = BuildScript::new("/bin/sh")
+        .arg("-c")
+        .phase(Phase::Compile, script);
+    deps.iter()
+        .fold(Manifest::build(name, "1.0.0", HASH, None), |m, dep| {
+            m.dependency(dep.compute_id())
+        })
+        .build_script(script)
+        .finish()
+        .expect("Failed to create manifest")
+}
+
+/// Builds `target` in `store` after adding `deps`, asserting that it was built.
+fn assert_builds(store: LocalStore, target: &Manifest, deps: &[&Manifest]) {
+    let target = target.clone();
+    let id = target.compute_id();
+    let deps: Vec<Manifest> = deps.iter().map(|&dep| dep.clone()).collect();
+    let progress = run(async move {
+        let mut store = store;
+        for dep in deps {
+            await!(store.add_manifest(dep)).expect("Failed to add manifest");
+        }
+
+        await!(store.build_manifest(target).collect::<Vec<_>>())
+    });
+
+    assert_built(&progress, &id);
+}
+
+/// Asserts that `id` was built according to `progress`, reporting what the builder wrote to stderr
+/// otherwise.
+fn assert_built(progress: &[Result<Progress, StoreError>], id: &ManifestId) {
+    let built = progress.iter().any(|progress| match progress {
+        Ok(Progress::Finished(ref finished)) => match finished.status {
+            FinalStatus::Built => finished.package_id == *id,
+            _ => false,
+        },
+        _ => false,
+    });
+
+    if !built {
+        let stderr: String = progress
+            .iter()
+            .filter_map(|progress| match progress {
+                Ok(Progress::Building(ref building)) => Some(building.stderr.clone()),
+                _ => None,
+            })
+            .map(|stderr| String::from_utf8_lossy(&stderr).into_owned())
+            .collect();
+        panic!("`{}` was not built: {:?}\n{}", id, progress.last(), stderr);
+    }
+}
+
+#[test]
+fn sees_only_closure() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let dep = manifest("dep", &["echo 'Hello, world!' > \"$out/README\""], &[]);
+    let dep_output = dep.outputs().next().expect("Manifest has no outputs");
+    let outputs = temp.0.join("outputs");
+
+    let top = manifest(
+        "top",
+        &[
+            "test \"$(cat \"$deps\"/*/README)\" = 'Hello, world!'",
+            "test ! -e /home && test ! -e /root && test ! -e /etc/shadow",
+            &format!("test ! -e '{}'", temp.0.join("manifests").display()),
+            &format!(
+                "test \"$(ls '{}')\" = '{}'",
+                outputs.display(),
+                dep_output.to_path().display()
+            ),
+            "test \"$(ls -A /.old-root)\" = ''",
+        ],
+        &[&dep],
+    );
+
+    assert_builds(temp.open(), &top, &[&dep]);
+}
+
+#[test]
+fn cannot_write_store_paths() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let dep = manifest("dep", &["echo 'Hello, world!' > \"$out/README\""], &[]);
+    let top = manifest(
+        "top",
+        &[
+            "! touch \"$deps\"/*/evil 2>/dev/null",
+            "! rm \"$deps\"/*/README 2>/dev/null",
+            "! touch /usr/evil 2>/dev/null",
+        ],
+        &[&dep],
+    );
+
+    assert_builds(temp.open(), &top, &[&dep]);
+    assert!(temp.output_path(&dep).join("README").exists());
+    assert!(!temp.output_path(&dep).join("evil").exists());
+}
+
+#[test]
+fn build_and_tmp_are_private() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let escaped = format!("deck-sandbox-escape-{}", Hash::random());
+    let top = manifest(
+        "top",
+        &[
+            "echo built > \"$out/result\"",
+            &format!("echo temp > /tmp/{}", escaped),
+            &format!("echo temp > \"$TMPDIR/{}\"", escaped),
+            "echo build > /build/escaped",
+        ],
+        &[],
+    );
+
+    assert_builds(temp.open(), &top, &[]);
+    assert!(temp.output_path(&top).join("result").exists());
+    assert!(!env::temp_dir().join(&escaped).exists());
+    assert!(!Path::new("/build/escaped").exists());
+}
+
+#[test]
+fn runs_as_unprivileged_pid_one() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let top = manifest(
+        "top",
+        &[
+            "test \"$$\" = 1",
+            "test \"$(id -u)\" = 1000 && test \"$(id -g)\" = 100",
+            "! kill -0 2 2>/dev/null",
+        ],
+        &[],
+    );
+
+    assert_builds(temp.open(), &top, &[]);
+}
+
+#[test]
+fn has_fixed_hostname() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let top = manifest(
+        "top",
+        &[
+            "test \"$(uname -n)\" = localhost",
+            "hostname evil 2>/dev/null; true",
+        ],
+        &[],
+    );
+
+    assert_builds(temp.open(), &top, &[]);
+    assert_ne!(hostname(), "evil");
+}
+
+#[test]
+fn has_no_network() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let top = manifest(
+        "top",
+        &[
+            "test ! -x /bin/bash || ! bash -c 'echo > /dev/tcp/1.1.1.1/80' 2>/dev/null",
+            "test ! -x /bin/bash || ! bash -c 'echo > /dev/tcp/127.0.0.1/22' 2>/dev/null",
+        ],
+        &[],
+    );
+
+    assert_builds(temp.open(), &top, &[]);
+}
+
+#[test]
+fn has_private_proc() {
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let top = manifest(
+        "top",
+        &[
+            "test -e /proc/self/status",
+            "test \"$(cat /proc/1/comm)\" = sh",
+        ],
+        &[],
+    );
+
+    assert_builds(temp.open(), &top, &[]);
+}
+
+#[test]
+#[ignore]
+fn maps_build_users_to_the_sandbox_user() {
+    // Leasing build users requires root, so this only runs when requested with `--ignored`.
+    let temp = match TempStore::new() {
+        Some(temp) => temp,
+        None => return,
+    };
+    let user = BuildUser {
+        name: "deckbld1".to_string(),
+        uid: 30001,
+        gid: 30000,
+    };
+    let top = manifest(
+        "top",
+        &[
+            "test \"$(id -u)\" = 1000 && test \"$(id -g)\" = 100",
+            "echo build > result && echo temp > /tmp/temp",
+            "test \"$(stat -c %u \"$out\")\" = 1000",
+        ],
+        &[],
+    );
+
+    let store = temp
+        .open()
+        .with_build_users(BuildUsers::new(vec![user], None));
+    assert_builds(store, &top, &[]);
+    assert!(temp.output_path(&top).join("ok").exists());
+}
+
+fn hostname() -> String {
+    let output = Command::new("uname").arg("-n").output();
+    output
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .unwrap_or_default()
+}
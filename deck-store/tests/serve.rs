@@ -0,0 +1,147 @@
+//! Requests sent to a `deck-store-serve` process serving a temporary store as a `command+` store.
+
+#![feature(async_await, await_macro, futures_api)]
+
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use deck_binary_cache::BinaryCache;
+use deck_store::core::{BuildScript, Hash, Manifest, Phase, Platform};
+use deck_store::local::archive;
+use deck_store::progress::{FinalStatus, Progress};
+use deck_store::remote::{RemoteStore, SERVE_PROGRAM};
+use deck_store::{Store, StoreId};
+use futures_preview::future::{FutureExt, TryFutureExt};
+use futures_preview::stream::StreamExt;
+use tokio::runtime::Runtime;
+
+const HASH: &str = "fc3j3vub6kodu4jtfoakfs5xhumqi62m";
+
+/// Empty store in a temporary directory, which is removed when dropped.
+struct TempStore(PathBuf);
+
+impl TempStore {
+    fn new() -> Self {
+        let dir = env::temp_dir().join(format!("deck-serve-test-{}", Hash::random()));
+        fs::create_dir_all(dir.join("store")).expect("Failed to create store");
+        TempStore(dir)
+    }
+
+    /// Opens the store as a `command+` store served by the `deck-store-serve` binary of this
+    /// package.
+    fn open(&self) -> RemoteStore {
+        // Integration tests are placed in `target/<profile>/deps`, next to which Cargo places the
+        // binaries of the package.
+        let exe = env::current_exe().expect("Failed to locate test executable");
+        let program = exe
+            .parent()
+            .and_then(Path::parent)
+            .expect("Test executable is not in a target directory")
+            .join(SERVE_PROGRAM);
+
+        let store = self.0.join("store");
+        let url = format!(
+            "command+file://{}?arg={}",
+            program.display(),
+            store.display()
+        );
+        let id: StoreId = url.parse().expect("Invalid store ID");
+        RemoteStore::open(&id).expect("Failed to open store")
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run<F, T>(future: F) -> T
+where
+    F: Future<Output = T> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut runtime = Runtime::new().expect("Failed to start runtime");
+    let future = future.map(Ok::<T, ()>).boxed().compat();
+    runtime.block_on(future).expect("Future failed")
+}
+
+#[test]
+fn reports_host_platform() {
+    let temp = TempStore::new();
+    let store = temp.open();
+
+    let platforms = run(async move { await!(store.supported_platforms()) });
+    let platforms = platforms.expect("Failed to query platforms");
+    if cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        let expected: Platform = "x86_64-unknown-linux".parse().unwrap();
+        assert_eq!(platforms, vec![expected]);
+    }
+}
+
+#[test]
+fn builds_and_serves_outputs() {
+    let temp = TempStore::new();
+    let mut store = temp.open();
+    let download = temp.0.join("download");
+
+    let script = BuildScript::new("/bin/sh")
+        .arg("-c")
+        .phase(Phase::Compile, "echo 'Hello, world!' > \"$out/greeting\"");
+    let manifest = Manifest::build("foo", "1.0.0", HASH, None)
+        .build_script(script)
+        .finish()
+        .expect("Failed to create manifest");
+    let id = manifest.compute_id();
+    let output = manifest.outputs().next().expect("Manifest has no outputs");
+    let missing = Manifest::build("bar", "1.0.0", HASH, None)
+        .finish()
+        .expect("Failed to create manifest")
+        .outputs()
+        .next()
+        .expect("Manifest has no outputs");
+
+    run(async move {
+        let mut build = store.build_manifest(manifest.clone());
+        let mut progress = Vec::new();
+        while let Some(next) = await!(build.next()) {
+            progress.push(next.expect("Build failed"));
+        }
+        drop(build);
+
+        let is_building = |p: &Progress| match *p {
+            Progress::Building(ref building) => building.package_id == id,
+            _ => false,
+        };
+        assert!(progress.iter().any(is_building), "{:?}", progress);
+        match progress.last() {
+            Some(Progress::Finished(ref finished)) => {
+                assert_eq!(finished.package_id, id);
+                assert!(finished.installed_bytes > 0);
+                match finished.status {
+                    FinalStatus::Built => {}
+                    ref status => panic!("Package was not built: {:?}", status),
+                }
+            }
+            last => panic!("Build did not finish: {:?}", last),
+        }
+
+        let uploaded = await!(store.query_manifest(&id)).expect("Failed to query manifest");
+        assert_eq!(uploaded, manifest);
+
+        assert!(await!(store.query_outputs(&output)).expect("Failed to query output"));
+        assert!(!await!(store.query_outputs(&missing)).expect("Failed to query output"));
+
+        let mut tree = Vec::new();
+        let mut chunks = store.fetch_output(&output);
+        while let Some(chunk) = await!(chunks.next()) {
+            tree.extend(chunk.expect("Failed to fetch output"));
+        }
+
+        archive::read_tree(&mut tree.as_slice(), &download).expect("Invalid output tree");
+        let greeting = fs::read_to_string(download.join("greeting")).expect("Missing file");
+        assert_eq!(greeting, "Hello, world!\n");
+    });
+}
@@ -1,5 +1,47 @@
-extern crate deck_daemon;
+//! Serves the global store to every user of the machine in multi-user mode.
+//!
+//! Usage: `deck-daemon [CONFIG_PATH]`. If no path is given, the configuration is read from
+//! `/deck/daemon.toml`, and the default configuration is used if that file does not exist.
+
+#![forbid(unsafe_code)]
+
+use std::env;
+use std::fmt::Display;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use deck_daemon::{Config, Daemon};
+use tokio::prelude::Future;
+
+const DEFAULT_CONFIG_PATH: &str = "/deck/daemon.toml";
 
 fn main() {
-    println!("Hello world!");
+    let serving = read_config()
+        .map_err(|e| e.to_string())
+        .and_then(|cfg| Daemon::new(cfg).map_err(|e| e.to_string()))
+        .and_then(|daemon| daemon.serve().map_err(|e| e.to_string()));
+
+    let serving = match serving {
+        Ok(serving) => serving,
+        Err(e) => fail(&e),
+    };
+
+    tokio::run(serving.map_err(|e| fail(&e)));
+}
+
+/// Reads the configuration file given on the command line, or the default one.
+fn read_config() -> io::Result<Config> {
+    match env::args_os().nth(1).map(PathBuf::from) {
+        Some(path) => Config::read(&path),
+        None => match Config::read(Path::new(DEFAULT_CONFIG_PATH)) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            result => result,
+        },
+    }
+}
+
+fn fail(err: &dyn Display) -> ! {
+    eprintln!("deck-daemon: {}", err);
+    process::exit(1);
 }
@@ -1,6 +1,14 @@
+use std::future::Future;
 use std::path::PathBuf;
 
+use deck_client::{Client, ProgressMode, ProgressRenderer, Transaction};
+use deck_core::ManifestId;
+use deck_store::progress::Action;
+use deck_store::BuildStream;
+use futures_preview::future::{FutureExt, TryFutureExt};
+use futures_preview::stream::StreamExt;
 use structopt::StructOpt;
+use tokio::runtime::Runtime;
 
 use self::build::Build;
 use self::completion::{Completion, AFTER_HELP as COMPLETION_AFTER_HELP};
@@ -42,6 +50,15 @@ pub struct GlobalFlags {
     /// Simulate an action without doing anything
     #[structopt(global = true, long = "dry-run")]
     dry_run: bool,
+    /// How to report build progress
+    #[structopt(
+        global = true,
+        long = "progress",
+        value_name = "MODE",
+        default_value = "auto",
+        raw(possible_values = r#"&["auto", "plain", "json"]"#)
+    )]
+    progress: ProgressMode,
     /// No output printed to stdout
     #[structopt(
         global = true,
@@ -63,11 +80,33 @@ pub struct GlobalFlags {
     )]
     /// Path to the store directory
     store_path: PathBuf,
+    /// Path to the socket of the daemon managing the store in multi-user mode
+    #[structopt(
+        global = true,
+        long = "daemon-socket",
+        env = "DECK_DAEMON_SOCKET",
+        empty_values = false,
+        value_name = "PATH",
+        default_value = "/deck/daemon.sock",
+        hide_default_value = true,
+        hide_env_values = true,
+        parse(from_os_str)
+    )]
+    socket_path: PathBuf,
     /// Increase verbosity level of output
     #[structopt(global = true, short = "v", long = "verbose", parse(from_occurrences))]
     verbosity: u8,
 }
 
+impl GlobalFlags {
+    /// Opens a client for the store, going through the daemon if one is listening.
+    fn client(&self) -> Result<Client, String> {
+        let store_path = self.store_path.clone();
+        let socket_path = self.socket_path.clone();
+        Client::open(store_path, socket_path).map_err(|e| e.to_string())
+    }
+}
+
 /// Built-in Deck client subcommands.
 #[derive(Debug, StructOpt)]
 pub enum Subcommand {
@@ -115,8 +154,6 @@ pub enum Subcommand {
 impl Subcommand {
     /// Executes the active subcommand with the given arguments.
     pub fn run(self, flags: GlobalFlags) -> Result<(), String> {
-        println!("{:?}", flags);
-        println!("{:?}", self);
         match self {
             Subcommand::Build(cmd) => cmd.run(flags),
             Subcommand::Completion(cmd) => cmd.run(flags),
@@ -134,3 +171,76 @@ impl Subcommand {
         }
     }
 }
+
+/// Runs `future` to completion on a new `tokio` runtime.
+fn block_on<F, T>(future: F) -> Result<T, String>
+where
+    F: Future<Output = Result<T, String>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut runtime = Runtime::new().map_err(|e| e.to_string())?;
+    runtime.block_on(future.boxed().compat())
+}
+
+/// Parses a package specifier as a manifest ID.
+///
+/// Packages cannot be looked up by name and version yet, so the specifier must be a full ID.
+fn parse_manifest_id(spec: &str) -> Result<ManifestId, String> {
+    spec.parse().map_err(|_| {
+        let reason = "looking up packages by name is not supported yet";
+        format!("`{}` is not a manifest ID, {}", spec, reason)
+    })
+}
+
+fn parse_manifest_ids(specifiers: &[String]) -> Result<Vec<ManifestId>, String> {
+    specifiers
+        .iter()
+        .map(|spec| parse_manifest_id(spec))
+        .collect()
+}
+
+/// Renders the progress of `build` to stdout until it finishes.
+async fn render_build(
+    mut build: BuildStream,
+    mode: ProgressMode,
+    quiet: bool,
+) -> Result<(), String> {
+    let mut renderer = ProgressRenderer::stdout(mode);
+    let mut result = Ok(());
+
+    while let Some(progress) = await!(build.next()) {
+        match progress {
+            Ok(_) if quiet => {}
+            Ok(progress) => {
+                if let Err(e) = renderer.update(&progress) {
+                    result = Err(e.to_string());
+                    break;
+                }
+            }
+            Err(e) => {
+                result = Err(e.to_string());
+                break;
+            }
+        }
+    }
+
+    renderer.finish().map_err(|e| e.to_string())?;
+    result
+}
+
+/// Prints what `transaction` would do to each package.
+fn print_transaction(transaction: &Transaction) {
+    for (id, action) in &transaction.installed {
+        let verb = match action {
+            Action::Memoize => "present",
+            Action::Substitute => "download",
+            Action::Build => "build",
+            Action::Check => "check",
+        };
+        println!("{:<8} {}", verb, id);
+    }
+
+    for id in &transaction.removed {
+        println!("{:<8} {}", "remove", id);
+    }
+}
@@ -1,17 +1,47 @@
+use std::fs;
 use std::path::PathBuf;
 
+use deck_core::Manifest;
+use deck_store::BuildOptions;
 use structopt::StructOpt;
 
-use super::{CliCommand, GlobalFlags};
+use super::{block_on, render_build, CliCommand, GlobalFlags};
 
 #[derive(Debug, StructOpt)]
 pub struct Build {
+    /// Keep building packages which do not depend on a failed package
+    #[structopt(short = "k", long = "keep-going")]
+    keep_going: bool,
+    /// Rebuild the package even if it is installed, checking that the build is reproducible
+    #[structopt(long = "check")]
+    check: bool,
     #[structopt(parse(from_os_str))]
     manifest: PathBuf,
 }
 
 impl CliCommand for Build {
-    fn run(self, _flags: GlobalFlags) -> Result<(), String> {
-        unimplemented!()
+    fn run(self, flags: GlobalFlags) -> Result<(), String> {
+        let manifest = fs::read_to_string(&self.manifest)
+            .map_err(|e| format!("failed to read {}: {}", self.manifest.display(), e))?;
+        let manifest: Manifest = manifest
+            .parse()
+            .map_err(|e| format!("invalid manifest {}: {}", self.manifest.display(), e))?;
+
+        if flags.dry_run {
+            println!("{}", manifest.compute_id());
+            return Ok(());
+        }
+
+        let options = BuildOptions {
+            keep_going: self.keep_going,
+            check: self.check,
+        };
+
+        let mut client = flags.client()?;
+        block_on(render_build(
+            client.build(manifest, options),
+            flags.progress,
+            flags.quiet,
+        ))
     }
 }
@@ -1,5 +1,6 @@
 use structopt::StructOpt;
 
+use super::{block_on, parse_manifest_ids, print_transaction, render_build};
 use super::{CliCommand, GlobalFlags};
 
 pub const AFTER_HELP: &str = r#"EXAMPLES:
@@ -33,7 +34,28 @@ pub struct Install {
 }
 
 impl CliCommand for Install {
-    fn run(self, _flags: GlobalFlags) -> Result<(), String> {
-        unimplemented!()
+    fn run(self, flags: GlobalFlags) -> Result<(), String> {
+        if self.profile.is_some() {
+            return Err("profiles are not supported yet".to_string());
+        }
+
+        let ids = parse_manifest_ids(&self.packages)?;
+        let mut client = flags.client()?;
+
+        if flags.dry_run {
+            let planning = async move {
+                let transaction = await!(client.plan(&ids, &[])).map_err(|e| e.to_string())?;
+                print_transaction(&transaction);
+                Ok(())
+            };
+
+            return block_on(planning);
+        }
+
+        block_on(render_build(
+            client.install(ids),
+            flags.progress,
+            flags.quiet,
+        ))
     }
 }
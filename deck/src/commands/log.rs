@@ -1,20 +1,53 @@
+use std::io::{self, Write};
+
+use deck_store::LogOptions;
+use futures_preview::stream::StreamExt;
 use structopt::StructOpt;
 
-use super::{CliCommand, GlobalFlags};
+use super::{block_on, parse_manifest_id, CliCommand, GlobalFlags};
 
 pub const AFTER_HELP: &str = r#"EXAMPLES:
     To get the build logs for a package:
-    $ deck log firefox:67.0.0-alpha1@fc3j3vub6kodu4jtfoakfs5xhumqi62m"#;
+    $ deck log firefox:67.0.0-alpha1@fc3j3vub6kodu4jtfoakfs5xhumqi62m
+
+    To show the last 20 lines and keep watching a build in progress:
+    $ deck log --follow --tail 20 firefox:67.0.0-alpha1@fc3j3vub6kodu4jtfoakfs5xhumqi62m"#;
 
 #[derive(Debug, StructOpt)]
 pub struct Log {
+    /// Keep printing new output until the build finishes
+    #[structopt(short = "f", long = "follow")]
+    follow: bool,
+    /// Only print this many lines from the end of the log
+    #[structopt(short = "n", long = "tail", value_name = "LINES")]
+    tail: Option<usize>,
     /// Package manifest specifier
     #[structopt(value_name = "PACKAGE", empty_values = false)]
     manifest_id: String,
 }
 
 impl CliCommand for Log {
-    fn run(self, _flags: GlobalFlags) -> Result<(), String> {
-        unimplemented!()
+    fn run(self, flags: GlobalFlags) -> Result<(), String> {
+        let id = parse_manifest_id(&self.manifest_id)?;
+        let options = LogOptions {
+            offset: 0,
+            tail: self.tail,
+            follow: self.follow,
+        };
+
+        let mut client = flags.client()?;
+        let printing = async move {
+            let mut chunks = client.log(&id, options);
+            let mut stdout = io::stdout();
+            while let Some(chunk) = await!(chunks.next()) {
+                let chunk = chunk.map_err(|e| e.to_string())?;
+                stdout.write_all(&chunk).map_err(|e| e.to_string())?;
+                stdout.flush().map_err(|e| e.to_string())?;
+            }
+
+            Ok(())
+        };
+
+        block_on(printing)
     }
 }
@@ -1,6 +1,6 @@
 use structopt::StructOpt;
 
-use super::{CliCommand, GlobalFlags};
+use super::{block_on, parse_manifest_ids, print_transaction, CliCommand, GlobalFlags};
 
 pub const AFTER_HELP: &str = r#"EXAMPLES:
     To remove the latest version of an installed package:
@@ -33,7 +33,27 @@ pub struct Remove {
 }
 
 impl CliCommand for Remove {
-    fn run(self, _flags: GlobalFlags) -> Result<(), String> {
-        unimplemented!()
+    fn run(self, flags: GlobalFlags) -> Result<(), String> {
+        if self.profile.is_some() {
+            return Err("profiles are not supported yet".to_string());
+        }
+
+        if !flags.dry_run {
+            return Err(
+                "removing packages is not supported until profiles are implemented, use \
+                 `--dry-run` to preview the transaction"
+                    .to_string(),
+            );
+        }
+
+        let ids = parse_manifest_ids(&self.packages)?;
+        let mut client = flags.client()?;
+        let removing = async move {
+            let transaction = await!(client.remove(&ids)).map_err(|e| e.to_string())?;
+            print_transaction(&transaction);
+            Ok(())
+        };
+
+        block_on(removing)
     }
 }
@@ -1,6 +1,8 @@
+use regex::RegexSet;
+use serde_json::json;
 use structopt::StructOpt;
 
-use super::{CliCommand, GlobalFlags};
+use super::{block_on, CliCommand, GlobalFlags};
 
 pub const AFTER_HELP: &str = r#"EXAMPLES:
     To search all repositories for a package containing a substring:
@@ -34,7 +36,46 @@ pub struct Search {
 }
 
 impl CliCommand for Search {
-    fn run(self, _flags: GlobalFlags) -> Result<(), String> {
-        unimplemented!()
+    fn run(self, flags: GlobalFlags) -> Result<(), String> {
+        if self.repo.is_some() {
+            return Err("searching specific repositories is not supported yet".to_string());
+        }
+
+        let patterns = RegexSet::new(&self.patterns).map_err(|e| e.to_string())?;
+        let mut client = flags.client()?;
+        let searching = async move {
+            let ids = await!(client.search(&patterns)).map_err(|e| e.to_string())?;
+
+            if self.json {
+                let packages: Vec<_> = ids
+                    .iter()
+                    .map(|id| {
+                        json!({
+                            "name": id.name(),
+                            "version": id.version(),
+                            "id": id.to_string(),
+                        })
+                    })
+                    .collect();
+                println!("{}", json!(packages));
+            } else if self.recutils {
+                for id in ids {
+                    println!(
+                        "name: {}\nversion: {}\nid: {}\n",
+                        id.name(),
+                        id.version(),
+                        id
+                    );
+                }
+            } else {
+                for id in ids {
+                    println!("{}", id);
+                }
+            }
+
+            Ok(())
+        };
+
+        block_on(searching)
     }
 }
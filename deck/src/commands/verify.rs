@@ -1,6 +1,9 @@
+use deck_store::verify::{Action, Object, Problem, Verified};
+use deck_store::{CheckContents, Repair};
+use futures_preview::stream::StreamExt;
 use structopt::StructOpt;
 
-use super::{CliCommand, GlobalFlags};
+use super::{block_on, CliCommand, GlobalFlags};
 
 pub const AFTER_HELP: &str =
     r#"By default, this will verify the integrity of all elements present in the store.
@@ -17,10 +20,19 @@ EXAMPLES:
     To verify a custom Deck store:
     $ deck verify --store-dir ./my-local-store
 
+    To rehash the contents of the store and repair any corruption:
+    $ deck verify --check-contents --repair
+
 "#;
 
 #[derive(Debug, StructOpt)]
 pub struct Verify {
+    /// Recompute and compare the hashes of the store contents
+    #[structopt(long = "check-contents")]
+    check_contents: bool,
+    /// Repair inconsistencies instead of only reporting them
+    #[structopt(long = "repair")]
+    repair: bool,
     /// Verify package manifests
     #[structopt(long = "manifests")]
     manifests: bool,
@@ -34,8 +46,92 @@ pub struct Verify {
     specifiers: Vec<String>,
 }
 
+impl Verify {
+    /// Returns whether the result for `object` was asked for on the command line.
+    fn includes(&self, object: &Object) -> bool {
+        let any_kind = !(self.manifests || self.outputs || self.sources);
+        let kind = match *object {
+            Object::Manifest(_) => self.manifests,
+            Object::Output(_) => self.outputs,
+            Object::Source(_) => self.sources,
+        };
+
+        let id = object.to_string();
+        (any_kind || kind) && (self.specifiers.is_empty() || self.specifiers.contains(&id))
+    }
+}
+
 impl CliCommand for Verify {
-    fn run(self, _flags: GlobalFlags) -> Result<(), String> {
-        unimplemented!()
+    fn run(self, flags: GlobalFlags) -> Result<(), String> {
+        let check = if self.check_contents {
+            CheckContents::Enabled
+        } else {
+            CheckContents::Disabled
+        };
+        let repair = if self.repair && !flags.dry_run {
+            Repair::Enabled
+        } else {
+            Repair::Disabled
+        };
+
+        let mut client = flags.client()?;
+        let verifying = async move {
+            let mut results = client.verify(check, repair);
+            let mut invalid = 0;
+            while let Some(verified) = await!(results.next()) {
+                let line = match verified.map_err(|e| e.to_string())? {
+                    Verified::Checked(ref object) if !self.includes(object) => continue,
+                    Verified::Checked(object) => {
+                        if flags.verbosity == 0 {
+                            continue;
+                        }
+                        format!("{:<12} {}", "ok", object)
+                    }
+                    Verified::Invalid(ref inv) if !self.includes(&inv.object) => continue,
+                    Verified::Invalid(inv) => {
+                        invalid += 1;
+                        format!("{:<12} {}", describe_problem(inv.problem), inv.object)
+                    }
+                    Verified::Repaired(ref rep) if !self.includes(&rep.object) => continue,
+                    Verified::Repaired(rep) => {
+                        let problem = describe_problem(rep.problem);
+                        let action = describe_action(rep.action);
+                        format!("{:<12} {} ({})", problem, rep.object, action)
+                    }
+                };
+
+                if !flags.quiet {
+                    println!("{}", line);
+                }
+            }
+
+            if invalid > 0 {
+                Err(format!(
+                    "found {} inconsistent objects in the store",
+                    invalid
+                ))
+            } else {
+                Ok(())
+            }
+        };
+
+        block_on(verifying)
+    }
+}
+
+fn describe_problem(problem: Problem) -> &'static str {
+    match problem {
+        Problem::Missing => "missing",
+        Problem::Unregistered => "unregistered",
+        Problem::Corrupted => "corrupted",
+    }
+}
+
+fn describe_action(action: Action) -> &'static str {
+    match action {
+        Action::Registered => "registered",
+        Action::Deregistered => "deregistered",
+        Action::Refetched => "refetched",
+        Action::Quarantined => "quarantined",
     }
 }